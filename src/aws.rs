@@ -1,196 +1,1931 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
 use tokio::process::{Child, Command};
 
-use aws_config::{BehaviorVersion, Region};
+use aws_config::default_provider::region::DefaultRegionChain;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
 use aws_sdk_iotsecuretunneling::{
     Client,
     error::SdkError,
-    types::{ClientMode, DestinationConfig, TunnelStatus},
+    operation::list_tunnels::ListTunnelsError,
+    operation::rotate_tunnel_access_token::{
+        RotateTunnelAccessTokenError, RotateTunnelAccessTokenOutput,
+    },
+    types::{
+        ClientMode, ConnectionStatus, DestinationConfig, TimeoutConfig, Tunnel, TunnelStatus,
+        TunnelSummary,
+    },
+};
+use aws_smithy_http_client::{Connector, proxy::ProxyConfig, tls};
+use aws_smithy_runtime_api::client::http::{SharedHttpClient, SharedHttpConnector, http_client_fn};
+
+use crate::aws_client::{AwsTunnelClient, TunnelClient};
+use crate::cleanup::CleanupGuard;
+use crate::config::{AuthBehavior, MultipleTunnelPolicy, SharedTunnelPolicy, TunnelConfig};
+use crate::device::validate_device_id;
+use crate::error::{TunnelError, TunnelResult, is_missing_credentials, is_transient};
+use crate::localproxy::{
+    AccessToken, OutputTail, Readiness, ServicePortMap, apply_ca_bundle, apply_extra_args,
+    apply_proxy_env, build_localproxy_command, build_templated_command, cached_localproxy_version,
+    resolve_localproxy_region, spawn_error, wait_for_ready,
 };
+#[cfg(feature = "dev-destination")]
+use crate::localproxy::{build_destination_command, destination_targets};
+use crate::orphans::PidFile;
+use crate::ports::allocate_ports;
+use crate::profiles::validate_profile;
+use crate::token_file::{TokenFile, permissions_warning};
+
+/// A running localproxy connected to a device's tunnel
+#[derive(Debug)]
+pub struct TunnelConnection {
+    pub device_id: String,
+    pub tunnel_id: String,
+    /// AWS region of the tunnel, resolved once so restarts and rotations use the same one
+    /// even if the profile changes. A token file connection has localproxy's region.
+    pub region: String,
+    /// How the tunnel was found or opened
+    pub action: ConnectAction,
+    pub child: Child,
+    /// Local ports localproxy listens on for each service
+    pub services: ServicePortMap,
+    /// Whether localproxy confirmed the tunnel within the readiness timeout
+    pub readiness: Readiness,
+    /// Marks localproxy as ours until the connection is dropped
+    pub pid_file: Option<PidFile>,
+    /// When the running localproxy was started, so a restart resets it
+    pub started_at: Instant,
+    /// Problems worth telling the operator about that didn't stop the connection
+    pub warnings: Vec<String>,
+    /// localproxy's latest output, for explaining why it exited
+    pub output: OutputTail,
+    /// Whether connecting needed a fresh AWS login
+    pub credentials_refreshed: bool,
+    /// When `max_session_duration` runs out, if there is a limit
+    pub expires_at: Option<Instant>,
+    /// When AWS closes the tunnel at the end of its lifetime, once it has been described
+    pub tunnel_expires_at: Option<SystemTime>,
+    /// Destination-mode localproxy standing in for the device, in `dev-destination` builds
+    pub test_destination: Option<Child>,
+    /// Closes a tunnel opened for this connection if it's dropped before being disarmed.
+    /// Last, so localproxy is killed before its tunnel is closed.
+    pub cleanup: CleanupGuard,
+}
+
+/// A tunnel and the access tokens for its ends
+#[derive(Debug, Clone)]
+pub struct TunnelTokens {
+    pub tunnel_id: String,
+    pub source_token: String,
+    /// `None` when no destination token was issued, as when only the source token was rotated
+    pub destination_token: Option<String>,
+}
+
+/// Longest tunnel lifetime AWS accepts, 12 hours
+pub const MAX_TUNNEL_LIFETIME_MINUTES: u32 = 720;
+
+/// What connecting did to get a tunnel for the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectAction {
+    /// An open tunnel was reused with a rotated source token
+    ReusedExisting,
+    /// The device's old tunnels were closed and a new one opened
+    ClosedStaleAndOpened,
+    /// The device had no tunnels, so one was opened
+    OpenedNew,
+    /// A token exported on another machine was used, without calling AWS
+    FromTokenFile,
+}
 
-use crate::error::{TunnelError, TunnelResult};
+impl ConnectAction {
+    /// Whether a new tunnel was opened for this connection
+    pub fn opened_tunnel(self) -> bool {
+        matches!(
+            self,
+            ConnectAction::ClosedStaleAndOpened | ConnectAction::OpenedNew
+        )
+    }
+}
+
+impl fmt::Display for ConnectAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectAction::ReusedExisting => "Reused the open tunnel",
+            ConnectAction::ClosedStaleAndOpened => "Closed old tunnels and opened a new one",
+            ConnectAction::OpenedNew => "Opened a new tunnel",
+            ConnectAction::FromTokenFile => "Connected with a token file",
+        })
+    }
+}
+
+/// Tunnel selected for a device and the source token to connect with
+#[derive(Debug)]
+pub struct DeviceTunnel {
+    pub tunnel_id: String,
+    pub src_token: String,
+    /// Only issued when a tunnel is opened or both its tokens are rotated
+    pub dst_token: Option<String>,
+    /// How the tunnel was found or opened
+    pub action: ConnectAction,
+    /// Whether the credentials had expired and were renewed by logging in
+    pub credentials_refreshed: bool,
+}
 
-const PROFILE: &str = "iotmgmt_prod";
 const REGION: &str = "eu-west-1";
 
-async fn open_tunnel(client: &Client, device_id: &str) -> TunnelResult<(String, String, String)> {
-    let dest = DestinationConfig::builder()
-        .thing_name(device_id)
-        .services("GORT")
-        .services("SSH")
+/// How often the tunnel is checked while waiting for the device to connect
+pub const DESTINATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A freshly opened tunnel's token can take a moment to propagate, so localproxy
+/// gets a few attempts before the failure is treated as genuine
+const FRESH_TUNNEL_ATTEMPTS: u32 = 3;
+const FRESH_TUNNEL_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// First wait before retrying a reused tunnel's token rotation, growing with each retry
+const ROTATE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// A tunnel listed as neither open nor closed is described again this many times,
+/// this far apart, for it to settle before it is left alone
+const TRANSITION_CHECKS: u32 = 3;
+const TRANSITION_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Destination for a device's tunnel, checking the services against the AWS limits first
+///
+/// AWS accepts a destination without a thing name, but then no device is told about
+/// the tunnel, so an empty thing name is an error here.
+pub fn build_destination_config(
+    thing_name: &str,
+    services: &ServicePortMap,
+) -> TunnelResult<DestinationConfig> {
+    if thing_name.trim().is_empty() {
+        return Err(TunnelError::tunnel_operation(
+            "A tunnel destination needs a device ID",
+        ));
+    }
+    services.validate()?;
+    DestinationConfig::builder()
+        .thing_name(thing_name)
+        .set_services(Some(services.services().map(String::from).collect()))
         .build()
-        .expect("Failed to build DestinationConfig for tunnel");
+        .map_err(|e| TunnelError::tunnel_operation(format!("Invalid tunnel destination: {}", e)))
+}
+
+/// Open a tunnel to the thing, with tokens for both ends
+async fn open_tunnel(
+    client: &dyn TunnelClient,
+    thing_name: &str,
+    services: &ServicePortMap,
+    timeout: Option<TimeoutConfig>,
+    description: String,
+) -> TunnelResult<TunnelTokens> {
+    let dest = build_destination_config(thing_name, services)?;
 
     let tokens = client
-        .open_tunnel()
-        .destination_config(dest)
-        .send()
+        .open_tunnel_with_config(dest, timeout, Some(description))
         .await
-        .map_err(|err| TunnelError::tunnel_operation(format!("Failed to open tunnel: {}", err)))?;
+        .map_err(|err| TunnelError::sdk_request("Failed to open tunnel", err))?;
 
-    let tunnel_id = tokens.tunnel_id().unwrap().to_string();
-    let src_token = tokens.source_access_token().unwrap().to_string();
-    let dst_token = tokens.destination_access_token().unwrap().to_string();
+    let tunnel_id = tokens
+        .tunnel_id()
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| TunnelError::tunnel_operation("No tunnel ID returned for the new tunnel"))?
+        .to_string();
+    let source_token = required_token(tokens.source_access_token(), "source", &tunnel_id)?;
+    let destination_token =
+        required_token(tokens.destination_access_token(), "destination", &tunnel_id)?;
 
-    Ok((tunnel_id, src_token, dst_token))
+    Ok(TunnelTokens {
+        tunnel_id,
+        source_token,
+        destination_token: Some(destination_token),
+    })
 }
 
-async fn aws_sso_login() -> TunnelResult<()> {
+/// Run `aws sso login` for an AWS profile
+pub async fn aws_sso_login(profile: &str) -> TunnelResult<()> {
     let output = Command::new("aws")
-        .args(["sso", "login", "--profile", PROFILE])
+        .args(["sso", "login", "--profile", profile])
+        .kill_on_drop(true)
         .output()
         .await
-        .map_err(|e| {
-            TunnelError::aws_auth(format!("Failed to execute aws sso login command: {}", e))
-        })?;
+        .map_err(|e| aws_cli_error(e, "execute aws sso login command"))?;
 
     if output.status.success() {
         Ok(())
     } else {
-        Err(TunnelError::aws_auth(
+        Err(sso_login_error(&output.stderr))
+    }
+}
+
+/// Longest part of the AWS CLI's output quoted in an error
+const MAX_CLI_MESSAGE_CHARS: usize = 500;
+
+/// Unbroken run of token characters long enough to be a credential rather than a word
+const MIN_TOKEN_CHARS: usize = 32;
+
+/// Error for a failed `aws sso login`, quoting what the AWS CLI said on stderr
+///
+/// The message is trimmed, anything that looks like a token is replaced with
+/// `<redacted>` and long output is cut short. Without any output it falls back
+/// to asking the operator to log in themselves.
+pub fn sso_login_error(stderr: &[u8]) -> TunnelError {
+    let message = cli_message(&String::from_utf8_lossy(stderr));
+    if message.is_empty() {
+        TunnelError::aws_auth(
             "Failed to execute aws sso login command. Please authenticate with aws-cli: aws sso login.",
+        )
+    } else {
+        TunnelError::aws_auth(format!("aws sso login failed: {}", message))
+    }
+}
+
+fn cli_message(output: &str) -> String {
+    let message = output
+        .split_whitespace()
+        .map(|word| {
+            if looks_like_token(word) {
+                "<redacted>"
+            } else {
+                word
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    match message.char_indices().nth(MAX_CLI_MESSAGE_CHARS) {
+        Some((end, _)) => format!("{}…", &message[..end]),
+        None => message,
+    }
+}
+
+/// A long run of base64 or JWT characters mixing upper and lower case and digits,
+/// which host names and paths don't
+fn looks_like_token(word: &str) -> bool {
+    word.split(|c: char| !(c.is_ascii_alphanumeric() || "+/=_-.".contains(c)))
+        .any(|run| {
+            run.len() >= MIN_TOKEN_CHARS
+                && run.contains(|c: char| c.is_ascii_uppercase())
+                && run.contains(|c: char| c.is_ascii_lowercase())
+                && run.contains(|c: char| c.is_ascii_digit())
+        })
+}
+
+/// ARN of the identity the AWS profile's credentials belong to
+///
+/// Uses `aws sts get-caller-identity`, so it also confirms the AWS CLI used for
+/// logging in is installed.
+pub async fn caller_identity(profile: &str) -> TunnelResult<String> {
+    let output = Command::new("aws")
+        .args(["sts", "get-caller-identity", "--profile", profile])
+        .args(["--query", "Arn", "--output", "text"])
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| aws_cli_error(e, "run the AWS CLI"))?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        Err(TunnelError::aws_auth(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
         ))
     }
 }
 
-async fn start_localproxy_for_source(region: &str, src_token: &str) -> Result<Child, String> {
-    let output = Command::new("localproxy")
-        .current_dir("assets")
-        .args(["-r", region])
-        .args(["-s", "SSH=2222,GORT=5555"])
-        .args(["-b", "0.0.0.0"])
-        // .args(["-t", &src_token])
-        .env("AWSIOT_TUNNEL_ACCESS_TOKEN", src_token)
-        .spawn()
-        .expect("Failed to execute localproxy command");
+/// Map a failure to run the AWS CLI, calling out a missing binary specifically
+pub fn aws_cli_error(err: io::Error, action: &str) -> TunnelError {
+    if err.kind() == io::ErrorKind::NotFound {
+        TunnelError::AwsCliMissing
+    } else {
+        TunnelError::aws_auth(format!("Failed to {}: {}", action, err))
+    }
+}
 
-    Ok(output)
+/// Run `aws sso login`, giving up if the operator hasn't finished within `timeout`
+pub async fn aws_sso_login_with_timeout(profile: &str, timeout: Duration) -> TunnelResult<()> {
+    tokio::time::timeout(timeout, aws_sso_login(profile))
+        .await
+        .map_err(|_| {
+            TunnelError::aws_auth(format!(
+                "aws sso login did not complete within {} seconds",
+                timeout.as_secs()
+            ))
+        })?
 }
 
-async fn rotate_access_tokens(
-    client: &Client,
-    device_id: &str,
+/// When connecting last launched `aws sso login`, shared by every connect a manager makes
+#[derive(Debug, Clone, Default)]
+pub struct LoginCooldown {
+    last_attempt: Arc<Mutex<Option<Instant>>>,
+}
+
+impl LoginCooldown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a login attempt, unless one was made within `cooldown`, in which case
+    /// return how long until the next is allowed
+    pub fn try_start(&self, cooldown: Duration) -> Result<(), Duration> {
+        let mut last_attempt = self.last_attempt.lock().unwrap();
+        let now = Instant::now();
+        if let Some(last) = *last_attempt {
+            let elapsed = now.duration_since(last);
+            if elapsed < cooldown {
+                return Err(cooldown - elapsed);
+            }
+        }
+        *last_attempt = Some(now);
+        Ok(())
+    }
+
+    /// Allow the next automatic login straight away, such as after the operator logged in
+    pub fn reset(&self) {
+        *self.last_attempt.lock().unwrap() = None;
+    }
+}
+
+/// Run `aws sso login` for a connect, at most once per `sso_login_cooldown`
+///
+/// Within the cooldown the credentials are still broken after logging in, so
+/// another login would only open the browser again. The error asks the operator
+/// to check the profile instead.
+pub async fn automatic_sso_login(
+    config: &TunnelConfig,
+    cooldown: &LoginCooldown,
+) -> TunnelResult<()> {
+    if let Err(remaining) = cooldown.try_start(config.sso_login_cooldown) {
+        return Err(TunnelError::aws_auth(format!(
+            "AWS credentials for profile '{}' are still invalid after logging in. Check the profile in ~/.aws/config and log in from the app; connecting won't open the browser again for {} minutes.",
+            config.profile,
+            remaining.as_secs().div_ceil(60)
+        )));
+    }
+    aws_sso_login_with_timeout(&config.profile, config.sso_login_timeout).await
+}
+
+async fn start_localproxy_for_source(
+    region: &str,
+    services: &ServicePortMap,
+    src_token: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<Child> {
+    services.validate()?;
+    // A wrapper's own version says nothing about the localproxy inside it
+    let program = match &config.localproxy_command {
+        Some(template) if !template.runs_localproxy() => None,
+        Some(template) => Some(template.program.as_str()),
+        None => Some("localproxy"),
+    };
+    if let Some(program) = program {
+        if let Some(version) = cached_localproxy_version(program).await {
+            version.require_supported()?;
+        }
+    }
+    let mut command = match &config.localproxy_command {
+        Some(template) => build_templated_command(template, region, services, src_token),
+        None => build_localproxy_command(region, services, src_token),
+    };
+    apply_extra_args(&mut command, &config.extra_localproxy_args)?;
+    apply_proxy_env(&mut command, &config.proxy);
+    apply_ca_bundle(&mut command, &config.proxy);
+    tracing::debug!("Starting {}", command);
+    command.spawn().map_err(spawn_error)
+}
+
+/// Issue new access tokens for an open tunnel
+///
+/// `ClientMode::Source` leaves the device connected and returns no destination
+/// token; `ClientMode::All` also makes the device reconnect. Rotating only the
+/// destination is rejected, since it leaves no token to connect with. Takes the
+/// thing name, which `TunnelConfig::thing_name` gives for a device ID.
+pub async fn rotate_tunnel_tokens_with_client(
+    client: &dyn TunnelClient,
+    thing_name: &str,
     tunnel_id: &str,
-) -> Result<(String, String), String> {
-    let dest = DestinationConfig::builder()
-        .thing_name(device_id)
-        .services("GORT")
-        .services("SSH")
-        .build()
-        .expect("Failed to build DestinationConfig for tunnel");
+    services: &ServicePortMap,
+    client_mode: ClientMode,
+) -> TunnelResult<TunnelTokens> {
+    if client_mode == ClientMode::Destination {
+        return Err(TunnelError::config(
+            "Rotating only the destination token leaves no source token to connect with; rotate all tokens instead",
+        ));
+    }
+    let dest = build_destination_config(thing_name, services)?;
 
     let response = client
-        .rotate_tunnel_access_token()
-        .tunnel_id(tunnel_id)
-        .client_mode(ClientMode::All)
-        .destination_config(dest)
-        .send()
+        .rotate_tunnel_tokens(tunnel_id, client_mode, dest)
         .await
-        .map_err(|e| {
-            format!(
-                "Failed to rotate access tokens for tunnel {}: {}",
-                tunnel_id, e
-            )
-        })?;
+        .map_err(|err| rotation_error(tunnel_id, err))?;
+    rotated_tokens(tunnel_id, &response)
+}
+
+/// Rotate the tokens of an open tunnel being reused, retrying transient failures up
+/// to `retries` times
+///
+/// `None` if AWS no longer has the tunnel, so a new one can be opened in its place.
+/// Any other failure is returned once the retries run out, rather than opening a
+/// second tunnel next to one that is still there.
+async fn rotate_reused_tunnel(
+    client: &dyn TunnelClient,
+    thing_name: &str,
+    tunnel_id: &str,
+    services: &ServicePortMap,
+    client_mode: ClientMode,
+    retries: u32,
+) -> TunnelResult<Option<TunnelTokens>> {
+    let dest = build_destination_config(thing_name, services)?;
+    let mut retry = 0;
+    loop {
+        let err = match client
+            .rotate_tunnel_tokens(tunnel_id, client_mode.clone(), dest.clone())
+            .await
+        {
+            Ok(response) => return rotated_tokens(tunnel_id, &response).map(Some),
+            Err(err) => err,
+        };
+        if matches!(&err, SdkError::ServiceError(context) if context.err().is_resource_not_found_exception())
+        {
+            tracing::info!("Tunnel {} closed before its tokens were rotated", tunnel_id);
+            return Ok(None);
+        }
+        if retry == retries || !is_transient(&err) {
+            return Err(rotation_error(tunnel_id, err));
+        }
+        retry += 1;
+        tracing::warn!(
+            "{} (retry {}/{})",
+            rotation_error(tunnel_id, err),
+            retry,
+            retries
+        );
+        tokio::time::sleep(ROTATE_RETRY_DELAY * retry).await;
+    }
+}
+
+fn rotation_error(tunnel_id: &str, err: SdkError<RotateTunnelAccessTokenError>) -> TunnelError {
+    TunnelError::sdk_request(
+        format!("Failed to rotate access tokens for tunnel {}", tunnel_id),
+        err,
+    )
+}
 
-    let src_token = response.source_access_token().unwrap().to_string();
-    let dst_token = response.destination_access_token().unwrap().to_string();
+fn rotated_tokens(
+    tunnel_id: &str,
+    response: &RotateTunnelAccessTokenOutput,
+) -> TunnelResult<TunnelTokens> {
+    Ok(TunnelTokens {
+        tunnel_id: tunnel_id.to_string(),
+        source_token: required_token(response.source_access_token(), "source", tunnel_id)?,
+        destination_token: response
+            .destination_access_token()
+            .filter(|token| !token.is_empty())
+            .map(String::from),
+    })
+}
+
+/// `rotate_tunnel_tokens_with_client` using the configured AWS profile and the
+/// device's services
+pub async fn rotate_tunnel_tokens(
+    device_id: &str,
+    tunnel_id: &str,
+    client_mode: ClientMode,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelTokens> {
+    validate_device_id(device_id)?;
+    let services = resolve_device_services(device_id, config).await;
+    let client = AwsTunnelClient::new(get_client(config).await?);
+    let thing_name = resolve_thing_name(device_id, config);
+    rotate_tunnel_tokens_with_client(&client, &thing_name, tunnel_id, &services, client_mode).await
+}
 
-    Ok((src_token, dst_token))
+/// A token AWS returned, treating an empty one as missing since localproxy rejects it
+fn required_token(token: Option<&str>, end: &str, tunnel_id: &str) -> TunnelResult<String> {
+    match token {
+        Some("") => Err(TunnelError::tunnel_operation(format!(
+            "Received empty {} access token for tunnel {}",
+            end, tunnel_id
+        ))),
+        Some(token) => Ok(token.to_string()),
+        None => Err(TunnelError::tunnel_operation(format!(
+            "No {} access token returned for tunnel {}",
+            end, tunnel_id
+        ))),
+    }
 }
 
-async fn open_tunnel_for_device(
-    client: &Client,
+/// Get a tunnel to a device and the tokens for both ends, without starting localproxy
+///
+/// An open tunnel is reused with both tokens rotated, which keeps its original
+/// lifetime; otherwise a tunnel is opened that AWS closes after `lifetime_minutes`.
+/// For running your own localproxy or handing the tokens to another machine.
+/// Takes the thing name, which `TunnelConfig::thing_name` gives for a device ID, and
+/// the description for a new tunnel, from `TunnelConfig::tunnel_description`.
+pub async fn open_only_with_client(
+    client: &dyn TunnelClient,
+    thing_name: &str,
+    lifetime_minutes: u32,
+    services: &ServicePortMap,
+    description: String,
+) -> TunnelResult<TunnelTokens> {
+    if !(1..=MAX_TUNNEL_LIFETIME_MINUTES).contains(&lifetime_minutes) {
+        return Err(TunnelError::config(format!(
+            "Tunnel lifetime must be between 1 and {} minutes, got {}",
+            MAX_TUNNEL_LIFETIME_MINUTES, lifetime_minutes
+        )));
+    }
+    services.validate()?;
+
+    let tunnels = list_thing_tunnels(client, thing_name)
+        .await
+        .map_err(|err| TunnelError::sdk_request("Failed to list tunnels", err))?;
+    let open = tunnels
+        .iter()
+        .find(|tunnel| tunnel.status == Some(TunnelStatus::Open));
+
+    if let Some(TunnelInfo { tunnel_id, .. }) = open {
+        return rotate_tunnel_tokens_with_client(
+            client,
+            thing_name,
+            tunnel_id,
+            services,
+            ClientMode::All,
+        )
+        .await;
+    }
+
+    let timeout = TimeoutConfig::builder()
+        .max_lifetime_timeout_minutes(lifetime_minutes as i32)
+        .build();
+    open_tunnel(client, thing_name, services, Some(timeout), description).await
+}
+
+/// `open_only_with_client` using the configured AWS profile
+pub async fn open_only(
     device_id: &str,
-) -> Result<(String, String), String> {
-    match client.list_tunnels().thing_name(device_id).send().await {
-        Ok(response) => {
-            if let Some(tunnel_summaries) = response.tunnel_summaries {
-                if tunnel_summaries.is_empty() {
-                    println!("No tunnels found for device ID: {}", device_id)
-                }
-                // Return first valid tunnel ID
-                for tunnel in &tunnel_summaries {
-                    if *tunnel.status().unwrap() == TunnelStatus::Open {
-                        if tunnel.tunnel_id.is_some() {
-                            let tunnel_id = tunnel.tunnel_id.clone().unwrap();
-                            println!(
-                                "Not Opening a new tunnel. There is a tunnel {} for {} with status {}",
-                                tunnel_id,
-                                device_id,
-                                tunnel.status().unwrap()
-                            );
-                            let (src_token, _) =
-                                rotate_access_tokens(client, device_id, &tunnel_id)
-                                    .await
-                                    .map_err(|_| "Failed to rotate access tokens".to_string())?;
-
-                            return Ok((tunnel_id, src_token));
-                        }
-                    } else {
-                        println!("Deleting tunnel: {:?}", tunnel);
-                        client
-                            .close_tunnel()
-                            .tunnel_id(tunnel.tunnel_id.clone().unwrap())
-                            .send()
-                            .await
-                            .map_err(|e| format!("Failed to close tunnel: {}", e))?;
-
-                        continue;
-                    }
+    lifetime_minutes: u32,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelTokens> {
+    validate_device_id(device_id)?;
+    let client = AwsTunnelClient::new(get_client(config).await?);
+    let thing_name = resolve_thing_name(device_id, config);
+    open_only_with_client(
+        &client,
+        &thing_name,
+        lifetime_minutes,
+        services,
+        config.tunnel_description(device_id),
+    )
+    .await
+}
+
+/// Find an open tunnel for a device, closing any stale ones, or open a new one
+pub async fn open_tunnel_for_device(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<DeviceTunnel> {
+    services.validate()?;
+    let thing_name = resolve_thing_name(device_id, config);
+    let tunnels = list_thing_tunnels(client, &thing_name)
+        .await
+        .map_err(|err| list_tunnels_error(err, config))?;
+    if tunnels.is_empty() {
+        tracing::info!("No tunnels found for device ID: {}", device_id);
+    }
+
+    let open_ids: Vec<&str> = tunnels
+        .iter()
+        .filter(|tunnel| tunnel.status == Some(TunnelStatus::Open))
+        .map(|tunnel| tunnel.tunnel_id.as_str())
+        .collect();
+    if open_ids.len() > 1 {
+        if config.multiple_open_tunnels == MultipleTunnelPolicy::Error {
+            return Err(TunnelError::tunnel_operation(format!(
+                "{} has {} open tunnels ({}). Close the ones not in use, or set multiple_open_tunnels to reuse one.",
+                device_id,
+                open_ids.len(),
+                open_ids.join(", ")
+            )));
+        }
+        tracing::warn!(
+            "{} has {} open tunnels: {}",
+            device_id,
+            open_ids.len(),
+            open_ids.join(", ")
+        );
+    }
+
+    let mut closed_any = false;
+    for tunnel in &tunnels {
+        let tunnel_id = tunnel.tunnel_id.as_str();
+        let status = match &tunnel.status {
+            Some(status @ (TunnelStatus::Open | TunnelStatus::Closed)) => status.clone(),
+            _ => match settled_status(client, tunnel_id).await {
+                Some(status) => status,
+                None => {
+                    tracing::warn!(
+                        "Tunnel {} for {} is still changing state, leaving it alone",
+                        tunnel_id,
+                        device_id
+                    );
+                    continue;
                 }
+            },
+        };
+        let open = status == TunnelStatus::Open;
+        if open && !config.rotate_on_reuse {
+            return Err(TunnelError::tunnel_operation(format!(
+                "Tunnel {} for {} is already open and rotate_on_reuse is off. AWS doesn't return an open tunnel's tokens, so connect with a token file from whatever manages them, or turn rotate_on_reuse on.",
+                tunnel_id, device_id
+            )));
+        }
+        if open && source_in_use(client, device_id, tunnel_id, config).await? {
+            tracing::info!(
+                "Tunnel {} for {} is in use, leaving it open and opening another",
+                tunnel_id,
+                device_id
+            );
+            continue;
+        }
+        if open {
+            tracing::info!(
+                "Not Opening a new tunnel. There is a tunnel {} for {} with status {}",
+                tunnel_id,
+                device_id,
+                TunnelStatus::Open
+            );
+            let client_mode = if config.rotate_destination_on_reuse {
+                ClientMode::All
             } else {
-                println!("No tunnels found for device ID: {}", device_id);
+                ClientMode::Source
+            };
+            let Some(tokens) = rotate_reused_tunnel(
+                client,
+                &thing_name,
+                tunnel_id,
+                services,
+                client_mode,
+                config.rotate_retries,
+            )
+            .await?
+            else {
+                continue;
+            };
+
+            if config.multiple_open_tunnels == MultipleTunnelPolicy::ReuseFirstCloseOthers {
+                // Open tunnels listed before this one are in use and were left alone
+                let later = open_ids
+                    .iter()
+                    .position(|id| *id == tunnel_id)
+                    .map_or(open_ids.len(), |reused| reused + 1);
+                close_extra_tunnels(client, device_id, &open_ids[later..], config).await;
+            }
+
+            return Ok(DeviceTunnel {
+                tunnel_id: tokens.tunnel_id,
+                src_token: tokens.source_token,
+                dst_token: tokens.destination_token,
+                action: ConnectAction::ReusedExisting,
+                credentials_refreshed: false,
+            });
+        }
+
+        tracing::info!("Deleting tunnel: {:?}", tunnel);
+        client
+            .close_tunnel_by_id(tunnel_id)
+            .await
+            .map_err(|err| TunnelError::sdk_request("Failed to close tunnel", err))?;
+        closed_any = true;
+    }
+
+    let description = config.tunnel_description(device_id);
+    let tokens = open_tunnel(client, &thing_name, services, None, description).await?;
+
+    Ok(DeviceTunnel {
+        tunnel_id: tokens.tunnel_id,
+        src_token: tokens.source_token,
+        dst_token: tokens.destination_token,
+        action: if closed_any {
+            ConnectAction::ClosedStaleAndOpened
+        } else {
+            ConnectAction::OpenedNew
+        },
+        credentials_refreshed: false,
+    })
+}
+
+/// Close open tunnels left over besides the one reused
+///
+/// One with a client connected to its source end is left open unless `shared_tunnel`
+/// is `Proceed`, as for the tunnel reused. The connect has already succeeded, so a
+/// tunnel that fails to close is only logged.
+async fn close_extra_tunnels(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    tunnel_ids: &[&str],
+    config: &TunnelConfig,
+) {
+    for tunnel_id in tunnel_ids {
+        if config.shared_tunnel != SharedTunnelPolicy::Proceed
+            && source_connected(client, tunnel_id).await
+        {
+            tracing::info!(
+                "Extra tunnel {} for {} is in use, leaving it open",
+                tunnel_id,
+                device_id
+            );
+            continue;
+        }
+        match client.close_tunnel_by_id(tunnel_id).await {
+            Ok(_) => tracing::info!("Closed extra tunnel {} for {}", tunnel_id, device_id),
+            Err(err) => tracing::warn!(
+                "Failed to close extra tunnel {} for {}: {}",
+                tunnel_id,
+                device_id,
+                TunnelError::sdk_request("Failed to close tunnel", err)
+            ),
+        }
+    }
+}
+
+/// Describe a tunnel listed in a transitional or unrecognised state until it reads
+/// as open or closed
+///
+/// `None` if it hasn't settled after `TRANSITION_CHECKS`, or can't be described; such
+/// a tunnel may be on its way to open, so it is neither reused nor closed.
+async fn settled_status(client: &dyn TunnelClient, tunnel_id: &str) -> Option<TunnelStatus> {
+    for check in 0..TRANSITION_CHECKS {
+        if check > 0 {
+            tokio::time::sleep(TRANSITION_CHECK_INTERVAL).await;
+        }
+        let response = match client.describe_tunnel_by_id(tunnel_id).await {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!("Could not check the state of tunnel {}: {}", tunnel_id, err);
+                return None;
             }
+        };
+        match response.tunnel().and_then(|tunnel| tunnel.status()) {
+            Some(status @ (TunnelStatus::Open | TunnelStatus::Closed)) => {
+                return Some(status.clone());
+            }
+            status => tracing::debug!("Tunnel {} is {:?}, checking again", tunnel_id, status),
+        }
+    }
+    None
+}
+
+/// The AWS IoT thing name for a device ID, logged when the two differ
+fn resolve_thing_name(device_id: &str, config: &TunnelConfig) -> String {
+    let thing_name = config.thing_name(device_id);
+    if thing_name != device_id {
+        tracing::info!("Device {} is AWS IoT thing {}", device_id, thing_name);
+    }
+    thing_name
+}
+
+/// Which tunnels a bulk close applies to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunnelFilter {
+    /// Only tunnels with this status, or any status when `None`
+    pub status: Option<TunnelStatus>,
+    /// Only tunnels to devices whose ID starts with this
+    pub device_prefix: Option<String>,
+}
 
-            let (tunnel_id, src_token, _) = open_tunnel(client, device_id)
+/// A tunnel to a device, as listed by `list_tunnels`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelInfo {
+    pub tunnel_id: String,
+    /// `None` if AWS didn't say. Unlike a missing ID this doesn't drop the tunnel, since
+    /// connecting describes such a tunnel until it settles rather than ignoring it
+    pub status: Option<TunnelStatus>,
+    pub created_at: Option<SystemTime>,
+}
+
+impl TunnelInfo {
+    /// The summary as a `TunnelInfo`, or `None` without a tunnel ID
+    pub fn from_summary(summary: &TunnelSummary) -> Option<Self> {
+        Some(Self {
+            tunnel_id: summary.tunnel_id().filter(|id| !id.is_empty())?.to_string(),
+            status: summary.status().cloned(),
+            created_at: summary
+                .created_at()
+                .and_then(|created_at| SystemTime::try_from(*created_at).ok()),
+        })
+    }
+}
+
+/// The tunnels to a device that AWS still lists, open or recently closed, following
+/// the pages of results
+pub async fn list_tunnels(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<Vec<TunnelInfo>> {
+    validate_device_id(device_id)?;
+    let thing_name = resolve_thing_name(device_id, config);
+    list_thing_tunnels(client, &thing_name)
+        .await
+        .map_err(|err| list_tunnels_error(err, config))
+}
+
+/// Every tunnel to an AWS IoT thing, following the pages of results
+async fn list_thing_tunnels(
+    client: &dyn TunnelClient,
+    thing_name: &str,
+) -> Result<Vec<TunnelInfo>, SdkError<ListTunnelsError>> {
+    let mut tunnels = Vec::new();
+    let mut next_token = None;
+    loop {
+        let response = client
+            .list_tunnels_for_thing_page(thing_name, next_token)
+            .await?;
+        next_token = response
+            .next_token()
+            .filter(|t| !t.is_empty())
+            .map(String::from);
+        // A missing list and an empty one both mean the thing has no tunnels
+        tunnels.extend(
+            response
+                .tunnel_summaries()
+                .iter()
+                .filter_map(TunnelInfo::from_summary),
+        );
+        if next_token.is_none() {
+            return Ok(tunnels);
+        }
+    }
+}
+
+/// Every tunnel in the account, following the pages of results
+pub async fn list_all_tunnels(client: &dyn TunnelClient) -> TunnelResult<Vec<TunnelSummary>> {
+    let mut tunnels = Vec::new();
+    let mut next_token = None;
+    loop {
+        let response = client
+            .list_tunnels_page(next_token)
+            .await
+            .map_err(|err| TunnelError::sdk_request("Failed to list tunnels", err))?;
+        next_token = response
+            .next_token()
+            .filter(|t| !t.is_empty())
+            .map(String::from);
+        tunnels.extend(response.tunnel_summaries.unwrap_or_default());
+        if next_token.is_none() {
+            return Ok(tunnels);
+        }
+    }
+}
+
+/// IDs of the tunnels `filter` matches
+///
+/// Tunnel summaries don't name the device, so a device prefix means describing
+/// each tunnel that has the right status.
+pub async fn find_tunnels_matching(
+    client: &dyn TunnelClient,
+    filter: &TunnelFilter,
+) -> TunnelResult<Vec<String>> {
+    let mut matching = Vec::new();
+    for tunnel in list_all_tunnels(client).await? {
+        let Some(tunnel_id) = tunnel.tunnel_id() else {
+            continue;
+        };
+        if filter
+            .status
+            .as_ref()
+            .is_some_and(|status| tunnel.status() != Some(status))
+        {
+            continue;
+        }
+        if let Some(prefix) = &filter.device_prefix {
+            let response = client
+                .describe_tunnel_by_id(tunnel_id)
                 .await
-                .map_err(|e| format!("Failed to open tunnel: {}", e))?;
+                .map_err(|err| {
+                    TunnelError::sdk_request(
+                        format!("Failed to describe tunnel {}", tunnel_id),
+                        err,
+                    )
+                })?;
+            let device = response
+                .tunnel()
+                .and_then(|tunnel| tunnel.destination_config())
+                .and_then(|config| config.thing_name());
+            if !device.is_some_and(|device| device.starts_with(prefix.as_str())) {
+                continue;
+            }
+        }
+        matching.push(tunnel_id.to_string());
+    }
+    Ok(matching)
+}
+
+/// Close tunnels by ID, stopping at the first that fails
+pub async fn close_tunnels(
+    client: &dyn TunnelClient,
+    tunnel_ids: &[String],
+) -> TunnelResult<Vec<String>> {
+    let mut closed = Vec::new();
+    for tunnel_id in tunnel_ids {
+        client.close_tunnel_by_id(tunnel_id).await.map_err(|err| {
+            TunnelError::sdk_request(
+                format!(
+                    "Closed {} of {} tunnels, then failed to close {}",
+                    closed.len(),
+                    tunnel_ids.len(),
+                    tunnel_id
+                ),
+                err,
+            )
+        })?;
+        closed.push(tunnel_id.clone());
+    }
+    Ok(closed)
+}
+
+/// Close every tunnel `filter` matches, returning the IDs closed
+pub async fn close_tunnels_matching(
+    client: &dyn TunnelClient,
+    filter: &TunnelFilter,
+) -> TunnelResult<Vec<String>> {
+    let tunnel_ids = find_tunnels_matching(client, filter).await?;
+    close_tunnels(client, &tunnel_ids).await
+}
+
+/// What closing a device's tunnels did
+#[derive(Debug, Default)]
+pub struct DeviceTunnelsClosed {
+    /// IDs of the tunnels closed
+    pub closed: Vec<String>,
+    /// Tunnels that failed to close, and why
+    pub failed: Vec<(String, TunnelError)>,
+}
+
+/// Close every tunnel to a device that isn't closed already
+///
+/// Unlike `close_tunnels` this carries on past tunnels that fail to close, collecting
+/// each, and only fails if none of them could be closed. Returns the tunnels closed and
+/// those that failed rather than just a count, so a bulk close can say which are still
+/// open; `closed.len()` is the count.
+pub async fn close_all_tunnels_for_device(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<DeviceTunnelsClosed> {
+    let open: Vec<String> = list_tunnels(client, device_id, config)
+        .await?
+        .into_iter()
+        .filter(|tunnel| tunnel.status != Some(TunnelStatus::Closed))
+        .map(|tunnel| tunnel.tunnel_id)
+        .collect();
 
-            Ok((tunnel_id, src_token))
+    let mut result = DeviceTunnelsClosed::default();
+    for tunnel_id in open {
+        match client.close_tunnel_by_id(&tunnel_id).await {
+            Ok(_) => result.closed.push(tunnel_id),
+            Err(err) => {
+                let error = TunnelError::sdk_request(
+                    format!("Failed to close tunnel {} for {}", tunnel_id, device_id),
+                    err,
+                );
+                tracing::warn!("{}", error);
+                result.failed.push((tunnel_id, error));
+            }
+        }
+    }
+    if !result.closed.is_empty() || result.failed.is_empty() {
+        return Ok(result);
+    }
+    let attempted = result.failed.len();
+    let (tunnel_id, error) = result.failed.swap_remove(0);
+    Err(TunnelError::aws_request(
+        format!(
+            "Closed 0 of {} tunnels for {}; the first to fail was {}",
+            attempted, device_id, tunnel_id
+        ),
+        error,
+    ))
+}
+
+/// Whether someone else is connected to the source end of a tunnel about to be reused
+///
+/// Only answers `true` under `SharedTunnelPolicy::ForceNew`, meaning the tunnel should be
+/// left to whoever is using it and another opened; under `Ask` a busy tunnel is an error
+/// so the operator can choose. A failed check is treated as not in use, since rotating
+/// the tokens will report any real problem.
+async fn source_in_use(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    tunnel_id: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<bool> {
+    if config.shared_tunnel == SharedTunnelPolicy::Proceed {
+        return Ok(false);
+    }
+
+    match (
+        source_connected(client, tunnel_id).await,
+        config.shared_tunnel,
+    ) {
+        (false, _) => Ok(false),
+        (true, SharedTunnelPolicy::ForceNew) => Ok(true),
+        (true, _) => Err(TunnelError::TunnelInUse {
+            device_id: device_id.to_string(),
+            tunnel_id: tunnel_id.to_string(),
+        }),
+    }
+}
+
+/// Whether a client is connected to the source end of a tunnel, `false` if it can't be
+/// checked
+async fn source_connected(client: &dyn TunnelClient, tunnel_id: &str) -> bool {
+    match client.describe_tunnel_by_id(tunnel_id).await {
+        Ok(response) => {
+            response
+                .tunnel()
+                .and_then(|tunnel| tunnel.source_connection_state())
+                .and_then(|state| state.status())
+                == Some(&ConnectionStatus::Connected)
         }
         Err(err) => {
-            if let SdkError::DispatchFailure(_) = err {
-                match aws_sso_login().await {
-                    Ok(_) => {
-                        return Err(String::from("Login successful, please try again."));
-                        // Retry the operation after successful login
-                        // return get_open_tunnels_for_device(client, device_id).await;
+            tracing::warn!("Could not check who is using tunnel {}: {}", tunnel_id, err);
+            false
+        }
+    }
+}
+
+/// Explain a failure to list a device's tunnels
+///
+/// A dispatch failure usually means there are no valid credentials, unless an
+/// outbound proxy is configured and couldn't be reached.
+fn list_tunnels_error(err: SdkError<ListTunnelsError>, config: &TunnelConfig) -> TunnelError {
+    if let SdkError::DispatchFailure(failure) = &err {
+        // Logging in can't help until a profile is set up
+        if is_missing_credentials(&err) {
+            return TunnelError::NoCredentials;
+        }
+        if let Some(proxy) = config.proxy.https_proxy() {
+            if failure.is_io() || failure.is_timeout() {
+                return TunnelError::sdk_request(
+                    format!(
+                        "Could not reach AWS through the proxy at {}. Check the proxy is running and reachable.",
+                        proxy
+                    ),
+                    err,
+                );
+            }
+        }
+        return TunnelError::aws_auth_from(
+            "Authentication required. Use 'Log in to AWS' and try again.",
+            err,
+        );
+    }
+    TunnelError::sdk_request("Failed to list tunnels", err)
+}
+
+/// Open the device's tunnel, logging in and retrying once if the credentials expired
+///
+/// The retry goes through a client from `refresh`, since the first client keeps
+/// the credentials it was built with. If the retry still isn't authorized, the error
+/// is `StillUnauthorized`, carrying the retry's failure, rather than asking for
+/// another login. With `AuthBehavior::Manual` the authentication error is returned
+/// for the operator to log in from the UI.
+pub async fn open_tunnel_with_login<L, R>(
+    client: &dyn TunnelClient,
+    login: L,
+    refresh: R,
+    device_id: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<DeviceTunnel>
+where
+    L: AsyncFnOnce() -> TunnelResult<()>,
+    R: AsyncFnOnce() -> TunnelResult<Box<dyn TunnelClient>>,
+{
+    match open_tunnel_for_device(client, device_id, services, config).await {
+        Err(TunnelError::AwsAuth { .. }) if config.auth_behavior == AuthBehavior::Automatic => {
+            login().await?;
+            let client = refresh().await?;
+            tracing::info!("AWS credentials refreshed");
+            let tunnel =
+                match open_tunnel_for_device(client.as_ref(), device_id, services, config).await {
+                    Err(e @ TunnelError::AwsAuth { .. }) => {
+                        return Err(TunnelError::StillUnauthorized {
+                            profile: config.profile.clone(),
+                            source: Box::new(e),
+                        });
                     }
-                    Err(e) => return Err(e.to_string()),
+                    result => result?,
+                };
+            Ok(DeviceTunnel {
+                credentials_refreshed: true,
+                ..tunnel
+            })
+        }
+        result => result,
+    }
+}
+
+pub async fn connect_to_tunnel(
+    device_id: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelConnection> {
+    let services = resolve_device_services(device_id, config).await;
+    let services = allocate_ports(config.port_allocation, &services, &HashSet::new())?;
+
+    let mut connection =
+        connect_with_services(device_id, &services, config, &LoginCooldown::new()).await?;
+    std::mem::take(&mut connection.cleanup).disarm();
+    Ok(connection)
+}
+
+/// Connect to a device with localproxy listening on already allocated ports
+///
+/// Logging in when the credentials have expired is limited by `login_cooldown`.
+/// The connection's `cleanup` is left armed for the caller to disarm once it has
+/// kept the connection, as with `start_connection`.
+pub async fn connect_with_services(
+    device_id: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+    login_cooldown: &LoginCooldown,
+) -> TunnelResult<TunnelConnection> {
+    services.validate()?;
+    validate_profile(&config.profile)?;
+    let client = get_client(config).await?;
+    let mut warnings = Vec::new();
+    let region = tunnel_region(config, &mut warnings).await?;
+    let proxy_region = resolve_localproxy_region(&region, &config.localproxy_region_overrides)?;
+
+    let tunnel_client = AwsTunnelClient::new(client);
+    let tunnel = open_tunnel_with_login(
+        &tunnel_client,
+        async || automatic_sso_login(config, login_cooldown).await,
+        async || {
+            let client = refresh_credentials(config).await?;
+            Ok(Box::new(AwsTunnelClient::new(client)) as Box<dyn TunnelClient>)
+        },
+        device_id,
+        services,
+        config,
+    )
+    .await?;
+    tracing::info!(
+        "{} {} for device {}",
+        tunnel.action,
+        tunnel.tunnel_id,
+        device_id
+    );
+
+    let close_config = config.clone();
+    let mut connection = start_connection(
+        device_id,
+        tunnel,
+        &region,
+        &proxy_region,
+        services,
+        config,
+        move |tunnel_id| async move { close_tunnel(&tunnel_id, &close_config).await },
+    )
+    .await?;
+    warnings.append(&mut connection.warnings);
+    connection.warnings = warnings;
+    // Only feeds the countdown, so the next session check can fill it in instead
+    connection.tunnel_expires_at =
+        match tunnel_expiry_with_client(&tunnel_client, &connection.tunnel_id).await {
+            Ok(expiry) => expiry,
+            Err(e) => {
+                tracing::debug!(
+                    "Couldn't read when tunnel {} expires: {}",
+                    connection.tunnel_id,
+                    e
+                );
+                None
+            }
+        };
+    Ok(connection)
+}
+
+/// Start localproxy on a tunnel already found or opened for the device
+///
+/// A tunnel opened for this connect is handed to `close` if localproxy doesn't
+/// start, or later if the connection is dropped while its `cleanup` is still armed,
+/// so whoever keeps the connection disarms it; a reused tunnel was there before
+/// and stays open.
+pub async fn start_connection<C, F>(
+    device_id: &str,
+    tunnel: DeviceTunnel,
+    region: &str,
+    proxy_region: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+    close: C,
+) -> TunnelResult<TunnelConnection>
+where
+    C: FnOnce(String) -> F + Send + 'static,
+    F: Future<Output = TunnelResult<()>> + Send + 'static,
+{
+    let mut cleanup = CleanupGuard::new();
+    if tunnel.action.opened_tunnel() {
+        let tunnel_id = tunnel.tunnel_id.clone();
+        cleanup.defer_async(async move {
+            match close(tunnel_id.clone()).await {
+                Ok(()) => tracing::info!("Closed tunnel {} after the connect failed", tunnel_id),
+                Err(e) => tracing::warn!(
+                    "Failed to close tunnel {} after the connect failed: {}",
+                    tunnel_id,
+                    e
+                ),
+            }
+        });
+    }
+
+    let attempts = if tunnel.action.opened_tunnel() {
+        FRESH_TUNNEL_ATTEMPTS
+    } else {
+        1
+    };
+    let mut attempt = 1;
+    let (child, readiness, pid_file, output) = loop {
+        match start_localproxy(device_id, proxy_region, services, &tunnel.src_token, config).await {
+            Ok(started) => break started,
+            // A handshake that timed out already waited long enough
+            Err(e) if attempt < attempts && !matches!(e, TunnelError::Connection { .. }) => {
+                tracing::warn!(
+                    "localproxy could not connect to new tunnel {} (attempt {}/{}), retrying: {}",
+                    tunnel.tunnel_id,
+                    attempt,
+                    attempts,
+                    e
+                );
+                attempt += 1;
+                tokio::time::sleep(FRESH_TUNNEL_RETRY_DELAY).await;
+            }
+            Err(e) => {
+                if attempts > 1 {
+                    tracing::warn!("localproxy failed to start after {} attempts", attempts);
                 }
+                return Err(e);
             }
-            Err(format!("Failed to list tunnels: {}", err))
         }
+    };
+
+    #[cfg_attr(not(feature = "dev-destination"), allow(unused_mut))]
+    let mut warnings = Vec::new();
+    #[cfg(feature = "dev-destination")]
+    let test_destination = if config.dev_destination.enabled {
+        match start_test_destination(proxy_region, services, tunnel.dst_token.as_deref(), config)
+            .await
+        {
+            Ok(child) => Some(child),
+            Err(e) => {
+                let warning = format!("The test destination didn't start: {}", e);
+                tracing::warn!("{}", warning);
+                warnings.push(warning);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(not(feature = "dev-destination"))]
+    let test_destination = None;
+
+    Ok(TunnelConnection {
+        device_id: device_id.to_string(),
+        tunnel_id: tunnel.tunnel_id,
+        region: region.to_string(),
+        action: tunnel.action,
+        child,
+        services: services.clone(),
+        readiness,
+        pid_file,
+        started_at: Instant::now(),
+        warnings,
+        output,
+        credentials_refreshed: tunnel.credentials_refreshed,
+        expires_at: (!config.max_session_duration.is_zero())
+            .then(|| Instant::now() + config.max_session_duration),
+        tunnel_expires_at: None,
+        test_destination,
+        cleanup,
+    })
+}
+
+/// Stand in for the device with a destination-mode localproxy on this machine, for
+/// testing a whole tunnel without hardware
+///
+/// The destination token is only known for a tunnel this connect opened, or one
+/// reused with `rotate_destination_on_reuse`.
+#[cfg(feature = "dev-destination")]
+async fn start_test_destination(
+    region: &str,
+    services: &ServicePortMap,
+    dst_token: Option<&str>,
+    config: &TunnelConfig,
+) -> TunnelResult<Child> {
+    let dst_token = dst_token.ok_or_else(|| {
+        TunnelError::localproxy_startup(
+            "No destination token for the reused tunnel. Set rotate_destination_on_reuse = true to get one.",
+        )
+    })?;
+    let targets = destination_targets(services, &config.dev_destination.targets)?;
+    let mut command = build_destination_command(region, &targets, dst_token);
+    apply_proxy_env(&mut command, &config.proxy);
+    apply_ca_bundle(&mut command, &config.proxy);
+    let output = OutputTail::default().redacting(command.token());
+    tracing::debug!("Starting {}", command);
+    let mut child = command.spawn().map_err(spawn_error)?;
+    tracing::warn!("Started a test destination localproxy in place of the device");
+    wait_for_ready(&mut child, config.ready_timeout, &output).await?;
+    Ok(child)
+}
+
+/// Open a tunnel with `open_only` and write what a machine without AWS access needs
+/// to connect to it with `connect_from_token_file`
+///
+/// The region is the one this machine's profile resolves to for localproxy.
+pub async fn export_token_file(
+    device_id: &str,
+    lifetime_minutes: u32,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+    path: &Path,
+) -> TunnelResult<TokenFile> {
+    let region = localproxy_region(config, &mut Vec::new()).await?;
+    let tokens = open_only(device_id, lifetime_minutes, services, config).await?;
+    let file = TokenFile {
+        device_id: device_id.to_string(),
+        tunnel_id: tokens.tunnel_id,
+        region,
+        source_token: tokens.source_token,
+        services: services.clone(),
+    };
+    file.write(path)?;
+    Ok(file)
+}
+
+/// Start localproxy with a token file from `export_token_file`, making no AWS calls
+pub async fn connect_from_token_file(
+    path: &Path,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelConnection> {
+    let file = TokenFile::read(path)?;
+    let warnings: Vec<String> = permissions_warning(path).into_iter().collect();
+    for warning in &warnings {
+        tracing::warn!("{}", warning);
     }
+    validate_device_id(&file.device_id)?;
+    file.services.validate()?;
+
+    let (child, readiness, pid_file, output) = start_localproxy(
+        &file.device_id,
+        &file.region,
+        &file.services,
+        &file.source_token,
+        config,
+    )
+    .await?;
+
+    Ok(TunnelConnection {
+        device_id: file.device_id,
+        tunnel_id: file.tunnel_id,
+        region: file.region,
+        action: ConnectAction::FromTokenFile,
+        child,
+        services: file.services,
+        readiness,
+        pid_file,
+        started_at: Instant::now(),
+        warnings,
+        output,
+        credentials_refreshed: false,
+        expires_at: (!config.max_session_duration.is_zero())
+            .then(|| Instant::now() + config.max_session_duration),
+        tunnel_expires_at: None,
+        test_destination: None,
+        cleanup: CleanupGuard::new(),
+    })
 }
 
-pub async fn connect_to_tunnel(device_id: &str) -> Result<Child, String> {
-    let client = get_client().await?;
-    let region = client
-        .config()
-        .region()
-        .unwrap_or(&Region::from_static(REGION))
-        .to_string();
+/// Issue a new source token for a connected tunnel once the credentials were refreshed
+///
+/// Always `ClientMode::Source`, whatever `rotate_destination_on_reuse` says: the
+/// device's end of the tunnel is still connected, and rotating its token too would
+/// make it reconnect.
+pub async fn refresh_source_token_with_client(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    tunnel_id: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<String> {
+    let thing_name = resolve_thing_name(device_id, config);
+    rotate_tunnel_tokens_with_client(client, &thing_name, tunnel_id, services, ClientMode::Source)
+        .await
+        .map(|tokens| tokens.source_token)
+}
 
-    match open_tunnel_for_device(&client, device_id).await {
-        Ok((tunnel_id, src_token)) => {
-            println!("Tunnel {} open for device {}", tunnel_id, device_id);
-            let child = start_localproxy_for_source(&region, &src_token)
-                .await
-                .map_err(|e| format!("Failed to start localproxy: {}", e))?;
+/// `refresh_source_token_with_client` with a client that picks up the latest login,
+/// in the region the tunnel was opened in
+pub async fn refresh_source_token(
+    device_id: &str,
+    tunnel_id: &str,
+    region: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<String> {
+    let client = AwsTunnelClient::new(get_client_in_region(config, region).await?);
+    refresh_source_token_with_client(&client, device_id, tunnel_id, services, config).await
+}
+
+/// Restart a connection's localproxy with a new source token, on the same tunnel, region
+/// and ports
+///
+/// Only this end reconnects; the device keeps its connection to the tunnel.
+pub async fn restart_localproxy(
+    connection: &mut TunnelConnection,
+    src_token: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<()> {
+    let restart = LocalproxyRestart::stop(connection, config).await?;
+    restart.start(src_token, config).await?.install(connection);
+    Ok(())
+}
+
+/// A connection's localproxy restart, split so the connection needn't stay borrowed
+/// while the new localproxy starts
+#[derive(Debug)]
+pub struct LocalproxyRestart {
+    device_id: String,
+    /// Region localproxy connects to, after any overrides
+    region: String,
+    services: ServicePortMap,
+}
+
+impl LocalproxyRestart {
+    /// Stop the connection's localproxy, keeping what starting its replacement needs
+    ///
+    /// The connection is left with an exited localproxy until `install` replaces it.
+    pub async fn stop(
+        connection: &mut TunnelConnection,
+        config: &TunnelConfig,
+    ) -> TunnelResult<Self> {
+        let region =
+            resolve_localproxy_region(&connection.region, &config.localproxy_region_overrides)?;
+        connection
+            .child
+            .kill()
+            .await
+            .map_err(|e| TunnelError::disconnection(&connection.device_id, e.to_string()))?;
+        connection.pid_file = None;
+        Ok(Self {
+            device_id: connection.device_id.clone(),
+            region,
+            services: connection.services.clone(),
+        })
+    }
+
+    /// Start the replacement localproxy on the same region and ports
+    pub async fn start(
+        self,
+        src_token: &str,
+        config: &TunnelConfig,
+    ) -> TunnelResult<RestartedLocalproxy> {
+        let (child, readiness, pid_file, output) = start_localproxy(
+            &self.device_id,
+            &self.region,
+            &self.services,
+            src_token,
+            config,
+        )
+        .await?;
+        Ok(RestartedLocalproxy {
+            child,
+            readiness,
+            pid_file,
+            output,
+        })
+    }
+}
+
+/// A localproxy started by `LocalproxyRestart`, killed if dropped before it's installed
+#[derive(Debug)]
+pub struct RestartedLocalproxy {
+    child: Child,
+    readiness: Readiness,
+    pid_file: Option<PidFile>,
+    output: OutputTail,
+}
+
+impl RestartedLocalproxy {
+    /// Make this the connection's localproxy
+    pub fn install(self, connection: &mut TunnelConnection) {
+        connection.child = self.child;
+        connection.readiness = self.readiness;
+        connection.pid_file = self.pid_file;
+        connection.started_at = Instant::now();
+        connection.output = self.output;
+        connection.credentials_refreshed = true;
+    }
+}
+
+/// Region localproxy connects to for the configured profile, noting a fallback in `warnings`
+async fn localproxy_region(
+    config: &TunnelConfig,
+    warnings: &mut Vec<String>,
+) -> TunnelResult<String> {
+    let region = tunnel_region(config, warnings).await?;
+    resolve_localproxy_region(&region, &config.localproxy_region_overrides)
+}
+
+/// AWS region tunnels are opened in for the configured profile, noting a fallback in
+/// `warnings`
+async fn tunnel_region(config: &TunnelConfig, warnings: &mut Vec<String>) -> TunnelResult<String> {
+    Ok(match resolve_region(config).await {
+        Some(region) => region,
+        None if config.strict_region => {
+            return Err(TunnelError::aws_config(format!(
+                "No AWS region is configured for profile {}. Set one in the AWS config or the region setting.",
+                config.profile
+            )));
+        }
+        None => {
+            let warning = format!(
+                "No AWS region is configured for profile {}, falling back to {}",
+                config.profile, REGION
+            );
+            tracing::warn!("{}", warning);
+            warnings.push(warning);
+            REGION.to_string()
+        }
+    })
+}
+
+/// Close a tunnel so neither end can use it again
+pub async fn close_tunnel(tunnel_id: &str, config: &TunnelConfig) -> TunnelResult<()> {
+    let client = AwsTunnelClient::new(get_client(config).await?);
+    client
+        .close_tunnel_by_id(tunnel_id)
+        .await
+        .map_err(|err| TunnelError::sdk_request("Failed to close tunnel", err))?;
+
+    Ok(())
+}
+
+/// `close_tunnel` in the region the tunnel was opened in
+pub async fn close_tunnel_in_region(
+    tunnel_id: &str,
+    region: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<()> {
+    let client = AwsTunnelClient::new(get_client_in_region(config, region).await?);
+    client
+        .close_tunnel_by_id(tunnel_id)
+        .await
+        .map_err(|err| TunnelError::sdk_request("Failed to close tunnel", err))?;
+
+    Ok(())
+}
+
+/// Whether the device has connected its end of the tunnel
+async fn destination_connected(client: &dyn TunnelClient, tunnel_id: &str) -> TunnelResult<bool> {
+    let response = client
+        .describe_tunnel_by_id(tunnel_id)
+        .await
+        .map_err(|err| {
+            TunnelError::sdk_request(format!("Failed to describe tunnel {}", tunnel_id), err)
+        })?;
 
-            Ok(child)
+    let status = response
+        .tunnel()
+        .and_then(|tunnel| tunnel.destination_connection_state())
+        .and_then(|state| state.status());
+    Ok(status == Some(&ConnectionStatus::Connected))
+}
+
+/// Wait for the device to connect its end of the tunnel
+///
+/// Returns `false` if it hasn't connected within `timeout`, which usually means the
+/// device is offline. Dropping the future cancels the wait.
+pub async fn wait_for_destination(
+    client: &dyn TunnelClient,
+    tunnel_id: &str,
+    timeout: Duration,
+    poll_interval: Duration,
+) -> TunnelResult<bool> {
+    let poll = async {
+        loop {
+            match destination_connected(client, tunnel_id).await {
+                Ok(false) => tokio::time::sleep(poll_interval).await,
+                result => return result,
+            }
         }
-        Err(e) => Err(format!("Error retrieving tunnels: {}", e)),
+    };
+
+    match tokio::time::timeout(timeout, poll).await {
+        Ok(result) => result,
+        Err(_) => Ok(false),
     }
 }
 
-pub async fn get_client() -> Result<Client, String> {
-    let config = aws_config::defaults(BehaviorVersion::latest())
-        .profile_name(PROFILE)
-        .region(Region::new(REGION))
-        .load()
-        .await;
+/// When AWS closes a tunnel, from when it was created and its maximum lifetime
+///
+/// A tunnel opened without a timeout gets AWS's default lifetime of
+/// `MAX_TUNNEL_LIFETIME_MINUTES`. `None` if the description has no creation time.
+pub fn tunnel_expiry(tunnel: &Tunnel) -> Option<SystemTime> {
+    let created_at = SystemTime::try_from(*tunnel.created_at()?).ok()?;
+    let minutes = tunnel
+        .timeout_config()
+        .and_then(|timeout| timeout.max_lifetime_timeout_minutes())
+        .map_or(MAX_TUNNEL_LIFETIME_MINUTES, |minutes| minutes.max(0) as u32);
+    Some(created_at + Duration::from_secs(u64::from(minutes) * 60))
+}
+
+/// Describe a tunnel to find when AWS closes it
+pub async fn tunnel_expiry_with_client(
+    client: &dyn TunnelClient,
+    tunnel_id: &str,
+) -> TunnelResult<Option<SystemTime>> {
+    let response = client
+        .describe_tunnel_by_id(tunnel_id)
+        .await
+        .map_err(|err| {
+            TunnelError::sdk_request(format!("Failed to describe tunnel {}", tunnel_id), err)
+        })?;
+    Ok(response.tunnel().and_then(tunnel_expiry))
+}
+
+/// Open a new tunnel to a device in place of one nearing the end of its lifetime
+///
+/// AWS can't extend a tunnel's lifetime, so a long session has to move to a new one.
+/// The device gets the new destination token through IoT as for any new tunnel, and
+/// reconnects. The old tunnel is left open for the caller to close once localproxy
+/// has moved to the new one.
+pub async fn reopen_tunnel_with_client(
+    client: &dyn TunnelClient,
+    device_id: &str,
+    tunnel_id: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelTokens> {
+    let thing_name = resolve_thing_name(device_id, config);
+    let description = config.tunnel_description(device_id);
+    let tokens = open_tunnel(client, &thing_name, services, None, description).await?;
+    tracing::info!(
+        "Opened tunnel {} for {} in place of expiring tunnel {}",
+        tokens.tunnel_id,
+        device_id,
+        tunnel_id
+    );
+    Ok(tokens)
+}
+
+/// `reopen_tunnel_with_client` with a client that picks up the latest login, in the
+/// region the tunnel was opened in
+pub async fn reopen_tunnel(
+    device_id: &str,
+    tunnel_id: &str,
+    region: &str,
+    services: &ServicePortMap,
+    config: &TunnelConfig,
+) -> TunnelResult<TunnelTokens> {
+    let client = AwsTunnelClient::new(get_client_in_region(config, region).await?);
+    reopen_tunnel_with_client(&client, device_id, tunnel_id, services, config).await
+}
+
+/// Confirm a tunnel is still open using the current credentials, returning when AWS
+/// closes it
+///
+/// `region` is the one the tunnel was opened in, whatever the config says now.
+/// Fails with `TunnelError::AwsAuth` once the SSO session has expired. Nothing here
+/// touches localproxy, so the SSH session survives until the operator logs in again.
+pub async fn check_tunnel_session(
+    tunnel_id: &str,
+    region: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<Option<SystemTime>> {
+    let client = get_client_in_region(config, region).await?;
+    let response = client
+        .describe_tunnel()
+        .tunnel_id(tunnel_id)
+        .send()
+        .await
+        .map_err(|err| match &err {
+            // Credential failures are dispatch failures too, so keep network trouble apart
+            SdkError::DispatchFailure(failure) if failure.is_io() || failure.is_timeout() => {
+                TunnelError::connection(format!("Could not reach AWS: {}", err))
+            }
+            _ => TunnelError::from(err),
+        })?;
+
+    match response.tunnel().and_then(|tunnel| tunnel.status()) {
+        Some(TunnelStatus::Closed) => Err(TunnelError::tunnel_operation(format!(
+            "Tunnel {} has been closed",
+            tunnel_id
+        ))),
+        _ => Ok(response.tunnel().and_then(tunnel_expiry)),
+    }
+}
+
+/// Start localproxy and wait for it to report the tunnel is established
+async fn start_localproxy(
+    device_id: &str,
+    region: &str,
+    services: &ServicePortMap,
+    src_token: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<(Child, Readiness, Option<PidFile>, OutputTail)> {
+    let mut child = start_localproxy_for_source(region, services, src_token, config).await?;
+    // Written before waiting, so a crash from here on still leaves a trace
+    let pid_file = child.id().and_then(|pid| {
+        PidFile::create(pid, device_id)
+            .map_err(|e| tracing::warn!("Failed to write localproxy pid file: {}", e))
+            .ok()
+    });
+    let output = OutputTail::for_services(services).redacting(&AccessToken::new(src_token));
+    if config.handshake_timeout.is_zero() {
+        let readiness = wait_for_ready(&mut child, config.ready_timeout, &output).await?;
+        return Ok((child, readiness, pid_file, output));
+    }
+
+    // A running localproxy whose handshake with AWS never completes would look
+    // connected without working, so give up on it instead. Drops after this are
+    // left to the connection monitor.
+    match wait_for_ready(&mut child, config.handshake_timeout, &output).await? {
+        Readiness::Ready => Ok((child, Readiness::Ready, pid_file, output)),
+        Readiness::TimedOut => {
+            if let Err(e) = child.kill().await {
+                tracing::warn!(
+                    "Failed to stop localproxy after its handshake timed out: {}",
+                    e
+                );
+            }
+            Err(TunnelError::connection(format!(
+                "Proxy failed to establish tunnel connection within {} seconds. The network to AWS may be unreliable; try again.",
+                config.handshake_timeout.as_secs()
+            )))
+        }
+    }
+}
+
+/// Resolve the services for a device: its profile, then the thing attributes, then the defaults
+pub async fn resolve_device_services(device_id: &str, config: &TunnelConfig) -> ServicePortMap {
+    if let Some(services) = config.device_services(device_id) {
+        return services.clone();
+    }
+
+    if config.discover_service_ports {
+        match discover_service_ports(device_id, config).await {
+            Ok(services) => return services,
+            Err(e) => tracing::warn!(
+                "Failed to discover service ports for {}, using defaults: {}",
+                device_id,
+                e
+            ),
+        }
+    }
+
+    config.services.clone()
+}
+
+async fn discover_service_ports(
+    device_id: &str,
+    config: &TunnelConfig,
+) -> TunnelResult<ServicePortMap> {
+    let defaults = &config.services;
+    let thing = get_iot_client(config)
+        .await?
+        .describe_thing()
+        .thing_name(resolve_thing_name(device_id, config))
+        .send()
+        .await?;
+
+    Ok(match thing.attributes() {
+        Some(attributes) => defaults.with_attribute_overrides(attributes),
+        None => defaults.clone(),
+    })
+}
+
+/// Build an HTTPS client that sends SDK requests through the proxy and trusts the CA bundle
+fn sdk_http_client(
+    https_proxy: Option<&str>,
+    no_proxy: Option<String>,
+    ca_bundle: Option<Vec<u8>>,
+) -> TunnelResult<SharedHttpClient> {
+    let proxy = https_proxy
+        .map(|https_proxy| {
+            let proxy = ProxyConfig::https(https_proxy).map_err(|e| {
+                TunnelError::config(format!("Invalid proxy URL '{}': {}", https_proxy, e))
+            })?;
+            Ok::<_, TunnelError>(match no_proxy {
+                Some(rules) => proxy.no_proxy(rules),
+                None => proxy,
+            })
+        })
+        .transpose()?;
+    let tls_context = match ca_bundle {
+        // The bundle is trusted alongside the platform roots, not instead of them
+        Some(pem) => tls::TlsContext::builder()
+            .with_trust_store(tls::TrustStore::default().with_pem_certificate(pem))
+            .build()
+            .map_err(|e| TunnelError::config(format!("Unusable CA bundle: {}", e)))?,
+        None => tls::TlsContext::default(),
+    };
+
+    Ok(http_client_fn(move |settings, components| {
+        let mut builder = Connector::builder().connector_settings(settings.clone());
+        if let Some(proxy) = &proxy {
+            builder = builder.proxy_config(proxy.clone());
+        }
+        if let Some(sleep) = components.sleep_impl() {
+            builder = builder.sleep_impl(sleep);
+        }
+        let connector = builder
+            .tls_provider(tls::Provider::Rustls(
+                tls::rustls_provider::CryptoMode::AwsLc,
+            ))
+            .tls_context(tls_context.clone())
+            .build();
+        SharedHttpConnector::new(connector)
+    }))
+}
+
+/// Region set for an AWS profile in the environment or the AWS config, if any
+pub async fn configured_region(profile: &str) -> Option<String> {
+    DefaultRegionChain::builder()
+        .profile_name(profile)
+        .build()
+        .region()
+        .await
+        .map(|region| region.to_string())
+}
+
+/// The configured `region`, else the profile's region
+pub async fn resolve_region(config: &TunnelConfig) -> Option<String> {
+    match &config.region {
+        Some(region) => Some(region.clone()),
+        None => configured_region(&config.profile).await,
+    }
+}
+
+async fn load_sdk_config(config: &TunnelConfig) -> TunnelResult<SdkConfig> {
+    let region = resolve_region(config)
+        .await
+        .unwrap_or_else(|| REGION.to_string());
+    load_sdk_config_in_region(config, region).await
+}
+
+async fn load_sdk_config_in_region(
+    config: &TunnelConfig,
+    region: String,
+) -> TunnelResult<SdkConfig> {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest())
+        .profile_name(&config.profile)
+        .region(Region::new(region));
+    let https_proxy = config.proxy.https_proxy();
+    let ca_bundle = config.proxy.read_ca_bundle()?;
+    if https_proxy.is_some() || ca_bundle.is_some() {
+        loader = loader.http_client(sdk_http_client(
+            https_proxy.as_deref(),
+            config.proxy.no_proxy(),
+            ca_bundle,
+        )?);
+    }
+
+    Ok(loader.load().await)
+}
+
+pub async fn get_client(config: &TunnelConfig) -> TunnelResult<Client> {
+    Ok(Client::new(&load_sdk_config(config).await?))
+}
+
+/// A client for a region already resolved, such as a connected tunnel's
+pub async fn get_client_in_region(config: &TunnelConfig, region: &str) -> TunnelResult<Client> {
+    Ok(Client::new(
+        &load_sdk_config_in_region(config, region.to_string()).await?,
+    ))
+}
+
+/// Build a client that picks up credentials from a login made since the last one
+///
+/// Clients hold on to the credentials they resolved, so call this after
+/// `aws sso login` rather than reusing an existing client.
+pub async fn refresh_credentials(config: &TunnelConfig) -> TunnelResult<Client> {
+    get_client(config).await
+}
 
-    Ok(Client::new(&config))
+/// Create an AWS IoT client for thing metadata lookups
+pub async fn get_iot_client(config: &TunnelConfig) -> TunnelResult<aws_sdk_iot::Client> {
+    Ok(aws_sdk_iot::Client::new(&load_sdk_config(config).await?))
 }
@@ -3,24 +3,42 @@ use aws_sdk_iotsecuretunneling::{
     error::SdkError,
     operation::{
         close_tunnel::{CloseTunnelError, CloseTunnelOutput},
+        describe_tunnel::{DescribeTunnelError, DescribeTunnelOutput},
         list_tunnels::{ListTunnelsError, ListTunnelsOutput},
         open_tunnel::{OpenTunnelError, OpenTunnelOutput},
         rotate_tunnel_access_token::{RotateTunnelAccessTokenError, RotateTunnelAccessTokenOutput},
     },
-    types::{ClientMode, DestinationConfig},
+    types::{ClientMode, DestinationConfig, TimeoutConfig},
 };
 
+use crate::rate_limit::RateLimiter;
+
 /// Trait for AWS IoT Secure Tunneling operations to enable mocking
 #[async_trait]
 pub trait TunnelClient: Send + Sync {
-    async fn list_tunnels_for_thing(
+    /// One page of the tunnels to a thing, continuing from `next_token`
+    async fn list_tunnels_for_thing_page(
         &self,
         thing_name: &str,
+        next_token: Option<String>,
+    ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>>;
+
+    /// One page of every tunnel in the account, continuing from `next_token`
+    async fn list_tunnels_page(
+        &self,
+        next_token: Option<String>,
     ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>>;
 
+    /// Open a tunnel, with AWS's default 12 hour lifetime unless `timeout_config` is set
+    ///
+    /// `description` is shown for the tunnel in the AWS console.
+    /// OpenTunnel has no client mode: it always issues both tokens and notifies the device
+    /// named in `dest_config`. Which side gets a new token is only chosen when rotating.
     async fn open_tunnel_with_config(
         &self,
         dest_config: DestinationConfig,
+        timeout_config: Option<TimeoutConfig>,
+        description: Option<String>,
     ) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>>;
 
     async fn rotate_tunnel_tokens(
@@ -34,6 +52,11 @@ pub trait TunnelClient: Send + Sync {
         &self,
         tunnel_id: &str,
     ) -> Result<CloseTunnelOutput, SdkError<CloseTunnelError>>;
+
+    async fn describe_tunnel_by_id(
+        &self,
+        tunnel_id: &str,
+    ) -> Result<DescribeTunnelOutput, SdkError<DescribeTunnelError>>;
 }
 
 /// Real AWS client implementation
@@ -49,13 +72,26 @@ impl AwsTunnelClient {
 
 #[async_trait]
 impl TunnelClient for AwsTunnelClient {
-    async fn list_tunnels_for_thing(
+    async fn list_tunnels_for_thing_page(
         &self,
         thing_name: &str,
+        next_token: Option<String>,
     ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
         self.client
             .list_tunnels()
             .thing_name(thing_name)
+            .set_next_token(next_token)
+            .send()
+            .await
+    }
+
+    async fn list_tunnels_page(
+        &self,
+        next_token: Option<String>,
+    ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
+        self.client
+            .list_tunnels()
+            .set_next_token(next_token)
             .send()
             .await
     }
@@ -63,10 +99,14 @@ impl TunnelClient for AwsTunnelClient {
     async fn open_tunnel_with_config(
         &self,
         dest_config: DestinationConfig,
+        timeout_config: Option<TimeoutConfig>,
+        description: Option<String>,
     ) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>> {
         self.client
             .open_tunnel()
             .destination_config(dest_config)
+            .set_timeout_config(timeout_config)
+            .set_description(description)
             .send()
             .await
     }
@@ -92,11 +132,111 @@ impl TunnelClient for AwsTunnelClient {
     ) -> Result<CloseTunnelOutput, SdkError<CloseTunnelError>> {
         self.client.close_tunnel().tunnel_id(tunnel_id).send().await
     }
+
+    async fn describe_tunnel_by_id(
+        &self,
+        tunnel_id: &str,
+    ) -> Result<DescribeTunnelOutput, SdkError<DescribeTunnelError>> {
+        self.client
+            .describe_tunnel()
+            .tunnel_id(tunnel_id)
+            .send()
+            .await
+    }
+}
+
+/// Client whose list and describe calls share a `RateLimiter`, for status polling
+///
+/// Opening, rotating and closing tunnels are operator actions and go straight through.
+pub struct RateLimitedClient<C> {
+    inner: C,
+    limiter: RateLimiter,
+    per_second: u32,
+}
+
+impl<C: TunnelClient> RateLimitedClient<C> {
+    pub fn new(inner: C, limiter: RateLimiter, per_second: u32) -> Self {
+        Self {
+            inner,
+            limiter,
+            per_second,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: TunnelClient> TunnelClient for RateLimitedClient<C> {
+    async fn list_tunnels_for_thing_page(
+        &self,
+        thing_name: &str,
+        next_token: Option<String>,
+    ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
+        self.limiter.acquire(self.per_second).await;
+        self.inner
+            .list_tunnels_for_thing_page(thing_name, next_token)
+            .await
+    }
+
+    async fn list_tunnels_page(
+        &self,
+        next_token: Option<String>,
+    ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
+        self.limiter.acquire(self.per_second).await;
+        self.inner.list_tunnels_page(next_token).await
+    }
+
+    async fn open_tunnel_with_config(
+        &self,
+        dest_config: DestinationConfig,
+        timeout_config: Option<TimeoutConfig>,
+        description: Option<String>,
+    ) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>> {
+        self.inner
+            .open_tunnel_with_config(dest_config, timeout_config, description)
+            .await
+    }
+
+    async fn rotate_tunnel_tokens(
+        &self,
+        tunnel_id: &str,
+        client_mode: ClientMode,
+        dest_config: DestinationConfig,
+    ) -> Result<RotateTunnelAccessTokenOutput, SdkError<RotateTunnelAccessTokenError>> {
+        self.inner
+            .rotate_tunnel_tokens(tunnel_id, client_mode, dest_config)
+            .await
+    }
+
+    async fn close_tunnel_by_id(
+        &self,
+        tunnel_id: &str,
+    ) -> Result<CloseTunnelOutput, SdkError<CloseTunnelError>> {
+        self.inner.close_tunnel_by_id(tunnel_id).await
+    }
+
+    async fn describe_tunnel_by_id(
+        &self,
+        tunnel_id: &str,
+    ) -> Result<DescribeTunnelOutput, SdkError<DescribeTunnelError>> {
+        self.limiter.acquire(self.per_second).await;
+        self.inner.describe_tunnel_by_id(tunnel_id).await
+    }
 }
 
 #[cfg(any(test, feature = "test-utils"))]
 pub mod test_utils {
+    use std::sync::Mutex;
+    use std::time::SystemTime;
+
     use super::*;
+    use aws_sdk_iotsecuretunneling::error::ErrorMetadata;
+    use aws_sdk_iotsecuretunneling::types::error::ResourceNotFoundException;
+    use aws_sdk_iotsecuretunneling::types::{
+        ConnectionState, ConnectionStatus, Tunnel, TunnelStatus, TunnelSummary,
+    };
+    use aws_smithy_runtime_api::http::{Response, StatusCode};
+    use aws_smithy_types::DateTime;
+    use aws_smithy_types::body::SdkBody;
     use mockall::mock;
 
     mock! {
@@ -104,8 +244,14 @@ pub mod test_utils {
 
         #[async_trait]
         impl TunnelClient for TunnelClient {
-            async fn list_tunnels_for_thing(&self, thing_name: &str) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>>;
-            async fn open_tunnel_with_config(&self, dest_config: DestinationConfig) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>>;
+            async fn list_tunnels_for_thing_page(&self, thing_name: &str, next_token: Option<String>) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>>;
+            async fn list_tunnels_page(&self, next_token: Option<String>) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>>;
+            async fn open_tunnel_with_config(
+                &self,
+                dest_config: DestinationConfig,
+                timeout_config: Option<TimeoutConfig>,
+                description: Option<String>,
+            ) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>>;
             async fn rotate_tunnel_tokens(
                 &self,
                 tunnel_id: &str,
@@ -113,6 +259,326 @@ pub mod test_utils {
                 dest_config: DestinationConfig,
             ) -> Result<RotateTunnelAccessTokenOutput, SdkError<RotateTunnelAccessTokenError>>;
             async fn close_tunnel_by_id(&self, tunnel_id: &str) -> Result<CloseTunnelOutput, SdkError<CloseTunnelError>>;
+            async fn describe_tunnel_by_id(&self, tunnel_id: &str) -> Result<DescribeTunnelOutput, SdkError<DescribeTunnelError>>;
+        }
+    }
+
+    /// A tunnel held by `FakeTunnelClient`
+    #[derive(Debug, Clone)]
+    struct FakeTunnel {
+        tunnel_id: String,
+        status: TunnelStatus,
+        destination: DestinationConfig,
+        description: Option<String>,
+        timeout: Option<TimeoutConfig>,
+        created_at: DateTime,
+        source_token: String,
+        destination_token: String,
+        source_status: ConnectionStatus,
+        destination_status: ConnectionStatus,
+    }
+
+    impl FakeTunnel {
+        fn thing_name(&self) -> Option<&str> {
+            self.destination.thing_name()
+        }
+
+        fn summary(&self) -> TunnelSummary {
+            TunnelSummary::builder()
+                .tunnel_id(&self.tunnel_id)
+                .status(self.status.clone())
+                .set_description(self.description.clone())
+                .build()
+        }
+
+        fn describe(&self) -> Tunnel {
+            Tunnel::builder()
+                .tunnel_id(&self.tunnel_id)
+                .status(self.status.clone())
+                .destination_config(self.destination.clone())
+                .set_description(self.description.clone())
+                .set_timeout_config(self.timeout.clone())
+                .created_at(self.created_at)
+                .source_connection_state(
+                    ConnectionState::builder()
+                        .status(self.source_status.clone())
+                        .build(),
+                )
+                .destination_connection_state(
+                    ConnectionState::builder()
+                        .status(self.destination_status.clone())
+                        .build(),
+                )
+                .build()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct FakeState {
+        /// Every token the fake has handed out, so each one is unique
+        issued: u32,
+        tunnels: Vec<FakeTunnel>,
+    }
+
+    impl FakeState {
+        fn token(&mut self, side: &str) -> String {
+            self.issued += 1;
+            format!("{}-token-{}", side, self.issued)
+        }
+
+        /// Position of an open tunnel, or the error AWS gives for any other
+        fn open_index(&self, tunnel_id: &str) -> Result<usize, ResourceNotFoundException> {
+            self.tunnels
+                .iter()
+                .position(|tunnel| {
+                    tunnel.tunnel_id == tunnel_id && tunnel.status == TunnelStatus::Open
+                })
+                .ok_or_else(|| not_found(tunnel_id))
+        }
+    }
+
+    /// In-memory stand-in for AWS IoT Secure Tunneling, for tests that run whole flows
+    ///
+    /// Opening adds a tunnel for the destination's thing, closing marks it closed and
+    /// rotating issues new tokens for the sides `client_mode` names, dropping whoever
+    /// was connected with the old ones. Closing a closed tunnel does nothing, while
+    /// unknown tunnels, and rotating a closed one, fail with `ResourceNotFoundException`.
+    /// Use `MockTunnelClient` to assert calls instead.
+    #[derive(Debug, Default)]
+    pub struct FakeTunnelClient {
+        state: Mutex<FakeState>,
+    }
+
+    impl FakeTunnelClient {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Add a tunnel to `thing_name` as if it was opened earlier, returning its ID
+        pub fn add_tunnel(&self, thing_name: &str, status: TunnelStatus) -> String {
+            let mut state = self.state.lock().unwrap();
+            let tunnel_id = format!("tunnel-{}", state.tunnels.len() + 1);
+            let source_token = state.token("source");
+            let destination_token = state.token("destination");
+            state.tunnels.push(FakeTunnel {
+                tunnel_id: tunnel_id.clone(),
+                status,
+                destination: DestinationConfig::builder()
+                    .thing_name(thing_name)
+                    .services("SSH")
+                    .build()
+                    .expect("services are set"),
+                description: None,
+                timeout: None,
+                created_at: DateTime::from(SystemTime::now()),
+                source_token,
+                destination_token,
+                source_status: ConnectionStatus::Disconnected,
+                destination_status: ConnectionStatus::Disconnected,
+            });
+            tunnel_id
+        }
+
+        /// The tunnel as DescribeTunnel would return it
+        pub fn tunnel(&self, tunnel_id: &str) -> Option<Tunnel> {
+            let state = self.state.lock().unwrap();
+            state
+                .tunnels
+                .iter()
+                .find(|tunnel| tunnel.tunnel_id == tunnel_id)
+                .map(FakeTunnel::describe)
+        }
+
+        /// IDs of the open tunnels to `thing_name`, oldest first
+        pub fn open_tunnel_ids(&self, thing_name: &str) -> Vec<String> {
+            let state = self.state.lock().unwrap();
+            state
+                .tunnels
+                .iter()
+                .filter(|tunnel| {
+                    tunnel.thing_name() == Some(thing_name) && tunnel.status == TunnelStatus::Open
+                })
+                .map(|tunnel| tunnel.tunnel_id.clone())
+                .collect()
+        }
+
+        /// Current source and destination tokens of a tunnel
+        pub fn tokens(&self, tunnel_id: &str) -> Option<(String, String)> {
+            let state = self.state.lock().unwrap();
+            state
+                .tunnels
+                .iter()
+                .find(|tunnel| tunnel.tunnel_id == tunnel_id)
+                .map(|tunnel| {
+                    (
+                        tunnel.source_token.clone(),
+                        tunnel.destination_token.clone(),
+                    )
+                })
+        }
+
+        /// Connect or disconnect one end of an open tunnel, as localproxy or the device would
+        pub fn set_connection(&self, tunnel_id: &str, mode: ClientMode, status: ConnectionStatus) {
+            let mut state = self.state.lock().unwrap();
+            let Some(tunnel) = state
+                .tunnels
+                .iter_mut()
+                .find(|tunnel| tunnel.tunnel_id == tunnel_id)
+            else {
+                panic!("no tunnel {}", tunnel_id);
+            };
+            if matches!(mode, ClientMode::Source | ClientMode::All) {
+                tunnel.source_status = status.clone();
+            }
+            if matches!(mode, ClientMode::Destination | ClientMode::All) {
+                tunnel.destination_status = status;
+            }
+        }
+    }
+
+    fn not_found(tunnel_id: &str) -> ResourceNotFoundException {
+        let message = format!("Tunnel {} not found", tunnel_id);
+        ResourceNotFoundException::builder()
+            .message(&message)
+            .meta(
+                ErrorMetadata::builder()
+                    .code("ResourceNotFoundException")
+                    .message(message)
+                    .build(),
+            )
+            .build()
+    }
+
+    fn service_error<E>(err: E) -> SdkError<E> {
+        SdkError::service_error(
+            err,
+            Response::new(StatusCode::try_from(404).unwrap(), SdkBody::empty()),
+        )
+    }
+
+    #[async_trait]
+    impl TunnelClient for FakeTunnelClient {
+        async fn list_tunnels_for_thing_page(
+            &self,
+            thing_name: &str,
+            _next_token: Option<String>,
+        ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
+            let state = self.state.lock().unwrap();
+            let summaries = state
+                .tunnels
+                .iter()
+                .filter(|tunnel| tunnel.thing_name() == Some(thing_name))
+                .map(FakeTunnel::summary)
+                .collect();
+            Ok(ListTunnelsOutput::builder()
+                .set_tunnel_summaries(Some(summaries))
+                .build())
+        }
+
+        async fn list_tunnels_page(
+            &self,
+            _next_token: Option<String>,
+        ) -> Result<ListTunnelsOutput, SdkError<ListTunnelsError>> {
+            let state = self.state.lock().unwrap();
+            let summaries = state.tunnels.iter().map(FakeTunnel::summary).collect();
+            Ok(ListTunnelsOutput::builder()
+                .set_tunnel_summaries(Some(summaries))
+                .build())
+        }
+
+        async fn open_tunnel_with_config(
+            &self,
+            dest_config: DestinationConfig,
+            timeout_config: Option<TimeoutConfig>,
+            description: Option<String>,
+        ) -> Result<OpenTunnelOutput, SdkError<OpenTunnelError>> {
+            let mut state = self.state.lock().unwrap();
+            let tunnel_id = format!("tunnel-{}", state.tunnels.len() + 1);
+            let source_token = state.token("source");
+            let destination_token = state.token("destination");
+            state.tunnels.push(FakeTunnel {
+                tunnel_id: tunnel_id.clone(),
+                status: TunnelStatus::Open,
+                destination: dest_config,
+                description,
+                timeout: timeout_config,
+                created_at: DateTime::from(SystemTime::now()),
+                source_token: source_token.clone(),
+                destination_token: destination_token.clone(),
+                source_status: ConnectionStatus::Disconnected,
+                destination_status: ConnectionStatus::Disconnected,
+            });
+            Ok(OpenTunnelOutput::builder()
+                .tunnel_id(tunnel_id)
+                .source_access_token(source_token)
+                .destination_access_token(destination_token)
+                .build())
+        }
+
+        async fn rotate_tunnel_tokens(
+            &self,
+            tunnel_id: &str,
+            client_mode: ClientMode,
+            dest_config: DestinationConfig,
+        ) -> Result<RotateTunnelAccessTokenOutput, SdkError<RotateTunnelAccessTokenError>> {
+            let mut state = self.state.lock().unwrap();
+            let index = state.open_index(tunnel_id).map_err(|err| {
+                service_error(RotateTunnelAccessTokenError::ResourceNotFoundException(err))
+            })?;
+            let rotate_source = matches!(client_mode, ClientMode::Source | ClientMode::All);
+            let rotate_destination =
+                matches!(client_mode, ClientMode::Destination | ClientMode::All);
+            let source_token = rotate_source.then(|| state.token("source"));
+            let destination_token = rotate_destination.then(|| state.token("destination"));
+
+            let tunnel = &mut state.tunnels[index];
+            if let Some(token) = &source_token {
+                tunnel.source_token = token.clone();
+                tunnel.source_status = ConnectionStatus::Disconnected;
+            }
+            if let Some(token) = &destination_token {
+                tunnel.destination = dest_config;
+                tunnel.destination_token = token.clone();
+                tunnel.destination_status = ConnectionStatus::Disconnected;
+            }
+            Ok(RotateTunnelAccessTokenOutput::builder()
+                .tunnel_arn(format!("arn:aws:iot:fake:tunnel/{}", tunnel_id))
+                .set_source_access_token(source_token)
+                .set_destination_access_token(destination_token)
+                .build())
+        }
+
+        async fn close_tunnel_by_id(
+            &self,
+            tunnel_id: &str,
+        ) -> Result<CloseTunnelOutput, SdkError<CloseTunnelError>> {
+            let mut state = self.state.lock().unwrap();
+            let Some(tunnel) = state
+                .tunnels
+                .iter_mut()
+                .find(|tunnel| tunnel.tunnel_id == tunnel_id)
+            else {
+                return Err(service_error(CloseTunnelError::ResourceNotFoundException(
+                    not_found(tunnel_id),
+                )));
+            };
+            tunnel.status = TunnelStatus::Closed;
+            tunnel.source_status = ConnectionStatus::Disconnected;
+            tunnel.destination_status = ConnectionStatus::Disconnected;
+            Ok(CloseTunnelOutput::builder().build())
+        }
+
+        async fn describe_tunnel_by_id(
+            &self,
+            tunnel_id: &str,
+        ) -> Result<DescribeTunnelOutput, SdkError<DescribeTunnelError>> {
+            self.tunnel(tunnel_id)
+                .map(|tunnel| DescribeTunnelOutput::builder().tunnel(tunnel).build())
+                .ok_or_else(|| {
+                    service_error(DescribeTunnelError::ResourceNotFoundException(not_found(
+                        tunnel_id,
+                    )))
+                })
         }
     }
 }
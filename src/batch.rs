@@ -0,0 +1,211 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use aws_sdk_iotsecuretunneling::types::{ConnectionStatus, TunnelStatus};
+
+use crate::aws::{close_all_tunnels_for_device, list_all_tunnels};
+use crate::aws_client::TunnelClient;
+use crate::config::TunnelConfig;
+use crate::error::{TunnelError, TunnelResult};
+
+/// Device IDs from a file with one per line
+///
+/// Blank lines and lines starting with `#` are skipped, so lists can carry notes.
+pub fn read_device_list(path: &Path) -> TunnelResult<Vec<String>> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        TunnelError::config(format!(
+            "Failed to read device list {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// What closing each device's tunnels did, in the order the devices were given
+#[derive(Debug, Default)]
+pub struct BatchCloseReport {
+    /// Devices whose tunnels could be closed, with the IDs closed
+    pub closed: Vec<(String, Vec<String>)>,
+    /// Devices that failed outright, or once for each tunnel that failed to close
+    pub failed: Vec<(String, TunnelError)>,
+}
+
+impl BatchCloseReport {
+    /// Tunnels closed across every device
+    pub fn tunnels_closed(&self) -> usize {
+        self.closed.iter().map(|(_, tunnels)| tunnels.len()).sum()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl fmt::Display for BatchCloseReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (device_id, tunnels) in &self.closed {
+            match tunnels.as_slice() {
+                [] => writeln!(f, "{}: no open tunnels", device_id)?,
+                tunnels => writeln!(
+                    f,
+                    "{}: closed {} ({})",
+                    device_id,
+                    tunnels.len(),
+                    tunnels.join(", ")
+                )?,
+            }
+        }
+        for (device_id, error) in &self.failed {
+            writeln!(f, "{}: failed: {}", device_id, error)?;
+        }
+        write!(
+            f,
+            "Closed {} tunnels for {} devices",
+            self.tunnels_closed(),
+            self.closed.len()
+        )?;
+        let mut failed_devices: Vec<&str> = self.failed.iter().map(|(id, _)| id.as_str()).collect();
+        failed_devices.dedup();
+        if !failed_devices.is_empty() {
+            write!(f, ", {} devices failed", failed_devices.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Close every open tunnel to each device, carrying on past devices that fail
+pub async fn close_tunnels_for_devices(
+    client: &dyn TunnelClient,
+    device_ids: &[String],
+    config: &TunnelConfig,
+) -> BatchCloseReport {
+    let mut report = BatchCloseReport::default();
+    for device_id in device_ids {
+        match close_all_tunnels_for_device(client, device_id, config).await {
+            Ok(result) => {
+                tracing::info!("Closed {} tunnels for {}", result.closed.len(), device_id);
+                report.closed.push((device_id.clone(), result.closed));
+                for (_, e) in result.failed {
+                    report.failed.push((device_id.clone(), e));
+                }
+            }
+            Err(e) => {
+                tracing::warn!("Failed to close the tunnels for {}: {}", device_id, e);
+                report.failed.push((device_id.clone(), e));
+            }
+        }
+    }
+    report
+}
+
+/// What closing the account's stale tunnels did
+#[derive(Debug, Default)]
+pub struct StaleTunnelReport {
+    /// Tunnels closed, with how long each had been open
+    pub closed: Vec<(String, Duration)>,
+    /// Tunnels old enough to close that were left open, since a client is connected
+    /// to the source end or its connection state is unknown
+    pub in_use: Vec<String>,
+    pub failed: Vec<(String, TunnelError)>,
+}
+
+impl fmt::Display for StaleTunnelReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (tunnel_id, age) in &self.closed {
+            writeln!(
+                f,
+                "{}: closed after {} hours",
+                tunnel_id,
+                age.as_secs() / 3600
+            )?;
+        }
+        for tunnel_id in &self.in_use {
+            writeln!(f, "{}: left open, a client is connected", tunnel_id)?;
+        }
+        for (tunnel_id, error) in &self.failed {
+            writeln!(f, "{}: failed: {}", tunnel_id, error)?;
+        }
+        write!(f, "Closed {} stale tunnels", self.closed.len())?;
+        if !self.failed.is_empty() {
+            write!(f, ", {} failed", self.failed.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Close every open tunnel in the account that has been open longer than `max_age`
+/// and has no client connected to its source end
+///
+/// Summaries carry neither the age nor the connections, so each open tunnel is
+/// described. A tunnel is only closed when its source end is known to be
+/// disconnected, and one that fails to describe or close doesn't stop the rest;
+/// only failing to list the tunnels is an error.
+pub async fn close_stale_tunnels(
+    client: &dyn TunnelClient,
+    max_age: Duration,
+    now: SystemTime,
+) -> TunnelResult<StaleTunnelReport> {
+    let mut report = StaleTunnelReport::default();
+    for summary in list_all_tunnels(client).await? {
+        let Some(tunnel_id) = summary.tunnel_id() else {
+            continue;
+        };
+        if summary.status() != Some(&TunnelStatus::Open) {
+            continue;
+        }
+        let response = match client.describe_tunnel_by_id(tunnel_id).await {
+            Ok(response) => response,
+            Err(err) => {
+                let error = TunnelError::sdk_request(
+                    format!("Failed to describe tunnel {}", tunnel_id),
+                    err,
+                );
+                report.failed.push((tunnel_id.to_string(), error));
+                continue;
+            }
+        };
+        let Some(tunnel) = response.tunnel() else {
+            continue;
+        };
+        let age = tunnel
+            .created_at()
+            .and_then(|created_at| SystemTime::try_from(*created_at).ok())
+            .and_then(|created_at| now.duration_since(created_at).ok());
+        let Some(age) = age.filter(|age| *age > max_age) else {
+            continue;
+        };
+        let source_status = tunnel
+            .source_connection_state()
+            .and_then(|state| state.status());
+        if source_status != Some(&ConnectionStatus::Disconnected) {
+            tracing::info!("Left stale tunnel {} open, it is in use", tunnel_id);
+            report.in_use.push(tunnel_id.to_string());
+            continue;
+        }
+        match client.close_tunnel_by_id(tunnel_id).await {
+            Ok(_) => {
+                tracing::info!(
+                    "Closed tunnel {}, open for {} hours",
+                    tunnel_id,
+                    age.as_secs() / 3600
+                );
+                report.closed.push((tunnel_id.to_string(), age));
+            }
+            Err(err) => {
+                let error =
+                    TunnelError::sdk_request(format!("Failed to close tunnel {}", tunnel_id), err);
+                tracing::warn!("{}", error);
+                report.failed.push((tunnel_id.to_string(), error));
+            }
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use tokio::task::JoinHandle;
+
+type AsyncUndo = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+enum Undo {
+    Now(Box<dyn FnOnce() + Send>),
+    Spawned(AsyncUndo),
+}
+
+/// Async undo steps spawned by dropped guards, so shutdown can wait for them
+static SPAWNED: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Undo steps for work in progress, run if the work doesn't finish
+///
+/// The steps run in reverse order when the guard drops, unless `disarm` was
+/// called first, so an early return, a cancelled future or a panic all clean up
+/// the same way. Drop can't wait, so async steps are spawned onto the current
+/// tokio runtime; anything about to drop the runtime should first await
+/// `finish_spawned_cleanup`, or the steps are aborted with it.
+#[must_use = "the undo steps run as soon as the guard is dropped"]
+#[derive(Default)]
+pub struct CleanupGuard {
+    steps: Vec<Undo>,
+}
+
+impl CleanupGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `undo` if the guard drops while armed
+    pub fn defer(&mut self, undo: impl FnOnce() + Send + 'static) {
+        self.steps.push(Undo::Now(Box::new(undo)));
+    }
+
+    /// Spawn `undo` if the guard drops while armed
+    pub fn defer_async(&mut self, undo: impl Future<Output = ()> + Send + 'static) {
+        self.steps.push(Undo::Spawned(Box::pin(undo)));
+    }
+
+    /// The work finished, so keep everything it created
+    pub fn disarm(mut self) {
+        self.steps.clear();
+    }
+}
+
+impl Drop for CleanupGuard {
+    fn drop(&mut self) {
+        while let Some(step) = self.steps.pop() {
+            match step {
+                Undo::Now(undo) => undo(),
+                Undo::Spawned(undo) => match tokio::runtime::Handle::try_current() {
+                    Ok(runtime) => {
+                        let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+                        spawned.retain(|task| !task.is_finished());
+                        spawned.push(runtime.spawn(undo));
+                    }
+                    Err(_) => tracing::warn!("No runtime left to clean up on, skipping a step"),
+                },
+            }
+        }
+    }
+}
+
+impl fmt::Debug for CleanupGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CleanupGuard")
+            .field("steps", &self.steps.len())
+            .finish()
+    }
+}
+
+/// Wait for the async undo steps of dropped guards, including any they spawn in turn
+pub async fn finish_spawned_cleanup() {
+    loop {
+        let spawned = std::mem::take(&mut *SPAWNED.lock().unwrap_or_else(|e| e.into_inner()));
+        if spawned.is_empty() {
+            return;
+        }
+        for task in spawned {
+            if let Err(e) = task.await {
+                tracing::warn!("A cleanup step didn't finish: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,717 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rustls_pki_types::CertificateDer;
+use rustls_pki_types::pem::PemObject;
+use serde::Deserialize;
+
+use crate::device::validate_device_id;
+use crate::error::{TunnelError, TunnelResult};
+use crate::localproxy::{CommandTemplate, ServicePortMap, validate_extra_args};
+use crate::logs::LogLevel;
+use crate::ports::PortAllocation;
+
+const CONFIG_DIR: &str = "tunnel-manager";
+const CONFIG_FILE: &str = "config.toml";
+
+/// Placeholder in `thing_name_format` and `tunnel_description` for the device ID
+const DEVICE_ID_PLACEHOLDER: &str = "{device_id}";
+
+/// Placeholder in `tunnel_description` for the operator's OS username
+const USER_PLACEHOLDER: &str = "{user}";
+
+/// Description given to tunnels the app opens unless `tunnel_description` is set
+const DEFAULT_TUNNEL_DESCRIPTION: &str = "Opened by tunnel-manager for {device_id} by {user}";
+
+/// How the app reacts when the AWS credentials are missing or expired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthBehavior {
+    /// Launch `aws sso login` automatically when authentication fails
+    #[default]
+    Automatic,
+    /// Never launch the browser on its own; the operator logs in from the UI
+    Manual,
+}
+
+/// What to do when the tunnel to reuse already has a client connected to its source end
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedTunnelPolicy {
+    /// Fail with `TunnelInUse` so the operator can decide
+    #[default]
+    Ask,
+    /// Reuse it anyway, disconnecting the other client
+    Proceed,
+    /// Leave it open for the other client and open another tunnel alongside it
+    ForceNew,
+}
+
+/// What to do when a device has more than one open tunnel, which usually means some leaked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultipleTunnelPolicy {
+    /// Reuse the first and leave the others open
+    ReuseFirst,
+    /// Reuse the first and close the others
+    #[default]
+    ReuseFirstCloseOthers,
+    /// Fail without touching any of them, so the operator can sort it out
+    Error,
+}
+
+/// Per-device overrides, keyed by device ID in the config file
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceProfile {
+    /// Services and local ports for this device, replacing the global `services`
+    pub services: Option<ServicePortMap>,
+    /// AWS IoT thing name for this device, when it doesn't follow `thing_name_format`
+    pub thing_name: Option<String>,
+}
+
+/// A named set of settings, such as `[environments.staging]`, that replaces the
+/// top-level ones it sets when the environment is picked
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Environment {
+    /// AWS profile, which also decides the account and region
+    pub profile: Option<String>,
+    /// Region for tunnels, instead of the profile's own
+    pub region: Option<String>,
+    pub services: Option<ServicePortMap>,
+    pub extra_localproxy_args: Option<Vec<String>>,
+    pub localproxy_command: Option<CommandTemplate>,
+    pub localproxy_region_overrides: Option<HashMap<String, String>>,
+    pub proxy: Option<ProxySettings>,
+    pub thing_name_format: Option<String>,
+    pub device_profiles: Option<HashMap<String, DeviceProfile>>,
+}
+
+/// Outbound proxy for the AWS SDK and localproxy
+///
+/// Unset fields fall back to the `HTTPS_PROXY` and `NO_PROXY` environment variables.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ProxySettings {
+    /// Proxy URL for HTTPS traffic, e.g. `http://proxy.corp:3128`
+    pub https_proxy: Option<String>,
+    /// Comma-separated hosts that bypass the proxy
+    pub no_proxy: Option<String>,
+    /// PEM file of root certificates, for proxies that intercept TLS
+    ///
+    /// AWS calls trust it alongside the platform roots, but for localproxy it replaces
+    /// OpenSSL's default CA file, so it must hold the full chain.
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl ProxySettings {
+    /// The proxy URL to use, if any
+    pub fn https_proxy(&self) -> Option<String> {
+        self.https_proxy
+            .clone()
+            .or_else(|| env_var(&["HTTPS_PROXY", "https_proxy"]))
+            .filter(|proxy| !proxy.is_empty())
+    }
+
+    /// The proxy bypass rules to use, if any
+    pub fn no_proxy(&self) -> Option<String> {
+        self.no_proxy
+            .clone()
+            .or_else(|| env_var(&["NO_PROXY", "no_proxy"]))
+            .filter(|rules| !rules.is_empty())
+    }
+
+    /// The configured CA bundle's contents, checked to hold at least one certificate
+    pub fn read_ca_bundle(&self) -> TunnelResult<Option<Vec<u8>>> {
+        let Some(path) = &self.ca_bundle else {
+            return Ok(None);
+        };
+        let pem = std::fs::read(path).map_err(|e| {
+            TunnelError::config(format!(
+                "Can't read the CA bundle {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let certificates = CertificateDer::pem_slice_iter(&pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| {
+                TunnelError::config(format!(
+                    "The CA bundle {} isn't valid PEM: {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+        if certificates.is_empty() {
+            return Err(TunnelError::config(format!(
+                "The CA bundle {} has no certificates in it",
+                path.display()
+            )));
+        }
+        Ok(Some(pem))
+    }
+}
+
+/// A destination-mode localproxy on this machine standing in for the device
+///
+/// For exercising a whole tunnel without hardware. Only builds with the
+/// `dev-destination` feature ever start it.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DevDestinationSettings {
+    /// Start the test destination with each connect that opens a tunnel
+    pub enabled: bool,
+    /// Local address each service's traffic is delivered to, e.g. `SSH = "localhost:22"`
+    pub targets: BTreeMap<String, String>,
+}
+
+/// A command run at a point in a connection's life, such as `post_connect`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct HookCommand {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+/// Commands run after connecting and before disconnecting, for launching an SSH
+/// client or other tools against the tunnel
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HookSettings {
+    /// Run once localproxy is up
+    pub post_connect: Option<HookCommand>,
+    /// Run before localproxy is stopped by a disconnect
+    pub pre_disconnect: Option<HookCommand>,
+    /// Longest a hook may run before it is stopped
+    #[serde(with = "duration_secs")]
+    pub timeout: Duration,
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        Self {
+            post_connect: None,
+            pre_disconnect: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl HookSettings {
+    fn validate(&self) -> TunnelResult<()> {
+        for (name, hook) in [
+            ("post_connect", &self.post_connect),
+            ("pre_disconnect", &self.pre_disconnect),
+        ] {
+            if hook
+                .as_ref()
+                .is_some_and(|hook| hook.program.trim().is_empty())
+            {
+                return Err(TunnelError::config(format!(
+                    "hooks.{} needs a program to run",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loopback control endpoint that lets other tools drive the manager
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ControlSettings {
+    /// Start the control endpoint with the app
+    pub enabled: bool,
+    /// Port on 127.0.0.1 to listen on
+    pub port: u16,
+    /// Bearer token clients must send; the endpoint won't start without one
+    pub token: Option<String>,
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 7878,
+            token: None,
+        }
+    }
+}
+
+/// Loopback Prometheus endpoint for headless installs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// Start the metrics endpoint with the app
+    pub enabled: bool,
+    /// Port on 127.0.0.1 to listen on
+    pub port: u16,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 9464,
+        }
+    }
+}
+
+/// AWS profile used when none is configured or picked
+pub const DEFAULT_PROFILE: &str = "iotmgmt_prod";
+
+/// Environment variable that overrides the configured `profile`
+pub const PROFILE_ENV: &str = "TUNNEL_MANAGER_PROFILE";
+
+/// Environment variable that overrides the configured `region`
+pub const REGION_ENV: &str = "TUNNEL_MANAGER_REGION";
+
+/// Window title used unless the config sets one; `TUNNEL_MANAGER_TITLE` overrides it at build time
+pub const DEFAULT_TITLE: &str = match option_env!("TUNNEL_MANAGER_TITLE") {
+    Some(title) => title,
+    None => "Gardin Tunnel Manager",
+};
+
+/// Window title and artwork, so other teams can rebrand the app without forking it
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BrandingSettings {
+    pub title: String,
+    /// PNG used as the window icon instead of the built-in one
+    pub icon: Option<PathBuf>,
+    /// SVG shown next to the device input instead of the built-in logo
+    pub logo: Option<PathBuf>,
+}
+
+impl Default for BrandingSettings {
+    fn default() -> Self {
+        Self {
+            title: DEFAULT_TITLE.to_string(),
+            icon: None,
+            logo: None,
+        }
+    }
+}
+
+fn env_var(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| std::env::var(name).ok())
+}
+
+/// Runtime configuration for the tunnel manager
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TunnelConfig {
+    /// Device ID filled in at launch, for apps pinned to one device
+    pub default_device_id: Option<String>,
+    /// Connect as soon as the app starts, to `default_device_id` or else the last device
+    pub auto_connect: bool,
+    /// AWS profile for credentials, region and `aws sso login`
+    pub profile: String,
+    /// AWS region for tunnels, instead of the profile's own region
+    pub region: Option<String>,
+    /// What to do when a request fails because the credentials expired
+    pub auth_behavior: AuthBehavior,
+    /// Maximum time to wait for `aws sso login` to complete
+    #[serde(with = "duration_secs")]
+    pub sso_login_timeout: Duration,
+    /// Minimum time between automatic `aws sso login` runs, so credentials that
+    /// logging in can't fix don't keep opening the browser; zero disables the limit
+    #[serde(with = "duration_secs")]
+    pub sso_login_cooldown: Duration,
+    /// How long to wait for localproxy to confirm the tunnel before warning, when
+    /// `handshake_timeout` is 0
+    #[serde(with = "duration_secs")]
+    pub ready_timeout: Duration,
+    /// How long localproxy gets to establish the tunnel before the connect fails and
+    /// it is stopped, 0 to only warn after `ready_timeout`
+    #[serde(with = "duration_secs")]
+    pub handshake_timeout: Duration,
+    /// How long to wait for the device to connect its end of the tunnel, 0 to skip waiting
+    #[serde(with = "duration_secs")]
+    pub destination_timeout: Duration,
+    /// How often to check a connected tunnel's AWS session, 0 to disable
+    #[serde(with = "duration_secs")]
+    pub session_check_interval: Duration,
+    /// Disconnect a tunnel this long after it connected, 0 for no limit
+    #[serde(with = "duration_secs")]
+    pub max_session_duration: Duration,
+    /// How long before `max_session_duration` runs out to warn the operator
+    #[serde(with = "duration_secs")]
+    pub session_expiry_warning: Duration,
+    /// Also close the AWS tunnel when `max_session_duration` disconnects it
+    pub close_tunnel_on_expiry: bool,
+    /// Open a new tunnel this long before AWS closes one this session opened at the end
+    /// of its lifetime, 0 to let it close
+    #[serde(with = "duration_secs")]
+    pub tunnel_reopen_margin: Duration,
+    /// Close the tunnels this session opened when the app exits, leaving reused ones open
+    pub close_tunnels_on_exit: bool,
+    /// At startup, close every open tunnel in the account older than this with no client
+    /// connected to its source end, including tunnels other people opened; 0 to disable
+    #[serde(with = "duration_secs")]
+    pub close_stale_tunnels_after: Duration,
+    /// Ask before quitting from the app while tunnels are connected
+    pub confirm_quit: bool,
+    /// Most AWS describe and list calls per second that status polling makes,
+    /// across every connection
+    pub poll_rate_limit: u32,
+    /// Services to tunnel and the local ports localproxy listens on
+    pub services: ServicePortMap,
+    /// Extra localproxy arguments, appended after the flags the app sets
+    pub extra_localproxy_args: Vec<String>,
+    /// Run localproxy through this command instead of the `localproxy` binary
+    pub localproxy_command: Option<CommandTemplate>,
+    /// Issue a new source token when reusing an open tunnel; with this off an open
+    /// tunnel is left alone, since AWS never hands out its existing tokens
+    pub rotate_on_reuse: bool,
+    /// Also rotate the device's destination token when reusing an open tunnel, which
+    /// makes the device reconnect and disconnects anyone else using the tunnel
+    pub rotate_destination_on_reuse: bool,
+    /// Times to retry rotating a reused tunnel's tokens after a transient AWS error,
+    /// before the connect fails
+    pub rotate_retries: u32,
+    /// What to do when someone else is already connected to the tunnel being reused
+    pub shared_tunnel: SharedTunnelPolicy,
+    /// What to do when the device has several open tunnels to choose from
+    pub multiple_open_tunnels: MultipleTunnelPolicy,
+    /// How local ports are chosen when several devices are connected
+    pub port_allocation: PortAllocation,
+    /// Fail instead of falling back to the default region when none is configured
+    pub strict_region: bool,
+    /// Region passed to localproxy for a given client region, for endpoints not built in
+    pub localproxy_region_overrides: HashMap<String, String>,
+    /// Look up service ports from the IoT thing attributes when a device has no profile
+    pub discover_service_ports: bool,
+    /// Stop localproxy processes left by a previous run at startup instead of asking
+    pub cleanup_orphans: bool,
+    /// Where to write connection events as newline-delimited JSON, a file or "stdout"
+    pub event_stream: Option<String>,
+    /// Also send the `connected` event when localproxy is restarted on a live connection
+    pub announce_reconnects: bool,
+    /// Least severe messages written to the console and log file, unless `RUST_LOG`
+    /// or `--quiet`/`--verbose` say otherwise
+    pub log_level: LogLevel,
+    /// Outbound proxy settings
+    pub proxy: ProxySettings,
+    /// AWS IoT thing name for a device ID, e.g. `acme-{device_id}`; unset uses the ID as is
+    pub thing_name_format: Option<String>,
+    /// Overrides for individual devices
+    pub device_profiles: HashMap<String, DeviceProfile>,
+    /// Environment applied at launch unless another was picked in the app
+    pub environment: Option<String>,
+    /// Named settings to switch between, e.g. "prod" and "staging"
+    pub environments: BTreeMap<String, Environment>,
+    /// Control endpoint settings, used when built with the `control` feature
+    pub control: ControlSettings,
+    /// Test destination settings, used when built with the `dev-destination` feature
+    pub dev_destination: DevDestinationSettings,
+    /// Metrics endpoint settings, used when built with the `metrics` feature
+    pub metrics: MetricsSettings,
+    /// Commands run after connecting and before disconnecting
+    pub hooks: HookSettings,
+    /// Window title, icon and logo
+    pub branding: BrandingSettings,
+    /// Friendly name the app shows for a service code, e.g. `GORT = "Gardin Orchestrator"`;
+    /// AWS and localproxy still get the codes
+    pub service_aliases: BTreeMap<String, String>,
+    /// Release endpoint checked at launch for a newer version; unset skips the check
+    pub update_check_url: Option<String>,
+    /// Description AWS shows for tunnels the app opens, with `{device_id}` and `{user}`
+    /// filled in; unset uses "Opened by tunnel-manager for {device_id} by {user}"
+    pub tunnel_description: Option<String>,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            default_device_id: None,
+            auto_connect: false,
+            profile: DEFAULT_PROFILE.to_string(),
+            region: None,
+            auth_behavior: AuthBehavior::default(),
+            sso_login_timeout: Duration::from_secs(120),
+            sso_login_cooldown: Duration::from_secs(600),
+            ready_timeout: Duration::from_secs(15),
+            handshake_timeout: Duration::from_secs(45),
+            destination_timeout: Duration::from_secs(60),
+            session_check_interval: Duration::from_secs(300),
+            max_session_duration: Duration::ZERO,
+            session_expiry_warning: Duration::from_secs(300),
+            close_tunnel_on_expiry: false,
+            tunnel_reopen_margin: Duration::from_secs(300),
+            close_tunnels_on_exit: false,
+            close_stale_tunnels_after: Duration::ZERO,
+            confirm_quit: true,
+            poll_rate_limit: 5,
+            services: ServicePortMap::default(),
+            extra_localproxy_args: Vec::new(),
+            localproxy_command: None,
+            rotate_on_reuse: true,
+            rotate_destination_on_reuse: false,
+            rotate_retries: 2,
+            shared_tunnel: SharedTunnelPolicy::default(),
+            multiple_open_tunnels: MultipleTunnelPolicy::default(),
+            port_allocation: PortAllocation::default(),
+            strict_region: false,
+            localproxy_region_overrides: HashMap::new(),
+            discover_service_ports: false,
+            cleanup_orphans: false,
+            event_stream: None,
+            announce_reconnects: false,
+            log_level: LogLevel::default(),
+            proxy: ProxySettings::default(),
+            thing_name_format: None,
+            device_profiles: HashMap::new(),
+            environment: None,
+            environments: BTreeMap::new(),
+            control: ControlSettings::default(),
+            dev_destination: DevDestinationSettings::default(),
+            metrics: MetricsSettings::default(),
+            hooks: HookSettings::default(),
+            branding: BrandingSettings::default(),
+            service_aliases: BTreeMap::new(),
+            update_check_url: None,
+            tunnel_description: None,
+        }
+    }
+}
+
+impl TunnelConfig {
+    /// Location of the config file in the platform config directory
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    /// Load the config file, falling back to the defaults if it doesn't exist, then apply
+    /// `TUNNEL_MANAGER_PROFILE` and `TUNNEL_MANAGER_REGION`
+    pub fn load() -> TunnelResult<Self> {
+        let config = match Self::path() {
+            Some(path) if path.exists() => {
+                let contents = std::fs::read_to_string(&path)?;
+                Self::from_toml(&contents)?
+            }
+            _ => Self::default(),
+        };
+        Ok(config.with_process_env_overrides())
+    }
+
+    /// The defaults with `TUNNEL_MANAGER_PROFILE` and `TUNNEL_MANAGER_REGION` applied,
+    /// ignoring the config file
+    pub fn from_env() -> Self {
+        Self::default().with_process_env_overrides()
+    }
+
+    /// `with_env_overrides` from this process's environment
+    ///
+    /// Apply it again after anything else that sets the profile or region, such as
+    /// an environment or the profile remembered from last time, so the variables win.
+    pub fn with_process_env_overrides(self) -> Self {
+        self.with_env_overrides(|name| std::env::var(name).ok())
+    }
+
+    /// Replace the profile and region with the values `var` finds for `PROFILE_ENV` and
+    /// `REGION_ENV`; unset or blank variables leave the setting alone
+    pub fn with_env_overrides(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name| var(name).filter(|value: &String| !value.trim().is_empty());
+        if let Some(profile) = var(PROFILE_ENV) {
+            self.profile = profile;
+        }
+        if let Some(region) = var(REGION_ENV) {
+            self.region = Some(region);
+        }
+        self
+    }
+
+    /// Path of the config file, writing an empty one first if it doesn't exist yet
+    pub fn create_if_missing() -> TunnelResult<PathBuf> {
+        let path = Self::path()
+            .ok_or_else(|| TunnelError::config("No config directory for the config file"))?;
+        if !path.exists() {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(
+                &path,
+                "# Tunnel Manager settings, see the README for every key\n",
+            )?;
+        }
+        Ok(path)
+    }
+
+    /// Parse a config from TOML
+    pub fn from_toml(contents: &str) -> TunnelResult<Self> {
+        toml::from_str(contents)
+            .map_err(|e| TunnelError::config(format!("Invalid config file: {}", e)))
+    }
+
+    /// Check values that parse but can't work, so they're reported at startup
+    pub fn validate(&self) -> TunnelResult<()> {
+        if let Some(device_id) = &self.default_device_id {
+            validate_device_id(device_id).map_err(|_| {
+                TunnelError::config(format!(
+                    "default_device_id '{}' is not a valid device ID. Use a G and six digits, such as G111070.",
+                    device_id
+                ))
+            })?;
+        }
+        if let Some(template) = &self.localproxy_command {
+            template.validate()?;
+        }
+        if let Some(format) = &self.thing_name_format {
+            validate_thing_name_format(format)?;
+        }
+        self.hooks.validate()?;
+        if let Some(name) = &self.environment {
+            self.environment_settings(name)?;
+        }
+        for (name, environment) in &self.environments {
+            let in_environment = |e: TunnelError| match e {
+                TunnelError::Config { message } => {
+                    TunnelError::config(format!("Environment '{}': {}", name, message))
+                }
+                e => e,
+            };
+            if let Some(template) = &environment.localproxy_command {
+                template.validate().map_err(in_environment)?;
+            }
+            if let Some(args) = &environment.extra_localproxy_args {
+                validate_extra_args(args).map_err(in_environment)?;
+            }
+            if let Some(format) = &environment.thing_name_format {
+                validate_thing_name_format(format).map_err(in_environment)?;
+            }
+            if let Some(proxy) = &environment.proxy {
+                proxy.read_ca_bundle().map_err(in_environment)?;
+            }
+        }
+        self.proxy.read_ca_bundle()?;
+        if self.poll_rate_limit == 0 {
+            return Err(TunnelError::config(
+                "poll_rate_limit must allow at least 1 call per second",
+            ));
+        }
+        validate_extra_args(&self.extra_localproxy_args)
+    }
+
+    fn environment_settings(&self, name: &str) -> TunnelResult<&Environment> {
+        self.environments.get(name).ok_or_else(|| {
+            TunnelError::config(format!(
+                "Unknown environment '{}'. Add it as [environments.{}] in the config file.",
+                name, name
+            ))
+        })
+    }
+
+    /// Names of the configured environments, in order
+    pub fn environment_names(&self) -> Vec<String> {
+        self.environments.keys().cloned().collect()
+    }
+
+    /// This config with an environment's settings applied over it
+    ///
+    /// Call it on the config as loaded, not one that already has an environment
+    /// applied, or settings only the previous environment sets carry over.
+    pub fn with_environment(&self, name: &str) -> TunnelResult<Self> {
+        let environment = self.environment_settings(name)?.clone();
+        let mut config = self.clone();
+        config.environment = Some(name.to_string());
+        if let Some(profile) = environment.profile {
+            config.profile = profile;
+        }
+        if let Some(region) = environment.region {
+            config.region = Some(region);
+        }
+        if let Some(services) = environment.services {
+            config.services = services;
+        }
+        if let Some(args) = environment.extra_localproxy_args {
+            config.extra_localproxy_args = args;
+        }
+        if let Some(template) = environment.localproxy_command {
+            config.localproxy_command = Some(template);
+        }
+        if let Some(overrides) = environment.localproxy_region_overrides {
+            config.localproxy_region_overrides = overrides;
+        }
+        if let Some(proxy) = environment.proxy {
+            config.proxy = proxy;
+        }
+        if let Some(format) = environment.thing_name_format {
+            config.thing_name_format = Some(format);
+        }
+        if let Some(device_profiles) = environment.device_profiles {
+            config.device_profiles = device_profiles;
+        }
+        Ok(config)
+    }
+
+    /// What to call a service in the app: its alias, or the code when it has none
+    pub fn service_label<'a>(&'a self, service: &'a str) -> &'a str {
+        self.service_aliases
+            .get(service)
+            .map_or(service, String::as_str)
+    }
+
+    /// Services configured for a device, if it has a profile that overrides them
+    pub fn device_services(&self, device_id: &str) -> Option<&ServicePortMap> {
+        self.device_profiles
+            .get(device_id)
+            .and_then(|profile| profile.services.as_ref())
+    }
+
+    /// AWS IoT thing name for a device: its profile's `thing_name`, else
+    /// `thing_name_format` filled in, else the device ID itself
+    pub fn thing_name(&self, device_id: &str) -> String {
+        if let Some(name) = self
+            .device_profiles
+            .get(device_id)
+            .and_then(|profile| profile.thing_name.as_ref())
+        {
+            return name.clone();
+        }
+        match &self.thing_name_format {
+            Some(format) => format.replace(DEVICE_ID_PLACEHOLDER, device_id),
+            None => device_id.to_string(),
+        }
+    }
+
+    /// Description for a tunnel opened to the device, naming who opened it
+    ///
+    /// The username comes from `USER`, or `USERNAME` on Windows.
+    pub fn tunnel_description(&self, device_id: &str) -> String {
+        let user = env_var(&["USER", "USERNAME"]).unwrap_or_else(|| String::from("unknown"));
+        self.tunnel_description
+            .as_deref()
+            .unwrap_or(DEFAULT_TUNNEL_DESCRIPTION)
+            .replace(DEVICE_ID_PLACEHOLDER, device_id)
+            .replace(USER_PLACEHOLDER, &user)
+    }
+}
+
+/// A format without the placeholder would send every device to the same thing
+fn validate_thing_name_format(format: &str) -> TunnelResult<()> {
+    if format.contains(DEVICE_ID_PLACEHOLDER) {
+        Ok(())
+    } else {
+        Err(TunnelError::config(format!(
+            "thing_name_format '{}' must contain {}",
+            format, DEVICE_ID_PLACEHOLDER
+        )))
+    }
+}
+
+mod duration_secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        u64::deserialize(deserializer).map(Duration::from_secs)
+    }
+}
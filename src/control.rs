@@ -0,0 +1,233 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::TunnelConfig;
+use crate::error::{TunnelError, TunnelResult};
+use crate::manager::ConnectionManager;
+
+/// Upper bound on request headers, so a misbehaving client can't hold a connection open
+const MAX_HEADERS: usize = 64;
+
+/// Longest request or header line read, so a client can't grow one without bound
+const MAX_LINE: u64 = 8 * 1024;
+
+/// How long a client gets to send its whole request before it is answered with a 408
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Minimal HTTP endpoint on the loopback interface for driving the manager
+///
+/// Routes, all requiring `Authorization: Bearer <token>`:
+/// - `GET /status` lists the active connections
+/// - `POST /connect/<device_id>` opens a tunnel to a device
+/// - `POST /disconnect/<device_id>` stops a device's localproxy
+pub struct ControlServer {
+    listener: TcpListener,
+    token: String,
+    manager: ConnectionManager,
+    config: TunnelConfig,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: &impl Serialize) -> Self {
+        Self {
+            status,
+            body: serde_json::to_string(body).unwrap_or_else(|_| String::from("null")),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, &json!({ "error": message.into() }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl ControlServer {
+    /// Bind to `127.0.0.1` on the configured port
+    ///
+    /// Fails if no token is configured, so the endpoint is never left open.
+    pub async fn bind(config: TunnelConfig, manager: ConnectionManager) -> TunnelResult<Self> {
+        let token = config
+            .control
+            .token
+            .clone()
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| TunnelError::config("The control endpoint requires a token"))?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.control.port)).await?;
+
+        Ok(Self {
+            listener,
+            token,
+            manager,
+            config,
+        })
+    }
+
+    pub fn local_addr(&self) -> TunnelResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections until the task is dropped, one request per connection
+    pub async fn run(self) -> TunnelResult<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let token = self.token.clone();
+            let manager = self.manager.clone();
+            let config = self.config.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &token, &manager, &config).await {
+                    tracing::warn!("Control request failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    token: &str,
+    manager: &ConnectionManager,
+    config: &TunnelConfig,
+) -> TunnelResult<()> {
+    let mut stream = BufReader::new(stream);
+    let response = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => match request? {
+            Some(request) => route(request, token, manager, config).await,
+            None => Response::error(400, "Malformed request"),
+        },
+        Err(_) => Response::error(408, "Request timed out"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> TunnelResult<Option<Request>> {
+    let mut line = String::new();
+    if !read_line(stream, &mut line).await? {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Ok(None);
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut authorization = None;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if !read_line(stream, &mut line).await? {
+            return Ok(None);
+        }
+        if line.trim().is_empty() {
+            return Ok(Some(Request {
+                method,
+                path,
+                authorization,
+            }));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Read a line of at most `MAX_LINE` bytes, `false` if it was longer
+///
+/// The end of the stream counts as an empty line.
+async fn read_line(stream: &mut BufReader<TcpStream>, line: &mut String) -> TunnelResult<bool> {
+    let read = (&mut *stream).take(MAX_LINE).read_line(line).await?;
+    Ok(read == 0 || line.ends_with('\n') || (read as u64) < MAX_LINE)
+}
+
+async fn route(
+    request: Request,
+    token: &str,
+    manager: &ConnectionManager,
+    config: &TunnelConfig,
+) -> Response {
+    let authorized = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| token_matches(token, provided));
+    if !authorized {
+        return Response::error(401, "Missing or invalid token");
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["status"]) => Response::json(200, &manager.status().await),
+        ("POST", ["connect", device_id]) if !device_id.is_empty() => {
+            if manager.is_active(device_id).await {
+                return Response::error(409, format!("Device {} is already connected", device_id));
+            }
+            match manager.connect(device_id, config).await {
+                Ok(summary) => Response::json(200, &summary),
+                Err(e @ TunnelError::TunnelInUse { .. }) => Response::error(409, e.to_string()),
+                Err(e) => Response::error(500, e.to_string()),
+            }
+        }
+        ("POST", ["disconnect", device_id]) if !device_id.is_empty() => {
+            match manager.disconnect(device_id).await {
+                Ok(()) => Response::json(200, &json!({ "disconnected": device_id })),
+                Err(e @ TunnelError::TunnelNotFound { .. }) => Response::error(404, e.to_string()),
+                Err(e) => Response::error(500, e.to_string()),
+            }
+        }
+        (_, ["status"] | ["connect", _] | ["disconnect", _]) => {
+            Response::error(405, "Method not allowed")
+        }
+        _ => Response::error(404, "Unknown route"),
+    }
+}
+
+/// Compare tokens without exiting early on the first mismatched byte
+fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
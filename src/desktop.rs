@@ -0,0 +1,27 @@
+use std::path::Path;
+
+use tokio::process::Command;
+
+use crate::error::{TunnelError, TunnelResult};
+
+/// Program that opens a file or folder with its default application
+fn opener() -> &'static str {
+    if cfg!(windows) {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    }
+}
+
+/// Show a folder in the file manager, or open a file in its default application
+///
+/// Doesn't wait for the opener, since some file managers keep running or exit
+/// with a failure code even when the window opened.
+pub fn open_path(path: &Path) -> TunnelResult<()> {
+    Command::new(opener()).arg(path).spawn().map_err(|e| {
+        TunnelError::process_execution(format!("Failed to open {}: {}", path.display(), e))
+    })?;
+    Ok(())
+}
@@ -0,0 +1,22 @@
+use crate::error::{TunnelError, TunnelResult};
+
+/// Digits after the `G` in a device ID
+const DEVICE_ID_DIGITS: usize = 6;
+
+/// Check a device ID has the display format, `G` and six digits such as `G111070`
+///
+/// This is the ID operators type. The AWS IoT thing name it maps to can look
+/// different; see `TunnelConfig::thing_name`.
+pub fn validate_device_id(device_id: &str) -> TunnelResult<()> {
+    let valid = device_id.strip_prefix('G').is_some_and(|digits| {
+        digits.len() == DEVICE_ID_DIGITS && digits.bytes().all(|b| b.is_ascii_digit())
+    });
+
+    if valid {
+        Ok(())
+    } else {
+        Err(TunnelError::InvalidDeviceId {
+            device_id: device_id.to_string(),
+        })
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use aws_sdk_iotsecuretunneling::error::{DisplayErrorContext, ProvideErrorMetadata};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::aws::{
+    ConnectAction, TunnelConnection, caller_identity, get_client, resolve_region,
+    wait_for_destination,
+};
+use crate::aws_client::TunnelClient;
+use crate::config::TunnelConfig;
+use crate::error::{AWS_CLI_INSTALL_URL, TunnelError, TunnelResult};
+use crate::localproxy::{
+    ASSETS_DIR, LocalproxyVersion, MIN_LOCALPROXY_VERSION, VERSION_TIMEOUT,
+    resolve_localproxy_region,
+};
+
+/// How long a connection test waits for the device when `destination_timeout` is 0
+pub const TEST_DESTINATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// IAM actions the app calls on the tunneling API
+const REQUIRED_ACTIONS: &[&str] = &[
+    "iot:ListTunnels",
+    "iot:OpenTunnel",
+    "iot:RotateTunnelAccessToken",
+    "iot:DescribeTunnel",
+    "iot:CloseTunnel",
+];
+
+/// Outcome of a single diagnostics check
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+    /// What to do about a failed check
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    pub fn pass(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            passed: true,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    pub fn fail(
+        name: impl Into<String>,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            passed: false,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.passed { "PASS" } else { "FAIL" };
+        write!(f, "[{}] {}: {}", status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check, in the order a new install is most likely to trip over them
+pub async fn run_diagnostics(config: &TunnelConfig) -> Vec<CheckResult> {
+    let region = resolve_region(config).await;
+
+    vec![
+        check_assets_dir(Path::new(ASSETS_DIR)),
+        check_localproxy().await,
+        check_credentials(&config.profile).await,
+        check_region(region.as_deref(), &config.localproxy_region_overrides),
+        check_permissions(config).await,
+    ]
+}
+
+/// Check the directory localproxy runs in exists
+pub fn check_assets_dir(path: &Path) -> CheckResult {
+    const NAME: &str = "Assets folder";
+
+    if path.is_dir() {
+        CheckResult::pass(NAME, format!("Found {}", path.display()))
+    } else {
+        CheckResult::fail(
+            NAME,
+            format!("{} is missing", path.display()),
+            format!(
+                "Run the app from its install folder, or create '{}' next to it.",
+                ASSETS_DIR
+            ),
+        )
+    }
+}
+
+/// Check the region localproxy would be given is one it can connect to
+pub fn check_region(region: Option<&str>, overrides: &HashMap<String, String>) -> CheckResult {
+    const NAME: &str = "Region";
+
+    let Some(region) = region else {
+        return CheckResult::fail(
+            NAME,
+            "No region is set for the AWS profile",
+            "Set a region for the AWS profile in ~/.aws/config. Until then eu-west-1 is used.",
+        );
+    };
+
+    match resolve_localproxy_region(region, overrides) {
+        Ok(resolved) if resolved == region => CheckResult::pass(NAME, region),
+        Ok(resolved) => {
+            CheckResult::pass(NAME, format!("{} (localproxy uses {})", region, resolved))
+        }
+        Err(err) => CheckResult::fail(
+            NAME,
+            err.to_string(),
+            "Use a region with a secure tunneling endpoint, or map it in localproxy_region_overrides.",
+        ),
+    }
+}
+
+async fn check_localproxy() -> CheckResult {
+    const NAME: &str = "localproxy";
+
+    let mut command = Command::new("localproxy");
+    command
+        .arg("--version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if Path::new(ASSETS_DIR).is_dir() {
+        command.current_dir(ASSETS_DIR);
+    }
+
+    match tokio::time::timeout(VERSION_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            let version = String::from_utf8_lossy(&output.stdout);
+            if let Some(found) = LocalproxyVersion::parse(&version) {
+                if found < MIN_LOCALPROXY_VERSION {
+                    return CheckResult::fail(
+                        NAME,
+                        format!(
+                            "localproxy {} required, found {}",
+                            MIN_LOCALPROXY_VERSION, found
+                        ),
+                        "Install a newer localproxy; older releases can't take the app's list of services.",
+                    );
+                }
+            }
+            let version = version.lines().next().unwrap_or_default().trim();
+            CheckResult::pass(NAME, format!("Runs ({})", version))
+        }
+        Ok(Ok(output)) => CheckResult::fail(
+            NAME,
+            format!("'localproxy --version' failed with {}", output.status),
+            "Reinstall localproxy; the installed binary may be for another platform or missing libraries.",
+        ),
+        Ok(Err(err)) if err.kind() == io::ErrorKind::NotFound => CheckResult::fail(
+            NAME,
+            "localproxy was not found",
+            "Install localproxy on your PATH or in the assets folder.",
+        ),
+        Ok(Err(err)) => CheckResult::fail(
+            NAME,
+            format!("Failed to run localproxy: {}", err),
+            "Check localproxy is executable by your user.",
+        ),
+        Err(_) => CheckResult::fail(
+            NAME,
+            "'localproxy --version' did not finish",
+            "Reinstall localproxy.",
+        ),
+    }
+}
+
+async fn check_credentials(profile: &str) -> CheckResult {
+    const NAME: &str = "AWS credentials";
+
+    match caller_identity(profile).await {
+        Ok(arn) => CheckResult::pass(NAME, arn),
+        Err(TunnelError::AwsCliMissing) => CheckResult::fail(
+            NAME,
+            "The AWS CLI was not found",
+            format!("Install it from {}.", AWS_CLI_INSTALL_URL),
+        ),
+        Err(err) => CheckResult::fail(
+            NAME,
+            err.to_string(),
+            format!(
+                "Use 'Log in to AWS', or run 'aws sso login --profile {}'.",
+                profile
+            ),
+        ),
+    }
+}
+
+/// Best-effort permissions check; only ListTunnels can be tried without side effects
+async fn check_permissions(config: &TunnelConfig) -> CheckResult {
+    const NAME: &str = "IAM permissions";
+
+    let client = match get_client(config).await {
+        Ok(client) => client,
+        Err(err) => {
+            return CheckResult::fail(NAME, err.to_string(), "Fix the checks above first.");
+        }
+    };
+
+    match client.list_tunnels().max_results(1).send().await {
+        Ok(_) => CheckResult::pass(NAME, "iot:ListTunnels is allowed"),
+        Err(err) if err.code() == Some("AccessDeniedException") => CheckResult::fail(
+            NAME,
+            "iot:ListTunnels was denied",
+            format!(
+                "Ask for a role that allows {}.",
+                REQUIRED_ACTIONS.join(", ")
+            ),
+        ),
+        Err(err) => CheckResult::fail(
+            NAME,
+            format!("Could not list tunnels: {}", DisplayErrorContext(&err)),
+            "Fix the checks above first.",
+        ),
+    }
+}
+
+/// Outcome of a connection test, with how long each stage took
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionTest {
+    pub device_id: String,
+    pub tunnel_id: String,
+    /// How the tunnel was found or opened
+    pub action: ConnectAction,
+    /// Getting the tunnel and starting localproxy
+    pub connect_time: Duration,
+    /// How long the device took to connect its end, `None` if it didn't within `destination_timeout`
+    pub destination_time: Option<Duration>,
+    pub destination_timeout: Duration,
+    /// Stopping localproxy and closing the tunnel if the test opened it
+    pub teardown_time: Duration,
+    /// Teardown steps that failed, such as a tunnel left open
+    pub cleanup_errors: Vec<String>,
+}
+
+impl ConnectionTest {
+    pub fn passed(&self) -> bool {
+        self.destination_time.is_some() && self.cleanup_errors.is_empty()
+    }
+
+    /// The test as one check per stage, for showing alongside the diagnostics
+    pub fn checks(&self) -> Vec<CheckResult> {
+        let connect = CheckResult::pass(
+            "Connect",
+            format!(
+                "{} {} and started localproxy in {}",
+                self.action,
+                self.tunnel_id,
+                format_seconds(self.connect_time)
+            ),
+        );
+        let device = match self.destination_time {
+            Some(time) => CheckResult::pass(
+                "Device",
+                format!(
+                    "{} connected its end after {}",
+                    self.device_id,
+                    format_seconds(time)
+                ),
+            ),
+            None => CheckResult::fail(
+                "Device",
+                format!(
+                    "{} didn't connect within {} seconds",
+                    self.device_id,
+                    self.destination_timeout.as_secs()
+                ),
+                "Check the device is online and its tunneling agent is running.",
+            ),
+        };
+        let cleanup = if self.cleanup_errors.is_empty() {
+            let closed = if self.action.opened_tunnel() {
+                "and closed the tunnel"
+            } else {
+                "and left the reused tunnel open"
+            };
+            CheckResult::pass(
+                "Cleanup",
+                format!(
+                    "Stopped localproxy {} in {}",
+                    closed,
+                    format_seconds(self.teardown_time)
+                ),
+            )
+        } else {
+            CheckResult::fail(
+                "Cleanup",
+                self.cleanup_errors.join("; "),
+                "Close leftover tunnels with 'Close tunnels'.",
+            )
+        };
+        vec![connect, device, cleanup]
+    }
+}
+
+fn format_seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f64())
+}
+
+/// Check a freshly made connection end to end, then tear it down
+///
+/// Waits for the device to connect its end of the tunnel, stops localproxy and
+/// closes the tunnel if the connect opened it; a reused tunnel is left open since
+/// something else may be using it. Teardown runs even if checking the device fails.
+pub async fn run_connection_test(
+    client: &dyn TunnelClient,
+    mut connection: TunnelConnection,
+    connect_time: Duration,
+    destination_timeout: Duration,
+    poll_interval: Duration,
+) -> TunnelResult<ConnectionTest> {
+    let waiting = Instant::now();
+    let connected = wait_for_destination(
+        client,
+        &connection.tunnel_id,
+        destination_timeout,
+        poll_interval,
+    )
+    .await;
+    let destination_time = waiting.elapsed();
+
+    let teardown = Instant::now();
+    let mut cleanup_errors = Vec::new();
+    if let Err(e) = connection.child.kill().await {
+        cleanup_errors.push(format!("Failed to stop localproxy: {}", e));
+    }
+    if connection.action.opened_tunnel() {
+        if let Err(e) = client.close_tunnel_by_id(&connection.tunnel_id).await {
+            let error = TunnelError::sdk_request(
+                format!("Failed to close tunnel {}", connection.tunnel_id),
+                e,
+            );
+            cleanup_errors.push(error.to_string());
+        }
+    }
+    let teardown_time = teardown.elapsed();
+    for error in &cleanup_errors {
+        tracing::warn!("Connection test for {}: {}", connection.device_id, error);
+    }
+
+    Ok(ConnectionTest {
+        device_id: connection.device_id,
+        tunnel_id: connection.tunnel_id,
+        action: connection.action,
+        connect_time,
+        destination_time: connected?.then_some(destination_time),
+        destination_timeout,
+        teardown_time,
+        cleanup_errors,
+    })
+}
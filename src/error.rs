@@ -1,15 +1,62 @@
-use aws_sdk_iotsecuretunneling::error::SdkError;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_iotsecuretunneling::error::{ProvideErrorMetadata, SdkError};
+use aws_smithy_runtime_api::http::Response as HttpResponse;
+use std::fmt::Debug;
 use std::io;
 use thiserror::Error;
 
+/// Where to get the AWS CLI used for `aws sso login`
+pub const AWS_CLI_INSTALL_URL: &str =
+    "https://docs.aws.amazon.com/cli/latest/userguide/getting-started-install.html";
+
+/// Error codes AWS uses when it rate-limits a request
+const THROTTLING_CODES: &[&str] = &[
+    "Throttling",
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "RequestLimitExceeded",
+];
+
 /// Custom error types for the tunnel manager application
 #[derive(Error, Debug)]
 pub enum TunnelError {
     #[error("AWS configuration error: {message}")]
     AwsConfig { message: String },
 
+    #[error("Configuration error: {message}")]
+    Config { message: String },
+
     #[error("AWS authentication failed: {message}")]
-    AwsAuth { message: String },
+    AwsAuth {
+        message: String,
+        /// What the credentials provider or SDK reported, when there was an error
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
+    /// Logging in worked but AWS still didn't accept the profile's credentials, so
+    /// logging in again won't help
+    #[error(
+        "Logged in to AWS, but profile {profile} is still not authorized. Check the profile's SSO account and role."
+    )]
+    StillUnauthorized {
+        profile: String,
+        #[source]
+        source: Box<TunnelError>,
+    },
+
+    #[error(
+        "The AWS CLI was not found. Install it from {} and make sure 'aws' is on your PATH.",
+        AWS_CLI_INSTALL_URL
+    )]
+    AwsCliMissing,
+
+    /// No profile, environment variables or other source gave any AWS credentials, as on a
+    /// fresh machine, so logging in can't help until a profile is set up
+    #[error(
+        "AWS configuration error: No AWS credentials configured; set up a profile or run aws configure sso"
+    )]
+    NoCredentials,
 
     #[error("Tunnel operation failed: {message}")]
     TunnelOperation { message: String },
@@ -32,11 +79,35 @@ pub enum TunnelError {
     #[error("LocalProxy startup failed: {message}")]
     LocalProxyStartup { message: String },
 
+    #[error("Another client appears connected to tunnel {tunnel_id} for {device_id}")]
+    TunnelInUse {
+        device_id: String,
+        tunnel_id: String,
+    },
+
+    #[error("Failed to stop localproxy for {device_id}: {message}")]
+    Disconnection { device_id: String, message: String },
+
     #[error("IO error: {0}")]
     Io(#[from] io::Error),
 
     #[error("AWS SDK error: {0}")]
     AwsSdk(String),
+
+    #[error("{message}")]
+    AwsRequest {
+        message: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// AWS rejected the request for exceeding its rate limit; trying again later should work
+    #[error("{message}: AWS is rate-limiting requests")]
+    Throttled {
+        message: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 }
 
 impl TunnelError {
@@ -47,10 +118,29 @@ impl TunnelError {
         }
     }
 
+    /// Create a new configuration error
+    pub fn config(message: impl Into<String>) -> Self {
+        Self::Config {
+            message: message.into(),
+        }
+    }
+
     /// Create a new AWS authentication error
     pub fn aws_auth(message: impl Into<String>) -> Self {
         Self::AwsAuth {
             message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Create a new AWS authentication error, keeping what reported it as the source
+    pub fn aws_auth_from(
+        message: impl Into<String>,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::AwsAuth {
+            message: message.into(),
+            source: Some(Box::new(source)),
         }
     }
 
@@ -81,19 +171,150 @@ impl TunnelError {
             message: message.into(),
         }
     }
+
+    /// Create a new error for a localproxy that could not be stopped
+    pub fn disconnection(device_id: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::Disconnection {
+            device_id: device_id.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Create a new AWS request error, keeping the SDK error as the source
+    pub fn aws_request(
+        message: impl Into<String>,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Self::AwsRequest {
+            message: message.into(),
+            source: Box::new(source),
+        }
+    }
+
+    /// Create an error for a failed SDK call, as `Throttled` if AWS rate-limited it
+    pub fn sdk_request<E, R>(message: impl Into<String>, err: SdkError<E, R>) -> Self
+    where
+        E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
+        R: Debug + Send + Sync + 'static,
+    {
+        if is_throttling_code(err.code()) {
+            Self::Throttled {
+                message: message.into(),
+                source: Box::new(err),
+            }
+        } else {
+            Self::aws_request(message, err)
+        }
+    }
+
+    /// Whether AWS rate-limited the request, which clears up by itself
+    pub fn is_throttling(&self) -> bool {
+        matches!(self, TunnelError::Throttled { .. })
+    }
+
+    /// Short, stable name for the kind of failure, for metrics and logs
+    pub fn category(&self) -> &'static str {
+        match self {
+            TunnelError::AwsAuth { .. }
+            | TunnelError::StillUnauthorized { .. }
+            | TunnelError::AwsCliMissing => "auth",
+            TunnelError::AwsConfig { .. }
+            | TunnelError::NoCredentials
+            | TunnelError::Config { .. }
+            | TunnelError::InvalidDeviceId { .. } => "config",
+            TunnelError::TunnelOperation { .. }
+            | TunnelError::TunnelNotFound { .. }
+            | TunnelError::TokenRotation { .. }
+            | TunnelError::TunnelInUse { .. }
+            | TunnelError::AwsSdk(_)
+            | TunnelError::AwsRequest { .. } => "aws",
+            TunnelError::ProcessExecution { .. }
+            | TunnelError::LocalProxyStartup { .. }
+            | TunnelError::Disconnection { .. } => "localproxy",
+            TunnelError::Throttled { .. } => "throttling",
+            TunnelError::Connection { .. } => "connection",
+            TunnelError::Io(_) => "io",
+        }
+    }
+
+    /// Full error text including every source, for bug reports
+    pub fn details(&self) -> String {
+        let mut details = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(err) = source {
+            // Wrapped errors often repeat their source's text, which adds nothing
+            let text = err.to_string();
+            if !details.ends_with(&text) {
+                details.push_str(&format!("\nCaused by: {}", text));
+            }
+            source = err.source();
+        }
+        details
+    }
+
+    /// Text of the innermost source, which usually says most precisely what went wrong
+    pub fn root_cause(&self) -> String {
+        let mut cause: &(dyn std::error::Error + 'static) = self;
+        while let Some(source) = cause.source() {
+            cause = source;
+        }
+        cause.to_string()
+    }
+}
+
+fn is_throttling_code(code: Option<&str>) -> bool {
+    code.is_some_and(|code| THROTTLING_CODES.contains(&code))
+}
+
+/// Whether a failed SDK call may succeed if made again: a timeout, a dropped
+/// connection, throttling or a fault on AWS's side
+///
+/// Dispatch failures other than timeouts and IO errors are usually missing or
+/// expired credentials, which retrying can't fix.
+pub fn is_transient<E>(err: &SdkError<E, HttpResponse>) -> bool
+where
+    E: ProvideErrorMetadata,
+{
+    match err {
+        SdkError::TimeoutError(_) | SdkError::ResponseError(_) => true,
+        SdkError::DispatchFailure(failure) => failure.is_io() || failure.is_timeout(),
+        SdkError::ServiceError(context) => {
+            context.raw().status().is_server_error() || is_throttling_code(err.code())
+        }
+        _ => false,
+    }
+}
+
+/// Whether the credentials provider chain found nothing at all, rather than
+/// credentials that have expired
+pub fn is_missing_credentials(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(CredentialsError::CredentialsNotLoaded(_)) = err.downcast_ref() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
 }
 
 // Convert AWS SDK errors to our custom error type
 impl<E> From<SdkError<E>> for TunnelError
 where
-    E: std::error::Error + Send + Sync + 'static,
+    E: ProvideErrorMetadata + std::error::Error + Send + Sync + 'static,
 {
     fn from(err: SdkError<E>) -> Self {
         match err {
-            SdkError::DispatchFailure(_) => TunnelError::AwsAuth {
-                message: "Authentication failed. Please run 'aws sso login' to authenticate."
-                    .to_string(),
-            },
+            SdkError::DispatchFailure(_) if is_missing_credentials(&err) => {
+                TunnelError::NoCredentials
+            }
+            SdkError::DispatchFailure(_) => TunnelError::aws_auth_from(
+                "Authentication failed. Please run 'aws sso login' to authenticate.",
+                err,
+            ),
+            _ if is_throttling_code(err.code()) => {
+                TunnelError::sdk_request("AWS request failed", err)
+            }
             _ => TunnelError::AwsSdk(err.to_string()),
         }
     }
@@ -117,16 +338,50 @@ pub enum UiError {
     #[error("Authentication required. Please try again after logging in.")]
     AuthenticationRequired,
 
+    /// Logging in didn't fix authentication, with what AWS reported
+    #[error("{message}")]
+    StillUnauthorized { message: String },
+
+    #[error("No AWS credentials configured")]
+    NoCredentials,
+
+    #[error("AWS is rate-limiting requests")]
+    Throttled,
+
     #[error("Unknown error occurred")]
     Unknown,
 }
 
 impl From<TunnelError> for UiError {
     fn from(err: TunnelError) -> Self {
+        UiError::from(&err)
+    }
+}
+
+impl From<&TunnelError> for UiError {
+    fn from(err: &TunnelError) -> Self {
         match err {
             TunnelError::AwsAuth { .. } => UiError::AuthenticationRequired,
+            TunnelError::StillUnauthorized { .. } => UiError::StillUnauthorized {
+                message: format!("{} AWS reported: {}", err, err.root_cause()),
+            },
+            TunnelError::NoCredentials => UiError::NoCredentials,
+            TunnelError::Throttled { .. } => UiError::Throttled,
             TunnelError::InvalidDeviceId { .. } => UiError::EmptyDeviceId,
-            TunnelError::Connection { message } => UiError::ConnectionFailed { message },
+            TunnelError::Connection { message } => UiError::ConnectionFailed {
+                message: message.clone(),
+            },
+            TunnelError::Disconnection { .. } => UiError::DisconnectionFailed {
+                message: err.to_string(),
+            },
+            TunnelError::Io(e) if e.kind() == io::ErrorKind::NotFound => {
+                UiError::ConnectionFailed {
+                    message: format!("A required file or program was not found: {}", e),
+                }
+            }
+            TunnelError::Io(e) => UiError::ConnectionFailed {
+                message: format!("A system error occurred: {}", e),
+            },
             _ => UiError::ConnectionFailed {
                 message: err.to_string(),
             },
@@ -144,6 +399,13 @@ impl UiError {
             UiError::AuthenticationRequired => {
                 "Authentication required. Please try connecting again."
             }
+            UiError::StillUnauthorized { message } => message,
+            UiError::NoCredentials => {
+                "No AWS credentials are set up on this machine. Open Setup from the status bar to pick a profile, or run 'aws configure sso'."
+            }
+            UiError::Throttled => {
+                "AWS is rate-limiting requests. It usually clears up within a minute."
+            }
             UiError::Unknown => "An unexpected error occurred",
         }
     }
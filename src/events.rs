@@ -0,0 +1,150 @@
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast;
+
+use crate::error::{TunnelError, TunnelResult};
+use crate::localproxy::ServicePortMap;
+
+/// Events kept for subscribers that fall behind before the oldest are dropped
+const EVENT_BUFFER: usize = 256;
+
+/// `event_stream` value that writes events to stdout instead of a file
+pub const STDOUT_SINK: &str = "stdout";
+
+/// Something that happened to a connection, for monitoring and front ends
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TunnelEvent {
+    ConnectStarted {
+        device_id: String,
+    },
+    /// A new tunnel was opened for the device
+    TunnelOpened {
+        device_id: String,
+        tunnel_id: String,
+    },
+    /// An open tunnel was reused with freshly rotated access tokens
+    TokensRotated {
+        device_id: String,
+        tunnel_id: String,
+    },
+    ProxyStarted {
+        device_id: String,
+        tunnel_id: String,
+        pid: Option<u32>,
+        services: ServicePortMap,
+    },
+    /// localproxy confirmed the tunnel is established, so its local ports are usable
+    ///
+    /// Sent at most once per connect, and not at all when localproxy didn't confirm
+    /// within the readiness timeout. With `announce_reconnects` it is also sent each
+    /// time localproxy is restarted on a live connection and confirms again.
+    Connected {
+        device_id: String,
+        tunnel_id: String,
+        region: String,
+        services: ServicePortMap,
+        /// localproxy was restarted on an existing connection, e.g. after a token rotation
+        reconnect: bool,
+    },
+    Disconnected {
+        device_id: String,
+        reason: String,
+    },
+    Error {
+        device_id: String,
+        message: String,
+    },
+}
+
+/// Fan-out of connection events to any number of subscribers
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<TunnelEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send an event to current subscribers; with none it is dropped
+    pub fn publish(&self, event: TunnelEvent) {
+        tracing::debug!("{:?}", event);
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive every event published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    /// Milliseconds since the Unix epoch
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a TunnelEvent,
+}
+
+/// Serialize an event as one line of newline-delimited JSON
+pub fn to_ndjson(event: &TunnelEvent, timestamp: SystemTime) -> TunnelResult<String> {
+    let timestamp = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default();
+    let line = serde_json::to_string(&EventRecord { timestamp, event })
+        .map_err(|e| TunnelError::config(format!("Failed to serialize event: {}", e)))?;
+    Ok(line + "\n")
+}
+
+/// Write events to `writer` as newline-delimited JSON until the bus is dropped
+///
+/// Events missed because the writer fell behind are logged and skipped.
+pub async fn write_events<W: AsyncWrite + Unpin>(
+    mut events: broadcast::Receiver<TunnelEvent>,
+    mut writer: W,
+) -> TunnelResult<()> {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::warn!("Event stream fell behind, {} events skipped", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        let line = to_ndjson(&event, SystemTime::now())?;
+        writer.write_all(line.as_bytes()).await?;
+        writer.flush().await?;
+    }
+}
+
+/// Open the `event_stream` destination, stdout or a file appended to
+pub async fn open_event_sink(setting: &str) -> TunnelResult<Box<dyn AsyncWrite + Unpin + Send>> {
+    if setting == STDOUT_SINK {
+        return Ok(Box::new(tokio::io::stdout()));
+    }
+
+    let path = Path::new(setting);
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(Box::new(file))
+}
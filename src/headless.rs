@@ -0,0 +1,92 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::config::TunnelConfig;
+use crate::error::TunnelResult;
+use crate::manager::{ConnectionManager, ConnectionSummary, SHUTDOWN_CLOSE_TIMEOUT};
+
+/// How often a headless connection checks that localproxy is still running
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long an interrupted connection waits for localproxy to stop and the
+/// session's tunnels to close before giving up
+pub const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(SHUTDOWN_CLOSE_TIMEOUT.as_secs() + 5);
+
+/// Exit code after an interrupt stopped everything cleanly, as shells report for SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Run `connect` and hold the connection until `interrupt` resolves, returning the exit code
+///
+/// An interrupt mid-connect drops the connect, so its cleanup guard undoes whatever it
+/// had set up. Either way every localproxy is then stopped and, with
+/// `close_tunnels_on_exit`, the tunnels this session opened are closed, the same as
+/// closing the window. The exit code is `EXIT_INTERRUPTED` once that finishes, 1 if it
+/// took longer than `INTERRUPT_TIMEOUT` or localproxy exited on its own, and 2 if the
+/// connect failed.
+pub async fn connect_until_interrupted(
+    manager: &ConnectionManager,
+    config: &TunnelConfig,
+    connect: impl Future<Output = TunnelResult<ConnectionSummary>>,
+    interrupt: impl Future<Output = ()>,
+) -> i32 {
+    tokio::pin!(interrupt);
+    let interrupted = tokio::select! {
+        result = connect => match result {
+            Ok(connection) => {
+                print_connection(&connection);
+                hold(manager, &mut interrupt).await
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        },
+        () = &mut interrupt => true,
+    };
+    if interrupted {
+        eprintln!("Disconnecting…");
+    }
+    match tokio::time::timeout(INTERRUPT_TIMEOUT, manager.shutdown(config)).await {
+        Ok(()) if interrupted => EXIT_INTERRUPTED,
+        Ok(()) => 1,
+        Err(_) => {
+            eprintln!(
+                "Gave up disconnecting after {} seconds",
+                INTERRUPT_TIMEOUT.as_secs()
+            );
+            1
+        }
+    }
+}
+
+/// Wait for the interrupt, or `false` if localproxy exits first
+async fn hold(
+    manager: &ConnectionManager,
+    interrupt: &mut (impl Future<Output = ()> + Unpin),
+) -> bool {
+    loop {
+        tokio::select! {
+            () = &mut *interrupt => return true,
+            () = tokio::time::sleep(EXIT_POLL_INTERVAL) => {
+                if let Some((device_id, error)) = manager.reap_exited().await.into_iter().next() {
+                    eprintln!("localproxy for {} exited: {}", device_id, error);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+fn print_connection(connection: &ConnectionSummary) {
+    println!(
+        "Connected to {} through tunnel {}",
+        connection.device_id, connection.tunnel_id
+    );
+    for (service, port) in connection.services.iter() {
+        println!("  {} on localhost:{}", service, port);
+    }
+    for warning in &connection.warnings {
+        eprintln!("{}", warning);
+    }
+    println!("Press Ctrl-C to disconnect");
+}
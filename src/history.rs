@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{TunnelError, TunnelResult};
+use crate::state::LayoutMode;
+
+const HISTORY_DIR: &str = "tunnel-manager";
+const HISTORY_FILE: &str = "history.toml";
+
+/// How many recent devices are remembered
+const MAX_RECENT_DEVICES: usize = 10;
+
+/// Recently connected devices, most recent first, and the last picked AWS profile,
+/// environment and window layout
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionHistory {
+    recent: Vec<String>,
+    profile: Option<String>,
+    environment: Option<String>,
+    layout: LayoutMode,
+}
+
+impl ConnectionHistory {
+    /// Location of the history file in the platform data directory
+    pub fn path() -> Option<PathBuf> {
+        dirs::data_local_dir().map(|dir| dir.join(HISTORY_DIR).join(HISTORY_FILE))
+    }
+
+    /// Load the history, starting empty if there is none yet
+    pub fn load() -> TunnelResult<Self> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> TunnelResult<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents)
+            .map_err(|e| TunnelError::config(format!("Invalid history file: {}", e)))
+    }
+
+    pub fn save_to(&self, path: &Path) -> TunnelResult<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = toml::to_string(self)
+            .map_err(|e| TunnelError::config(format!("Failed to write history: {}", e)))?;
+        Ok(fs::write(path, contents)?)
+    }
+
+    /// Move a device to the front of the history
+    pub fn record(&mut self, device_id: &str) {
+        self.recent.retain(|recent| recent != device_id);
+        self.recent.insert(0, device_id.to_string());
+        self.recent.truncate(MAX_RECENT_DEVICES);
+    }
+
+    /// The most recently connected device
+    pub fn last(&self) -> Option<&str> {
+        self.recent.first().map(String::as_str)
+    }
+
+    pub fn recent(&self) -> &[String] {
+        &self.recent
+    }
+
+    /// The AWS profile picked in the UI, if one was
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn set_profile(&mut self, profile: &str) {
+        self.profile = Some(profile.to_string());
+    }
+
+    /// The environment picked in the UI, if one was
+    pub fn environment(&self) -> Option<&str> {
+        self.environment.as_deref()
+    }
+
+    pub fn set_environment(&mut self, environment: &str) {
+        self.environment = Some(environment.to_string());
+    }
+
+    /// The window layout last picked, expanded until one is
+    pub fn layout(&self) -> LayoutMode {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: LayoutMode) {
+        self.layout = layout;
+    }
+}
+
+/// Record a successful connection in the history file
+pub fn record_connection(device_id: &str) -> TunnelResult<()> {
+    let path = ConnectionHistory::path()
+        .ok_or_else(|| TunnelError::config("No local data directory for the history file"))?;
+    let mut history = ConnectionHistory::load_from(&path)?;
+    history.record(device_id);
+    history.save_to(&path)
+}
+
+/// Remember the AWS profile picked in the UI for the next launch
+pub fn record_profile(profile: &str) -> TunnelResult<()> {
+    let path = ConnectionHistory::path()
+        .ok_or_else(|| TunnelError::config("No local data directory for the history file"))?;
+    let mut history = ConnectionHistory::load_from(&path)?;
+    history.set_profile(profile);
+    history.save_to(&path)
+}
+
+/// Remember the environment picked in the UI for the next launch
+pub fn record_environment(environment: &str) -> TunnelResult<()> {
+    let path = ConnectionHistory::path()
+        .ok_or_else(|| TunnelError::config("No local data directory for the history file"))?;
+    let mut history = ConnectionHistory::load_from(&path)?;
+    history.set_environment(environment);
+    history.save_to(&path)
+}
+
+/// Remember the window layout for the next launch
+pub fn record_layout(layout: LayoutMode) -> TunnelResult<()> {
+    let path = ConnectionHistory::path()
+        .ok_or_else(|| TunnelError::config("No local data directory for the history file"))?;
+    let mut history = ConnectionHistory::load_from(&path)?;
+    history.set_layout(layout);
+    history.save_to(&path)
+}
@@ -0,0 +1,105 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::config::HookCommand;
+use crate::error::{TunnelError, TunnelResult};
+use crate::manager::ConnectionSummary;
+
+/// Prefix of the environment variables describing the connection to a hook
+const ENV_PREFIX: &str = "TUNNEL_MANAGER_";
+
+/// Environment a hook runs with, describing the connection
+///
+/// `TUNNEL_MANAGER_HOOK` names the hook, `TUNNEL_MANAGER_SERVICES` lists every service
+/// as localproxy takes them (`SSH=2222,GORT=5555`) and each service also gets its own
+/// `TUNNEL_MANAGER_PORT_<SERVICE>`.
+pub fn hook_env(name: &str, connection: &ConnectionSummary) -> Vec<(String, String)> {
+    let mut env = vec![
+        (format!("{}HOOK", ENV_PREFIX), name.to_string()),
+        (
+            format!("{}DEVICE_ID", ENV_PREFIX),
+            connection.device_id.clone(),
+        ),
+        (
+            format!("{}TUNNEL_ID", ENV_PREFIX),
+            connection.tunnel_id.clone(),
+        ),
+        (format!("{}REGION", ENV_PREFIX), connection.region.clone()),
+        (
+            format!("{}SERVICES", ENV_PREFIX),
+            connection.services.to_localproxy_arg(),
+        ),
+    ];
+    env.extend(connection.services.iter().map(|(service, port)| {
+        let service: String = service
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        (format!("{}PORT_{}", ENV_PREFIX, service), port.to_string())
+    }));
+    env
+}
+
+/// Run a hook for a connection, logging what it prints
+///
+/// Stdout is logged as info and stderr as warnings, under the `hook` target so both
+/// show in the log view. A hook still running after `timeout` is stopped. Failing
+/// hooks are errors for the caller to report; they never affect the tunnel.
+pub async fn run_hook(
+    name: &str,
+    hook: &HookCommand,
+    connection: &ConnectionSummary,
+    timeout: Duration,
+) -> TunnelResult<()> {
+    tracing::info!(
+        "Running {} hook for {}: {}",
+        name,
+        connection.device_id,
+        hook.program
+    );
+    let output = Command::new(&hook.program)
+        .args(&hook.args)
+        .envs(hook_env(name, connection))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = match tokio::time::timeout(timeout, output).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            return Err(TunnelError::process_execution(format!(
+                "Failed to run the {} hook '{}': {}",
+                name, hook.program, e
+            )));
+        }
+        Err(_) => {
+            return Err(TunnelError::process_execution(format!(
+                "The {} hook didn't finish within {} seconds and was stopped",
+                name,
+                timeout.as_secs()
+            )));
+        }
+    };
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        tracing::info!(target: "hook", "{}: {}", name, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        tracing::warn!(target: "hook", "{}: {}", name, line);
+    }
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(TunnelError::process_execution(format!(
+            "The {} hook failed ({})",
+            name, output.status
+        )))
+    }
+}
@@ -1,3 +1,28 @@
 pub mod aws;
 pub mod aws_client;
+pub mod batch;
+pub mod cleanup;
+pub mod config;
+#[cfg(feature = "control")]
+pub mod control;
+pub mod desktop;
+pub mod device;
+pub mod diagnostics;
 pub mod error;
+pub mod events;
+pub mod headless;
+pub mod history;
+pub mod hooks;
+pub mod localproxy;
+pub mod logs;
+pub mod manager;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod onboarding;
+pub mod orphans;
+pub mod ports;
+pub mod profiles;
+pub mod rate_limit;
+pub mod state;
+pub mod token_file;
+pub mod update;
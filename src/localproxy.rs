@@ -0,0 +1,1031 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc;
+use tracing::Level;
+
+use crate::config::ProxySettings;
+use crate::error::{TunnelError, TunnelResult};
+
+/// Environment variable localproxy reads the access token from
+pub const TOKEN_ENV: &str = "AWSIOT_TUNNEL_ACCESS_TOKEN";
+
+/// What an access token is shown as in logs and diagnostics
+const REDACTED: &str = "***";
+
+/// Prefix of the IoT thing attributes that advertise a service port, e.g. `tunnel_port_GORT`
+pub const PORT_ATTRIBUTE_PREFIX: &str = "tunnel_port_";
+
+/// Most services AWS IoT Secure Tunneling allows in one tunnel
+pub const MAX_TUNNEL_SERVICES: usize = 3;
+
+/// Directory localproxy runs in, which may also hold the localproxy binary
+pub const ASSETS_DIR: &str = "assets";
+
+/// Address localproxy binds its local listeners to
+const BIND_ADDRESS: &str = "0.0.0.0";
+
+/// Oldest localproxy that takes the `-s SERVICE=port,...` list of services the app passes
+pub const MIN_LOCALPROXY_VERSION: LocalproxyVersion = LocalproxyVersion {
+    major: 2,
+    minor: 1,
+    patch: 0,
+};
+
+/// Where to get a current localproxy
+const LOCALPROXY_URL: &str = "https://github.com/aws-samples/aws-iot-securetunneling-localproxy";
+
+/// How long `localproxy --version` may take before the version is treated as unknown
+pub const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Versions found by `cached_localproxy_version`, by program
+static VERSIONS: Mutex<BTreeMap<String, Option<LocalproxyVersion>>> = Mutex::new(BTreeMap::new());
+
+/// localproxy flags the app manages, which extra arguments may not repeat. The
+/// token flags are listed so the token only ever comes from `TOKEN_ENV`.
+const MANAGED_FLAGS: &[&str] = &[
+    "-r",
+    "--region",
+    "-s",
+    "--source-listen-port",
+    "-d",
+    "--destination-app",
+    "-b",
+    "--bind-address",
+    "-t",
+    "--access-token",
+];
+
+/// Log messages localproxy prints once the tunnel is usable
+const READY_MARKERS: &[&str] = &[
+    "Successfully established websocket connection",
+    "Listening for new connection",
+];
+
+/// How many of localproxy's most recent output lines are kept for diagnostics
+const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Output that means localproxy could not listen on its local ports
+const BIND_FAILURE_MARKERS: &[&str] = &["address already in use", "failed to bind", "bind error"];
+
+/// Log message localproxy prints for each local port it listens on
+const LISTENING_MARKER: &str = "listening for new connection";
+
+/// How long to keep reading after the tunnel is up for the other services to report,
+/// so one that failed to bind is known before the connect returns
+const SERVICE_BIND_GRACE: Duration = Duration::from_secs(2);
+
+/// Output that means the tunneling service refused localproxy's access token
+///
+/// Status phrases such as `401 Unauthorized` are matched by their text rather than
+/// the bare code, since ports and pids can contain `401` too.
+const TOKEN_REJECTED_MARKERS: &[&str] = &[
+    "unauthorized",
+    "invalid access token",
+    "access token is invalid",
+    "forbidden",
+];
+
+/// Output that means the websocket to the tunneling service went away
+const WEBSOCKET_CLOSED_MARKERS: &[&str] = &[
+    "websocket connection closed",
+    "websocket closed",
+    "websocket stream closed",
+    "failed to establish websocket",
+];
+
+/// Regions with an AWS IoT Secure Tunneling data-plane endpoint
+pub const SUPPORTED_REGIONS: &[&str] = &[
+    "us-east-1",
+    "us-east-2",
+    "us-west-1",
+    "us-west-2",
+    "ca-central-1",
+    "sa-east-1",
+    "eu-west-1",
+    "eu-west-2",
+    "eu-west-3",
+    "eu-central-1",
+    "eu-north-1",
+    "me-south-1",
+    "ap-east-1",
+    "ap-south-1",
+    "ap-northeast-1",
+    "ap-northeast-2",
+    "ap-southeast-1",
+    "ap-southeast-2",
+    "cn-north-1",
+    "cn-northwest-1",
+    "us-gov-east-1",
+    "us-gov-west-1",
+];
+
+/// Whether localproxy confirmed the tunnel before the readiness timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+    Ready,
+    TimedOut,
+}
+
+/// Ordered mapping of tunnel service names to the local ports localproxy listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServicePortMap {
+    entries: Vec<(String, u16)>,
+}
+
+impl ServicePortMap {
+    /// Create an empty service map
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add a service, replacing the port if the service is already mapped
+    pub fn with_service(mut self, service: impl Into<String>, port: u16) -> Self {
+        self.insert(service, port);
+        self
+    }
+
+    /// Insert a service, replacing the port if the service is already mapped
+    pub fn insert(&mut self, service: impl Into<String>, port: u16) {
+        let service = service.into();
+        match self.entries.iter_mut().find(|(name, _)| *name == service) {
+            Some(entry) => entry.1 = port,
+            None => self.entries.push((service, port)),
+        }
+    }
+
+    /// Get the local port for a service
+    pub fn port(&self, service: &str) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|(name, _)| name == service)
+            .map(|(_, port)| *port)
+    }
+
+    /// Iterate over the services and their ports in declaration order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.entries
+            .iter()
+            .map(|(name, port)| (name.as_str(), *port))
+    }
+
+    /// Iterate over the service names in declaration order
+    pub fn services(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Apply port overrides from IoT thing attributes named `tunnel_port_<SERVICE>`
+    ///
+    /// Only services already in the map are overridden; attributes that don't parse
+    /// as a port are ignored so a bad attribute can't break the connection.
+    pub fn with_attribute_overrides(&self, attributes: &HashMap<String, String>) -> Self {
+        let mut services = self.clone();
+        for (name, port) in services.entries.iter_mut() {
+            let attribute = format!("{}{}", PORT_ATTRIBUTE_PREFIX, name);
+            if let Some(value) = attributes.get(&attribute).and_then(|v| v.parse().ok()) {
+                *port = value;
+            }
+        }
+        services
+    }
+
+    /// Check the services fit within what a single tunnel supports
+    ///
+    /// Names only differing in case are rejected as well, since they can't be told
+    /// apart at a glance and usually mean a typo in the config.
+    pub fn validate(&self) -> TunnelResult<()> {
+        if self.is_empty() {
+            return Err(TunnelError::tunnel_operation(
+                "At least one service must be configured",
+            ));
+        }
+        if self.len() > MAX_TUNNEL_SERVICES {
+            return Err(TunnelError::tunnel_operation(format!(
+                "At most {} services allowed per tunnel, but {} are configured: {}",
+                MAX_TUNNEL_SERVICES,
+                self.len(),
+                self.services().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        for (i, (name, _)) in self.entries.iter().enumerate() {
+            if let Some((duplicate, _)) = self.entries[i + 1..]
+                .iter()
+                .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            {
+                return Err(TunnelError::tunnel_operation(format!(
+                    "Service names must be unique, but '{}' and '{}' are both configured",
+                    name, duplicate
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Just the named services, in declaration order, for a connect that doesn't
+    /// need them all
+    ///
+    /// Fails on a name that isn't mapped, and on a selection `validate` rejects, such
+    /// as an empty one or more than a tunnel supports.
+    pub fn only<S: AsRef<str>>(&self, services: &[S]) -> TunnelResult<Self> {
+        if let Some(unknown) = services
+            .iter()
+            .map(AsRef::as_ref)
+            .find(|name| self.port(name).is_none())
+        {
+            return Err(TunnelError::tunnel_operation(format!(
+                "Service '{}' isn't configured; choose from {}",
+                unknown,
+                self.services().collect::<Vec<_>>().join(", ")
+            )));
+        }
+        let selected = Self {
+            entries: self
+                .entries
+                .iter()
+                .filter(|(name, _)| services.iter().any(|s| s.as_ref() == name))
+                .cloned()
+                .collect(),
+        };
+        selected.validate()?;
+        Ok(selected)
+    }
+
+    /// Format the map as localproxy's `-s` argument, e.g. `SSH=2222,GORT=5555`
+    pub fn to_localproxy_arg(&self) -> String {
+        self.iter()
+            .map(|(name, port)| format!("{}={}", name, port))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl Default for ServicePortMap {
+    fn default() -> Self {
+        Self::new()
+            .with_service("SSH", 2222)
+            .with_service("GORT", 5555)
+    }
+}
+
+impl<'de> Deserialize<'de> for ServicePortMap {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ServicePortMapVisitor;
+
+        impl<'de> Visitor<'de> for ServicePortMapVisitor {
+            type Value = ServicePortMap;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a table of service names to local ports")
+            }
+
+            fn visit_map<A>(self, mut access: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut services = ServicePortMap::new();
+                while let Some((service, port)) = access.next_entry::<String, u16>()? {
+                    services.insert(service, port);
+                }
+                Ok(services)
+            }
+        }
+
+        deserializer.deserialize_map(ServicePortMapVisitor)
+    }
+}
+
+impl Serialize for ServicePortMap {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (service, port) in self.iter() {
+            map.serialize_entry(service, &port)?;
+        }
+        map.end()
+    }
+}
+
+/// A tunnel access token, masked wherever it is printed
+#[derive(Clone, PartialEq, Eq)]
+pub struct AccessToken(String);
+
+impl AccessToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    /// The token itself, only for handing to localproxy
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// `text` with every occurrence of the token masked
+    pub fn redact(&self, text: &str) -> String {
+        if self.0.is_empty() {
+            return text.to_string();
+        }
+        text.replace(&self.0, REDACTED)
+    }
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AccessToken({})", REDACTED)
+    }
+}
+
+impl fmt::Display for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// A localproxy command being built, with the access token kept apart from the rest
+/// of its environment
+///
+/// The token only joins the environment in `spawn`, so neither this type's `Debug`
+/// and `Display`, which mask it, nor the underlying `Command` can print it.
+pub struct LocalproxyCommand {
+    command: Command,
+    token: AccessToken,
+}
+
+impl LocalproxyCommand {
+    fn new(program: impl AsRef<OsStr>, token: &str) -> Self {
+        let mut command = Command::new(program);
+        command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Dropping the connection stops localproxy instead of leaving it orphaned
+            .kill_on_drop(true);
+        Self {
+            command,
+            token: AccessToken::new(token),
+        }
+    }
+
+    /// Program, arguments and environment so far, without the token
+    pub fn as_std(&self) -> &std::process::Command {
+        self.command.as_std()
+    }
+
+    pub fn token(&self) -> &AccessToken {
+        &self.token
+    }
+
+    /// Start localproxy with the token in its environment
+    pub fn spawn(mut self) -> io::Result<Child> {
+        self.command.env(TOKEN_ENV, self.token.expose()).spawn()
+    }
+}
+
+impl fmt::Debug for LocalproxyCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = self.as_std();
+        f.debug_struct("LocalproxyCommand")
+            .field("program", &command.get_program())
+            .field("args", &command.get_args().collect::<Vec<_>>())
+            .field("envs", &command.get_envs().collect::<Vec<_>>())
+            .field("token", &self.token)
+            .finish()
+    }
+}
+
+/// The command as a shell line, e.g. `AWSIOT_TUNNEL_ACCESS_TOKEN=*** localproxy -r eu-west-1`
+impl fmt::Display for LocalproxyCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = self.as_std();
+        write!(f, "{}={}", TOKEN_ENV, self.token)?;
+        for (name, value) in command.get_envs() {
+            if let Some(value) = value {
+                write!(f, " {}={}", name.to_string_lossy(), value.to_string_lossy())?;
+            }
+        }
+        write!(f, " {}", command.get_program().to_string_lossy())?;
+        for arg in command.get_args() {
+            write!(f, " {}", self.token.redact(&arg.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the localproxy command for source mode without spawning it
+pub fn build_localproxy_command(
+    region: &str,
+    services: &ServicePortMap,
+    src_token: &str,
+) -> LocalproxyCommand {
+    let mut command = LocalproxyCommand::new("localproxy", src_token);
+    command
+        .command
+        .current_dir(ASSETS_DIR)
+        .args(["-r", region])
+        .args(["-s", &services.to_localproxy_arg()])
+        .args(["-b", BIND_ADDRESS]);
+
+    command
+}
+
+/// Build a destination-mode localproxy command, which delivers the tunnel's traffic to
+/// `targets` as the device would
+///
+/// Only for testing without a device; `targets` comes from `destination_targets`.
+pub fn build_destination_command(
+    region: &str,
+    targets: &str,
+    dst_token: &str,
+) -> LocalproxyCommand {
+    let mut command = LocalproxyCommand::new("localproxy", dst_token);
+    command
+        .command
+        .current_dir(ASSETS_DIR)
+        .args(["-r", region])
+        .args(["-d", targets]);
+
+    command
+}
+
+/// localproxy's `-d` argument sending each tunnelled service to its local target
+pub fn destination_targets(
+    services: &ServicePortMap,
+    targets: &BTreeMap<String, String>,
+) -> TunnelResult<String> {
+    services
+        .services()
+        .map(|service| match targets.get(service) {
+            Some(target) => Ok(format!("{}={}", service, target)),
+            None => Err(TunnelError::config(format!(
+                "dev_destination has no target for {}. Add {} = \"localhost:<port>\" under [dev_destination.targets].",
+                service, service
+            ))),
+        })
+        .collect::<TunnelResult<Vec<_>>>()
+        .map(|targets| targets.join(","))
+}
+
+/// Build the command from an operator-supplied template instead of running localproxy directly
+///
+/// The template runs from the app's working directory rather than the assets folder,
+/// and the token is still only passed in the environment.
+pub fn build_templated_command(
+    template: &CommandTemplate,
+    region: &str,
+    services: &ServicePortMap,
+    src_token: &str,
+) -> LocalproxyCommand {
+    let mut command = LocalproxyCommand::new(&template.program, src_token);
+    command.command.args(template.render_args(region, services));
+
+    command
+}
+
+/// Program and arguments used to start localproxy, for wrappers such as `docker run`
+///
+/// Arguments may contain `{region}`, `{services}`, `{bind}` and `{token_env}`, the name of
+/// the environment variable holding the access token.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct CommandTemplate {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl CommandTemplate {
+    /// Run the localproxy binary at `path` with the arguments the app normally passes
+    pub fn localproxy_at(path: &Path) -> Self {
+        Self {
+            program: path.display().to_string(),
+            args: ["-r", "{region}", "-s", "{services}", "-b", "{bind}"]
+                .map(String::from)
+                .to_vec(),
+        }
+    }
+
+    /// Whether the program is localproxy itself rather than a wrapper such as `docker`
+    pub fn runs_localproxy(&self) -> bool {
+        Path::new(&self.program)
+            .file_stem()
+            .is_some_and(|stem| stem == "localproxy")
+    }
+
+    /// Check the program is set and every placeholder is one the app fills in
+    pub fn validate(&self) -> TunnelResult<()> {
+        if self.program.trim().is_empty() {
+            return Err(TunnelError::config(
+                "localproxy_command needs a program to run",
+            ));
+        }
+        for arg in &self.args {
+            for name in placeholders(arg)? {
+                if !PLACEHOLDERS.contains(&name) {
+                    let hint = if name.contains("token") {
+                        " The token is only passed in the environment; use {token_env} for the variable name."
+                    } else {
+                        ""
+                    };
+                    return Err(TunnelError::config(format!(
+                        "localproxy_command has an unknown placeholder '{{{}}}' in '{}'.{}",
+                        name, arg, hint
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Arguments with the placeholders filled in
+    pub fn render_args(&self, region: &str, services: &ServicePortMap) -> Vec<String> {
+        let services = services.to_localproxy_arg();
+        self.args
+            .iter()
+            .map(|arg| {
+                arg.replace("{region}", region)
+                    .replace("{services}", &services)
+                    .replace("{bind}", BIND_ADDRESS)
+                    .replace("{token_env}", TOKEN_ENV)
+            })
+            .collect()
+    }
+}
+
+/// Placeholder names the command template can use
+const PLACEHOLDERS: &[&str] = &["region", "services", "bind", "token_env"];
+
+/// Names of the `{placeholders}` in a template argument
+fn placeholders(arg: &str) -> TunnelResult<Vec<&str>> {
+    let mut names = Vec::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            return Err(TunnelError::config(format!(
+                "localproxy_command has an unclosed '{{' in '{}'",
+                arg
+            )));
+        };
+        names.push(&rest[start + 1..start + len]);
+        rest = &rest[start + len + 1..];
+    }
+    Ok(names)
+}
+
+/// Resolve the region passed to localproxy's `-r` flag
+///
+/// An entry in `overrides` wins and is trusted as-is, so new or unusual endpoints can be
+/// used without a code change. Otherwise the region must be a known tunneling region.
+pub fn resolve_localproxy_region(
+    region: &str,
+    overrides: &HashMap<String, String>,
+) -> TunnelResult<String> {
+    if let Some(mapped) = overrides.get(region) {
+        return Ok(mapped.clone());
+    }
+
+    if SUPPORTED_REGIONS.contains(&region) {
+        Ok(region.to_string())
+    } else {
+        Err(TunnelError::tunnel_operation(format!(
+            "Region '{}' has no secure tunneling endpoint. Configure a supported region or add it to localproxy_region_overrides.",
+            region
+        )))
+    }
+}
+
+/// A localproxy release, as `localproxy --version` reports it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LocalproxyVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl LocalproxyVersion {
+    /// The first version number in `localproxy --version` output, such as `3.1.1`,
+    /// `v3.1.1` or `localproxy 3.1.1-2a5f`
+    pub fn parse(output: &str) -> Option<Self> {
+        output
+            .split(|c: char| !c.is_ascii_digit() && c != '.')
+            .find_map(|word| {
+                let mut parts = word.split('.').map(|part| part.parse::<u32>().ok());
+                let (major, minor) = (parts.next()??, parts.next()??);
+                Some(Self {
+                    major,
+                    minor,
+                    patch: parts.next().flatten().unwrap_or(0),
+                })
+            })
+    }
+
+    /// Fail with upgrade guidance if this is older than `MIN_LOCALPROXY_VERSION`
+    pub fn require_supported(self) -> TunnelResult<()> {
+        if self >= MIN_LOCALPROXY_VERSION {
+            return Ok(());
+        }
+        Err(TunnelError::localproxy_startup(format!(
+            "localproxy {} required, found {}. Install a newer localproxy from {} and restart the app.",
+            MIN_LOCALPROXY_VERSION, self, LOCALPROXY_URL
+        )))
+    }
+}
+
+impl fmt::Display for LocalproxyVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Run `program --version` where localproxy runs, or `None` if it doesn't say
+pub async fn localproxy_version(program: &str) -> Option<LocalproxyVersion> {
+    let mut command = Command::new(program);
+    command
+        .arg("--version")
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if Path::new(ASSETS_DIR).is_dir() {
+        command.current_dir(ASSETS_DIR);
+    }
+    match tokio::time::timeout(VERSION_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => {
+            LocalproxyVersion::parse(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => None,
+    }
+}
+
+/// `localproxy_version`, run once per program for the life of the process
+///
+/// An unknown version is cached too, so a binary without `--version` isn't asked
+/// again on every connect.
+pub async fn cached_localproxy_version(program: &str) -> Option<LocalproxyVersion> {
+    if let Some(version) = VERSIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(program)
+    {
+        return *version;
+    }
+    let version = localproxy_version(program).await;
+    match version {
+        Some(version) => tracing::debug!("{} is localproxy {}", program, version),
+        None => tracing::debug!("Could not tell which localproxy {} is", program),
+    }
+    VERSIONS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(program.to_string(), version);
+    version
+}
+
+/// Map a failure to spawn localproxy, calling out a missing binary specifically
+pub fn spawn_error(err: io::Error) -> TunnelError {
+    if err.kind() == io::ErrorKind::NotFound {
+        TunnelError::localproxy_startup(
+            "localproxy was not found. Install it on your PATH or in the assets folder.",
+        )
+    } else {
+        TunnelError::Io(err)
+    }
+}
+
+/// Check extra localproxy arguments don't clash with the flags the app manages
+pub fn validate_extra_args(args: &[String]) -> TunnelResult<()> {
+    let managed = args.iter().find(|arg| {
+        MANAGED_FLAGS.iter().any(|flag| {
+            if flag.starts_with("--") {
+                *arg == flag || arg.starts_with(&format!("{}=", flag))
+            } else {
+                // Short flags also take their value attached, e.g. `-tTOKEN`
+                arg.starts_with(flag)
+            }
+        })
+    });
+    match managed {
+        Some(arg) => Err(TunnelError::config(format!(
+            "extra_localproxy_args can't set '{}', it is managed by the app",
+            arg
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Append extra arguments after the managed flags, refusing any that would override them
+pub fn apply_extra_args(command: &mut LocalproxyCommand, args: &[String]) -> TunnelResult<()> {
+    validate_extra_args(args)?;
+    command.command.args(args);
+    Ok(())
+}
+
+/// Pass the proxy settings to localproxy, which reads them from its environment
+pub fn apply_proxy_env(command: &mut LocalproxyCommand, proxy: &ProxySettings) {
+    if let Some(https_proxy) = proxy.https_proxy() {
+        command.command.env("HTTPS_PROXY", https_proxy);
+    }
+    if let Some(no_proxy) = proxy.no_proxy() {
+        command.command.env("NO_PROXY", no_proxy);
+    }
+}
+
+/// Have localproxy verify TLS against the CA bundle, through `SSL_CERT_FILE`
+///
+/// This replaces OpenSSL's default CA file rather than adding to it, so the bundle must
+/// hold every root localproxy needs, including those for AWS's public endpoints. A
+/// hashed directory of extra certificates can still be passed with `--capath` in
+/// `extra_localproxy_args`.
+pub fn apply_ca_bundle(command: &mut LocalproxyCommand, proxy: &ProxySettings) {
+    if let Some(ca_bundle) = &proxy.ca_bundle {
+        command.command.env("SSL_CERT_FILE", ca_bundle);
+    }
+}
+
+/// Check whether a localproxy log line signals that the tunnel is usable
+pub fn is_ready_line(line: &str) -> bool {
+    READY_MARKERS.iter().any(|marker| line.contains(marker))
+}
+
+/// The severity localproxy tagged a line with, such as `[warning]`, or info if untagged
+pub fn line_level(line: &str) -> Level {
+    let line = line.to_lowercase();
+    if line.contains("[error]") || line.contains("[fatal]") {
+        Level::ERROR
+    } else if line.contains("[warning]") {
+        Level::WARN
+    } else if line.contains("[debug]") {
+        Level::DEBUG
+    } else if line.contains("[trace]") {
+        Level::TRACE
+    } else {
+        Level::INFO
+    }
+}
+
+/// Whether localproxy is listening on a service's local port, going by its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceStatus {
+    /// localproxy hasn't mentioned the service yet
+    Unknown,
+    Listening,
+    /// localproxy couldn't bind the port, usually because another program has it
+    BindFailed,
+}
+
+/// What a localproxy line says about a service on `port`, if it mentions it
+///
+/// localproxy names the port in its listening and bind messages, and some versions
+/// name the service as well.
+pub fn service_line_status(line: &str, service: &str, port: u16) -> Option<ServiceStatus> {
+    let mentioned = line
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-')
+        .any(|word| word == service || word.parse() == Ok(port));
+    if !mentioned {
+        return None;
+    }
+    let line = line.to_lowercase();
+    if BIND_FAILURE_MARKERS
+        .iter()
+        .any(|marker| line.contains(marker))
+    {
+        Some(ServiceStatus::BindFailed)
+    } else if line.contains(LISTENING_MARKER) {
+        Some(ServiceStatus::Listening)
+    } else {
+        None
+    }
+}
+
+/// The last lines localproxy printed, shared with the tasks echoing its output,
+/// and what they said about each service
+#[derive(Debug, Clone, Default)]
+pub struct OutputTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    services: Arc<Mutex<Vec<(String, u16, ServiceStatus)>>>,
+    /// Token masked in every line, should localproxy print it
+    token: Option<AccessToken>,
+}
+
+impl OutputTail {
+    /// A tail that also tracks whether localproxy is listening for each of `services`
+    pub fn for_services(services: &ServicePortMap) -> Self {
+        let statuses = services
+            .iter()
+            .map(|(name, port)| (name.to_string(), port, ServiceStatus::Unknown))
+            .collect();
+        Self {
+            lines: Arc::default(),
+            services: Arc::new(Mutex::new(statuses)),
+            token: None,
+        }
+    }
+
+    /// Mask `token` in the lines kept and logged
+    pub fn redacting(mut self, token: &AccessToken) -> Self {
+        self.token = Some(token.clone());
+        self
+    }
+
+    fn redact(&self, line: String) -> String {
+        match &self.token {
+            Some(token) => token.redact(&line),
+            None => line,
+        }
+    }
+
+    pub fn push(&self, line: impl Into<String>) {
+        let line = line.into();
+        {
+            let mut services = self.services.lock().unwrap_or_else(|e| e.into_inner());
+            for (name, port, status) in services.iter_mut() {
+                if let Some(seen) = service_line_status(&line, name, *port) {
+                    *status = seen;
+                }
+            }
+        }
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == OUTPUT_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+
+    /// Each tracked service and whether localproxy is listening for it, in declaration order
+    pub fn service_status(&self) -> Vec<(String, ServiceStatus)> {
+        let services = self.services.lock().unwrap_or_else(|e| e.into_inner());
+        services
+            .iter()
+            .map(|(name, _, status)| (name.clone(), *status))
+            .collect()
+    }
+
+    /// Services localproxy couldn't listen for
+    pub fn failed_services(&self) -> Vec<String> {
+        self.service_status()
+            .into_iter()
+            .filter(|(_, status)| *status == ServiceStatus::BindFailed)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Whether every tracked service has either bound or failed to
+    fn services_settled(&self) -> bool {
+        self.service_status()
+            .iter()
+            .all(|(_, status)| *status != ServiceStatus::Unknown)
+    }
+}
+
+/// Explain why localproxy exited, from its exit status and last output
+///
+/// localproxy exits with 1 for most failures, so the cause is read from its
+/// output and the code is reported alongside it. Bind failures and rejected
+/// tokens are startup problems; anything else is treated as a lost connection.
+pub fn exit_error(status: ExitStatus, output: &[String]) -> TunnelError {
+    let exit = match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => String::from("terminated by a signal"),
+    };
+    let tail = if output.is_empty() {
+        String::new()
+    } else {
+        format!("\nLast output:\n{}", output.join("\n"))
+    };
+    let text = output.join("\n").to_lowercase();
+    let mentions = |markers: &[&str]| markers.iter().any(|marker| text.contains(marker));
+
+    if mentions(BIND_FAILURE_MARKERS) {
+        TunnelError::localproxy_startup(format!(
+            "localproxy could not listen on its local ports ({}). Another program may be using them.{}",
+            exit, tail
+        ))
+    } else if mentions(TOKEN_REJECTED_MARKERS) {
+        TunnelError::localproxy_startup(format!(
+            "The tunnel rejected localproxy's access token ({}). Reconnect to get a new token.{}",
+            exit, tail
+        ))
+    } else if mentions(WEBSOCKET_CLOSED_MARKERS) {
+        TunnelError::connection(format!(
+            "localproxy lost its connection to the tunneling service ({}).{}",
+            exit, tail
+        ))
+    } else {
+        TunnelError::connection(format!(
+            "localproxy exited unexpectedly ({}).{}",
+            exit, tail
+        ))
+    }
+}
+
+/// Wait for localproxy to report the tunnel is established
+///
+/// Takes over the child's stdout and stderr and keeps logging them after
+/// returning, so localproxy never blocks on a full pipe. The latest lines are
+/// kept in `output` for explaining a later exit. When `output` tracks services,
+/// the wait continues briefly after the tunnel is up for each to bind or fail,
+/// and fails if none of them could bind.
+pub async fn wait_for_ready(
+    child: &mut Child,
+    timeout: Duration,
+    output: &OutputTail,
+) -> TunnelResult<Readiness> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(forward_lines(stdout, tx.clone(), output.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(forward_lines(stderr, tx.clone(), output.clone()));
+    }
+    drop(tx);
+
+    let ready = async {
+        while let Some(line) = rx.recv().await {
+            if is_ready_line(&line) {
+                let settled =
+                    async { while !output.services_settled() && rx.recv().await.is_some() {} };
+                let _ = tokio::time::timeout(SERVICE_BIND_GRACE, settled).await;
+                return true;
+            }
+        }
+        false
+    };
+
+    match tokio::time::timeout(timeout, ready).await {
+        Ok(true) => {
+            let statuses = output.service_status();
+            if !statuses.is_empty()
+                && statuses
+                    .iter()
+                    .all(|(_, status)| *status == ServiceStatus::BindFailed)
+            {
+                return Err(TunnelError::localproxy_startup(format!(
+                    "localproxy could not listen on any of its local ports. Another program may be using them.\nLast output:\n{}",
+                    output.lines().join("\n")
+                )));
+            }
+            Ok(Readiness::Ready)
+        }
+        Ok(false) => {
+            let status = tokio::time::timeout(timeout, child.wait()).await;
+            Err(match status {
+                Ok(Ok(status)) => match exit_error(status, &output.lines()) {
+                    e @ TunnelError::LocalProxyStartup { .. } => e,
+                    e => TunnelError::localproxy_startup(format!(
+                        "localproxy exited before the tunnel connection was established: {}",
+                        e
+                    )),
+                },
+                _ => TunnelError::localproxy_startup(
+                    "localproxy exited before the tunnel connection was established",
+                ),
+            })
+        }
+        Err(_) => Ok(Readiness::TimedOut),
+    }
+}
+
+async fn forward_lines(
+    output: impl AsyncRead + Unpin,
+    tx: mpsc::UnboundedSender<String>,
+    tail: OutputTail,
+) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = tail.redact(line);
+        match line_level(&line) {
+            Level::ERROR => tracing::error!(target: "localproxy", "{}", line),
+            Level::WARN => tracing::warn!(target: "localproxy", "{}", line),
+            Level::DEBUG => tracing::debug!(target: "localproxy", "{}", line),
+            Level::TRACE => tracing::trace!(target: "localproxy", "{}", line),
+            _ => tracing::info!(target: "localproxy", "{}", line),
+        }
+        tail.push(line.as_str());
+        let _ = tx.send(line);
+    }
+}
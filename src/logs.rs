@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use serde::Deserialize;
+use tracing::Level;
+use tracing::level_filters::LevelFilter;
+
+use crate::error::{TunnelError, TunnelResult};
+
+const LOG_DIR: &str = "tunnel-manager";
+const LOG_SUBDIR: &str = "logs";
+pub const LOG_FILE: &str = "tunnel-manager.log";
+
+/// Directory the app and localproxy logs are written to
+pub fn log_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join(LOG_DIR).join(LOG_SUBDIR))
+}
+
+/// Open the log file for appending, creating the log directory if needed
+pub fn open_log_file() -> TunnelResult<File> {
+    let dir = log_dir().ok_or_else(|| TunnelError::config("No local data directory for logs"))?;
+    fs::create_dir_all(&dir)?;
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE))?)
+}
+
+/// Least severe level written to the console and log file, set by `log_level` in the config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Console verbosity asked for on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// `--quiet`: errors only, so scripts reading the output aren't confused by progress
+    Quiet,
+    #[default]
+    Normal,
+    /// `--verbose`: debug output as well
+    Verbose,
+}
+
+impl Verbosity {
+    /// Read `--quiet`/`-q` and `--verbose`/`-v` from the arguments; the last one given wins
+    pub fn from_args<I, S>(args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        args.into_iter()
+            .fold(Verbosity::Normal, |verbosity, arg| match arg.as_ref() {
+                "--quiet" | "-q" => Verbosity::Quiet,
+                "--verbose" | "-v" => Verbosity::Verbose,
+                _ => verbosity,
+            })
+    }
+
+    /// The console's level, or `None` to leave it to `RUST_LOG` and the config
+    pub fn level(self) -> Option<LevelFilter> {
+        match self {
+            Verbosity::Quiet => Some(LevelFilter::ERROR),
+            Verbosity::Normal => None,
+            Verbosity::Verbose => Some(LevelFilter::DEBUG),
+        }
+    }
+}
+
+/// How many recent lines the in-app log view keeps
+pub const BUFFER_LINES: usize = 1000;
+
+/// How much detail the in-app log view shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFilter {
+    Errors,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl LogFilter {
+    pub const ALL: [LogFilter; 3] = [LogFilter::Errors, LogFilter::Info, LogFilter::Debug];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            LogFilter::Errors => "Errors only",
+            LogFilter::Info => "Info",
+            LogFilter::Debug => "Debug",
+        }
+    }
+
+    /// Whether lines logged at `level` are shown
+    pub fn shows(self, level: Level) -> bool {
+        let most_verbose = match self {
+            LogFilter::Errors => Level::ERROR,
+            LogFilter::Info => Level::INFO,
+            LogFilter::Debug => Level::TRACE,
+        };
+        level <= most_verbose
+    }
+}
+
+/// A line of app or localproxy output kept for the log view
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>5} {}: {}", self.level, self.target, self.message)
+    }
+}
+
+#[derive(Debug, Default)]
+struct BufferState {
+    lines: VecDeque<LogLine>,
+    /// Bumped on every change, so the view only re-renders when there is something new
+    version: u64,
+}
+
+/// The latest log lines at every level, filtered when displayed
+///
+/// Filtering never drops lines, so switching to a more detailed filter shows
+/// what was already logged.
+#[derive(Debug, Clone, Default)]
+pub struct LogBuffer {
+    state: Arc<Mutex<BufferState>>,
+}
+
+impl LogBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, line: LogLine) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.lines.len() == BUFFER_LINES {
+            state.lines.pop_front();
+        }
+        state.lines.push_back(line);
+        state.version += 1;
+    }
+
+    /// Remove every kept line; the log file is unaffected
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.lines.clear();
+        state.version += 1;
+    }
+
+    pub fn version(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).version
+    }
+
+    /// Kept lines the filter shows, oldest first
+    pub fn lines(&self, filter: LogFilter) -> Vec<LogLine> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state
+            .lines
+            .iter()
+            .filter(|line| filter.shows(line.level))
+            .cloned()
+            .collect()
+    }
+}
@@ -3,29 +3,338 @@
     windows_subsystem = "windows"
 )]
 
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use dioxus_clipboard::prelude::use_clipboard;
 use freya::prelude::*;
-use tokio::process::Child;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::prelude::*;
 
-use tunnel_manager::aws::connect_to_tunnel;
+use aws_sdk_iotsecuretunneling::types::TunnelStatus;
+use tunnel_manager::aws::{
+    TunnelFilter, aws_sso_login_with_timeout, caller_identity, close_tunnels, configured_region,
+    find_tunnels_matching, get_client,
+};
+use tunnel_manager::aws_client::AwsTunnelClient;
+use tunnel_manager::batch::{close_stale_tunnels, close_tunnels_for_devices, read_device_list};
+use tunnel_manager::config::{SharedTunnelPolicy, TunnelConfig};
+#[cfg(feature = "control")]
+use tunnel_manager::control::ControlServer;
+use tunnel_manager::desktop::open_path;
+use tunnel_manager::diagnostics::{CheckResult, run_diagnostics};
+use tunnel_manager::error::{TunnelError, UiError};
+use tunnel_manager::events::{open_event_sink, write_events};
+use tunnel_manager::headless::{EXIT_INTERRUPTED, connect_until_interrupted};
+use tunnel_manager::history::{
+    ConnectionHistory, record_environment, record_layout, record_profile,
+};
+use tunnel_manager::logs::{
+    LogBuffer, LogFilter, LogLevel, LogLine, Verbosity, log_dir, open_log_file,
+};
+use tunnel_manager::manager::{ConnectionManager, ConnectionSummary, ManagedProcess};
+#[cfg(feature = "metrics")]
+use tunnel_manager::metrics::MetricsServer;
+use tunnel_manager::onboarding::{SetupChoices, find_localproxy, is_first_run, save_setup};
+use tunnel_manager::orphans::{OrphanedProcess, find_orphans, pid_dir, terminate};
+use tunnel_manager::profiles::{account_id, list_profiles};
+use tunnel_manager::state::{AppMsg, ConnectionState, LayoutMode, StatusLevel};
+use tunnel_manager::update::{CURRENT_VERSION, Release, check_for_update};
+use winit::dpi::LogicalSize;
 
 const ICON: &[u8] = include_bytes!("../assets/icon.png");
 const LOGO: &[u8] = include_bytes!("../assets/logo.svg");
 
+/// How long the status bar shows that the AWS credentials were refreshed
+const CREDENTIALS_NOTICE: Duration = Duration::from_secs(4);
+
+/// How often the open log view checks for new lines
+const LOG_VIEW_REFRESH: Duration = Duration::from_millis(500);
+
+/// Connect attempts retried after AWS rate-limits one, each waiting longer than the last
+const THROTTLED_RETRIES: u32 = 3;
+const THROTTLED_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// What `main` hands the app on launch
+#[derive(Clone)]
+struct LaunchState {
+    config: TunnelConfig,
+    /// The config before any environment was applied, to switch environments from
+    base_config: TunnelConfig,
+    logs: LogBuffer,
+    manager: ConnectionManager,
+    /// Window layout picked in the last session
+    layout: LayoutMode,
+}
+
 fn main() {
+    // Loaded before launch so the branding can shape the window; the app takes it from there
+    let mut config = TunnelConfig::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        TunnelConfig::default()
+    });
+    let logs = init_logging(
+        config.log_level,
+        Verbosity::from_args(std::env::args().skip(1)),
+    );
+    let history = ConnectionHistory::load().ok();
+    // The profile picked last time wins over the config file while it still exists
+    let picked_profile = history
+        .as_ref()
+        .and_then(|history| history.profile().map(String::from))
+        .filter(|profile| list_profiles().is_ok_and(|profiles| profiles.contains(profile)));
+    if let Some(profile) = picked_profile {
+        config.profile = profile;
+    }
+    // Environments apply over that, again preferring the one picked last time
+    let base_config = config.clone();
+    let environment = history
+        .as_ref()
+        .and_then(|history| history.environment().map(String::from))
+        .filter(|name| config.environments.contains_key(name))
+        .or_else(|| config.environment.clone());
+    if let Some(name) = environment {
+        match base_config.with_environment(&name) {
+            Ok(environment_config) => config = environment_config,
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    // TUNNEL_MANAGER_PROFILE and TUNNEL_MANAGER_REGION are set for this run, so they beat
+    // anything remembered
+    let config = config.with_process_env_overrides();
+    // `close-tunnels <file>` and `connect <device>` run without a window and exit with
+    // their outcome
+    let commands: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with('-'))
+        .collect();
+    if let [command, argument] = commands.as_slice() {
+        match command.as_str() {
+            "close-tunnels" => {
+                std::process::exit(close_tunnels_from_file(Path::new(argument), &config))
+            }
+            "connect" => std::process::exit(connect_from_cli(argument, &config)),
+            _ => {}
+        }
+    }
+    let title: &'static str = Box::leak(config.branding.title.clone().into_boxed_str());
+    let icon = config
+        .branding
+        .icon
+        .as_deref()
+        .and_then(|path| {
+            let bytes = read_branding_file(path)?;
+            // Freya panics on images it can't decode, so fall back instead
+            std::panic::catch_unwind(|| LaunchConfig::load_icon(&bytes))
+                .map_err(|_| eprintln!("{} is not a usable icon", path.display()))
+                .ok()
+        })
+        .unwrap_or_else(|| LaunchConfig::load_icon(ICON));
+
+    let layout = ConnectionHistory::load()
+        .map(|history| history.layout())
+        .unwrap_or_default();
+    let (width, height) = layout.window_size();
+
+    let manager = ConnectionManager::new();
     launch_cfg(
         app,
-        LaunchConfig::<()>::new()
-            .with_title("Gardin Tunnel Manager")
-            .with_size(430., 120.)
-            // .with_min_size(430., 120.)
-            // .with_max_size(430., 120.)
-            .with_icon(LaunchConfig::load_icon(ICON)),
-    )
+        LaunchConfig::<LaunchState>::new()
+            .with_title(title)
+            .with_size(width, height)
+            .with_icon(icon)
+            .with_state(LaunchState {
+                config: config.clone(),
+                base_config,
+                logs,
+                manager: manager.clone(),
+                layout,
+            }),
+    );
+
+    // The window has closed and taken the UI's runtime with it
+    match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime.block_on(manager.shutdown(&config)),
+        Err(e) => eprintln!("Failed to shut down cleanly: {}", e),
+    }
+}
+
+/// Close every open tunnel to each device listed in `path`, returning the exit code
+fn close_tunnels_from_file(path: &Path, config: &TunnelConfig) -> i32 {
+    let device_ids = match read_device_list(path) {
+        Ok(device_ids) => device_ids,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start: {}", e);
+            return 2;
+        }
+    };
+    let report = runtime.block_on(async {
+        let client = AwsTunnelClient::new(get_client(config).await?);
+        Ok::<_, TunnelError>(close_tunnels_for_devices(&client, &device_ids, config).await)
+    });
+    match report {
+        Ok(report) => {
+            println!("{}", report);
+            if report.is_success() { 0 } else { 1 }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        }
+    }
+}
+
+/// Connect to a device and hold the tunnel until Ctrl-C, returning the exit code
+///
+/// The first Ctrl-C disconnects cleanly; a second one exits straight away.
+fn connect_from_cli(device_id: &str, config: &TunnelConfig) -> i32 {
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start: {}", e);
+            return 2;
+        }
+    };
+    runtime.block_on(async {
+        let (interrupted, on_interrupt) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                eprintln!("Can't listen for Ctrl-C: {}", e);
+                return;
+            }
+            let _ = interrupted.send(());
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Exiting without disconnecting");
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+        });
+        let interrupt = async {
+            // Without a Ctrl-C handler, only localproxy exiting ends the connection
+            if on_interrupt.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        let manager = ConnectionManager::new();
+        connect_until_interrupted(
+            &manager,
+            config,
+            manager.connect(device_id, config),
+            interrupt,
+        )
+        .await
+    })
+}
+
+/// Read a configured icon or logo, or `None` to use the built-in one
+fn read_branding_file(path: &Path) -> Option<Vec<u8>> {
+    fs::read(path)
+        .map_err(|e| eprintln!("Failed to read {}: {}", path.display(), e))
+        .ok()
+}
+
+/// Log to the console, the in-app log view and, when the log directory is
+/// writable, the log file
+///
+/// `RUST_LOG`, when set, replaces `log_level` for the console and the file, and
+/// `--quiet` or `--verbose` override both for the console. The console writes to
+/// stderr so nothing but results ever reaches stdout.
+fn init_logging(log_level: LogLevel, verbosity: Verbosity) -> LogBuffer {
+    let file_layer = match open_log_file() {
+        Ok(file) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file)),
+        ),
+        Err(e) => {
+            eprintln!("Logging to the console only: {}", e);
+            None
+        }
+    };
+    let logs = LogBuffer::new();
+    // The log view also keeps this app's and localproxy's debug output, for its debug filter
+    let view_filter = Targets::new()
+        .with_default(LevelFilter::INFO)
+        .with_target("tunnel_manager", LevelFilter::TRACE)
+        .with_target("localproxy", LevelFilter::TRACE);
+    let configured = Targets::new().with_default(LevelFilter::from(log_level));
+    let configured = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => {
+            directives.parse::<Targets>().unwrap_or_else(|e| {
+                eprintln!("Ignoring RUST_LOG '{}': {}", directives, e);
+                configured
+            })
+        }
+        _ => configured,
+    };
+    let console = match verbosity.level() {
+        Some(level) => Targets::new().with_default(level),
+        None => configured.clone(),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(console),
+        )
+        .with(file_layer.with_filter(configured))
+        .with(LogViewLayer(logs.clone()).with_filter(view_filter))
+        .init();
+    logs
+}
+
+/// Copies every log event into the buffer behind the in-app log view
+struct LogViewLayer(LogBuffer);
+
+impl<S: Subscriber> Layer<S> for LogViewLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut message = MessageVisitor::default();
+        event.record(&mut message);
+        let metadata = event.metadata();
+        self.0.push(LogLine {
+            level: *metadata.level(),
+            target: metadata.target().to_string(),
+            message: message.0,
+        });
+    }
+}
+
+/// An event's message followed by its other fields as `name=value`
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?}", value);
+        } else {
+            let _ = write!(self.0, "{}={:?}", field.name(), value);
+        }
+    }
 }
 
 #[component]
-fn GardinLogo() -> Element {
-    let logo = static_bytes(LOGO);
+fn BrandLogo(custom: Option<Vec<u8>>) -> Element {
+    let logo = match custom {
+        Some(bytes) => dynamic_bytes(bytes),
+        None => static_bytes(LOGO),
+    };
     rsx!(svg {
         width: "70",
         height: "50",
@@ -62,68 +371,424 @@ fn DeviceInput(device_id: Signal<String>) -> Element {
 }
 
 #[component]
-fn ConnectButton(device_id: Signal<String>, proxy_process: Signal<Option<Child>>) -> Element {
-    let mut loading = use_signal(|| false);
-    let mut connected = use_signal(|| false);
-    // TODO: Make this an enum rather
+fn LoginButton(
+    config: Signal<TunnelConfig>,
+    logging_in: Signal<bool>,
+    credentials_refreshed: Signal<bool>,
+) -> Element {
     let mut show_popup = use_signal(String::new);
+    let manager = use_context::<ConnectionManager>();
 
     rsx!(
         rect {
-            width: "flex(1)",
             height: "100%",
             direction: "horizontal",
             main_align: "center",
             cross_align: "center",
             spacing: "10",
-            FilledButton {
-                theme: theme_with!(ButtonTheme {
-                    background: "#89BC2B".into(),
-                    hover_background: "rgb(117, 168, 23)".into(),
-                    font_theme: FontThemeWith {
-                        color: Some("black".into()),
-                    }
-                }),
+            margin: "0 0 0 10",
+            Button {
                 onclick: move |_| {
+                    if *logging_in.read() {
+                        return;
+                    }
+                    let manager = manager.clone();
                     spawn(async move {
-                        if *connected.read() {
-                            let mut child = proxy_process.take().unwrap();
-                            if child.kill().await.is_err() {
-                                show_popup.set(String::from("Failed to kill proxy process"));
-                            }
-                            proxy_process.set(Option::None);
-                            connected.set(false);
-                            return;
-                        }
-
-                        if device_id.read().is_empty() {
-                            show_popup.set(String::from("No Device"));
-                            return;
-                        }
-                        loading.set(true);
-                        let result = connect_to_tunnel(&device_id.read()).await;
-                        match result {
-                            Ok(child) => {
-                                connected.set(true);
-                                proxy_process.set(Some(child));
-                                // let _ = proxy_process.take().unwrap().wait().await;
-                            },
-                            Err(e) => show_popup.set(e),
+                        let config = config.read().clone();
+                        let message =
+                            log_in(&manager, &config, logging_in, credentials_refreshed).await;
+                        show_popup.set(message);
+                    });
+                },
+                label {
+                    if *logging_in.read() {
+                        "Logging in..."
+                    } else {
+                        "Log in to AWS"
+                    }
+                }
+            }
+            if !show_popup.read().is_empty() {
+                Popup {
+                    oncloserequest: move |_| {
+                        show_popup.write().clear()
+                    },
+                    PopupContent {
+                        label {
+                            "{show_popup}"
                         }
-                        loading.set(false);
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Run `aws sso login` and pick the connections' sessions back up, returning what to
+/// tell the operator
+async fn log_in(
+    manager: &ConnectionManager,
+    config: &TunnelConfig,
+    mut logging_in: Signal<bool>,
+    credentials_refreshed: Signal<bool>,
+) -> String {
+    logging_in.set(true);
+    let message = match aws_sso_login_with_timeout(&config.profile, config.sso_login_timeout).await
+    {
+        Ok(()) => {
+            manager.login_cooldown().reset();
+            manager.refresh_sessions(config).await;
+            // Clear any expired-session warning straight away
+            manager.check_sessions(config).await;
+            show_credentials_refreshed(credentials_refreshed);
+            String::from("Logged in to AWS")
+        }
+        Err(e) => e.to_string(),
+    };
+    logging_in.set(false);
+    message
+}
+
+/// Open (or reuse) a tunnel, confirm the device connects, then tear it all down
+#[component]
+fn TestConnectionButton(
+    device_id: Signal<String>,
+    config: Signal<TunnelConfig>,
+    mut connection_test: Signal<Option<Vec<CheckResult>>>,
+) -> Element {
+    let mut testing = use_signal(|| false);
+    let manager = use_context::<ConnectionManager>();
+
+    rsx!(
+        rect {
+            height: "100%",
+            main_align: "center",
+            margin: "0 0 0 10",
+            Button {
+                onclick: move |_| {
+                    let device = device_id.read().trim().to_string();
+                    if *testing.read() || device.is_empty() {
+                        return;
+                    }
+                    let manager = manager.clone();
+                    testing.set(true);
+                    // An empty list shows the panel with a loader until the test finishes
+                    connection_test.set(Some(Vec::new()));
+                    spawn(async move {
+                        let config = config.peek().clone();
+                        let results = test_connection_checks(&manager, &device, &config).await;
+                        connection_test.set(Some(results));
+                        testing.set(false);
                     });
                 },
                 label {
-                    if *connected.read() {
-                        "Disconnect"
+                    if *testing.read() {
+                        "Testing..."
                     } else {
-                        "Connect"
+                        "Test connection"
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Results of a connection test, with a failed connect as the only check
+async fn test_connection_checks(
+    manager: &ConnectionManager,
+    device_id: &str,
+    config: &TunnelConfig,
+) -> Vec<CheckResult> {
+    match manager.test_connection(device_id, config).await {
+        Ok(test) => test.checks(),
+        Err(e) => vec![CheckResult::fail(
+            "Connect",
+            e.to_string(),
+            "Run Diagnostics to check the setup.",
+        )],
+    }
+}
+
+#[component]
+fn ConnectButton(
+    device_id: Signal<String>,
+    config: Signal<TunnelConfig>,
+    connection_state: Signal<ConnectionState>,
+    logging_in: Signal<bool>,
+    last_device: Signal<Option<String>>,
+    credentials_refreshed: Signal<bool>,
+    auto_connect: bool,
+    /// Leave out reconnecting the last device and picking services
+    compact: bool,
+) -> Element {
+    let mut show_popup = use_signal(String::new);
+    let mut error = use_signal(|| Option::<TunnelError>::None);
+    let mut stuck = use_signal(|| Option::<TunnelError>::None);
+    // Open tunnel another client is connected to, awaiting the operator's choice
+    let mut in_use = use_signal(|| Option::<TunnelError>::None);
+    let mut shared_choice = use_signal(|| Option::<SharedTunnelPolicy>::None);
+    // Device that didn't connect its end of the tunnel in time
+    let mut device_timeout = use_signal(|| Option::<String>::None);
+    let mut wait_task = use_signal(|| Option::<Task>::None);
+    // Seconds until a rate-limited connect is retried
+    let mut throttled = use_signal(|| Option::<u64>::None);
+    // Connection where some services couldn't bind, awaiting the operator's choice
+    let mut partial = use_signal(|| Option::<ConnectionSummary>::None);
+    // Services unticked for the next connect only
+    let mut skipped = use_signal(Vec::<String>::new);
+    let manager = use_context::<ConnectionManager>();
+
+    let wait_for_device = use_callback({
+        let manager = manager.clone();
+        move |device: String| {
+            let manager = manager.clone();
+            let task = spawn(async move {
+                let config = config.read().clone();
+                match manager.wait_for_device(&device, &config).await {
+                    // Disconnected while waiting
+                    Ok(true) | Err(TunnelError::TunnelNotFound { .. }) => {}
+                    Ok(false) => device_timeout.set(Some(device)),
+                    Err(e) => error.set(Some(e)),
+                }
+            });
+            wait_task.set(Some(task));
+        }
+    });
+
+    let toggle_connection = use_callback(move |()| {
+        if *logging_in.read() || connection_state.read().is_connecting() {
+            return;
+        }
+        let manager = manager.clone();
+        spawn(async move {
+            let current = connection_state.read().clone();
+            if current.is_connected() {
+                if let Some(task) = wait_task.write().take() {
+                    task.cancel();
+                }
+                device_timeout.set(None);
+                let connected = current.device_id().unwrap_or_default();
+                match manager.disconnect(connected).await {
+                    // Still running, so stay connected and offer to force kill it
+                    Err(e @ TunnelError::Disconnection { .. }) => stuck.set(Some(e)),
+                    result => {
+                        if let Err(e) = result {
+                            error.set(Some(e));
+                        }
+                        dispatch(connection_state, AppMsg::Disconnected);
+                    }
+                }
+                return;
+            }
+
+            if device_id.read().is_empty() {
+                show_popup.set(UiError::EmptyDeviceId.user_message().to_string());
+                return;
+            }
+            let device = device_id.read().clone();
+            dispatch(
+                connection_state,
+                AppMsg::ConnectRequested {
+                    device_id: device.clone(),
+                },
+            );
+            let mut config = config.read().clone();
+            if let Some(policy) = shared_choice.write().take() {
+                config.shared_tunnel = policy;
+            }
+            let only = (!skipped.read().is_empty()).then(|| {
+                service_names(&config, &device)
+                    .into_iter()
+                    .filter(|service| !skipped.read().contains(service))
+                    .collect::<Vec<_>>()
+            });
+            // Rate limiting clears up by itself, so retry quietly before reporting it
+            let mut retries = 0;
+            let result = loop {
+                let attempt = match &only {
+                    Some(only) => manager.connect_services(&device, only, &config).await,
+                    None => manager.connect(&device, &config).await,
+                };
+                match attempt {
+                    Err(e) if e.is_throttling() && retries < THROTTLED_RETRIES => {
+                        retries += 1;
+                        let delay = THROTTLED_RETRY_DELAY * retries;
+                        tracing::warn!("{}, retrying in {} seconds", e, delay.as_secs());
+                        for remaining in (1..=delay.as_secs()).rev() {
+                            throttled.set(Some(remaining));
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                        throttled.set(None);
+                        dispatch(
+                            connection_state,
+                            AppMsg::ReconnectAttempt {
+                                attempt: retries + 1,
+                                max_attempts: THROTTLED_RETRIES + 1,
+                            },
+                        );
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok(connection) => {
+                    skipped.write().clear();
+                    let mut warnings = connection.warnings.clone();
+                    if !connection.ready {
+                        warnings.push(String::from(
+                            "localproxy has not confirmed the tunnel yet. SSH may refuse connections for a moment.",
+                        ));
+                    }
+                    if !warnings.is_empty() {
+                        show_popup.set(warnings.join("\n"));
+                    }
+                    if connection.credentials_refreshed {
+                        show_credentials_refreshed(credentials_refreshed);
+                    }
+                    if !connection.failed_services.is_empty() {
+                        partial.set(Some(connection.clone()));
+                    }
+                    last_device.set(Some(connection.device_id.clone()));
+                    let waiting_for_device = !config.destination_timeout.is_zero();
+                    if waiting_for_device {
+                        wait_for_device.call(connection.device_id.clone());
+                    }
+                    dispatch(
+                        connection_state,
+                        AppMsg::Connected {
+                            device_id: connection.device_id,
+                            tunnel_id: connection.tunnel_id,
+                            waiting_for_device,
+                        },
+                    );
+                }
+                Err(e @ TunnelError::TunnelInUse { .. }) => {
+                    dispatch(connection_state, AppMsg::ConnectAbandoned);
+                    in_use.set(Some(e));
+                }
+                Err(e) => {
+                    dispatch(connection_state, AppMsg::ConnectFailed);
+                    error.set(Some(e));
+                }
+            }
+        });
+    });
+
+    let timeout_message = device_timeout.read().as_ref().map(|device| {
+        format!(
+            "{} hasn't connected to the tunnel after {} seconds. It may be offline.",
+            device,
+            config.read().destination_timeout.as_secs()
+        )
+    });
+
+    let reconnect_label = match &*last_device.read() {
+        Some(last) => format!("Reconnect {}", last),
+        None => String::from("Reconnect last"),
+    };
+
+    // Connecting would have nothing to tunnel, so the button is disabled with the reason.
+    // Services discovered from thing attributes aren't known until connecting.
+    let no_services = if connection_state.read().is_connected() {
+        None
+    } else {
+        let config = config.read();
+        let services = service_names(&config, &device_id.read());
+        if services.is_empty() && !config.discover_service_ports {
+            Some("No services are configured. Add them under [services] in the config file.")
+        } else if !services.is_empty() && services.iter().all(|s| skipped.read().contains(s)) {
+            Some("Tick at least one service to connect")
+        } else {
+            None
+        }
+    };
+    let connect_button = rsx!(
+        FilledButton {
+            theme: theme_with!(ButtonTheme {
+                background: "#89BC2B".into(),
+                hover_background: "rgb(117, 168, 23)".into(),
+                font_theme: FontThemeWith {
+                    color: Some("black".into()),
+                }
+            }),
+            onclick: move |_| {
+                if no_services.is_none() {
+                    toggle_connection.call(());
+                }
+            },
+            label {
+                if connection_state.read().is_connected() {
+                    "Disconnect"
+                } else {
+                    "Connect"
+                }
+            }
+        }
+    );
+
+    // Connect to the configured default device on launch
+    use_hook(move || {
+        if auto_connect {
+            toggle_connection.call(());
+        }
+    });
+
+    rsx!(
+        rect {
+            width: "flex(1)",
+            height: "100%",
+            direction: "horizontal",
+            main_align: "center",
+            cross_align: "center",
+            spacing: "10",
+            opacity: if *logging_in.read() { "0.5" } else { "1" },
+            if let Some(reason) = no_services {
+                TooltipContainer {
+                    tooltip: rsx!(Tooltip { text: "{reason}" }),
+                    rect {
+                        opacity: "0.5",
+                        {connect_button}
+                    }
+                }
+            } else {
+                {connect_button}
+            }
+            if !compact && !connection_state.read().is_connected() {
+                rect {
+                    opacity: if last_device.read().is_some() { "1" } else { "0.5" },
+                    Button {
+                        onclick: move |_| {
+                            let Some(last) = last_device.read().clone() else {
+                                return;
+                            };
+                            device_id.set(last);
+                            toggle_connection.call(());
+                        },
+                        label {
+                            "{reconnect_label}"
+                        }
                     }
                 }
             }
-            if *loading.read() {
+            if !compact && !connection_state.read().is_connected() {
+                ServicePicker {device_id, config, skipped}
+            }
+            if let ConnectionState::Reconnecting {
+                attempt,
+                max_attempts,
+                ..
+            } = *connection_state.read()
+            {
+                ReconnectingIndicator {attempt, max_attempts}
+            } else if connection_state.read().is_connecting() {
                 Loader {}
             }
+            if let Some(secs) = *throttled.read() {
+                label {
+                    color: "rgb(230, 190, 60)",
+                    "AWS is rate-limiting requests, retrying in {secs}s…"
+                }
+            }
             if !show_popup.read().is_empty() {
                 Popup {
                     oncloserequest: move |_| {
@@ -131,34 +796,2210 @@ fn ConnectButton(device_id: Signal<String>, proxy_process: Signal<Option<Child>>
                     },
                     PopupContent {
                         label {
-                            if show_popup.read().as_str() == "No Device" {
-                                "Device ID cannot be empty"
+                            "{show_popup}"
+                        }
+                    }
+                }
+            }
+            if let Some(message) = timeout_message {
+                Popup {
+                    oncloserequest: move |_| device_timeout.set(None),
+                    PopupTitle {
+                        label {
+                            "Waiting for device"
+                        }
+                    }
+                    PopupContent {
+                        label {
+                            "{message}"
+                        }
+                        rect {
+                            direction: "horizontal",
+                            spacing: "10",
+                            margin: "12 0 0 0",
+                            Button {
+                                onclick: move |_| {
+                                    if let Some(device) = device_timeout.write().take() {
+                                        wait_for_device.call(device);
+                                    }
+                                },
+                                label { "Keep waiting" }
+                            }
+                            Button {
+                                onclick: move |_| {
+                                    device_timeout.set(None);
+                                    if connection_state.read().is_connected() {
+                                        toggle_connection.call(());
+                                    }
+                                },
+                                label { "Cancel" }
+                            }
+                            Button {
+                                onclick: move |_| device_timeout.set(None),
+                                label { "Proceed anyway" }
                             }
                         }
                     }
                 }
             }
+            ErrorPopup {error}
+            ForceKillPrompt {stuck, connection_state}
+            SharedTunnelPrompt {in_use, shared_choice, connect: toggle_connection}
+            PartialServicesPrompt {partial, connection_state, config, connect: toggle_connection}
         }
     )
 }
 
-fn app() -> Element {
-    use_init_theme(|| DARK_THEME);
+/// Move the shown connection on by `msg`, the only way it changes
+fn dispatch(mut connection_state: Signal<ConnectionState>, msg: AppMsg) {
+    let next = connection_state.peek().apply(msg);
+    if *connection_state.peek() != next {
+        connection_state.set(next);
+    }
+}
+
+/// Amber spinner and attempt count while a connect is retried, so it doesn't look
+/// like a fresh attempt
+#[component]
+fn ReconnectingIndicator(attempt: u32, max_attempts: u32) -> Element {
+    rsx!(
+        rect {
+            direction: "horizontal",
+            cross_align: "center",
+            spacing: "8",
+            Loader {
+                theme: theme_with!(LoaderTheme {
+                    primary_color: "rgb(230, 190, 60)".into(),
+                }),
+            }
+            label {
+                color: "rgb(230, 190, 60)",
+                "Reconnecting (attempt {attempt}/{max_attempts})…"
+            }
+        }
+    )
+}
+
+/// Services a connect to the device would use, from its profile or the config
+fn service_names(config: &TunnelConfig, device_id: &str) -> Vec<String> {
+    config
+        .device_services(device_id)
+        .unwrap_or(&config.services)
+        .services()
+        .map(String::from)
+        .collect()
+}
 
-    let device_id = use_signal(String::new);
-    let proxy_process = use_signal(|| Option::<Child>::None);
+/// A checkbox per service, all ticked; unticking one leaves it out of the next connect
+#[component]
+fn ServicePicker(
+    device_id: Signal<String>,
+    config: Signal<TunnelConfig>,
+    mut skipped: Signal<Vec<String>>,
+) -> Element {
+    let services = service_names(&config.read(), &device_id.read());
+    if services.len() < 2 {
+        return rsx!();
+    }
 
     rsx!(
-        Body {
-            rect {
-                width: "fill",
-                height: "fill",
-                direction: "horizontal",
-                content: "flex",
-                padding: "24",
-                GardinLogo {}
-                DeviceInput {device_id}
-                ConnectButton {device_id, proxy_process}
+        rect {
+            direction: "horizontal",
+            cross_align: "center",
+            {services.into_iter().map(|service| {
+                let selected = !skipped.read().contains(&service);
+                let alias = config.read().service_label(&service).to_string();
+                let name = service.clone();
+                rsx!(
+                    Tile {
+                        key: "{service}",
+                        onselect: move |_| {
+                            if selected {
+                                skipped.write().push(name.clone());
+                            } else {
+                                skipped.write().retain(|skip| skip != &name);
+                            }
+                        },
+                        leading: rsx!(
+                            Checkbox {
+                                selected,
+                            }
+                        ),
+                        label { "{alias}" }
+                    }
+                )
+            })}
+        }
+    )
+}
+
+/// Ask whether to share, replace or leave a tunnel another client is connected to
+#[component]
+fn SharedTunnelPrompt(
+    mut in_use: Signal<Option<TunnelError>>,
+    mut shared_choice: Signal<Option<SharedTunnelPolicy>>,
+    connect: EventHandler,
+) -> Element {
+    let Some(message) = in_use.read().as_ref().map(|e| e.to_string()) else {
+        return rsx!();
+    };
+    let mut choose = move |policy: SharedTunnelPolicy| {
+        in_use.set(None);
+        shared_choice.set(Some(policy));
+        connect.call(());
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| in_use.set(None),
+            PopupTitle {
+                label {
+                    "Tunnel already in use"
+                }
+            }
+            PopupContent {
+                label {
+                    "{message}. Connecting to it will disconnect them."
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| choose(SharedTunnelPolicy::Proceed),
+                        label { "Proceed" }
+                    }
+                    Button {
+                        onclick: move |_| choose(SharedTunnelPolicy::ForceNew),
+                        label { "Open another tunnel" }
+                    }
+                    Button {
+                        onclick: move |_| in_use.set(None),
+                        label { "Cancel" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Offer to carry on with the services that bound, or reconnect to try the rest again
+#[component]
+fn PartialServicesPrompt(
+    mut partial: Signal<Option<ConnectionSummary>>,
+    connection_state: Signal<ConnectionState>,
+    config: Signal<TunnelConfig>,
+    connect: EventHandler,
+) -> Element {
+    let manager = use_context::<ConnectionManager>();
+    let Some(connection) = partial.read().clone() else {
+        return rsx!();
+    };
+    let config = config.read();
+    let failed = connection
+        .failed_services
+        .iter()
+        .map(|service| config.service_label(service))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let working = connection
+        .services
+        .services()
+        .filter(|service| !connection.failed_services.iter().any(|f| f == service))
+        .map(|service| config.service_label(service))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let device_id = connection.device_id.clone();
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| partial.set(None),
+            PopupTitle {
+                label {
+                    "Some services aren't available"
+                }
+            }
+            PopupContent {
+                label {
+                    "localproxy couldn't listen for {failed}, usually because another program is using the port. {working} is working."
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| partial.set(None),
+                        label { "Continue with {working}" }
+                    }
+                    Button {
+                        onclick: move |_| {
+                            let manager = manager.clone();
+                            let device_id = device_id.clone();
+                            partial.set(None);
+                            spawn(async move {
+                                match manager.disconnect(&device_id).await {
+                                    Ok(()) | Err(TunnelError::TunnelNotFound { .. }) => {
+                                        dispatch(connection_state, AppMsg::Disconnected);
+                                        connect.call(());
+                                    }
+                                    Err(e) => eprintln!("Failed to disconnect {} to retry: {}", device_id, e),
+                                }
+                            });
+                        },
+                        label { "Retry" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Offer to force kill a localproxy that didn't stop on disconnect
+#[component]
+fn ForceKillPrompt(
+    mut stuck: Signal<Option<TunnelError>>,
+    connection_state: Signal<ConnectionState>,
+) -> Element {
+    let manager = use_context::<ConnectionManager>();
+    let (device_id, message) = match &*stuck.read() {
+        Some(err @ TunnelError::Disconnection { device_id, .. }) => (
+            device_id.clone(),
+            UiError::from(err).user_message().to_string(),
+        ),
+        _ => return rsx!(),
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| stuck.set(None),
+            PopupTitle {
+                label {
+                    "localproxy did not stop"
+                }
+            }
+            PopupContent {
+                label {
+                    "{message}"
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| {
+                            let manager = manager.clone();
+                            let device_id = device_id.clone();
+                            spawn(async move {
+                                match manager.force_disconnect(&device_id).await {
+                                    // Not found means it exited and was reaped meanwhile
+                                    Ok(()) | Err(TunnelError::TunnelNotFound { .. }) => {
+                                        stuck.set(None);
+                                        dispatch(connection_state, AppMsg::Disconnected);
+                                    }
+                                    Err(e) => stuck.set(Some(e)),
+                                }
+                            });
+                        },
+                        label { "Force kill" }
+                    }
+                    Button {
+                        onclick: move |_| stuck.set(None),
+                        label { "Keep running" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Friendly error text, with the full error chain available for bug reports
+#[component]
+fn ErrorPopup(mut error: Signal<Option<TunnelError>>) -> Element {
+    let mut show_details = use_signal(|| false);
+    let mut clipboard = use_clipboard();
+
+    let Some((message, details)) = error
+        .read()
+        .as_ref()
+        .map(|e| (UiError::from(e).user_message().to_string(), e.details()))
+    else {
+        return rsx!();
+    };
+    let copied_details = details.clone();
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| {
+                error.set(None);
+                show_details.set(false);
+            },
+            PopupContent {
+                SelectableText {
+                    value: message,
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0",
+                    Button {
+                        onclick: move |_| show_details.toggle(),
+                        label {
+                            if *show_details.read() {
+                                "Hide details"
+                            } else {
+                                "Show details"
+                            }
+                        }
+                    }
+                    Button {
+                        onclick: move |_| {
+                            if let Err(e) = clipboard.set(copied_details.clone()) {
+                                eprintln!("Failed to copy error details: {:?}", e);
+                            }
+                        },
+                        label {
+                            "Copy details"
+                        }
+                    }
+                }
+                if *show_details.read() {
+                    ScrollView {
+                        height: "120",
+                        SelectableText {
+                            value: details,
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+#[component]
+fn ProfilePicker(mut config: Signal<TunnelConfig>, credentials_refreshed: Signal<bool>) -> Element {
+    let profiles = use_hook(|| {
+        list_profiles().unwrap_or_else(|e| {
+            eprintln!("Failed to read AWS profiles: {}", e);
+            Vec::new()
+        })
+    });
+    // Looked up again whenever the profile changes or a login refreshes the credentials
+    let identity = use_resource(move || async move {
+        let profile = config.read().profile.clone();
+        let _ = credentials_refreshed.read();
+        caller_identity(&profile).await
+    });
+
+    if profiles.is_empty() {
+        return rsx!();
+    }
+    let selected = config.read().profile.clone();
+    let account = match &*identity.read() {
+        Some(Ok(arn)) => match account_id(arn) {
+            Some(account) => format!("Account {}", account),
+            None => arn.clone(),
+        },
+        Some(Err(_)) => String::from("Not logged in"),
+        None => String::from("Checking account..."),
+    };
+
+    rsx!(
+        rect {
+            main_align: "center",
+            spacing: "4",
+            margin: "0 0 0 10",
+            Dropdown {
+                value: selected,
+                for profile in profiles {
+                    DropdownItem {
+                        value: profile.clone(),
+                        onpress: {
+                            let profile = profile.clone();
+                            move |_| {
+                                config.write().profile = profile.clone();
+                                if let Err(e) = record_profile(&profile) {
+                                    eprintln!("{}", e);
+                                }
+                            }
+                        },
+                        label { "{profile}" }
+                    }
+                }
+            }
+            rect {
+                font_size: "11",
+                color: "rgb(150, 150, 150)",
+                SelectableText {
+                    value: account,
+                }
+            }
+        }
+    )
+}
+
+/// Switch every setting an environment bundles at once, remembering the choice
+#[component]
+fn EnvironmentPicker(mut config: Signal<TunnelConfig>) -> Element {
+    let base_config = use_hook(|| consume_context::<LaunchState>().base_config);
+
+    let names = base_config.environment_names();
+    if names.is_empty() {
+        return rsx!();
+    }
+    let selected = config.read().environment.clone().unwrap_or_default();
+
+    rsx!(
+        rect {
+            main_align: "center",
+            margin: "0 0 0 10",
+            Dropdown {
+                value: selected,
+                for name in names {
+                    DropdownItem {
+                        value: name.clone(),
+                        onpress: {
+                            let name = name.clone();
+                            let base_config = base_config.clone();
+                            move |_| match base_config.with_environment(&name) {
+                                Ok(environment_config) => {
+                                    config.set(environment_config.with_process_env_overrides());
+                                    if let Err(e) = record_environment(&name) {
+                                        eprintln!("{}", e);
+                                    }
+                                }
+                                Err(e) => eprintln!("{}", e),
+                            }
+                        },
+                        label { "{name}" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Flag the refreshed credentials in the status bar for a few seconds
+fn show_credentials_refreshed(mut credentials_refreshed: Signal<bool>) {
+    credentials_refreshed.set(true);
+    spawn(async move {
+        tokio::time::sleep(CREDENTIALS_NOTICE).await;
+        credentials_refreshed.set(false);
+    });
+}
+
+#[component]
+fn StatusBar(
+    connection_state: Signal<ConnectionState>,
+    active_connections: Signal<Vec<ConnectionSummary>>,
+    logging_in: Signal<bool>,
+    credentials_refreshed: Signal<bool>,
+    config: Signal<TunnelConfig>,
+    diagnostics: Signal<Option<Vec<CheckResult>>>,
+    mut show_setup: Signal<bool>,
+    mut show_bulk_close: Signal<bool>,
+    mut show_processes: Signal<bool>,
+    mut show_logs: Signal<bool>,
+    show_quit: Signal<bool>,
+    layout: Signal<LayoutMode>,
+) -> Element {
+    let mut clipboard = use_clipboard();
+    let compact = *layout.read() == LayoutMode::Compact;
+    let platform = use_platform();
+    let logs_toggle = if *show_logs.read() {
+        "Hide logs"
+    } else {
+        "Show logs"
+    };
+    let state = connection_state.read().to_string();
+    let tunnel_id = connection_state.read().tunnel_id().map(String::from);
+    // Local ports to point the SSH client at
+    let current = connection_state
+        .read()
+        .device_id()
+        .filter(|_| connection_state.read().is_connected())
+        .and_then(|device_id| {
+            active_connections
+                .read()
+                .iter()
+                .find(|c| c.device_id == device_id)
+                .cloned()
+        });
+    let ports = current
+        .as_ref()
+        .map(|c| {
+            let mut ports = format_ports(c, &config.read());
+            if let Some(secs) = c.expires_in_secs {
+                ports = format!("{}  closes in {}", ports, format_duration(secs));
+            }
+            if let Some(secs) = c.tunnel_expires_in_secs {
+                ports = format!(
+                    "{}  tunnel expires in {}",
+                    ports,
+                    format_hours_minutes(secs)
+                );
+            }
+            ports
+        })
+        .unwrap_or_default();
+    let color = status_color(connection_state.read().level());
+    let tasks = if *logging_in.read() {
+        "AWS login in progress"
+    } else if *credentials_refreshed.read() {
+        "AWS credentials refreshed"
+    } else {
+        "No background tasks"
+    };
+
+    rsx!(
+        rect {
+            width: "fill",
+            height: "24",
+            direction: "horizontal",
+            main_align: "space-between",
+            cross_align: "center",
+            padding: "0 12",
+            background: "rgb(35, 35, 35)",
+            font_size: "12",
+            rect {
+                direction: "horizontal",
+                cross_align: "center",
+                rect {
+                    color: "{color}",
+                    SelectableText {
+                        value: state,
+                    }
+                }
+                if let Some(tunnel_id) = tunnel_id {
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 0 0 12",
+                        onclick: move |_| {
+                            if let Err(e) = clipboard.set(tunnel_id.clone()) {
+                                eprintln!("Failed to copy tunnel ID: {:?}", e);
+                            }
+                        },
+                        "Copy tunnel ID"
+                    }
+                }
+            }
+            label {
+                color: "rgb(200, 200, 200)",
+                "{ports}"
+            }
+            if !compact {
+                rect {
+                    direction: "horizontal",
+                    cross_align: "center",
+                    label {
+                        color: "rgb(150, 150, 150)",
+                        margin: "0 12 0 0",
+                        "{tasks}"
+                    }
+                    label {
+                        color: "rgb(150, 150, 150)",
+                        margin: "0 12 0 0",
+                        "v{CURRENT_VERSION}"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| show_logs.toggle(),
+                        "{logs_toggle}"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| open_logs_folder(),
+                        "Open logs"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| open_config_file(),
+                        "Open config"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| show_setup.set(true),
+                        "Setup"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| show_bulk_close.set(true),
+                        "Close tunnels"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| show_processes.set(true),
+                        "Processes"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| start_diagnostics(diagnostics, config),
+                        "Diagnostics"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| switch_layout(layout, LayoutMode::Compact),
+                        "Compact view"
+                    }
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        onclick: move |_| quit(config, active_connections, show_quit, &platform),
+                        "Quit"
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Everything the compact layout leaves out, one click away
+#[component]
+fn CompactMenu(
+    device_id: Signal<String>,
+    config: Signal<TunnelConfig>,
+    active_connections: Signal<Vec<ConnectionSummary>>,
+    logging_in: Signal<bool>,
+    credentials_refreshed: Signal<bool>,
+    diagnostics: Signal<Option<Vec<CheckResult>>>,
+    mut connection_test: Signal<Option<Vec<CheckResult>>>,
+    mut session_notice: Signal<Option<String>>,
+    mut show_setup: Signal<bool>,
+    mut show_bulk_close: Signal<bool>,
+    mut show_processes: Signal<bool>,
+    mut show_logs: Signal<bool>,
+    show_quit: Signal<bool>,
+    layout: Signal<LayoutMode>,
+) -> Element {
+    let mut show_menu = use_signal(|| false);
+    let manager = use_context::<ConnectionManager>();
+    let platform = use_platform();
+    let login_manager = manager.clone();
+
+    rsx!(
+        rect {
+            height: "100%",
+            main_align: "center",
+            margin: "0 0 0 10",
+            Button {
+                onclick: move |_| show_menu.toggle(),
+                label { "Menu" }
+            }
+            if *show_menu.read() {
+                Menu {
+                    onclose: move |_| show_menu.set(false),
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            if *logging_in.read() {
+                                return;
+                            }
+                            let manager = login_manager.clone();
+                            spawn(async move {
+                                let config = config.read().clone();
+                                let message =
+                                    log_in(&manager, &config, logging_in, credentials_refreshed).await;
+                                session_notice.set(Some(message));
+                            });
+                        },
+                        label { "Log in to AWS" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            let device = device_id.read().trim().to_string();
+                            if device.is_empty() || connection_test.read().is_some() {
+                                return;
+                            }
+                            let manager = manager.clone();
+                            connection_test.set(Some(Vec::new()));
+                            spawn(async move {
+                                let config = config.peek().clone();
+                                let results = test_connection_checks(&manager, &device, &config).await;
+                                connection_test.set(Some(results));
+                            });
+                        },
+                        label { "Test connection" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            start_diagnostics(diagnostics, config);
+                        },
+                        label { "Diagnostics" }
+                    }
+                    // The logs and connection list need the room of the expanded layout
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            show_logs.set(true);
+                            switch_layout(layout, LayoutMode::Expanded);
+                        },
+                        label { "Show logs" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            show_processes.set(true);
+                        },
+                        label { "Processes" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            show_bulk_close.set(true);
+                        },
+                        label { "Close tunnels" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            show_setup.set(true);
+                        },
+                        label { "Setup" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            open_config_file();
+                        },
+                        label { "Open config" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            switch_layout(layout, LayoutMode::Expanded);
+                        },
+                        label { "Expanded view" }
+                    }
+                    MenuButton {
+                        onpress: move |_| {
+                            show_menu.set(false);
+                            quit(config, active_connections, show_quit, &platform);
+                        },
+                        label { "Quit" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Open the diagnostics panel, which shows a loader until the checks finish
+fn start_diagnostics(
+    mut diagnostics: Signal<Option<Vec<CheckResult>>>,
+    config: Signal<TunnelConfig>,
+) {
+    diagnostics.set(Some(Vec::new()));
+    spawn(async move {
+        let config = config.peek().clone();
+        diagnostics.set(Some(run_diagnostics(&config).await));
+    });
+}
+
+/// Quit, first asking to close the tunnels if any are connected and `confirm_quit` is on
+fn quit(
+    config: Signal<TunnelConfig>,
+    active_connections: Signal<Vec<ConnectionSummary>>,
+    mut show_quit: Signal<bool>,
+    platform: &UsePlatform,
+) {
+    if config.read().confirm_quit && !active_connections.read().is_empty() {
+        show_quit.set(true);
+    } else {
+        platform.exit();
+    }
+}
+
+/// Switch the window layout and remember it for the next launch
+fn switch_layout(mut layout: Signal<LayoutMode>, next: LayoutMode) {
+    layout.set(next);
+    if let Err(e) = record_layout(next) {
+        eprintln!("{}", e);
+    }
+}
+
+fn status_color(level: StatusLevel) -> &'static str {
+    match level {
+        StatusLevel::Ok => "#89BC2B",
+        StatusLevel::Pending => "rgb(230, 190, 60)",
+        StatusLevel::Error => "rgb(220, 80, 80)",
+        StatusLevel::Idle => "rgb(150, 150, 150)",
+    }
+}
+
+/// Every device the manager knows about, with a status dot and label per row
+#[component]
+fn ConnectionList(
+    active_connections: Signal<Vec<ConnectionSummary>>,
+    pending_devices: Signal<Vec<String>>,
+    mut failed_devices: Signal<Vec<(String, String)>>,
+    config: Signal<TunnelConfig>,
+) -> Element {
+    let mut rows: Vec<(String, ConnectionState, String)> = pending_devices
+        .read()
+        .iter()
+        .map(|device_id| {
+            let state = ConnectionState::Connecting {
+                device_id: device_id.clone(),
+            };
+            (device_id.clone(), state, String::new())
+        })
+        .collect();
+    rows.extend(active_connections.read().iter().map(|connection| {
+        let detail = format!(
+            "{}  {}  {}",
+            connection.region,
+            format_ports(connection, &config.read()),
+            connection.action
+        );
+        let device_id = connection.device_id.clone();
+        (device_id, ConnectionState::from(connection.clone()), detail)
+    }));
+    rows.extend(
+        failed_devices.read().iter().map(|(device_id, reason)| {
+            (device_id.clone(), ConnectionState::Failed, reason.clone())
+        }),
+    );
+
+    rsx!(
+        ScrollView {
+            height: "72",
+            padding: "0 24",
+            if rows.is_empty() {
+                ConnectionRow {
+                    device_id: String::from("No devices connected"),
+                    state: ConnectionState::Disconnected,
+                    detail: String::new(),
+                    failed_devices,
+                }
+            }
+            for (device_id, state, detail) in rows {
+                ConnectionRow {key: "{device_id}", device_id, state, detail, failed_devices}
+            }
+        }
+    )
+}
+
+#[component]
+fn ConnectionRow(
+    device_id: String,
+    state: ConnectionState,
+    detail: String,
+    mut failed_devices: Signal<Vec<(String, String)>>,
+) -> Element {
+    let color = status_color(state.level());
+    let label = state.short_label();
+    let failed = state == ConnectionState::Failed;
+    let dismissed = device_id.clone();
+
+    rsx!(
+        rect {
+            direction: "horizontal",
+            cross_align: "center",
+            spacing: "8",
+            height: "22",
+            rect {
+                width: "10",
+                height: "10",
+                corner_radius: "5",
+                background: "{color}",
+            }
+            label {
+                width: "110",
+                "{device_id}"
+            }
+            label {
+                width: "130",
+                color: "{color}",
+                "{label}"
+            }
+            label {
+                color: "rgb(150, 150, 150)",
+                font_size: "12",
+                max_lines: "1",
+                text_overflow: "ellipsis",
+                "{detail}"
+            }
+            if failed {
+                label {
+                    color: "rgb(120, 170, 220)",
+                    font_size: "12",
+                    onclick: move |_| {
+                        failed_devices.write().retain(|(device_id, _)| *device_id != dismissed)
+                    },
+                    "Dismiss"
+                }
+            }
+        }
+    )
+}
+
+/// Each service, by its alias, and its local port, marking any localproxy couldn't listen on
+fn format_ports(connection: &ConnectionSummary, config: &TunnelConfig) -> String {
+    connection
+        .services
+        .iter()
+        .map(|(service, port)| {
+            let label = config.service_label(service);
+            if connection
+                .failed_services
+                .iter()
+                .any(|failed| failed == service)
+            {
+                format!("{} :{} not listening", label, port)
+            } else {
+                format!("{} :{}", label, port)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+/// A duration such as the time left in a session, to the minute once it is over one
+fn format_duration(secs: u64) -> String {
+    let (hours, minutes) = (secs / 3600, secs % 3600 / 60);
+    if hours > 0 {
+        format!("{}h {:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// A countdown as HH:MM, rounding up so it only reads 00:00 once the time is up
+fn format_hours_minutes(secs: u64) -> String {
+    let minutes = secs.div_ceil(60);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Reveal the log directory so operators can attach the log to a support request
+fn open_logs_folder() {
+    let Some(dir) = log_dir() else {
+        eprintln!("No local data directory for logs");
+        return;
+    };
+    spawn(async move {
+        let opened = fs::create_dir_all(&dir)
+            .map_err(TunnelError::from)
+            .and_then(|()| open_path(&dir));
+        if let Err(e) = opened {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+/// Open the config file in the default editor, creating an empty one on first use
+fn open_config_file() {
+    spawn(async move {
+        if let Err(e) = TunnelConfig::create_if_missing().and_then(|path| open_path(&path)) {
+            eprintln!("{}", e);
+        }
+    });
+}
+
+/// Every localproxy this app runs, plus any left by an earlier run, each with a kill button
+#[component]
+fn ProcessesPanel(
+    mut show_processes: Signal<bool>,
+    connection_state: Signal<ConnectionState>,
+    mut active_connections: Signal<Vec<ConnectionSummary>>,
+    config: Signal<TunnelConfig>,
+) -> Element {
+    let manager = use_context::<ConnectionManager>();
+    let mut processes = use_signal(Vec::<ManagedProcess>::new);
+    let mut orphans = use_signal(Vec::<OrphanedProcess>::new);
+    let mut message = use_signal(String::new);
+
+    // Uptimes tick, so the list is refreshed while the panel is open
+    use_future({
+        let manager = manager.clone();
+        move || {
+            let manager = manager.clone();
+            async move {
+                if let Some(dir) = pid_dir() {
+                    orphans.set(find_orphans(&dir).await);
+                }
+                loop {
+                    processes.set(manager.processes().await);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    });
+
+    // Killing goes through the manager, so the connection is dropped along with it
+    let kill = move |device_id: String| {
+        let manager = manager.clone();
+        spawn(async move {
+            match manager.force_disconnect(&device_id).await {
+                // Not found means it exited and was reaped meanwhile
+                Ok(()) | Err(TunnelError::TunnelNotFound { .. }) => {
+                    active_connections
+                        .write()
+                        .retain(|connection| connection.device_id != device_id);
+                    if connection_state.peek().device_id() == Some(device_id.as_str()) {
+                        dispatch(connection_state, AppMsg::Disconnected);
+                    }
+                    message.set(format!("Stopped localproxy for {}", device_id));
+                }
+                Err(e) => message.set(e.to_string()),
+            }
+            processes.set(manager.processes().await);
+        });
+    };
+    let rows: Vec<(ManagedProcess, String)> = processes
+        .read()
+        .iter()
+        .map(|process| {
+            let ports = process
+                .services
+                .iter()
+                .map(|(service, port)| {
+                    format!("{} :{}", config.read().service_label(service), port)
+                })
+                .collect::<Vec<_>>()
+                .join("  ");
+            let detail = format!("{}  up {}", ports, format_duration(process.uptime_secs));
+            (process.clone(), detail)
+        })
+        .collect();
+    let leftovers = orphans.read().clone();
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| show_processes.set(false),
+            PopupTitle {
+                label {
+                    "localproxy processes"
+                }
+            }
+            PopupContent {
+                if rows.is_empty() && leftovers.is_empty() {
+                    label {
+                        "No localproxy running"
+                    }
+                }
+                for (process, detail) in rows {
+                    ProcessRow {
+                        key: "{process.pid}",
+                        pid: process.pid,
+                        device_id: process.device_id.clone(),
+                        detail,
+                        onkill: {
+                            let kill = kill.clone();
+                            move |_| kill(process.device_id.clone())
+                        },
+                    }
+                }
+                for orphan in leftovers {
+                    ProcessRow {
+                        key: "{orphan.pid}",
+                        pid: orphan.pid,
+                        device_id: orphan.device_id.clone(),
+                        detail: String::from("Left running by an earlier session"),
+                        onkill: move |_| {
+                            let orphan = orphan.clone();
+                            spawn(async move {
+                                match terminate(&orphan).await {
+                                    Ok(()) => {
+                                        orphans.write().retain(|o| o.pid != orphan.pid);
+                                        message.set(format!("Stopped orphaned localproxy (pid {})", orphan.pid));
+                                    }
+                                    Err(e) => message.set(e.to_string()),
+                                }
+                            });
+                        },
+                    }
+                }
+                label {
+                    margin: "8 0 0 0",
+                    "{message}"
+                }
+            }
+        }
+    )
+}
+
+/// One localproxy in the process panel
+#[component]
+fn ProcessRow(pid: u32, device_id: String, detail: String, onkill: EventHandler<()>) -> Element {
+    rsx!(
+        rect {
+            direction: "horizontal",
+            cross_align: "center",
+            spacing: "8",
+            height: "26",
+            label {
+                width: "70",
+                "{pid}"
+            }
+            label {
+                width: "110",
+                "{device_id}"
+            }
+            label {
+                width: "fill",
+                color: "rgb(150, 150, 150)",
+                font_size: "12",
+                max_lines: "1",
+                text_overflow: "ellipsis",
+                "{detail}"
+            }
+            label {
+                color: "rgb(220, 80, 80)",
+                onclick: move |_| onkill.call(()),
+                "Kill"
+            }
+        }
+    )
+}
+
+/// Status choices for a bulk close, as shown in the dropdown
+const BULK_CLOSE_STATUSES: [&str; 3] = ["Open", "Closed", "Any"];
+
+/// Close every tunnel in the account matching a status and device prefix, after
+/// showing how many that is
+#[component]
+fn BulkClosePanel(mut show_bulk_close: Signal<bool>, config: Signal<TunnelConfig>) -> Element {
+    let mut status = use_signal(|| String::from(BULK_CLOSE_STATUSES[0]));
+    let mut device_prefix = use_signal(String::new);
+    // Tunnels found by the last search, waiting for confirmation
+    let mut matching = use_signal(|| Option::<Vec<String>>::None);
+    let mut busy = use_signal(|| false);
+    let mut message = use_signal(String::new);
+
+    let filter = move || TunnelFilter {
+        status: match status.read().as_str() {
+            "Open" => Some(TunnelStatus::Open),
+            "Closed" => Some(TunnelStatus::Closed),
+            _ => None,
+        },
+        device_prefix: Some(device_prefix.read().trim().to_string()).filter(|p| !p.is_empty()),
+    };
+    let selected = status.read().clone();
+    let count = matching.read().as_ref().map(Vec::len);
+    let confirm = match count {
+        Some(0) => String::from("No tunnels match"),
+        Some(1) => String::from("1 tunnel will be closed"),
+        Some(n) => format!("{} tunnels will be closed", n),
+        None => String::new(),
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| show_bulk_close.set(false),
+            PopupTitle {
+                label {
+                    "Close tunnels"
+                }
+            }
+            PopupContent {
+                rect {
+                    spacing: "8",
+                    direction: "horizontal",
+                    cross_align: "center",
+                    Dropdown {
+                        value: selected,
+                        for choice in BULK_CLOSE_STATUSES {
+                            DropdownItem {
+                                value: String::from(choice),
+                                onpress: move |_| {
+                                    status.set(String::from(choice));
+                                    matching.set(None);
+                                },
+                                label { "{choice}" }
+                            }
+                        }
+                    }
+                    label {
+                        "Device ID prefix"
+                    }
+                    Input {
+                        value: device_prefix,
+                        width: "fill",
+                        onchange: move |txt| {
+                            device_prefix.set(txt);
+                            matching.set(None);
+                        },
+                    }
+                }
+                label {
+                    margin: "8 0",
+                    "{confirm}{message}"
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    Button {
+                        onclick: move |_| {
+                            if *busy.read() {
+                                return;
+                            }
+                            let filter = filter();
+                            spawn(async move {
+                                busy.set(true);
+                                message.set(String::from("Looking for tunnels..."));
+                                let config = config.read().clone();
+                                let found = match get_client(&config).await {
+                                    Ok(client) => {
+                                        let client = AwsTunnelClient::new(client);
+                                        find_tunnels_matching(&client, &filter).await
+                                    }
+                                    Err(e) => Err(e),
+                                };
+                                match found {
+                                    Ok(found) => {
+                                        message.set(String::new());
+                                        matching.set(Some(found));
+                                    }
+                                    Err(e) => message.set(e.to_string()),
+                                }
+                                busy.set(false);
+                            });
+                        },
+                        label { "Find tunnels" }
+                    }
+                    if count.is_some_and(|n| n > 0) {
+                        Button {
+                            onclick: move |_| {
+                                if *busy.read() {
+                                    return;
+                                }
+                                let Some(tunnel_ids) = matching.write().take() else {
+                                    return;
+                                };
+                                spawn(async move {
+                                    busy.set(true);
+                                    let config = config.read().clone();
+                                    let closed = match get_client(&config).await {
+                                        Ok(client) => {
+                                            let client = AwsTunnelClient::new(client);
+                                            close_tunnels(&client, &tunnel_ids).await
+                                        }
+                                        Err(e) => Err(e),
+                                    };
+                                    match closed {
+                                        Ok(closed) => message.set(format!("Closed {} tunnels", closed.len())),
+                                        Err(e) => message.set(e.to_string()),
+                                    }
+                                    busy.set(false);
+                                });
+                            },
+                            label { "Close them" }
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// Titles of the first-run setup steps, in order
+const SETUP_STEPS: [&str; 4] = ["Prerequisites", "AWS profile", "localproxy", "Log in"];
+
+/// Walk a new user through the prerequisites, their AWS profile, localproxy and a first login
+#[component]
+fn SetupWizard(
+    mut show_setup: Signal<bool>,
+    mut config: Signal<TunnelConfig>,
+    credentials_refreshed: Signal<bool>,
+) -> Element {
+    let mut step = use_signal(|| 0usize);
+    let mut profile = use_signal(|| config.peek().profile.clone());
+    let mut localproxy_path = use_signal(String::new);
+    let mut logging_in = use_signal(|| false);
+    let mut login_result = use_signal(|| Option::<String>::None);
+    let mut save_error = use_signal(|| Option::<String>::None);
+    let profiles = use_hook(|| list_profiles().unwrap_or_default());
+    let found = use_hook(find_localproxy);
+    let mut checks = use_resource(move || async move {
+        let config = config.peek().clone();
+        run_diagnostics(&config).await
+    });
+    let region = use_resource(move || async move {
+        let profile = profile.read().clone();
+        configured_region(&profile).await
+    });
+
+    let current = *step.read();
+    let last = current == SETUP_STEPS.len() - 1;
+    let title = format!(
+        "Setup {}/{}: {}",
+        current + 1,
+        SETUP_STEPS.len(),
+        SETUP_STEPS[current]
+    );
+    let results = checks.read().clone();
+    let region_text = match &*region.read() {
+        Some(Some(region)) => format!("Region: {}", region),
+        Some(None) => String::from(
+            "No region is set for this profile, so eu-west-1 is used. Set one in ~/.aws/config.",
+        ),
+        None => String::from("Checking region..."),
+    };
+    let found_text = match &found {
+        Some(path) => format!("Found {}. Leave the path empty to use it.", path.display()),
+        None => String::from(
+            "localproxy wasn't found in the assets folder or on PATH. Enter the path to it.",
+        ),
+    };
+    let selected = profile.read().clone();
+    let login_text = login_result.read().clone().unwrap_or_else(|| {
+        format!(
+            "Log in with '{}' to check your access, or finish and log in later.",
+            selected
+        )
+    });
+
+    // Skipping still creates the config file so the setup doesn't come back every launch
+    let skip = move |_| {
+        if let Err(e) = TunnelConfig::create_if_missing() {
+            eprintln!("{}", e);
+        }
+        show_setup.set(false);
+    };
+    let finish = move |_| {
+        let path = localproxy_path.read().trim().to_string();
+        let choices = SetupChoices {
+            profile: profile.read().clone(),
+            localproxy: (!path.is_empty()).then(|| PathBuf::from(path)),
+        };
+        match save_setup(&choices) {
+            Ok(_) => {
+                choices.apply_to(&mut config.write());
+                if let Err(e) = record_profile(&choices.profile) {
+                    eprintln!("{}", e);
+                }
+                show_setup.set(false);
+            }
+            Err(e) => save_error.set(Some(e.to_string())),
+        }
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: skip,
+            PopupTitle {
+                label {
+                    "{title}"
+                }
+            }
+            PopupContent {
+                ScrollView {
+                    height: "160",
+                    spacing: "8",
+                    if current == 0 {
+                        label {
+                            font_size: "12",
+                            "The credentials and permissions checks pass once you've logged in."
+                        }
+                        if let Some(results) = results {
+                            for result in results {
+                                DiagnosticsRow {result}
+                            }
+                        } else {
+                            Loader {}
+                        }
+                    }
+                    if current == 1 {
+                        if profiles.is_empty() {
+                            label {
+                                "No profiles were found in the AWS config. Run 'aws configure sso', then restart the setup."
+                            }
+                        } else {
+                            Dropdown {
+                                value: selected.clone(),
+                                for name in profiles.clone() {
+                                    DropdownItem {
+                                        value: name.clone(),
+                                        onpress: {
+                                            let name = name.clone();
+                                            move |_| profile.set(name.clone())
+                                        },
+                                        label { "{name}" }
+                                    }
+                                }
+                            }
+                        }
+                        label {
+                            font_size: "12",
+                            "{region_text}"
+                        }
+                    }
+                    if current == 2 {
+                        label {
+                            "{found_text}"
+                        }
+                        Input {
+                            value: localproxy_path,
+                            width: "fill",
+                            onchange: move |txt| localproxy_path.set(txt),
+                        }
+                    }
+                    if last {
+                        label {
+                            "{login_text}"
+                        }
+                        Button {
+                            onclick: move |_| {
+                                if *logging_in.read() {
+                                    return;
+                                }
+                                spawn(async move {
+                                    logging_in.set(true);
+                                    let profile = profile.read().clone();
+                                    let timeout = config.read().sso_login_timeout;
+                                    match aws_sso_login_with_timeout(&profile, timeout).await {
+                                        Ok(()) => {
+                                            show_credentials_refreshed(credentials_refreshed);
+                                            login_result.set(Some(String::from("Logged in to AWS")));
+                                        }
+                                        Err(e) => login_result.set(Some(e.to_string())),
+                                    }
+                                    logging_in.set(false);
+                                });
+                            },
+                            label {
+                                if *logging_in.read() {
+                                    "Logging in..."
+                                } else {
+                                    "Log in to AWS"
+                                }
+                            }
+                        }
+                    }
+                    if let Some(error) = save_error.read().clone() {
+                        label {
+                            color: "rgb(220, 80, 80)",
+                            "{error}"
+                        }
+                    }
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: skip,
+                        label { "Skip setup" }
+                    }
+                    if current == 0 {
+                        Button {
+                            onclick: move |_| checks.restart(),
+                            label { "Check again" }
+                        }
+                    } else {
+                        Button {
+                            onclick: move |_| step -= 1,
+                            label { "Back" }
+                        }
+                    }
+                    if last {
+                        Button {
+                            onclick: finish,
+                            label { "Finish" }
+                        }
+                    } else {
+                        Button {
+                            onclick: move |_| step += 1,
+                            label { "Next" }
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+#[component]
+fn SessionNotice(mut notice: Signal<Option<String>>) -> Element {
+    let Some(message) = notice.read().clone() else {
+        return rsx!();
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| notice.set(None),
+            PopupTitle {
+                label {
+                    "Session limit"
+                }
+            }
+            PopupContent {
+                label {
+                    "{message}"
+                }
+                rect {
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| notice.set(None),
+                        label { "OK" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+/// One line above the status bar while a newer release is available, until dismissed
+#[component]
+fn UpdateBanner(mut update: Signal<Option<Release>>) -> Element {
+    let Some(release) = update.read().clone() else {
+        return rsx!();
+    };
+
+    rsx!(
+        rect {
+            width: "fill",
+            height: "24",
+            direction: "horizontal",
+            main_align: "space-between",
+            cross_align: "center",
+            padding: "0 12",
+            background: "rgb(45, 60, 80)",
+            font_size: "12",
+            label {
+                color: "rgb(200, 200, 200)",
+                "Version {release.version} is available, you are running {CURRENT_VERSION}"
+            }
+            rect {
+                direction: "horizontal",
+                cross_align: "center",
+                if let Some(url) = release.url {
+                    label {
+                        color: "rgb(120, 170, 220)",
+                        margin: "0 12 0 0",
+                        onclick: move |_| {
+                            let url = url.clone();
+                            spawn(async move {
+                                if let Err(e) = open_path(Path::new(&url)) {
+                                    eprintln!("{}", e);
+                                }
+                            });
+                        },
+                        "Release notes"
+                    }
+                }
+                label {
+                    color: "rgb(120, 170, 220)",
+                    onclick: move |_| update.set(None),
+                    "Dismiss"
+                }
+            }
+        }
+    )
+}
+
+/// Confirm quitting while tunnels are connected; `main` stops them once the window closes
+#[component]
+fn QuitPrompt(
+    mut show_quit: Signal<bool>,
+    active_connections: Signal<Vec<ConnectionSummary>>,
+) -> Element {
+    let platform = use_platform();
+    let message = match active_connections.read().len() {
+        1 => String::from("You have 1 active tunnel. Close it and quit?"),
+        n => format!("You have {} active tunnels. Close them and quit?", n),
+    };
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| show_quit.set(false),
+            PopupTitle {
+                label {
+                    "Quit"
+                }
+            }
+            PopupContent {
+                label {
+                    "{message}"
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| platform.exit(),
+                        label { "Close and quit" }
+                    }
+                    Button {
+                        onclick: move |_| show_quit.set(false),
+                        label { "Cancel" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+fn log_color(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "rgb(220, 80, 80)",
+        Level::WARN => "rgb(230, 190, 60)",
+        Level::INFO => "rgb(200, 200, 200)",
+        _ => "rgb(150, 150, 150)",
+    }
+}
+
+/// Recent app and localproxy output, newest first, filtered by level
+#[component]
+fn LogView() -> Element {
+    let logs = use_context::<LogBuffer>();
+    let mut filter = use_signal(LogFilter::default);
+    let mut version = use_signal(|| logs.version());
+
+    // Pick up lines logged while the view is open
+    use_future({
+        let logs = logs.clone();
+        move || {
+            let logs = logs.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(LOG_VIEW_REFRESH).await;
+                    let latest = logs.version();
+                    if *version.peek() != latest {
+                        version.set(latest);
+                    }
+                }
+            }
+        }
+    });
+
+    // Reading the version re-renders the view when lines arrive or are cleared
+    let _ = version.read();
+    let mut lines = logs.lines(*filter.read());
+    lines.reverse();
+    let selected = filter.read().label();
+    let shown = match lines.len() {
+        1 => String::from("1 line"),
+        n => format!("{} lines", n),
+    };
+
+    rsx!(
+        rect {
+            width: "fill",
+            padding: "0 24",
+            spacing: "6",
+            rect {
+                direction: "horizontal",
+                cross_align: "center",
+                spacing: "10",
+                Dropdown {
+                    value: selected,
+                    for choice in LogFilter::ALL {
+                        DropdownItem {
+                            value: choice.label(),
+                            onpress: move |_| filter.set(choice),
+                            label { "{choice.label()}" }
+                        }
+                    }
+                }
+                Button {
+                    onclick: move |_| {
+                        logs.clear();
+                        version.set(logs.version());
+                    },
+                    label { "Clear" }
+                }
+                label {
+                    color: "rgb(150, 150, 150)",
+                    font_size: "12",
+                    "{shown}"
+                }
+            }
+            ScrollView {
+                height: "120",
+                for line in lines {
+                    label {
+                        color: log_color(line.level),
+                        font_size: "12",
+                        "{line}"
+                    }
+                }
+            }
+        }
+    )
+}
+
+#[component]
+fn DiagnosticsPanel(title: String, mut diagnostics: Signal<Option<Vec<CheckResult>>>) -> Element {
+    let mut clipboard = use_clipboard();
+    let Some(results) = diagnostics.read().clone() else {
+        return rsx!();
+    };
+    let report = results
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| diagnostics.set(None),
+            PopupTitle {
+                label {
+                    "{title}"
+                }
+            }
+            PopupContent {
+                if results.is_empty() {
+                    rect {
+                        width: "fill",
+                        main_align: "center",
+                        cross_align: "center",
+                        Loader {}
+                    }
+                }
+                for result in results {
+                    DiagnosticsRow {result}
+                }
+                if !report.is_empty() {
+                    Button {
+                        onclick: move |_| {
+                            if let Err(e) = clipboard.set(report.clone()) {
+                                eprintln!("Failed to copy diagnostics: {:?}", e);
+                            }
+                        },
+                        label {
+                            "Copy results"
+                        }
+                    }
+                }
+            }
+        }
+    )
+}
+
+#[component]
+fn DiagnosticsRow(result: CheckResult) -> Element {
+    let (status, color) = if result.passed {
+        ("PASS", "#89BC2B")
+    } else {
+        ("FAIL", "rgb(220, 80, 80)")
+    };
+    let hint = result.hint.unwrap_or_default();
+
+    rsx!(
+        rect {
+            margin: "0 0 8 0",
+            label {
+                color: "{color}",
+                "{status}  {result.name}"
+            }
+            // Details include the caller identity, which is worth pasting into a ticket
+            rect {
+                color: "rgb(200, 200, 200)",
+                font_size: "12",
+                SelectableText {
+                    value: result.detail,
+                }
+            }
+            if !hint.is_empty() {
+                label {
+                    color: "rgb(150, 150, 150)",
+                    font_size: "12",
+                    "{hint}"
+                }
+            }
+        }
+    )
+}
+
+#[component]
+fn OrphanPrompt(mut orphans: Signal<Vec<OrphanedProcess>>) -> Element {
+    if orphans.read().is_empty() {
+        return rsx!();
+    }
+    let devices = orphans
+        .read()
+        .iter()
+        .map(|orphan| format!("{} (pid {})", orphan.device_id, orphan.pid))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    rsx!(
+        Popup {
+            oncloserequest: move |_| {
+                orphans.write().clear()
+            },
+            PopupTitle {
+                label {
+                    "localproxy still running"
+                }
+            }
+            PopupContent {
+                label {
+                    "A previous session left localproxy running and it may be holding ports: {devices}"
+                }
+                rect {
+                    direction: "horizontal",
+                    spacing: "10",
+                    margin: "12 0 0 0",
+                    Button {
+                        onclick: move |_| {
+                            spawn(async move {
+                                let found = orphans.read().clone();
+                                for orphan in &found {
+                                    if let Err(e) = terminate(orphan).await {
+                                        eprintln!("{}", e);
+                                    }
+                                }
+                                orphans.write().clear();
+                            });
+                        },
+                        label { "Stop them" }
+                    }
+                    Button {
+                        onclick: move |_| {
+                            orphans.write().clear()
+                        },
+                        label { "Keep running" }
+                    }
+                }
+            }
+        }
+    )
+}
+
+fn app() -> Element {
+    use_init_theme(|| DARK_THEME);
+
+    let launch = use_hook(consume_context::<LaunchState>);
+    let manager = use_context_provider(|| launch.manager.clone());
+    let config = use_signal(|| launch.config.clone());
+    use_context_provider(|| launch.logs.clone());
+    let custom_logo = use_hook(|| {
+        config
+            .peek()
+            .branding
+            .logo
+            .as_deref()
+            .and_then(read_branding_file)
+    });
+    // A malformed default device is reported up front instead of failing on connect
+    let startup_error = use_signal(|| config.peek().validate().err());
+    let last_device = use_signal(|| {
+        ConnectionHistory::load()
+            .map_err(|e| eprintln!("{}", e))
+            .ok()
+            .and_then(|history| history.last().map(String::from))
+    });
+    // The configured default wins, otherwise pick up where the last session left off
+    let launch_device = use_hook(|| {
+        let valid_default = startup_error.peek().is_none();
+        config
+            .peek()
+            .default_device_id
+            .clone()
+            .filter(|_| valid_default)
+            .or_else(|| last_device.peek().clone())
+    });
+    let auto_connect =
+        config.peek().auto_connect && startup_error.peek().is_none() && launch_device.is_some();
+    let device_id = use_signal(|| launch_device.clone().unwrap_or_default());
+    let logging_in = use_signal(|| false);
+    let credentials_refreshed = use_signal(|| false);
+    let mut orphans = use_signal(Vec::<OrphanedProcess>::new);
+    let connection_state = use_signal(ConnectionState::default);
+    let mut active_connections = use_signal(Vec::<ConnectionSummary>::new);
+    let mut pending_devices = use_signal(Vec::<String>::new);
+    // Devices whose localproxy exited on its own, until dismissed or reconnected
+    let mut failed_devices = use_signal(Vec::<(String, String)>::new);
+    let mut proxy_error = use_signal(|| Option::<TunnelError>::None);
+    let diagnostics = use_signal(|| Option::<Vec<CheckResult>>::None);
+    let connection_test = use_signal(|| Option::<Vec<CheckResult>>::None);
+    let mut session_notice = use_signal(|| Option::<String>::None);
+    // Shown by itself on the first launch, and from the status bar after that
+    let show_setup = use_signal(is_first_run);
+    let show_bulk_close = use_signal(|| false);
+    let show_processes = use_signal(|| false);
+    let show_logs = use_signal(|| false);
+    let show_quit = use_signal(|| false);
+    let mut update = use_signal(|| Option::<Release>::None);
+    let layout = use_signal(|| launch.layout);
+    let compact = *layout.read() == LayoutMode::Compact;
+
+    // Panels and popups don't fit the compact window, so it grows while one is open
+    let platform = use_platform();
+    let mut window_size = use_signal(|| launch.layout.window_size());
+    use_effect(move || {
+        let panel_open = *show_setup.read()
+            || *show_bulk_close.read()
+            || *show_processes.read()
+            || *show_quit.read()
+            || diagnostics.read().is_some()
+            || connection_test.read().is_some()
+            || session_notice.read().is_some()
+            || proxy_error.read().is_some()
+            || !orphans.read().is_empty();
+        let size = if panel_open {
+            LayoutMode::Expanded.window_size()
+        } else {
+            layout.read().window_size()
+        };
+        // Only on a change, so a window the operator resized stays that size
+        if *window_size.peek() != size {
+            window_size.set(size);
+            platform.with_window(move |window| {
+                let _ = window.request_inner_size(LogicalSize::new(size.0, size.1));
+            });
+        }
+    });
+
+    #[cfg(feature = "control")]
+    {
+        let manager = manager.clone();
+        use_hook(move || {
+            let config = config.peek().clone();
+            if config.control.enabled {
+                spawn(async move {
+                    match ControlServer::bind(config, manager).await {
+                        Ok(server) => {
+                            if let Err(e) = server.run().await {
+                                eprintln!("Control endpoint stopped: {}", e);
+                            }
+                        }
+                        Err(e) => eprintln!("Control endpoint not started: {}", e),
+                    }
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        let manager = manager.clone();
+        use_hook(move || {
+            let config = config.peek().clone();
+            if config.metrics.enabled {
+                spawn(async move {
+                    match MetricsServer::bind(&config, manager).await {
+                        Ok(server) => {
+                            if let Err(e) = server.run().await {
+                                eprintln!("Metrics endpoint stopped: {}", e);
+                            }
+                        }
+                        Err(e) => eprintln!("Metrics endpoint not started: {}", e),
+                    }
+                });
+            }
+        });
+    }
+
+    // Stream connection events for external monitoring
+    use_hook({
+        let manager = manager.clone();
+        move || {
+            let Some(setting) = config.peek().event_stream.clone() else {
+                return;
+            };
+            let events = manager.events().subscribe();
+            spawn(async move {
+                let written = match open_event_sink(&setting).await {
+                    Ok(sink) => write_events(events, sink).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = written {
+                    eprintln!("Event stream to {} stopped: {}", setting, e);
+                }
+            });
+        }
+    });
+
+    // Look for localproxy processes left running by a crashed session
+    use_future(move || async move {
+        let Some(dir) = pid_dir() else {
+            return;
+        };
+        let found = find_orphans(&dir).await;
+        if !config.peek().cleanup_orphans {
+            orphans.set(found);
+            return;
+        }
+        for orphan in &found {
+            match terminate(orphan).await {
+                Ok(()) => tracing::info!("Stopped orphaned localproxy (pid {})", orphan.pid),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+    });
+
+    // Opt-in sweep of tunnels left open in the account, reporting anything it closed
+    use_future(move || async move {
+        let config = config.peek().clone();
+        if config.close_stale_tunnels_after.is_zero() {
+            return;
+        }
+        let report = match get_client(&config).await {
+            Ok(client) => {
+                let client = AwsTunnelClient::new(client);
+                close_stale_tunnels(&client, config.close_stale_tunnels_after, SystemTime::now())
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match report {
+            Ok(report) if report.closed.is_empty() && report.failed.is_empty() => {}
+            Ok(report) => session_notice.set(Some(report.to_string())),
+            Err(e) => eprintln!("Stale tunnels not closed: {}", e),
+        }
+    });
+
+    // Opt-in check for a newer release, which stays quiet if it can't reach the endpoint
+    use_future(move || async move {
+        let config = config.peek().clone();
+        if let Some(release) = check_for_update(&config).await {
+            update.set(Some(release));
+        }
+    });
+
+    // Check connected tunnels' AWS sessions in the background
+    use_future({
+        let manager = manager.clone();
+        move || {
+            let manager = manager.clone();
+            let config = config.peek().clone();
+            async move { manager.watch_sessions(config).await }
+        }
+    });
+
+    // Pick up connections made or dropped through the control endpoint, expired
+    // sessions and localproxy processes that exited on their own
+    use_future(move || {
+        let manager = manager.clone();
+        async move {
+            // Devices already warned that their session limit is close
+            let mut warned = HashSet::new();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let exited = manager.reap_exited().await;
+                for (device_id, error) in &exited {
+                    failed_devices
+                        .write()
+                        .push((device_id.clone(), error.to_string()));
+                }
+                if let Some((_, error)) = exited.into_iter().next() {
+                    proxy_error.set(Some(error));
+                }
+                let pending = manager.pending().await;
+                if *pending_devices.peek() != pending {
+                    pending_devices.set(pending);
+                }
+                let settings = config.peek().clone();
+                let expired = manager.expire_sessions(&settings).await;
+                if !expired.is_empty() {
+                    session_notice.set(Some(format!(
+                        "Session limit reached, disconnected {}",
+                        expired.join(", ")
+                    )));
+                }
+                for (device_id, error) in manager.reopen_expiring_tunnels(&settings).await {
+                    session_notice.set(Some(format!(
+                        "The tunnel to {} is about to expire and couldn't be reopened: {}",
+                        device_id, error
+                    )));
+                }
+                let connections = manager.status().await;
+                warned.retain(|device_id: &String| {
+                    connections.iter().any(|c| &c.device_id == device_id)
+                });
+                let warning = settings.session_expiry_warning.as_secs();
+                for connection in &connections {
+                    let Some(secs) = connection.expires_in_secs else {
+                        continue;
+                    };
+                    if secs <= warning && warned.insert(connection.device_id.clone()) {
+                        session_notice.set(Some(format!(
+                            "The tunnel to {} will be disconnected in {}",
+                            connection.device_id,
+                            format_duration(secs)
+                        )));
+                    }
+                }
+                if *active_connections.peek() != connections {
+                    active_connections.set(connections.clone());
+                }
+                let reconnected = failed_devices
+                    .peek()
+                    .iter()
+                    .any(|(device_id, _)| connections.iter().any(|c| &c.device_id == device_id));
+                if reconnected {
+                    failed_devices.write().retain(|(device_id, _)| {
+                        !connections.iter().any(|c| &c.device_id == device_id)
+                    });
+                }
+                let live = connections.into_iter().map(ConnectionState::from).collect();
+                dispatch(connection_state, AppMsg::Synced(live));
+            }
+        }
+    });
+
+    let environment_key = config.read().environment.clone().unwrap_or_default();
+
+    rsx!(
+        Body {
+            rect {
+                width: "fill",
+                height: "fill",
+                content: "flex",
+                rect {
+                    width: "fill",
+                    height: "flex(1)",
+                    direction: "horizontal",
+                    content: "flex",
+                    padding: "24 24 12 24",
+                    if !compact {
+                        BrandLogo {custom: custom_logo}
+                    }
+                    DeviceInput {device_id}
+                    // Kept in place across layouts, so switching doesn't remount it
+                    ConnectButton {device_id, config, connection_state, logging_in, last_device, credentials_refreshed, auto_connect, compact}
+                    if compact {
+                        CompactMenu {device_id, config, active_connections, logging_in, credentials_refreshed, diagnostics, connection_test, session_notice, show_setup, show_bulk_close, show_processes, show_logs, show_quit, layout}
+                    } else {
+                        LoginButton {config, logging_in, credentials_refreshed}
+                        TestConnectionButton {device_id, config, connection_test}
+                        EnvironmentPicker {config}
+                        // Remounted on a switch of environment, which can change the profile
+                        ProfilePicker {key: "{environment_key}", config, credentials_refreshed}
+                    }
+                }
+                if !compact {
+                    ConnectionList {active_connections, pending_devices, failed_devices, config}
+                }
+                if !compact && *show_logs.read() {
+                    LogView {}
+                }
+                if !compact {
+                    UpdateBanner {update}
+                }
+                StatusBar {connection_state, active_connections, logging_in, credentials_refreshed, config, diagnostics, show_setup, show_bulk_close, show_processes, show_logs, show_quit, layout}
+                DiagnosticsPanel {title: "Diagnostics", diagnostics}
+                DiagnosticsPanel {title: "Connection test", diagnostics: connection_test}
+                if *show_setup.read() {
+                    SetupWizard {show_setup, config, credentials_refreshed}
+                }
+                if *show_bulk_close.read() {
+                    BulkClosePanel {show_bulk_close, config}
+                }
+                if *show_processes.read() {
+                    ProcessesPanel {show_processes, connection_state, active_connections, config}
+                }
+                if *show_quit.read() {
+                    QuitPrompt {show_quit, active_connections}
+                }
+                SessionNotice {notice: session_notice}
+                OrphanPrompt {orphans}
+                ErrorPopup {error: startup_error}
+                ErrorPopup {error: proxy_error}
             }
         }
     )
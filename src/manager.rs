@@ -0,0 +1,1333 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tokio::process::Child;
+use tokio::sync::Mutex;
+
+use crate::aws::{
+    ConnectAction, DESTINATION_POLL_INTERVAL, LocalproxyRestart, LoginCooldown,
+    MAX_TUNNEL_LIFETIME_MINUTES, TunnelConnection, check_tunnel_session, close_tunnel,
+    close_tunnel_in_region, connect_from_token_file, connect_with_services, get_client_in_region,
+    refresh_source_token, reopen_tunnel, resolve_device_services, wait_for_destination,
+};
+use crate::aws_client::{AwsTunnelClient, RateLimitedClient};
+use crate::cleanup::{CleanupGuard, finish_spawned_cleanup};
+use crate::config::{HookSettings, TunnelConfig};
+use crate::diagnostics::{ConnectionTest, TEST_DESTINATION_TIMEOUT, run_connection_test};
+use crate::error::{TunnelError, TunnelResult};
+use crate::events::{EventBus, TunnelEvent};
+use crate::history::record_connection;
+use crate::hooks::run_hook;
+use crate::localproxy::{Readiness, ServicePortMap, exit_error};
+use crate::orphans::{force_kill, request_stop};
+use crate::ports::allocate_ports;
+use crate::rate_limit::RateLimiter;
+use crate::state::ConnectionState;
+use crate::token_file::TokenFile;
+
+/// How long localproxy gets to exit after being asked to stop
+const STOP_WAIT: Duration = Duration::from_secs(3);
+
+/// How long a force-killed localproxy gets to exit before it's reported as still running
+const FORCE_KILL_WAIT: Duration = Duration::from_secs(5);
+
+/// Longest shutdown waits for this session's tunnels to close
+pub const SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Snapshot of a managed connection, safe to share with the UI and control clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionSummary {
+    pub device_id: String,
+    pub tunnel_id: String,
+    /// AWS region of the tunnel
+    pub region: String,
+    pub pid: Option<u32>,
+    /// Local port localproxy listens on for each service
+    pub services: ServicePortMap,
+    /// Services localproxy couldn't listen for, such as one whose port is taken;
+    /// the rest still work
+    pub failed_services: Vec<String>,
+    /// Whether localproxy confirmed the tunnel within the readiness timeout
+    pub ready: bool,
+    /// The AWS session expired and the operator needs to log in again
+    pub auth_required: bool,
+    /// Waiting for the device to connect its end of the tunnel
+    pub waiting_for_device: bool,
+    /// Problems that didn't stop the connection, such as a fallback region
+    pub warnings: Vec<String>,
+    /// Connecting logged in to AWS again because the credentials had expired
+    pub credentials_refreshed: bool,
+    /// Seconds until `max_session_duration` disconnects the tunnel, if there is a limit
+    pub expires_in_secs: Option<u64>,
+    /// Seconds until AWS closes the tunnel at the end of its lifetime, once known
+    pub tunnel_expires_in_secs: Option<u64>,
+    /// How the tunnel was found or opened
+    pub action: ConnectAction,
+}
+
+impl ConnectionSummary {
+    fn new(connection: &TunnelConnection, auth_required: bool, waiting_for_device: bool) -> Self {
+        Self {
+            device_id: connection.device_id.clone(),
+            tunnel_id: connection.tunnel_id.clone(),
+            region: connection.region.clone(),
+            pid: connection.child.id(),
+            services: connection.services.clone(),
+            failed_services: connection.output.failed_services(),
+            ready: connection.readiness == Readiness::Ready,
+            auth_required,
+            waiting_for_device,
+            warnings: connection.warnings.clone(),
+            credentials_refreshed: connection.credentials_refreshed,
+            expires_in_secs: connection
+                .expires_at
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            tunnel_expires_in_secs: connection.tunnel_expires_at.map(|at| {
+                at.duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            action: connection.action,
+        }
+    }
+}
+
+impl From<ConnectionSummary> for ConnectionState {
+    fn from(summary: ConnectionSummary) -> Self {
+        if summary.auth_required {
+            ConnectionState::AuthenticationRequired {
+                device_id: summary.device_id,
+                tunnel_id: summary.tunnel_id,
+            }
+        } else if summary.waiting_for_device {
+            ConnectionState::WaitingForDevice {
+                device_id: summary.device_id,
+                tunnel_id: summary.tunnel_id,
+            }
+        } else {
+            ConnectionState::Connected {
+                device_id: summary.device_id,
+                tunnel_id: summary.tunnel_id,
+            }
+        }
+    }
+}
+
+/// A localproxy the manager is running, as listed in the UI's process panel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ManagedProcess {
+    pub pid: u32,
+    pub device_id: String,
+    /// Local port localproxy listens on for each service
+    pub services: ServicePortMap,
+    /// Seconds since this localproxy was started, which a token rotation restarts
+    pub uptime_secs: u64,
+}
+
+/// Connect attempts and outcomes since the manager started, for monitoring
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectStats {
+    pub active_connections: usize,
+    /// Connects that ran, whether or not they succeeded
+    pub connects: u64,
+    /// Failed connects by `TunnelError::category`
+    pub failures: BTreeMap<&'static str, u64>,
+    /// How long the latest successful connect took, up to localproxy starting
+    pub last_connect_latency: Option<Duration>,
+}
+
+/// A tunnel this session opened, rather than reused, and the settings it was opened with
+struct SessionTunnel {
+    device_id: String,
+    config: TunnelConfig,
+}
+
+#[derive(Default)]
+struct ManagerState {
+    connections: HashMap<String, TunnelConnection>,
+    /// Devices with a connect in flight and the ports reserved for them, so
+    /// concurrent requests can't race for a device or a port
+    pending: HashMap<String, ServicePortMap>,
+    /// Devices whose localproxy is being stopped outside the lock and their ports, so
+    /// neither is reused until it has exited
+    stopping: HashMap<String, ServicePortMap>,
+    /// Connections taken out while their localproxy restarts, as `status` shows them
+    /// meanwhile; their ports stay reserved too
+    restarting: HashMap<String, ConnectionSummary>,
+    /// Connected devices whose AWS session has expired
+    auth_required: HashSet<String>,
+    /// Connected devices that haven't connected their end of the tunnel yet
+    waiting: HashSet<String>,
+    stats: ConnectStats,
+    /// Tunnels opened this session by ID, which `shutdown` may close
+    session_tunnels: HashMap<String, SessionTunnel>,
+    /// Hooks configured when each device connected, for its `pre_disconnect`
+    hooks: HashMap<String, HookSettings>,
+    /// Held while a connection's token is rotated and localproxy restarted with it
+    rotation_locks: HashMap<String, Arc<Mutex<()>>>,
+    /// Rotations completed per connection, so a trigger can tell one finished while it waited
+    rotations: HashMap<String, u64>,
+    /// Expiring tunnels whose reopen failed, so it isn't retried on every poll
+    reopen_failed: HashSet<String>,
+}
+
+impl ManagerState {
+    fn connection_mut(&mut self, device_id: &str) -> TunnelResult<&mut TunnelConnection> {
+        self.connections
+            .get_mut(device_id)
+            .ok_or_else(|| TunnelError::TunnelNotFound {
+                device_id: device_id.to_string(),
+            })
+    }
+
+    /// Forget a connection once its localproxy is confirmed dead
+    fn finish_disconnect(&mut self, device_id: &str, failure: Option<String>) -> TunnelResult<()> {
+        let connection = self.connection_mut(device_id)?;
+        match connection.child.try_wait() {
+            Ok(Some(_)) => {
+                self.forget(device_id);
+                Ok(())
+            }
+            _ => Err(still_running(connection, failure)),
+        }
+    }
+
+    /// Forget a connection taken out to be stopped once its localproxy is confirmed
+    /// dead, or put it back so the stop can be retried
+    fn finish_stop(
+        &mut self,
+        mut connection: TunnelConnection,
+        failure: Option<String>,
+    ) -> TunnelResult<()> {
+        let device_id = connection.device_id.clone();
+        self.stopping.remove(&device_id);
+        match connection.child.try_wait() {
+            Ok(Some(_)) => {
+                self.forget(&device_id);
+                Ok(())
+            }
+            _ => {
+                let error = still_running(&connection, failure);
+                self.connections.insert(device_id, connection);
+                Err(error)
+            }
+        }
+    }
+
+    /// A connection whose rotation holds `lock`, unless it was dropped since the
+    /// rotation began
+    fn rotating_connection_mut(
+        &mut self,
+        device_id: &str,
+        lock: &Arc<Mutex<()>>,
+    ) -> TunnelResult<&mut TunnelConnection> {
+        // Dropping a connection drops its lock, and a new connection gets a new one
+        if !self
+            .rotation_locks
+            .get(device_id)
+            .is_some_and(|held| Arc::ptr_eq(held, lock))
+        {
+            return Err(TunnelError::TunnelNotFound {
+                device_id: device_id.to_string(),
+            });
+        }
+        self.connection_mut(device_id)
+    }
+
+    /// Put back a connection taken out by `take_for_restart` once its localproxy
+    /// has restarted
+    fn finish_restart(&mut self, connection: TunnelConnection) {
+        self.restarting.remove(&connection.device_id);
+        self.connections
+            .insert(connection.device_id.clone(), connection);
+    }
+
+    fn forget(&mut self, device_id: &str) {
+        self.connections.remove(device_id);
+        self.auth_required.remove(device_id);
+        self.waiting.remove(device_id);
+        self.hooks.remove(device_id);
+        self.rotation_locks.remove(device_id);
+        self.rotations.remove(device_id);
+    }
+
+    /// Ports held by live connections, connects still in flight and localproxies
+    /// still stopping or restarting
+    fn ports_in_use(&self) -> HashSet<u16> {
+        let live = self.connections.values().map(|c| &c.services);
+        live.chain(self.pending.values())
+            .chain(self.stopping.values())
+            .chain(self.restarting.values().map(|summary| &summary.services))
+            .flat_map(|services| services.iter().map(|(_, port)| port))
+            .collect()
+    }
+}
+
+/// Connections shared between the UI and any other front end driving the manager
+#[derive(Clone, Default)]
+pub struct ConnectionManager {
+    state: Arc<Mutex<ManagerState>>,
+    events: EventBus,
+    login_cooldown: LoginCooldown,
+    /// Shared by every status poll, bounded by `poll_rate_limit`
+    poll_limiter: RateLimiter,
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Events for every connection the manager makes or drops
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Limits how often connects launch `aws sso login`; reset it after the operator logs in
+    pub fn login_cooldown(&self) -> &LoginCooldown {
+        &self.login_cooldown
+    }
+
+    fn publish_disconnected(&self, device_id: &str, reason: impl Into<String>) {
+        self.events.publish(TunnelEvent::Disconnected {
+            device_id: device_id.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    /// Announce a connection whose localproxy confirmed the tunnel; reconnects only with
+    /// `announce_reconnects`
+    fn publish_connected(
+        &self,
+        summary: &ConnectionSummary,
+        reconnect: bool,
+        config: &TunnelConfig,
+    ) {
+        if !summary.ready || (reconnect && !config.announce_reconnects) {
+            return;
+        }
+        self.events.publish(TunnelEvent::Connected {
+            device_id: summary.device_id.clone(),
+            tunnel_id: summary.tunnel_id.clone(),
+            region: summary.region.clone(),
+            services: summary.services.clone(),
+            reconnect,
+        });
+    }
+
+    fn publish_error(&self, device_id: &str, error: &TunnelError) {
+        self.events.publish(TunnelEvent::Error {
+            device_id: device_id.to_string(),
+            message: error.to_string(),
+        });
+    }
+
+    /// Connect to a device, refusing if it is already connected or connecting
+    pub async fn connect(
+        &self,
+        device_id: &str,
+        config: &TunnelConfig,
+    ) -> TunnelResult<ConnectionSummary> {
+        self.track_connect(
+            device_id,
+            config,
+            self.reserve_and_connect(device_id, None, config),
+        )
+        .await
+    }
+
+    /// Connect to a device with only some of its services, for this connection alone
+    ///
+    /// The selection narrows the services `connect` would use; the config is left as is.
+    pub async fn connect_services(
+        &self,
+        device_id: &str,
+        services: &[String],
+        config: &TunnelConfig,
+    ) -> TunnelResult<ConnectionSummary> {
+        self.track_connect(
+            device_id,
+            config,
+            self.reserve_and_connect(device_id, Some(services), config),
+        )
+        .await
+    }
+
+    /// Connect with a token file exported on a machine with AWS access, making no AWS calls
+    pub async fn connect_from_token_file(
+        &self,
+        path: &Path,
+        config: &TunnelConfig,
+    ) -> TunnelResult<ConnectionSummary> {
+        let device_id = TokenFile::read(path)?.device_id;
+        self.track_connect(&device_id, config, connect_from_token_file(path, config))
+            .await
+    }
+
+    /// Run a connect for a device, registering the connection if it succeeds
+    ///
+    /// For a connect of the caller's own, such as `start_connection` on a tunnel
+    /// opened with another client. The connection's `cleanup` is disarmed once it's
+    /// registered, so dropping this part way still undoes the connect.
+    pub async fn track_connect(
+        &self,
+        device_id: &str,
+        config: &TunnelConfig,
+        connect: impl Future<Output = TunnelResult<TunnelConnection>>,
+    ) -> TunnelResult<ConnectionSummary> {
+        let reservation = self.reserve(device_id).await?;
+        self.events.publish(TunnelEvent::ConnectStarted {
+            device_id: device_id.to_string(),
+        });
+
+        let started = Instant::now();
+        let result = connect.await;
+
+        let mut state = self.state.lock().await;
+        state.pending.remove(device_id);
+        reservation.disarm();
+        state.stats.connects += 1;
+        let mut connection = match result {
+            Ok(connection) => connection,
+            Err(e) => {
+                *state.stats.failures.entry(e.category()).or_default() += 1;
+                self.publish_error(device_id, &e);
+                return Err(e);
+            }
+        };
+        state.stats.last_connect_latency = Some(started.elapsed());
+        let summary = ConnectionSummary::new(&connection, false, false);
+        if connection.action.opened_tunnel() {
+            state.session_tunnels.insert(
+                connection.tunnel_id.clone(),
+                SessionTunnel {
+                    device_id: device_id.to_string(),
+                    config: config.clone(),
+                },
+            );
+        }
+        let (device_id, tunnel_id) = (device_id.to_string(), connection.tunnel_id.clone());
+        let opened = match connection.action {
+            ConnectAction::ReusedExisting => Some(TunnelEvent::TokensRotated {
+                device_id,
+                tunnel_id,
+            }),
+            ConnectAction::ClosedStaleAndOpened | ConnectAction::OpenedNew => {
+                Some(TunnelEvent::TunnelOpened {
+                    device_id,
+                    tunnel_id,
+                })
+            }
+            // Opened on another machine, which has already reported it
+            ConnectAction::FromTokenFile => None,
+        };
+        std::mem::take(&mut connection.cleanup).disarm();
+        state
+            .connections
+            .insert(summary.device_id.clone(), connection);
+        state
+            .hooks
+            .insert(summary.device_id.clone(), config.hooks.clone());
+        drop(state);
+        if let Some(opened) = opened {
+            self.events.publish(opened);
+        }
+        self.events.publish(TunnelEvent::ProxyStarted {
+            device_id: summary.device_id.clone(),
+            tunnel_id: summary.tunnel_id.clone(),
+            pid: summary.pid,
+            services: summary.services.clone(),
+        });
+        self.publish_connected(&summary, false, config);
+
+        if let Err(e) = record_connection(&summary.device_id) {
+            tracing::warn!("Failed to update the connection history: {}", e);
+        }
+
+        // In the background, so a hook that launches a client doesn't hold up the connect
+        if let Some(hook) = config.hooks.post_connect.clone() {
+            let (connection, timeout) = (summary.clone(), config.hooks.timeout);
+            tokio::spawn(async move {
+                if let Err(e) = run_hook("post_connect", &hook, &connection, timeout).await {
+                    tracing::warn!("{}", e);
+                }
+            });
+        }
+
+        Ok(summary)
+    }
+
+    async fn reserve_and_connect(
+        &self,
+        device_id: &str,
+        only: Option<&[String]>,
+        config: &TunnelConfig,
+    ) -> TunnelResult<TunnelConnection> {
+        let services = resolve_device_services(device_id, config).await;
+        let services = match only {
+            Some(only) => services.only(only)?,
+            None => services,
+        };
+        let services = {
+            let mut state = self.state.lock().await;
+            let taken = state.ports_in_use();
+            let services = allocate_ports(config.port_allocation, &services, &taken)?;
+            state
+                .pending
+                .insert(device_id.to_string(), services.clone());
+            services
+        };
+
+        connect_with_services(device_id, &services, config, &self.login_cooldown).await
+    }
+
+    /// Connect to a device, wait for it to connect its end, then tear everything down
+    ///
+    /// Goes through the same open or reuse path as `connect`, but the connection is
+    /// never tracked, so it doesn't show up in `status` or the events. Fails like
+    /// `connect` if the device is already connected or connecting.
+    pub async fn test_connection(
+        &self,
+        device_id: &str,
+        config: &TunnelConfig,
+    ) -> TunnelResult<ConnectionTest> {
+        let reservation = self.reserve(device_id).await?;
+
+        let result = async {
+            let started = Instant::now();
+            let mut connection = self.reserve_and_connect(device_id, None, config).await?;
+            let connect_time = started.elapsed();
+
+            // Still closes a tunnel the test opened if the test is abandoned part way
+            let cleanup = std::mem::take(&mut connection.cleanup);
+
+            let client = RateLimitedClient::new(
+                AwsTunnelClient::new(get_client_in_region(config, &connection.region).await?),
+                self.poll_limiter.clone(),
+                config.poll_rate_limit,
+            );
+            let destination_timeout = if config.destination_timeout.is_zero() {
+                TEST_DESTINATION_TIMEOUT
+            } else {
+                config.destination_timeout
+            };
+            let test = run_connection_test(
+                &client,
+                connection,
+                connect_time,
+                destination_timeout,
+                DESTINATION_POLL_INTERVAL,
+            )
+            .await;
+            cleanup.disarm();
+            test
+        }
+        .await;
+
+        self.state.lock().await.pending.remove(device_id);
+        reservation.disarm();
+        result
+    }
+
+    /// Mark a device as connecting, refusing if it is already connected or connecting
+    ///
+    /// The returned guard drops the mark again if the connect is abandoned before it
+    /// clears the mark itself, so a cancelled connect doesn't leave the device looking
+    /// connected.
+    async fn reserve(&self, device_id: &str) -> TunnelResult<CleanupGuard> {
+        let mut state = self.state.lock().await;
+        if state.connections.contains_key(device_id)
+            || state.pending.contains_key(device_id)
+            || state.stopping.contains_key(device_id)
+            || state.restarting.contains_key(device_id)
+        {
+            return Err(TunnelError::connection(format!(
+                "Device {} is already connected",
+                device_id
+            )));
+        }
+        state
+            .pending
+            .insert(device_id.to_string(), ServicePortMap::new());
+
+        let mut reservation = CleanupGuard::new();
+        let device_id = device_id.to_string();
+        self.defer_state_update(&mut reservation, move |state| {
+            state.pending.remove(&device_id);
+        });
+        Ok(reservation)
+    }
+
+    /// Have `guard` apply `update` to the state when it drops
+    fn defer_state_update<F>(&self, guard: &mut CleanupGuard, update: F)
+    where
+        F: FnOnce(&mut ManagerState) + Send + 'static,
+    {
+        let shared = self.state.clone();
+        guard.defer(move || {
+            if let Ok(mut state) = shared.try_lock() {
+                update(&mut state);
+                return;
+            }
+            // Whoever holds the lock may be waiting on this very call
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(async move {
+                        update(&mut *shared.lock().await);
+                    });
+                }
+                Err(_) => tracing::warn!("No runtime left to update the connections on"),
+            }
+        });
+    }
+
+    /// Stop localproxy for a device
+    ///
+    /// The `pre_disconnect` hook runs first, while the tunnel is still up; a failing
+    /// hook is only logged. localproxy is asked to stop rather than killed, so it can
+    /// close its connections; on Windows it is stopped outright. The connection stays
+    /// tracked until localproxy has exited, so a failed stop can be retried with
+    /// `force_disconnect` instead of leaving it running unseen.
+    pub async fn disconnect(&self, device_id: &str) -> TunnelResult<()> {
+        let pre_disconnect = {
+            let state = self.state.lock().await;
+            let hook = state.hooks.get(device_id).and_then(|hooks| {
+                let hook = hooks.pre_disconnect.clone()?;
+                Some((hook, hooks.timeout))
+            });
+            hook.zip(
+                state
+                    .connections
+                    .get(device_id)
+                    .map(|connection| ConnectionSummary::new(connection, false, false)),
+            )
+        };
+        if let Some(((hook, timeout), connection)) = pre_disconnect {
+            if let Err(e) = run_hook("pre_disconnect", &hook, &connection, timeout).await {
+                tracing::warn!("{}", e);
+            }
+        }
+
+        let (mut connection, abandoned) = self.take_for_stop(device_id).await?;
+        let failure = stop(&mut connection.child).await;
+        let result = self.state.lock().await.finish_stop(connection, failure);
+        abandoned.disarm();
+        self.publish_stop(device_id, &result, "requested");
+        result
+    }
+
+    /// Take a connection out of the state, so its localproxy can be stopped without
+    /// holding up other devices
+    ///
+    /// The device and its ports stay reserved until `finish_stop` forgets it or puts it
+    /// back. The returned guard forgets it if the stop is abandoned, since dropping the
+    /// connection kills localproxy anyway.
+    async fn take_for_stop(
+        &self,
+        device_id: &str,
+    ) -> TunnelResult<(TunnelConnection, CleanupGuard)> {
+        let mut state = self.state.lock().await;
+        let connection =
+            state
+                .connections
+                .remove(device_id)
+                .ok_or_else(|| TunnelError::TunnelNotFound {
+                    device_id: device_id.to_string(),
+                })?;
+        state
+            .stopping
+            .insert(device_id.to_string(), connection.services.clone());
+        Ok((connection, self.forget_if_abandoned(device_id)))
+    }
+
+    /// Take a rotating connection out of the state, so its localproxy can be restarted
+    /// without holding up other devices
+    ///
+    /// `status` shows it and its ports stay reserved until `finish_restart` puts it
+    /// back. The returned guard forgets it if the restart is abandoned, as for
+    /// `take_for_stop`.
+    async fn take_for_restart(
+        &self,
+        device_id: &str,
+        lock: &Arc<Mutex<()>>,
+    ) -> TunnelResult<(TunnelConnection, CleanupGuard)> {
+        let mut state = self.state.lock().await;
+        state.rotating_connection_mut(device_id, lock)?;
+        let connection =
+            state
+                .connections
+                .remove(device_id)
+                .ok_or_else(|| TunnelError::TunnelNotFound {
+                    device_id: device_id.to_string(),
+                })?;
+        let summary = ConnectionSummary {
+            pid: None,
+            ready: false,
+            ..ConnectionSummary::new(
+                &connection,
+                state.auth_required.contains(device_id),
+                state.waiting.contains(device_id),
+            )
+        };
+        state.restarting.insert(device_id.to_string(), summary);
+        Ok((connection, self.forget_if_abandoned(device_id)))
+    }
+
+    /// A guard that forgets a connection taken out of the state unless disarmed
+    fn forget_if_abandoned(&self, device_id: &str) -> CleanupGuard {
+        let mut abandoned = CleanupGuard::new();
+        let device_id = device_id.to_string();
+        self.defer_state_update(&mut abandoned, move |state| {
+            state.stopping.remove(&device_id);
+            state.restarting.remove(&device_id);
+            state.forget(&device_id);
+        });
+        abandoned
+    }
+
+    fn publish_stop(&self, device_id: &str, result: &TunnelResult<()>, reason: &str) {
+        match result {
+            Ok(()) => self.publish_disconnected(device_id, reason),
+            Err(e) => self.publish_error(device_id, e),
+        }
+    }
+
+    /// Kill a localproxy that survived `disconnect`, waiting briefly for it to exit
+    ///
+    /// Unlike `disconnect` this can't be ignored; on Windows it also kills whatever
+    /// a wrapping `localproxy_command` started.
+    pub async fn force_disconnect(&self, device_id: &str) -> TunnelResult<()> {
+        let (mut connection, abandoned) = self.take_for_stop(device_id).await?;
+        // No pid means the process has already been reaped
+        let failure = match connection.child.id() {
+            Some(pid) => match force_kill(pid).await {
+                Ok(()) => tokio::time::timeout(FORCE_KILL_WAIT, connection.child.wait())
+                    .await
+                    .err()
+                    .map(|_| String::from("localproxy did not exit after being killed")),
+                Err(e) => Some(e.to_string()),
+            },
+            None => None,
+        };
+        let result = self.state.lock().await.finish_stop(connection, failure);
+        abandoned.disarm();
+        self.publish_stop(device_id, &result, "force killed");
+        result
+    }
+
+    /// Drop connections whose localproxy has exited, explaining why for each
+    ///
+    /// Meant to be polled by whichever front end monitors the connections.
+    pub async fn reap_exited(&self) -> Vec<(String, TunnelError)> {
+        let mut state = self.state.lock().await;
+        let mut exited = Vec::new();
+        for (device_id, connection) in state.connections.iter_mut() {
+            match connection.child.try_wait() {
+                Ok(Some(status)) => {
+                    let error = exit_error(status, &connection.output.lines());
+                    exited.push((device_id.clone(), error));
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to check localproxy for {}: {}", device_id, e),
+            }
+        }
+        for (device_id, error) in &exited {
+            tracing::warn!("localproxy for {} exited: {}", device_id, error);
+            state.forget(device_id);
+            self.publish_disconnected(device_id, error.to_string());
+        }
+        exited.sort_by(|a, b| a.0.cmp(&b.0));
+        exited
+    }
+
+    /// Disconnect every connection that has reached `max_session_duration`
+    ///
+    /// Returns the devices that were disconnected. Meant to be polled alongside
+    /// `reap_exited`; a connection whose localproxy won't stop is retried next time.
+    pub async fn expire_sessions(&self, config: &TunnelConfig) -> Vec<String> {
+        self.expire_sessions_with(config, async |tunnel_id| {
+            close_tunnel(tunnel_id, config).await
+        })
+        .await
+    }
+
+    /// `expire_sessions`, closing tunnels with `close` instead of the configured AWS account
+    pub async fn expire_sessions_with<C>(&self, config: &TunnelConfig, close: C) -> Vec<String>
+    where
+        C: AsyncFn(&str) -> TunnelResult<()>,
+    {
+        let now = Instant::now();
+        let due: Vec<String> = {
+            let state = self.state.lock().await;
+            state
+                .connections
+                .values()
+                .filter(|c| c.expires_at.is_some_and(|at| at <= now))
+                .map(|c| c.device_id.clone())
+                .collect()
+        };
+        let mut expired = Vec::new();
+        for device_id in due {
+            // Disconnected meanwhile
+            let Ok((mut connection, abandoned)) = self.take_for_stop(&device_id).await else {
+                continue;
+            };
+            let tunnel_id = connection.tunnel_id.clone();
+            let failure = stop(&mut connection.child).await;
+            let result = self.state.lock().await.finish_stop(connection, failure);
+            abandoned.disarm();
+            match result {
+                Ok(()) => {
+                    tracing::info!("Session limit reached, disconnected {}", device_id);
+                    self.publish_disconnected(&device_id, "session limit reached");
+                    expired.push((device_id, tunnel_id));
+                }
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    self.publish_error(&device_id, &e);
+                }
+            }
+        }
+
+        if config.close_tunnel_on_expiry {
+            for (device_id, tunnel_id) in &expired {
+                match close(tunnel_id).await {
+                    Ok(()) => {
+                        self.state.lock().await.session_tunnels.remove(tunnel_id);
+                    }
+                    Err(e) => {
+                        tracing::warn!("Failed to close the tunnel for {}: {}", device_id, e)
+                    }
+                }
+            }
+        }
+
+        let mut devices: Vec<String> = expired
+            .into_iter()
+            .map(|(device_id, _)| device_id)
+            .collect();
+        devices.sort();
+        devices
+    }
+
+    /// Stop every localproxy and, with `close_tunnels_on_exit`, close the tunnels
+    /// this session opened
+    ///
+    /// Tunnels that were already open when connecting are left alone. Closing
+    /// gives up after `SHUTDOWN_CLOSE_TIMEOUT`, so an unreachable AWS can't hold
+    /// up exit; failures are logged since there is no UI left to show them.
+    pub async fn shutdown(&self, config: &TunnelConfig) {
+        // A connect interrupted just before may still be closing its tunnel
+        if tokio::time::timeout(SHUTDOWN_CLOSE_TIMEOUT, finish_spawned_cleanup())
+            .await
+            .is_err()
+        {
+            tracing::warn!("Gave up waiting for interrupted connects to clean up");
+        }
+        let session_tunnels = {
+            let mut state = self.state.lock().await;
+            let devices: Vec<String> = state.connections.keys().cloned().collect();
+            for device_id in devices {
+                let Ok(connection) = state.connection_mut(&device_id) else {
+                    continue;
+                };
+                let failure = connection.child.kill().await.err().map(|e| e.to_string());
+                if let Err(e) = state.finish_disconnect(&device_id, failure) {
+                    tracing::warn!("{}", e);
+                }
+            }
+            std::mem::take(&mut state.session_tunnels)
+        };
+        if !config.close_tunnels_on_exit || session_tunnels.is_empty() {
+            return;
+        }
+
+        let close_all = async {
+            for (tunnel_id, tunnel) in &session_tunnels {
+                match close_tunnel(tunnel_id, &tunnel.config).await {
+                    Ok(()) => tracing::info!(
+                        "Closed tunnel {} for {} on exit",
+                        tunnel_id,
+                        tunnel.device_id
+                    ),
+                    Err(e) => tracing::warn!(
+                        "Failed to close tunnel {} for {} on exit: {}",
+                        tunnel_id,
+                        tunnel.device_id,
+                        e
+                    ),
+                }
+            }
+        };
+        if tokio::time::timeout(SHUTDOWN_CLOSE_TIMEOUT, close_all)
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Stopped closing this session's tunnels after {} seconds",
+                SHUTDOWN_CLOSE_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    /// Wait for a connected device to connect its end of the tunnel
+    ///
+    /// Returns `false` once `destination_timeout` passes without the device
+    /// connecting. The connection shows as waiting until this returns or the
+    /// device is disconnected.
+    pub async fn wait_for_device(
+        &self,
+        device_id: &str,
+        config: &TunnelConfig,
+    ) -> TunnelResult<bool> {
+        let (tunnel_id, region) = {
+            let mut state = self.state.lock().await;
+            let connection = state.connection_mut(device_id)?;
+            let tunnel = (connection.tunnel_id.clone(), connection.region.clone());
+            state.waiting.insert(device_id.to_string());
+            tunnel
+        };
+
+        let result = async {
+            let client = RateLimitedClient::new(
+                AwsTunnelClient::new(get_client_in_region(config, &region).await?),
+                self.poll_limiter.clone(),
+                config.poll_rate_limit,
+            );
+            wait_for_destination(
+                &client,
+                &tunnel_id,
+                config.destination_timeout,
+                DESTINATION_POLL_INTERVAL,
+            )
+            .await
+        }
+        .await;
+
+        self.state.lock().await.waiting.remove(device_id);
+        result
+    }
+
+    /// Whether a device is connected or has a connect in flight
+    pub async fn is_active(&self, device_id: &str) -> bool {
+        let state = self.state.lock().await;
+        state.connections.contains_key(device_id)
+            || state.pending.contains_key(device_id)
+            || state.restarting.contains_key(device_id)
+    }
+
+    /// Devices with a connect in flight, in order
+    pub async fn pending(&self) -> Vec<String> {
+        let state = self.state.lock().await;
+        let mut pending: Vec<String> = state.pending.keys().cloned().collect();
+        pending.sort();
+        pending
+    }
+
+    /// Connect counters and the number of active connections
+    pub async fn stats(&self) -> ConnectStats {
+        let state = self.state.lock().await;
+        ConnectStats {
+            active_connections: state.connections.len(),
+            ..state.stats.clone()
+        }
+    }
+
+    /// Every localproxy still running, by device
+    ///
+    /// Stop one with `force_disconnect`, which also drops its connection.
+    pub async fn processes(&self) -> Vec<ManagedProcess> {
+        let state = self.state.lock().await;
+        let mut processes: Vec<ManagedProcess> = state
+            .connections
+            .values()
+            .filter_map(|connection| {
+                Some(ManagedProcess {
+                    pid: connection.child.id()?,
+                    device_id: connection.device_id.clone(),
+                    services: connection.services.clone(),
+                    uptime_secs: connection.started_at.elapsed().as_secs(),
+                })
+            })
+            .collect();
+        processes.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        processes
+    }
+
+    /// Summaries of every active connection
+    pub async fn status(&self) -> Vec<ConnectionSummary> {
+        let state = self.state.lock().await;
+        let mut summaries: Vec<ConnectionSummary> = state
+            .connections
+            .values()
+            .map(|connection| {
+                let auth_required = state.auth_required.contains(&connection.device_id);
+                let waiting = state.waiting.contains(&connection.device_id);
+                ConnectionSummary::new(connection, auth_required, waiting)
+            })
+            .chain(state.restarting.values().cloned())
+            .collect();
+        summaries.sort_by(|a, b| a.device_id.cmp(&b.device_id));
+        summaries
+    }
+
+    /// Check every connection's AWS session, flagging those whose credentials expired
+    ///
+    /// localproxy is left running either way; a flagged connection clears on the
+    /// first check that succeeds after the operator logs in again.
+    pub async fn check_sessions(&self, config: &TunnelConfig) {
+        let tunnels: Vec<(String, String, String)> = {
+            let state = self.state.lock().await;
+            state
+                .connections
+                .values()
+                .map(|c| (c.device_id.clone(), c.tunnel_id.clone(), c.region.clone()))
+                .collect()
+        };
+
+        for (device_id, tunnel_id, region) in tunnels {
+            self.poll_limiter.acquire(config.poll_rate_limit).await;
+            let result = check_tunnel_session(&tunnel_id, &region, config).await;
+            let mut state = self.state.lock().await;
+            if !state.connections.contains_key(&device_id) {
+                continue;
+            }
+            match result {
+                Ok(expiry) => {
+                    if state.auth_required.remove(&device_id) {
+                        tracing::info!("AWS session for {} restored", device_id);
+                    }
+                    if let Ok(connection) = state.connection_mut(&device_id) {
+                        // Unless it moved to a new tunnel during the check
+                        if connection.tunnel_id == tunnel_id && expiry.is_some() {
+                            connection.tunnel_expires_at = expiry;
+                        }
+                    }
+                }
+                Err(TunnelError::AwsAuth { .. }) => {
+                    if state.auth_required.insert(device_id.clone()) {
+                        tracing::warn!(
+                            "AWS session expired while connected to {}, keeping localproxy running until login",
+                            device_id
+                        );
+                    }
+                }
+                Err(e) => tracing::warn!("Session check for {} failed: {}", device_id, e),
+            }
+        }
+    }
+
+    /// Reconnect localproxy with a new source token for each connection whose AWS
+    /// session expired, once the operator has logged in again
+    ///
+    /// Only the source token is rotated, so the device stays connected to the
+    /// tunnel. Connections from a token file are left alone, since they make no
+    /// AWS calls. Returns the devices that couldn't be refreshed and why.
+    pub async fn refresh_sessions(&self, config: &TunnelConfig) -> Vec<(String, TunnelError)> {
+        let flagged: Vec<(String, String)> = {
+            let state = self.state.lock().await;
+            state
+                .connections
+                .values()
+                .filter(|c| state.auth_required.contains(&c.device_id))
+                .filter(|c| c.action != ConnectAction::FromTokenFile)
+                .map(|c| (c.device_id.clone(), c.tunnel_id.clone()))
+                .collect()
+        };
+
+        let mut failed = Vec::new();
+        for (device_id, tunnel_id) in flagged {
+            let result = self
+                .rotate_and_restart(&device_id, config, async |tunnel_id, region, services| {
+                    refresh_source_token(&device_id, tunnel_id, region, services, config).await
+                })
+                .await;
+            match result {
+                // Another trigger rotated the token after this one started
+                Ok(false) => {
+                    self.state.lock().await.auth_required.remove(&device_id);
+                }
+                Ok(true) => {
+                    self.state.lock().await.auth_required.remove(&device_id);
+                    tracing::info!("Refreshed the source token for {}", device_id);
+                    self.events.publish(TunnelEvent::TokensRotated {
+                        device_id: device_id.clone(),
+                        tunnel_id,
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to refresh the session for {}: {}", device_id, e);
+                    self.publish_error(&device_id, &e);
+                    failed.push((device_id, e));
+                }
+            }
+        }
+        failed
+    }
+
+    /// Rotate a connection's source token with `rotate` and restart its localproxy with it
+    ///
+    /// `rotate` gets the tunnel ID, AWS region and services and returns the new token.
+    /// Only one rotation runs per connection at a time, so overlapping triggers can't
+    /// leave localproxy on an older token than the tunnel's. A trigger that had to
+    /// wait for another to finish does nothing and returns `false`, since the
+    /// connection already has a newer token than when it was triggered. localproxy
+    /// restarts without holding up other devices; if it fails to start again the
+    /// connection is dropped.
+    pub async fn rotate_and_restart<R>(
+        &self,
+        device_id: &str,
+        config: &TunnelConfig,
+        rotate: R,
+    ) -> TunnelResult<bool>
+    where
+        R: AsyncFnOnce(&str, &str, &ServicePortMap) -> TunnelResult<String>,
+    {
+        let (triggered_after, lock) = self.rotation_lock(device_id).await?;
+        let _rotating = lock.lock().await;
+
+        let (tunnel_id, region, services) = {
+            let mut state = self.state.lock().await;
+            if state.rotations.get(device_id).copied().unwrap_or(0) != triggered_after {
+                tracing::debug!("Token for {} was already rotated, skipping", device_id);
+                return Ok(false);
+            }
+            let connection = state.connection_mut(device_id)?;
+            (
+                connection.tunnel_id.clone(),
+                connection.region.clone(),
+                connection.services.clone(),
+            )
+        };
+        let src_token = rotate(&tunnel_id, &region, &services).await?;
+
+        self.restart_with(device_id, &lock, &src_token, config, |_, _| {})
+            .await?;
+        Ok(true)
+    }
+
+    /// Restart a rotating connection's localproxy with `src_token`, with the connection
+    /// taken out of the state meanwhile so other devices aren't held up
+    ///
+    /// `update` adjusts the state and connection once localproxy is back. A connection
+    /// whose localproxy fails to start again is dropped.
+    async fn restart_with<U>(
+        &self,
+        device_id: &str,
+        lock: &Arc<Mutex<()>>,
+        src_token: &str,
+        config: &TunnelConfig,
+        update: U,
+    ) -> TunnelResult<()>
+    where
+        U: FnOnce(&mut ManagerState, &mut TunnelConnection),
+    {
+        let (mut connection, abandoned) = self.take_for_restart(device_id, lock).await?;
+        let restarted = async {
+            let restart = LocalproxyRestart::stop(&mut connection, config).await?;
+            restart.start(src_token, config).await
+        }
+        .await;
+
+        let mut state = self.state.lock().await;
+        abandoned.disarm();
+        match restarted {
+            Ok(restarted) => restarted.install(&mut connection),
+            Err(e) => {
+                self.drop_failed_restart(&mut state, device_id, &e);
+                return Err(e);
+            }
+        }
+        update(&mut state, &mut connection);
+        self.publish_connected(
+            &ConnectionSummary::new(&connection, false, false),
+            true,
+            config,
+        );
+        state.finish_restart(connection);
+        *state.rotations.entry(device_id.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    /// Drop a connection whose localproxy was stopped but couldn't be started again
+    fn drop_failed_restart(&self, state: &mut ManagerState, device_id: &str, error: &TunnelError) {
+        tracing::warn!("localproxy for {} failed to restart: {}", device_id, error);
+        state.restarting.remove(device_id);
+        state.forget(device_id);
+        self.publish_disconnected(
+            device_id,
+            format!("localproxy failed to restart: {}", error),
+        );
+    }
+
+    /// The lock serializing a connection's rotations, and how many it has had so far
+    async fn rotation_lock(&self, device_id: &str) -> TunnelResult<(u64, Arc<Mutex<()>>)> {
+        let mut state = self.state.lock().await;
+        state.connection_mut(device_id)?;
+        let completed = state.rotations.get(device_id).copied().unwrap_or(0);
+        let lock = state
+            .rotation_locks
+            .entry(device_id.to_string())
+            .or_default()
+            .clone();
+        Ok((completed, lock))
+    }
+
+    /// Move each connection whose tunnel AWS closes within `tunnel_reopen_margin` to a
+    /// new tunnel, restarting localproxy on it
+    ///
+    /// Meant to be polled alongside `expire_sessions`. Only tunnels this session opened
+    /// are moved: a reused one may be shared with someone else, who would be cut off
+    /// when it closes, and a token file's connection makes no AWS calls. A tunnel that
+    /// fails to reopen is reported once and then left to close. Returns the devices
+    /// that couldn't be moved and why.
+    pub async fn reopen_expiring_tunnels(
+        &self,
+        config: &TunnelConfig,
+    ) -> Vec<(String, TunnelError)> {
+        if config.tunnel_reopen_margin.is_zero() {
+            return Vec::new();
+        }
+        let due: Vec<(String, String)> = {
+            let state = self.state.lock().await;
+            let deadline = SystemTime::now() + config.tunnel_reopen_margin;
+            state
+                .connections
+                .values()
+                .filter(|c| state.session_tunnels.contains_key(&c.tunnel_id))
+                .filter(|c| c.tunnel_expires_at.is_some_and(|at| at <= deadline))
+                .filter(|c| !state.reopen_failed.contains(&c.tunnel_id))
+                .map(|c| (c.device_id.clone(), c.tunnel_id.clone()))
+                .collect()
+        };
+
+        let mut failed = Vec::new();
+        for (device_id, tunnel_id) in due {
+            match self.reopen(&device_id, &tunnel_id, config).await {
+                Ok(Some(new_tunnel_id)) => {
+                    self.events.publish(TunnelEvent::TunnelOpened {
+                        device_id,
+                        tunnel_id: new_tunnel_id,
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to reopen tunnel {} for {}: {}",
+                        tunnel_id,
+                        device_id,
+                        e
+                    );
+                    self.state.lock().await.reopen_failed.insert(tunnel_id);
+                    failed.push((device_id, e));
+                }
+            }
+        }
+        failed
+    }
+
+    /// Move one connection off `tunnel_id`, returning the new tunnel, or `None` if it
+    /// already moved
+    ///
+    /// The old tunnel is closed once localproxy has restarted on the new one. If it
+    /// can't, the new tunnel is closed instead.
+    async fn reopen(
+        &self,
+        device_id: &str,
+        tunnel_id: &str,
+        config: &TunnelConfig,
+    ) -> TunnelResult<Option<String>> {
+        let (_, lock) = self.rotation_lock(device_id).await?;
+        let _rotating = lock.lock().await;
+
+        let (region, services) = {
+            let mut state = self.state.lock().await;
+            let connection = state.connection_mut(device_id)?;
+            if connection.tunnel_id != tunnel_id {
+                return Ok(None);
+            }
+            (connection.region.clone(), connection.services.clone())
+        };
+        let tokens = reopen_tunnel(device_id, tunnel_id, &region, &services, config).await?;
+
+        let moved = self
+            .restart_with(
+                device_id,
+                &lock,
+                &tokens.source_token,
+                config,
+                |state, connection| {
+                    // The old tunnel is about to be closed, and this session opened the new one
+                    state.session_tunnels.remove(tunnel_id);
+                    state.session_tunnels.insert(
+                        tokens.tunnel_id.clone(),
+                        SessionTunnel {
+                            device_id: device_id.to_string(),
+                            config: config.clone(),
+                        },
+                    );
+                    connection.tunnel_id = tokens.tunnel_id.clone();
+                    // Opened without a timeout, so it gets AWS's default lifetime
+                    connection.tunnel_expires_at = Some(
+                        SystemTime::now()
+                            + Duration::from_secs(u64::from(MAX_TUNNEL_LIFETIME_MINUTES) * 60),
+                    );
+                },
+            )
+            .await;
+
+        // Whichever tunnel nothing is connected to any more
+        let unused = match moved {
+            Ok(()) => tunnel_id,
+            Err(_) => &tokens.tunnel_id,
+        };
+        if let Err(e) = close_tunnel_in_region(unused, &region, config).await {
+            tracing::warn!("Failed to close tunnel {} for {}: {}", unused, device_id, e);
+        }
+        moved.map(|()| Some(tokens.tunnel_id))
+    }
+
+    /// Run `check_sessions` every `session_check_interval` until the task is dropped
+    pub async fn watch_sessions(&self, config: TunnelConfig) {
+        if config.session_check_interval.is_zero() {
+            return;
+        }
+        loop {
+            tokio::time::sleep(config.session_check_interval).await;
+            self.check_sessions(&config).await;
+        }
+    }
+}
+
+/// The error for a connection whose localproxy is still running after being stopped
+fn still_running(connection: &TunnelConnection, failure: Option<String>) -> TunnelError {
+    let reason = failure.unwrap_or_else(|| String::from("the process is still running"));
+    let pid = connection
+        .child
+        .id()
+        .map(|pid| format!(" (pid {})", pid))
+        .unwrap_or_default();
+    TunnelError::disconnection(&connection.device_id, format!("{}{}", reason, pid))
+}
+
+/// Ask localproxy to stop and wait up to `STOP_WAIT`, returning why it didn't
+async fn stop(child: &mut Child) -> Option<String> {
+    let pid = child.id()?;
+    if cfg!(windows) {
+        return child.kill().await.err().map(|e| e.to_string());
+    }
+    if let Err(e) = request_stop(pid).await {
+        return Some(e.to_string());
+    }
+    match tokio::time::timeout(STOP_WAIT, child.wait()).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!(
+            "localproxy did not stop within {} seconds",
+            STOP_WAIT.as_secs()
+        )),
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, SocketAddr};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::TunnelConfig;
+use crate::error::TunnelResult;
+use crate::manager::{ConnectStats, ConnectionManager};
+
+/// Upper bound on request headers, so a misbehaving client can't hold a connection open
+const MAX_HEADERS: usize = 64;
+
+/// Prometheus text endpoint on the loopback interface
+///
+/// `GET /metrics` reports the manager's connections and connect counters. There is no
+/// token, since scrapers rarely send one; it only listens on `127.0.0.1`.
+pub struct MetricsServer {
+    listener: TcpListener,
+    manager: ConnectionManager,
+}
+
+impl MetricsServer {
+    /// Bind to `127.0.0.1` on the configured port
+    pub async fn bind(config: &TunnelConfig, manager: ConnectionManager) -> TunnelResult<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, config.metrics.port)).await?;
+        Ok(Self { listener, manager })
+    }
+
+    pub fn local_addr(&self) -> TunnelResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections until the task is dropped, one request per connection
+    pub async fn run(self) -> TunnelResult<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let manager = self.manager.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(stream, &manager).await {
+                    tracing::warn!("Metrics request failed: {}", e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, manager: &ConnectionManager) -> TunnelResult<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+
+    // Headers carry nothing the endpoint needs, but must be read before replying
+    let mut header = String::new();
+    for _ in 0..MAX_HEADERS {
+        header.clear();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render_metrics(&manager.stats().await)),
+        (Some(_), Some("/metrics")) => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Connect stats in the Prometheus text exposition format
+pub fn render_metrics(stats: &ConnectStats) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+        let _ = writeln!(out, "# HELP tunnel_manager_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE tunnel_manager_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "tunnel_manager_{}{} {}", name, labels, value);
+        }
+    };
+
+    metric(
+        "active_connections",
+        "gauge",
+        "Connections with localproxy running",
+        &[(String::new(), stats.active_connections.to_string())],
+    );
+    metric(
+        "connects_total",
+        "counter",
+        "Connects attempted, successful or not",
+        &[(String::new(), stats.connects.to_string())],
+    );
+    let failures: Vec<_> = stats
+        .failures
+        .iter()
+        .map(|(category, count)| (format!("{{category=\"{}\"}}", category), count.to_string()))
+        .collect();
+    metric(
+        "connect_failures_total",
+        "counter",
+        "Connects that failed, by error category",
+        &failures,
+    );
+    if let Some(latency) = stats.last_connect_latency {
+        metric(
+            "last_connect_seconds",
+            "gauge",
+            "How long the latest successful connect took",
+            &[(String::new(), latency.as_secs_f64().to_string())],
+        );
+    }
+    out
+}
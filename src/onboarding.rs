@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml_edit::{Array, DocumentMut, Item, Table, value};
+
+use crate::config::TunnelConfig;
+use crate::error::{TunnelError, TunnelResult};
+use crate::localproxy::{ASSETS_DIR, CommandTemplate};
+
+/// File names localproxy is installed under
+const LOCALPROXY_NAMES: &[&str] = &["localproxy", "localproxy.exe"];
+
+/// Answers from the first-run setup
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetupChoices {
+    pub profile: String,
+    /// localproxy binary to run when it isn't in the assets folder or on PATH
+    pub localproxy: Option<PathBuf>,
+}
+
+impl SetupChoices {
+    /// Apply the choices to the running config, as saving them would on the next launch
+    pub fn apply_to(&self, config: &mut TunnelConfig) {
+        config.profile = self.profile.clone();
+        if let Some(path) = &self.localproxy {
+            config.localproxy_command = Some(CommandTemplate::localproxy_at(path));
+        }
+    }
+}
+
+/// Whether the app is running for the first time, meaning it has no config file yet
+pub fn is_first_run() -> bool {
+    TunnelConfig::path().is_some_and(|path| !path.exists())
+}
+
+/// First localproxy binary found in `dirs`
+pub fn find_localproxy_in(dirs: impl IntoIterator<Item = PathBuf>) -> Option<PathBuf> {
+    dirs.into_iter()
+        .flat_map(|dir| LOCALPROXY_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// localproxy in the assets folder or on PATH, where the app runs it from by default
+pub fn find_localproxy() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let assets = PathBuf::from(ASSETS_DIR);
+    find_localproxy_in(std::iter::once(assets).chain(std::env::split_paths(&path)))
+}
+
+/// Write the setup choices into config file contents, keeping the comments and every
+/// other setting as they were
+pub fn apply_setup(contents: &str, choices: &SetupChoices) -> TunnelResult<String> {
+    let mut doc = contents
+        .parse::<DocumentMut>()
+        .map_err(|e| TunnelError::config(format!("Invalid config file: {}", e)))?;
+    doc["profile"] = value(choices.profile.as_str());
+    if let Some(path) = &choices.localproxy {
+        let template = CommandTemplate::localproxy_at(path);
+        let mut table = Table::new();
+        table["program"] = value(template.program);
+        table["args"] = value(template.args.iter().map(String::as_str).collect::<Array>());
+        doc["localproxy_command"] = Item::Table(table);
+    }
+    Ok(doc.to_string())
+}
+
+/// Save the setup choices to the config file at `path`, creating it if needed
+pub fn save_setup_to(path: &Path, choices: &SetupChoices) -> TunnelResult<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    Ok(fs::write(path, apply_setup(&contents, choices)?)?)
+}
+
+/// Save the setup choices to the config file, which also marks the setup as done
+pub fn save_setup(choices: &SetupChoices) -> TunnelResult<PathBuf> {
+    let path = TunnelConfig::create_if_missing()?;
+    save_setup_to(&path, choices)?;
+    Ok(path)
+}
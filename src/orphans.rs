@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tokio::process::Command;
+
+use crate::error::{TunnelError, TunnelResult};
+
+const PID_DIR: &str = "tunnel-manager";
+const PID_SUBDIR: &str = "localproxy";
+const PID_EXTENSION: &str = "pid";
+
+/// Directory holding a pid file for every localproxy this app has running
+pub fn pid_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join(PID_DIR).join(PID_SUBDIR))
+}
+
+/// Record of a spawned localproxy, removed again when dropped
+///
+/// A file left behind means the app exited without cleaning up, so the
+/// process it names may still be holding ports.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write a pid file in the default pid directory
+    pub fn create(pid: u32, device_id: &str) -> TunnelResult<Self> {
+        let dir = pid_dir()
+            .ok_or_else(|| TunnelError::config("No local data directory for pid files"))?;
+        Self::create_in(&dir, pid, device_id)
+    }
+
+    pub fn create_in(dir: &Path, pid: u32, device_id: &str) -> TunnelResult<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.{}", pid, PID_EXTENSION));
+        let owner = std::process::id();
+        fs::write(&path, format!("{}\n{}\n{}\n", pid, device_id, owner))?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A localproxy from a previous run that is still alive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrphanedProcess {
+    pub pid: u32,
+    pub device_id: String,
+    path: PathBuf,
+}
+
+/// Find localproxy processes left behind by earlier runs
+///
+/// Pid files whose process has exited, or whose pid now belongs to another
+/// program, are stale and deleted along the way. Processes started by this
+/// run are never reported.
+pub async fn find_orphans(dir: &Path) -> Vec<OrphanedProcess> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut orphans = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().is_none_or(|ext| ext != PID_EXTENSION) {
+            continue;
+        }
+        match read_pid_file(&path) {
+            Some(record) if record.owner == Some(std::process::id()) => continue,
+            Some(record) if is_localproxy(record.pid).await => orphans.push(OrphanedProcess {
+                pid: record.pid,
+                device_id: record.device_id,
+                path,
+            }),
+            _ => {
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+    orphans.sort_by_key(|orphan| orphan.pid);
+    orphans
+}
+
+/// Stop an orphaned localproxy and delete its pid file
+pub async fn terminate(orphan: &OrphanedProcess) -> TunnelResult<()> {
+    let pid = orphan.pid.to_string();
+    let status = if cfg!(windows) {
+        Command::new("taskkill")
+            .args(["/PID", &pid, "/F"])
+            .status()
+            .await?
+    } else {
+        Command::new("kill").arg(&pid).status().await?
+    };
+
+    if !status.success() && is_localproxy(orphan.pid).await {
+        return Err(TunnelError::process_execution(format!(
+            "Failed to stop localproxy (pid {})",
+            orphan.pid
+        )));
+    }
+    let _ = fs::remove_file(&orphan.path);
+    Ok(())
+}
+
+/// Ask a process to exit with SIGTERM, letting localproxy close its connections
+///
+/// Unix only; Windows has no equivalent for a console process.
+pub async fn request_stop(pid: u32) -> TunnelResult<()> {
+    let status = Command::new("kill").arg(pid.to_string()).status().await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(TunnelError::process_execution(format!(
+            "Failed to stop process {}",
+            pid
+        )))
+    }
+}
+
+/// Kill a process outright, for a localproxy that ignored a normal stop
+///
+/// On Windows this also takes down anything the process started.
+pub async fn force_kill(pid: u32) -> TunnelResult<()> {
+    let pid = pid.to_string();
+    let status = if cfg!(windows) {
+        Command::new("taskkill")
+            .args(["/PID", &pid, "/F", "/T"])
+            .status()
+            .await?
+    } else {
+        Command::new("kill").args(["-9", &pid]).status().await?
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(TunnelError::process_execution(format!(
+            "Failed to force kill process {}",
+            pid
+        )))
+    }
+}
+
+struct PidRecord {
+    pid: u32,
+    device_id: String,
+    /// The tunnel manager process that spawned localproxy
+    owner: Option<u32>,
+}
+
+fn read_pid_file(path: &Path) -> Option<PidRecord> {
+    let contents = fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines().map(str::trim);
+    Some(PidRecord {
+        pid: lines.next()?.parse().ok()?,
+        device_id: lines.next().unwrap_or_default().to_string(),
+        owner: lines.next().and_then(|owner| owner.parse().ok()),
+    })
+}
+
+/// Whether `pid` is alive and still a localproxy, guarding against pid reuse
+async fn is_localproxy(pid: u32) -> bool {
+    let pid = pid.to_string();
+    let output = if cfg!(windows) {
+        let filter = format!("PID eq {}", pid);
+        Command::new("tasklist")
+            .args(["/FI", &filter, "/FO", "CSV", "/NH"])
+            .output()
+            .await
+    } else {
+        Command::new("ps")
+            .args(["-p", &pid, "-o", "comm="])
+            .output()
+            .await
+    };
+
+    output.is_ok_and(|output| {
+        output.status.success() && String::from_utf8_lossy(&output.stdout).contains("localproxy")
+    })
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, TcpListener};
+
+use serde::Deserialize;
+
+use crate::error::{TunnelError, TunnelResult};
+use crate::localproxy::ServicePortMap;
+
+/// Attempts at drawing ephemeral ports before giving up on collisions
+const EPHEMERAL_ATTEMPTS: usize = 16;
+
+fn default_stride() -> u16 {
+    10
+}
+
+/// How local ports are chosen when several devices are connected at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum PortAllocation {
+    /// Use the configured ports as-is; a second connection needing them is refused
+    #[default]
+    Fixed,
+    /// Offset every configured port by `stride` per connection, e.g. SSH at 2222, 2232, 2242
+    Stride {
+        #[serde(default = "default_stride")]
+        stride: u16,
+    },
+    /// Let the OS pick a free port for every service
+    Ephemeral,
+}
+
+/// Choose local ports for `services` that don't clash with any port in `taken`
+///
+/// The lowest free stride slot is reused, so ports stay predictable as connections
+/// come and go.
+pub fn allocate_ports(
+    strategy: PortAllocation,
+    services: &ServicePortMap,
+    taken: &HashSet<u16>,
+) -> TunnelResult<ServicePortMap> {
+    match strategy {
+        PortAllocation::Fixed => {
+            if services.iter().any(|(_, port)| taken.contains(&port)) {
+                return Err(TunnelError::connection(
+                    "The service ports are already used by another connection. Set a port_allocation strategy to connect several devices.",
+                ));
+            }
+            Ok(services.clone())
+        }
+        PortAllocation::Stride { stride } => {
+            let stride = stride.max(1);
+            // Inclusive, since an open range panics stepping past the last slot
+            for slot in 0..=u16::MAX {
+                let Some(candidate) = offset_ports(services, slot, stride) else {
+                    break;
+                };
+                if candidate.iter().all(|(_, port)| !taken.contains(&port)) {
+                    return Ok(candidate);
+                }
+            }
+            Err(TunnelError::config(format!(
+                "No free local ports left for another connection with a port stride of {}. Lower the service ports or the stride.",
+                stride
+            )))
+        }
+        PortAllocation::Ephemeral => {
+            for _ in 0..EPHEMERAL_ATTEMPTS {
+                let candidate = ephemeral_ports(services)?;
+                if candidate.iter().all(|(_, port)| !taken.contains(&port)) {
+                    return Ok(candidate);
+                }
+            }
+            Err(TunnelError::connection(
+                "Could not find free local ports for the connection",
+            ))
+        }
+    }
+}
+
+fn offset_ports(services: &ServicePortMap, slot: u16, stride: u16) -> Option<ServicePortMap> {
+    let offset = slot.checked_mul(stride)?;
+    let mut offset_services = ServicePortMap::new();
+    for (name, port) in services.iter() {
+        offset_services.insert(name, port.checked_add(offset)?);
+    }
+    Some(offset_services)
+}
+
+/// Ask the OS for one free port per service, holding every listener until all are
+/// chosen so the services get distinct ports
+fn ephemeral_ports(services: &ServicePortMap) -> TunnelResult<ServicePortMap> {
+    let mut listeners = Vec::with_capacity(services.len());
+    let mut ephemeral = ServicePortMap::new();
+    for name in services.services() {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        ephemeral.insert(name, listener.local_addr()?.port());
+        listeners.push(listener);
+    }
+    Ok(ephemeral)
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::error::{TunnelError, TunnelResult};
+
+/// Location of the AWS CLI config file, honouring `AWS_CONFIG_FILE`
+pub fn aws_config_path() -> Option<PathBuf> {
+    match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::home_dir().map(|home| home.join(".aws").join("config")),
+    }
+}
+
+/// Profile names declared in the contents of an AWS config file, in file order
+///
+/// `[default]` and `[profile name]` sections are profiles; other sections such as
+/// `[sso-session name]` are not.
+pub fn parse_profiles(contents: &str) -> Vec<String> {
+    let mut profiles = Vec::new();
+    for line in contents.lines() {
+        let Some(section) = line
+            .trim()
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        else {
+            continue;
+        };
+        let section = section.trim();
+        let name = if section == "default" {
+            section
+        } else if let Some(name) = section.strip_prefix("profile ") {
+            name.trim()
+        } else {
+            continue;
+        };
+        if !name.is_empty() && !profiles.iter().any(|p| p == name) {
+            profiles.push(name.to_string());
+        }
+    }
+    profiles
+}
+
+/// Profiles in the AWS config file, empty if there is no file
+pub fn list_profiles() -> TunnelResult<Vec<String>> {
+    let Some(path) = aws_config_path() else {
+        return Ok(Vec::new());
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(parse_profiles(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Account ID from a caller identity ARN such as `arn:aws:sts::123456789012:assumed-role/...`
+pub fn account_id(arn: &str) -> Option<&str> {
+    arn.split(':').nth(4).filter(|account| !account.is_empty())
+}
+
+/// Check a profile is declared in the AWS config file before using it
+pub fn validate_profile(profile: &str) -> TunnelResult<()> {
+    if list_profiles()?.iter().any(|p| p == profile) {
+        Ok(())
+    } else {
+        Err(TunnelError::aws_config(format!(
+            "AWS profile '{}' was not found in the AWS config. Pick another profile or add it with 'aws configure sso'.",
+            profile
+        )))
+    }
+}
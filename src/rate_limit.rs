@@ -0,0 +1,54 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket shared by every status poller, so their combined AWS call rate
+/// stays under a ceiling
+///
+/// Callers over the rate wait their turn rather than failing, which spreads a
+/// burst of polls out instead of sending it at once. The bucket holds one
+/// second's worth of calls.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Option<Bucket>>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Calls available now; negative when callers are already waiting for the next ones
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait until a call fits within `per_second`
+    pub async fn acquire(&self, per_second: u32) {
+        let wait = self.reserve(per_second, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Take the next call slot at `now`, returning how long until it comes round
+    pub fn reserve(&self, per_second: u32, now: Instant) -> Duration {
+        let rate = f64::from(per_second.max(1));
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.updated = bucket.updated.max(now);
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// How much of the app the window shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutMode {
+    /// Device input, connect and status only, with everything else in a menu
+    Compact,
+    /// Every control, the connection list and the logs
+    #[default]
+    Expanded,
+}
+
+impl LayoutMode {
+    /// Window width and height in logical pixels
+    pub fn window_size(self) -> (f64, f64) {
+        match self {
+            LayoutMode::Compact => (430., 120.),
+            LayoutMode::Expanded => (680., 240.),
+        }
+    }
+
+    pub fn toggled(self) -> Self {
+        match self {
+            LayoutMode::Compact => LayoutMode::Expanded,
+            LayoutMode::Expanded => LayoutMode::Compact,
+        }
+    }
+}
+
+/// Coarse status behind colour-coded indicators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusLevel {
+    /// Connected and usable
+    Ok,
+    /// Connecting, or connected but waiting on the device or a login
+    Pending,
+    Error,
+    Idle,
+}
+
+/// Connection lifecycle shared by the UI components
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    Connecting {
+        device_id: String,
+    },
+    /// Trying the connect again after a failed attempt, `attempt` of `max_attempts`
+    Reconnecting {
+        device_id: String,
+        attempt: u32,
+        max_attempts: u32,
+    },
+    Connected {
+        device_id: String,
+        tunnel_id: String,
+    },
+    /// localproxy is running but the device hasn't connected its end of the tunnel yet
+    WaitingForDevice {
+        device_id: String,
+        tunnel_id: String,
+    },
+    /// The AWS session expired while connected; localproxy keeps running until re-login
+    AuthenticationRequired {
+        device_id: String,
+        tunnel_id: String,
+    },
+    Failed,
+}
+
+/// Something that happened to the connection the UI shows
+///
+/// Spawned tasks send these through `ConnectionState::apply` rather than setting the
+/// state themselves, so every transition is made, and can be tested, in one place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AppMsg {
+    /// The operator asked to connect to a device
+    ConnectRequested {
+        device_id: String,
+    },
+    /// localproxy is running; `waiting_for_device` until the device connects its end
+    Connected {
+        device_id: String,
+        tunnel_id: String,
+        waiting_for_device: bool,
+    },
+    /// The connect in flight is being tried again
+    ReconnectAttempt {
+        attempt: u32,
+        max_attempts: u32,
+    },
+    ConnectFailed,
+    /// The connect stopped short without failing, such as to ask about a shared tunnel
+    ConnectAbandoned,
+    /// The connection shown was stopped
+    Disconnected,
+    /// The manager's live connections, from the background poll
+    Synced(Vec<ConnectionState>),
+}
+
+impl ConnectionState {
+    /// The state after `msg`
+    ///
+    /// A second connect while one is in flight or up is ignored, and polls don't
+    /// override a connect in flight, since the manager doesn't list it yet.
+    pub fn apply(&self, msg: AppMsg) -> ConnectionState {
+        match msg {
+            AppMsg::ConnectRequested { .. } if self.is_connecting() || self.is_connected() => {
+                self.clone()
+            }
+            AppMsg::ConnectRequested { device_id } => ConnectionState::Connecting { device_id },
+            AppMsg::Connected {
+                device_id,
+                tunnel_id,
+                waiting_for_device: true,
+            } => ConnectionState::WaitingForDevice {
+                device_id,
+                tunnel_id,
+            },
+            AppMsg::Connected {
+                device_id,
+                tunnel_id,
+                waiting_for_device: false,
+            } => ConnectionState::Connected {
+                device_id,
+                tunnel_id,
+            },
+            AppMsg::ReconnectAttempt {
+                attempt,
+                max_attempts,
+            } => match self {
+                ConnectionState::Connecting { device_id }
+                | ConnectionState::Reconnecting { device_id, .. } => {
+                    ConnectionState::Reconnecting {
+                        device_id: device_id.clone(),
+                        attempt,
+                        max_attempts,
+                    }
+                }
+                // Only a connect in flight is retried
+                _ => self.clone(),
+            },
+            AppMsg::ConnectFailed => ConnectionState::Failed,
+            AppMsg::ConnectAbandoned | AppMsg::Disconnected => ConnectionState::Disconnected,
+            AppMsg::Synced(_) if self.is_connecting() => self.clone(),
+            AppMsg::Synced(connections) => match self.device_id() {
+                // The connection shown, or idle once it is gone
+                Some(device_id) => connections
+                    .into_iter()
+                    .find(|c| c.device_id() == Some(device_id))
+                    .unwrap_or_default(),
+                // Nothing shown, so pick up a connection made elsewhere
+                None => connections
+                    .into_iter()
+                    .next()
+                    .unwrap_or_else(|| self.clone()),
+            },
+        }
+    }
+
+    /// Whether a connect is in flight, including a retry of one
+    pub fn is_connecting(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connecting { .. } | ConnectionState::Reconnecting { .. }
+        )
+    }
+
+    /// Whether the connect in flight is a retry after a failed attempt
+    pub fn is_reconnecting(&self) -> bool {
+        matches!(self, ConnectionState::Reconnecting { .. })
+    }
+
+    /// Whether a tunnel is up, including while waiting for the operator to log in again
+    pub fn is_connected(&self) -> bool {
+        matches!(
+            self,
+            ConnectionState::Connected { .. }
+                | ConnectionState::WaitingForDevice { .. }
+                | ConnectionState::AuthenticationRequired { .. }
+        )
+    }
+
+    /// Device of the current or pending connection
+    pub fn device_id(&self) -> Option<&str> {
+        match self {
+            ConnectionState::Connecting { device_id }
+            | ConnectionState::Reconnecting { device_id, .. }
+            | ConnectionState::Connected { device_id, .. }
+            | ConnectionState::WaitingForDevice { device_id, .. }
+            | ConnectionState::AuthenticationRequired { device_id, .. } => Some(device_id),
+            ConnectionState::Disconnected | ConnectionState::Failed => None,
+        }
+    }
+
+    /// Status level for indicators that summarise many connections
+    pub fn level(&self) -> StatusLevel {
+        match self {
+            ConnectionState::Connected { .. } => StatusLevel::Ok,
+            ConnectionState::Connecting { .. }
+            | ConnectionState::Reconnecting { .. }
+            | ConnectionState::WaitingForDevice { .. }
+            | ConnectionState::AuthenticationRequired { .. } => StatusLevel::Pending,
+            ConnectionState::Failed => StatusLevel::Error,
+            ConnectionState::Disconnected => StatusLevel::Idle,
+        }
+    }
+
+    /// One or two words for a list row, so the status doesn't rely on colour alone
+    pub fn short_label(&self) -> &'static str {
+        match self {
+            ConnectionState::Disconnected => "Idle",
+            ConnectionState::Connecting { .. } => "Connecting",
+            ConnectionState::Reconnecting { .. } => "Reconnecting",
+            ConnectionState::Connected { .. } => "Connected",
+            ConnectionState::WaitingForDevice { .. } => "Waiting for device",
+            ConnectionState::AuthenticationRequired { .. } => "Login required",
+            ConnectionState::Failed => "Failed",
+        }
+    }
+
+    /// Tunnel of the current connection, once it has been opened
+    pub fn tunnel_id(&self) -> Option<&str> {
+        match self {
+            ConnectionState::Connected { tunnel_id, .. }
+            | ConnectionState::WaitingForDevice { tunnel_id, .. }
+            | ConnectionState::AuthenticationRequired { tunnel_id, .. } => Some(tunnel_id),
+            ConnectionState::Disconnected
+            | ConnectionState::Connecting { .. }
+            | ConnectionState::Reconnecting { .. }
+            | ConnectionState::Failed => None,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Disconnected => write!(f, "Disconnected"),
+            ConnectionState::Connecting { device_id } => {
+                write!(f, "Connecting to {}...", device_id)
+            }
+            ConnectionState::Reconnecting {
+                device_id,
+                attempt,
+                max_attempts,
+            } => write!(
+                f,
+                "Reconnecting to {} (attempt {}/{})…",
+                device_id, attempt, max_attempts
+            ),
+            ConnectionState::Connected {
+                device_id,
+                tunnel_id,
+            } => write!(f, "Connected to {} ({})", device_id, tunnel_id),
+            ConnectionState::WaitingForDevice { device_id, .. } => {
+                write!(f, "Waiting for {} to connect to the tunnel...", device_id)
+            }
+            ConnectionState::AuthenticationRequired { device_id, .. } => {
+                write!(
+                    f,
+                    "Connected to {}, log in to AWS to keep the session",
+                    device_id
+                )
+            }
+            ConnectionState::Failed => write!(f, "Connection failed"),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{TunnelError, TunnelResult};
+use crate::localproxy::ServicePortMap;
+
+/// What a machine without AWS access needs to connect to an open tunnel
+///
+/// The source token lets anyone holding it connect to the device, so the file is
+/// written readable by its owner only.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenFile {
+    pub device_id: String,
+    pub tunnel_id: String,
+    /// Region localproxy connects to, already resolved for localproxy
+    pub region: String,
+    pub source_token: String,
+    /// Services and the local ports localproxy listens on
+    pub services: ServicePortMap,
+}
+
+impl fmt::Debug for TokenFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenFile")
+            .field("device_id", &self.device_id)
+            .field("tunnel_id", &self.tunnel_id)
+            .field("region", &self.region)
+            .field("source_token", &"<redacted>")
+            .field("services", &self.services)
+            .finish()
+    }
+}
+
+impl TokenFile {
+    pub fn read(path: &Path) -> TunnelResult<Self> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| {
+            TunnelError::config(format!("Invalid token file {}: {}", path.display(), e))
+        })
+    }
+
+    /// Write the file readable by its owner only, replacing any existing one
+    pub fn write(&self, path: &Path) -> TunnelResult<()> {
+        let contents = toml::to_string(self)
+            .map_err(|e| TunnelError::config(format!("Failed to write token file: {}", e)))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        // The mode only applies to new files, so tighten one that was already there
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        }
+        file.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// A warning if users other than the owner can read the token file
+pub fn permissions_warning(path: &Path) -> Option<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path).ok()?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Some(format!(
+                "Token file {} can be read by other users (mode {:o}). Anyone who can read it can connect to the device; run 'chmod 600' on it.",
+                path.display(),
+                mode & 0o777
+            ));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    None
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::config::TunnelConfig;
+use crate::error::{TunnelError, TunnelResult};
+
+/// Version of the running build
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Longest the update check waits for the release endpoint before giving up
+pub const UPDATE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The latest release as described by `update_check_url`
+///
+/// Takes `{"version": "1.2.0", "url": "..."}` or a GitHub latest release, whose
+/// `tag_name` and `html_url` are read the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Release {
+    #[serde(alias = "tag_name")]
+    pub version: String,
+    /// Release notes or download page
+    #[serde(default, alias = "html_url")]
+    pub url: Option<String>,
+}
+
+/// Whether `latest` is a later version than `current`
+///
+/// Compares the dotted numbers, ignoring a leading `v` and any pre-release or
+/// build suffix. A version that doesn't parse is never newer.
+pub fn is_newer(latest: &str, current: &str) -> bool {
+    match (parse_version(latest), parse_version(current)) {
+        (Some(latest), Some(current)) => latest > current,
+        _ => false,
+    }
+}
+
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    let version = version.trim();
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let core = version.split(['-', '+']).next()?;
+    let mut parts: Vec<u64> = core
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    // 1.2 and 1.2.0 are the same release
+    while parts.len() > 1 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(parts)
+}
+
+/// Fetch the latest release from `url`, through the configured proxy if there is one
+pub async fn fetch_latest_release(url: &str, config: &TunnelConfig) -> TunnelResult<Release> {
+    let mut builder = reqwest::Client::builder()
+        .timeout(UPDATE_CHECK_TIMEOUT)
+        .user_agent(concat!("tunnel-manager/", env!("CARGO_PKG_VERSION")));
+    if let Some(proxy) = config.proxy.https_proxy() {
+        let proxy = reqwest::Proxy::https(&proxy)
+            .map_err(|e| TunnelError::config(format!("Invalid proxy {}: {}", proxy, e)))?
+            .no_proxy(
+                config
+                    .proxy
+                    .no_proxy()
+                    .and_then(|rules| reqwest::NoProxy::from_string(&rules)),
+            );
+        builder = builder.proxy(proxy);
+    }
+    if let Some(pem) = config.proxy.read_ca_bundle()? {
+        for certificate in reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| TunnelError::config(format!("Unusable CA bundle: {}", e)))?
+        {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    let client = builder
+        .build()
+        .map_err(|e| TunnelError::connection(format!("Failed to build HTTP client: {}", e)))?;
+
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| TunnelError::connection(format!("Update check failed: {}", e)))?;
+    response
+        .json()
+        .await
+        .map_err(|e| TunnelError::connection(format!("Unexpected update check response: {}", e)))
+}
+
+/// The latest release when it is newer than the running build
+///
+/// Does nothing unless `update_check_url` is set. Failures are only logged, so an
+/// unreachable endpoint never gets in the way.
+pub async fn check_for_update(config: &TunnelConfig) -> Option<Release> {
+    let url = config.update_check_url.as_deref()?;
+    match fetch_latest_release(url, config).await {
+        Ok(release) if is_newer(&release.version, CURRENT_VERSION) => {
+            tracing::info!(
+                "Version {} is available, running {}",
+                release.version,
+                CURRENT_VERSION
+            );
+            Some(release)
+        }
+        Ok(_) => None,
+        Err(e) => {
+            tracing::debug!("{}", e);
+            None
+        }
+    }
+}
@@ -1,10 +1,37 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_iotsecuretunneling::error::SdkError;
+use aws_sdk_iotsecuretunneling::operation::close_tunnel::CloseTunnelOutput;
+use aws_sdk_iotsecuretunneling::operation::describe_tunnel::DescribeTunnelOutput;
 use aws_sdk_iotsecuretunneling::operation::list_tunnels::ListTunnelsOutput;
 use aws_sdk_iotsecuretunneling::operation::open_tunnel::OpenTunnelOutput;
+use aws_sdk_iotsecuretunneling::operation::rotate_tunnel_access_token::RotateTunnelAccessTokenError;
 use aws_sdk_iotsecuretunneling::operation::rotate_tunnel_access_token::RotateTunnelAccessTokenOutput;
-use aws_sdk_iotsecuretunneling::types::{TunnelStatus, TunnelSummary};
+use aws_sdk_iotsecuretunneling::types::error::ResourceNotFoundException;
+use aws_sdk_iotsecuretunneling::types::{
+    ClientMode, ConnectionState, ConnectionStatus, DestinationConfig, TimeoutConfig, Tunnel,
+    TunnelStatus, TunnelSummary,
+};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::http::{Response as HttpResponse, StatusCode};
+use aws_smithy_types::body::SdkBody;
+use mockall::Sequence;
 use mockall::predicate::*;
+use tunnel_manager::aws::{
+    ConnectAction, LoginCooldown, TunnelFilter, automatic_sso_login, build_destination_config,
+    close_tunnels, close_tunnels_matching, find_tunnels_matching, open_only_with_client,
+    open_tunnel_for_device, open_tunnel_with_login, refresh_source_token_with_client,
+    rotate_tunnel_tokens_with_client, tunnel_expiry, wait_for_destination,
+};
 use tunnel_manager::aws_client::TunnelClient;
 use tunnel_manager::aws_client::test_utils::MockTunnelClient;
+use tunnel_manager::config::{
+    AuthBehavior, MultipleTunnelPolicy, SharedTunnelPolicy, TunnelConfig,
+};
+use tunnel_manager::error::{TunnelError, UiError};
+use tunnel_manager::localproxy::ServicePortMap;
 
 /// Test helper to create a mock tunnel summary
 fn create_mock_tunnel_summary(tunnel_id: &str, status: TunnelStatus) -> TunnelSummary {
@@ -14,6 +41,30 @@ fn create_mock_tunnel_summary(tunnel_id: &str, status: TunnelStatus) -> TunnelSu
         .build()
 }
 
+/// Test helper to describe a tunnel whose device side is in `status`
+fn create_mock_describe_output(status: ConnectionStatus) -> DescribeTunnelOutput {
+    DescribeTunnelOutput::builder()
+        .tunnel(
+            Tunnel::builder()
+                .tunnel_id("tunnel-123")
+                .destination_connection_state(ConnectionState::builder().status(status).build())
+                .build(),
+        )
+        .build()
+}
+
+/// Test helper to describe a tunnel whose source side is in `status`
+fn create_mock_source_state(status: ConnectionStatus) -> DescribeTunnelOutput {
+    DescribeTunnelOutput::builder()
+        .tunnel(
+            Tunnel::builder()
+                .tunnel_id("open-tunnel-456")
+                .source_connection_state(ConnectionState::builder().status(status).build())
+                .build(),
+        )
+        .build()
+}
+
 /// Test helper to create mock tokens response
 fn create_mock_open_tunnel_output(tunnel_id: &str) -> OpenTunnelOutput {
     OpenTunnelOutput::builder()
@@ -34,7 +85,9 @@ mod aws_business_logic_tests {
         mock_client
             .expect_open_tunnel_with_config()
             .times(1)
-            .returning(|_config| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+            .returning(|_config, _timeout, _description| {
+                Ok(create_mock_open_tunnel_output("new-tunnel-123"))
+            });
 
         let dest_config = aws_sdk_iotsecuretunneling::types::DestinationConfig::builder()
             .thing_name("test-device")
@@ -42,7 +95,9 @@ mod aws_business_logic_tests {
             .build()
             .expect("Failed to build DestinationConfig");
 
-        let result = mock_client.open_tunnel_with_config(dest_config).await;
+        let result = mock_client
+            .open_tunnel_with_config(dest_config, None, None)
+            .await;
         assert!(result.is_ok());
 
         let output = result.unwrap();
@@ -56,17 +111,17 @@ mod aws_business_logic_tests {
         let mut mock_client = MockTunnelClient::new();
 
         mock_client
-            .expect_list_tunnels_for_thing()
-            .with(eq("device-with-open-tunnel"))
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("device-with-open-tunnel"), eq(None))
             .times(1)
-            .returning(|_thing_name| {
+            .returning(|_thing_name, _| {
                 Ok(ListTunnelsOutput::builder()
                     .tunnel_summaries(create_mock_tunnel_summary("tunnel-456", TunnelStatus::Open))
                     .build())
             });
 
         let result = mock_client
-            .list_tunnels_for_thing("device-with-open-tunnel")
+            .list_tunnels_for_thing_page("device-with-open-tunnel", None)
             .await;
         assert!(result.is_ok());
 
@@ -81,10 +136,10 @@ mod aws_business_logic_tests {
         let mut mock_client = MockTunnelClient::new();
 
         mock_client
-            .expect_list_tunnels_for_thing()
-            .with(eq("device-with-closed-tunnel"))
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("device-with-closed-tunnel"), eq(None))
             .times(1)
-            .returning(|_thing_name| {
+            .returning(|_thing_name, _| {
                 Ok(ListTunnelsOutput::builder()
                     .tunnel_summaries(create_mock_tunnel_summary(
                         "tunnel-789",
@@ -94,7 +149,7 @@ mod aws_business_logic_tests {
             });
 
         let result = mock_client
-            .list_tunnels_for_thing("device-with-closed-tunnel")
+            .list_tunnels_for_thing_page("device-with-closed-tunnel", None)
             .await;
         assert!(result.is_ok());
 
@@ -160,10 +215,10 @@ mod aws_business_logic_tests {
         let mut mock_client = MockTunnelClient::new();
 
         mock_client
-            .expect_list_tunnels_for_thing()
-            .with(eq("device-with-multiple-tunnels"))
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("device-with-multiple-tunnels"), eq(None))
             .times(1)
-            .returning(|_thing_name| {
+            .returning(|_thing_name, _| {
                 Ok(ListTunnelsOutput::builder()
                     .tunnel_summaries(create_mock_tunnel_summary("tunnel-1", TunnelStatus::Open))
                     .tunnel_summaries(create_mock_tunnel_summary("tunnel-2", TunnelStatus::Closed))
@@ -172,7 +227,7 @@ mod aws_business_logic_tests {
             });
 
         let result = mock_client
-            .list_tunnels_for_thing("device-with-multiple-tunnels")
+            .list_tunnels_for_thing_page("device-with-multiple-tunnels", None)
             .await;
         assert!(result.is_ok());
 
@@ -205,6 +260,1447 @@ mod aws_business_logic_tests {
         assert!(valid_device_id.starts_with('G'));
         assert!(valid_device_id.len() > 1);
     }
+
+    #[tokio::test]
+    async fn test_open_tunnel_for_device_closes_stale_tunnels_before_opening() {
+        let mut mock_client = MockTunnelClient::new();
+        let mut sequence = Sequence::new();
+
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("device-with-stale-tunnels"), eq(None))
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "closed-tunnel-1",
+                        TunnelStatus::Closed,
+                    ))
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "closed-tunnel-2",
+                        TunnelStatus::Closed,
+                    ))
+                    .build())
+            });
+
+        for tunnel_id in ["closed-tunnel-1", "closed-tunnel-2"] {
+            mock_client
+                .expect_close_tunnel_by_id()
+                .with(eq(tunnel_id))
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        }
+
+        mock_client
+            .expect_open_tunnel_with_config()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-stale-tunnels",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+        assert_eq!(tunnel.src_token, "mock-source-token");
+        assert_eq!(tunnel.action, ConnectAction::ClosedStaleAndOpened);
+    }
+
+    /// Mock client listing three open tunnels whose source ends are free, expecting the
+    /// first to be reused and `extras_closed` of the others to be closed
+    fn mock_three_open_tunnels(extras_closed: usize, rotations: usize) -> MockTunnelClient {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                let summaries = ["open-1", "open-2", "open-3"]
+                    .map(|id| create_mock_tunnel_summary(id, TunnelStatus::Open));
+                Ok(ListTunnelsOutput::builder()
+                    .set_tunnel_summaries(Some(summaries.to_vec()))
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .with(eq("open-1"))
+            .times(rotations)
+            .returning(|_| Ok(create_mock_source_state(ConnectionStatus::Disconnected)));
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-1"), eq(ClientMode::Source), always())
+            .times(rotations)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("new-source-token")
+                    .build())
+            });
+        for tunnel_id in ["open-2", "open-3"].into_iter().take(extras_closed) {
+            mock_client
+                .expect_describe_tunnel_by_id()
+                .with(eq(tunnel_id))
+                .times(1)
+                .returning(|_| Ok(create_mock_source_state(ConnectionStatus::Disconnected)));
+            mock_client
+                .expect_close_tunnel_by_id()
+                .with(eq(tunnel_id))
+                .times(1)
+                .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        }
+        mock_client.expect_open_tunnel_with_config().never();
+        mock_client
+    }
+
+    fn multiple_tunnel_config(policy: MultipleTunnelPolicy) -> TunnelConfig {
+        TunnelConfig {
+            multiple_open_tunnels: policy,
+            ..TunnelConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiple_open_tunnels_close_the_extras_by_default() {
+        assert_eq!(
+            TunnelConfig::default().multiple_open_tunnels,
+            MultipleTunnelPolicy::ReuseFirstCloseOthers
+        );
+        let mock_client = mock_three_open_tunnels(2, 1);
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &multiple_tunnel_config(MultipleTunnelPolicy::ReuseFirstCloseOthers),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-1");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_extra_tunnel_in_use_is_left_open() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                let summaries = ["open-1", "open-2", "open-3"]
+                    .map(|id| create_mock_tunnel_summary(id, TunnelStatus::Open));
+                Ok(ListTunnelsOutput::builder()
+                    .set_tunnel_summaries(Some(summaries.to_vec()))
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .returning(|tunnel_id| {
+                let source = if tunnel_id == "open-2" {
+                    ConnectionStatus::Connected
+                } else {
+                    ConnectionStatus::Disconnected
+                };
+                Ok(create_mock_source_state(source))
+            });
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-1"), eq(ClientMode::Source), always())
+            .times(1)
+            .returning(|_, _, _| Ok(rotated("new-source-token")));
+        mock_client
+            .expect_close_tunnel_by_id()
+            .with(eq("open-3"))
+            .times(1)
+            .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &multiple_tunnel_config(MultipleTunnelPolicy::ReuseFirstCloseOthers),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-1");
+    }
+
+    #[tokio::test]
+    async fn test_multiple_open_tunnels_reuse_first_leaves_the_rest() {
+        let mock_client = mock_three_open_tunnels(0, 1);
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &multiple_tunnel_config(MultipleTunnelPolicy::ReuseFirst),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-1");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_open_tunnels_error_touches_nothing() {
+        let mock_client = mock_three_open_tunnels(0, 0);
+
+        let error = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &multiple_tunnel_config(MultipleTunnelPolicy::Error),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, TunnelError::TunnelOperation { .. }));
+        assert!(
+            error
+                .to_string()
+                .contains("G111070 has 3 open tunnels (open-1, open-2, open-3)")
+        );
+    }
+
+    /// Mock client listing one tunnel with `listed` status, which describes as `settles_to`
+    fn mock_transitional_tunnel(
+        listed: Option<TunnelStatus>,
+        settles_to: Option<TunnelStatus>,
+    ) -> MockTunnelClient {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .returning(move |_, _| {
+                let summary = TunnelSummary::builder()
+                    .tunnel_id("settling-tunnel")
+                    .set_status(listed.clone())
+                    .build();
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(summary)
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .with(eq("settling-tunnel"))
+            .returning(move |_| {
+                Ok(DescribeTunnelOutput::builder()
+                    .tunnel(
+                        Tunnel::builder()
+                            .tunnel_id("settling-tunnel")
+                            .set_status(settles_to.clone())
+                            .source_connection_state(
+                                ConnectionState::builder()
+                                    .status(ConnectionStatus::Disconnected)
+                                    .build(),
+                            )
+                            .build(),
+                    )
+                    .build())
+            });
+        mock_client
+    }
+
+    #[tokio::test]
+    async fn test_transitional_tunnel_that_opens_is_reused() {
+        let mut mock_client = mock_transitional_tunnel(
+            Some(TunnelStatus::from("OPENING")),
+            Some(TunnelStatus::Open),
+        );
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("settling-tunnel"), eq(ClientMode::Source), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .build())
+            });
+        mock_client.expect_close_tunnel_by_id().never();
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "settling-tunnel");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_transitional_tunnel_that_closes_is_cleaned_up() {
+        let mut mock_client = mock_transitional_tunnel(
+            Some(TunnelStatus::from("CLOSING")),
+            Some(TunnelStatus::Closed),
+        );
+        mock_client
+            .expect_close_tunnel_by_id()
+            .with(eq("settling-tunnel"))
+            .times(1)
+            .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        mock_client
+            .expect_open_tunnel_with_config()
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+        assert_eq!(tunnel.action, ConnectAction::ClosedStaleAndOpened);
+    }
+
+    #[tokio::test]
+    async fn test_unsettled_tunnel_is_left_alone() {
+        for listed in [None, Some(TunnelStatus::from("OPENING"))] {
+            let mut mock_client = mock_transitional_tunnel(listed, None);
+            mock_client.expect_rotate_tunnel_tokens().never();
+            mock_client.expect_close_tunnel_by_id().never();
+            mock_client
+                .expect_open_tunnel_with_config()
+                .times(1)
+                .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+
+            let tunnel = open_tunnel_for_device(
+                &mock_client,
+                "G111070",
+                &ServicePortMap::default(),
+                &TunnelConfig::default(),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+            assert_eq!(tunnel.action, ConnectAction::OpenedNew);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_for_device_with_empty_tunnel_list() {
+        // AWS may send an empty list rather than leaving it out; both open a new tunnel
+        for summaries in [None, Some(Vec::new())] {
+            let mut mock_client = MockTunnelClient::new();
+            mock_client
+                .expect_list_tunnels_for_thing_page()
+                .times(1)
+                .returning(move |_, _| {
+                    Ok(ListTunnelsOutput::builder()
+                        .set_tunnel_summaries(summaries.clone())
+                        .build())
+                });
+            mock_client.expect_close_tunnel_by_id().never();
+            mock_client.expect_rotate_tunnel_tokens().never();
+            mock_client
+                .expect_open_tunnel_with_config()
+                .times(1)
+                .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+
+            let tunnel = open_tunnel_for_device(
+                &mock_client,
+                "device-without-tunnels",
+                &ServicePortMap::default(),
+                &TunnelConfig::default(),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+            assert_eq!(tunnel.action, ConnectAction::OpenedNew);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_for_device_uses_mapped_thing_name() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("acme-G111070"), eq(None))
+            .times(1)
+            .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
+        mock_client
+            .expect_open_tunnel_with_config()
+            .withf(|destination, _, _| destination.thing_name() == Some("acme-G111070"))
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+        let config = TunnelConfig {
+            thing_name_format: Some("acme-{device_id}".to_string()),
+            ..TunnelConfig::default()
+        };
+
+        let tunnel =
+            open_tunnel_for_device(&mock_client, "G111070", &ServicePortMap::default(), &config)
+                .await
+                .unwrap();
+
+        assert_eq!(tunnel.action, ConnectAction::OpenedNew);
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_for_device_sets_description() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
+        mock_client
+            .expect_open_tunnel_with_config()
+            .withf(|_, _, description| {
+                description.as_deref() == Some("G111070 for incident 42, opened by field-eng")
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+        let config = TunnelConfig {
+            tunnel_description: Some(
+                "{device_id} for incident 42, opened by field-eng".to_string(),
+            ),
+            ..TunnelConfig::default()
+        };
+
+        open_tunnel_for_device(&mock_client, "G111070", &ServicePortMap::default(), &config)
+            .await
+            .unwrap();
+
+        // Unset, the description names the device and the OS user
+        let description = TunnelConfig::default().tunnel_description("G111070");
+        assert!(description.starts_with("Opened by tunnel-manager for G111070 by "));
+        assert!(!description.contains("{user}"));
+    }
+
+    #[tokio::test]
+    async fn test_open_only_opens_tunnel_with_lifetime() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "closed-tunnel",
+                        TunnelStatus::Closed,
+                    ))
+                    .build())
+            });
+        mock_client
+            .expect_open_tunnel_with_config()
+            .withf(|_, timeout, _| {
+                timeout
+                    .as_ref()
+                    .and_then(|t| t.max_lifetime_timeout_minutes())
+                    == Some(30)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("short-tunnel")));
+        mock_client.expect_close_tunnel_by_id().never();
+
+        let tokens = open_only_with_client(
+            &mock_client,
+            "G111070",
+            30,
+            &ServicePortMap::default(),
+            String::from("test"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tokens.tunnel_id, "short-tunnel");
+        assert_eq!(tokens.source_token, "mock-source-token");
+        assert_eq!(tokens.destination_token.as_deref(), Some("mock-dest-token"));
+    }
+
+    #[tokio::test]
+    async fn test_open_only_reuses_open_tunnel_with_both_tokens() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "open-tunnel-456",
+                        TunnelStatus::Open,
+                    ))
+                    .build())
+            });
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), eq(ClientMode::All), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .destination_access_token("rotated-dest-token")
+                    .build())
+            });
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let tokens = open_only_with_client(
+            &mock_client,
+            "G111070",
+            30,
+            &ServicePortMap::default(),
+            String::from("test"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tokens.tunnel_id, "open-tunnel-456");
+        assert_eq!(tokens.source_token, "rotated-source-token");
+        assert_eq!(
+            tokens.destination_token.as_deref(),
+            Some("rotated-dest-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rotate_source_tokens_leaves_destination_alone() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), eq(ClientMode::Source), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .build())
+            });
+        let services = ServicePortMap::new().with_service("SSH", 22);
+
+        let tokens = rotate_tunnel_tokens_with_client(
+            &mock_client,
+            "G111070",
+            "open-tunnel-456",
+            &services,
+            ClientMode::Source,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tokens.tunnel_id, "open-tunnel-456");
+        assert_eq!(tokens.source_token, "rotated-source-token");
+        assert_eq!(tokens.destination_token, None);
+    }
+
+    #[tokio::test]
+    async fn test_open_with_empty_source_token_fails() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
+        mock_client
+            .expect_open_tunnel_with_config()
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(OpenTunnelOutput::builder()
+                    .tunnel_id("new-tunnel-123")
+                    .source_access_token("")
+                    .destination_access_token("mock-dest-token")
+                    .build())
+            });
+
+        let err = open_only_with_client(
+            &mock_client,
+            "G111070",
+            30,
+            &ServicePortMap::default(),
+            String::from("test"),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, TunnelError::TunnelOperation { .. }));
+        assert!(err.to_string().contains("empty source access token"));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_with_empty_source_token_fails() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("")
+                    .build())
+            });
+
+        let err = refresh_source_token_with_client(
+            &mock_client,
+            "G111070",
+            "open-tunnel-456",
+            &ServicePortMap::new().with_service("SSH", 22),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(err, TunnelError::TunnelOperation { .. }));
+        assert!(err.to_string().contains("empty source access token"));
+    }
+
+    #[tokio::test]
+    async fn test_rotate_destination_only_is_rejected() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client.expect_rotate_tunnel_tokens().never();
+        let services = ServicePortMap::new().with_service("SSH", 22);
+
+        let result = rotate_tunnel_tokens_with_client(
+            &mock_client,
+            "G111070",
+            "open-tunnel-456",
+            &services,
+            ClientMode::Destination,
+        )
+        .await;
+
+        assert!(matches!(result, Err(TunnelError::Config { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_open_only_rejects_out_of_range_lifetime() {
+        for lifetime in [0, 721] {
+            let mock_client = MockTunnelClient::new();
+            let result = open_only_with_client(
+                &mock_client,
+                "G111070",
+                lifetime,
+                &ServicePortMap::default(),
+                String::from("test"),
+            )
+            .await;
+            assert!(matches!(result, Err(TunnelError::Config { .. })));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_destination_until_device_connects() {
+        let mut mock_client = MockTunnelClient::new();
+        let mut sequence = Sequence::new();
+        for status in [ConnectionStatus::Disconnected, ConnectionStatus::Connected] {
+            mock_client
+                .expect_describe_tunnel_by_id()
+                .with(eq("tunnel-123"))
+                .times(1)
+                .in_sequence(&mut sequence)
+                .returning(move |_| Ok(create_mock_describe_output(status.clone())));
+        }
+
+        let connected = wait_for_destination(
+            &mock_client,
+            "tunnel-123",
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+
+        assert!(connected);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_destination_times_out_for_offline_device() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .returning(|_| Ok(create_mock_describe_output(ConnectionStatus::Disconnected)));
+
+        let connected = wait_for_destination(
+            &mock_client,
+            "tunnel-123",
+            Duration::from_millis(100),
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+
+        assert!(!connected);
+    }
+
+    /// Mock client whose credentials have expired, so every request fails to dispatch
+    fn mock_expired_credentials() -> MockTunnelClient {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Err(SdkError::dispatch_failure(ConnectorError::other(
+                    "the SSO session has expired".into(),
+                    None,
+                )))
+            });
+        mock_client.expect_open_tunnel_with_config().never();
+        mock_client
+    }
+
+    #[tokio::test]
+    async fn test_login_retries_with_a_fresh_client() {
+        let expired_client = mock_expired_credentials();
+        let logins = AtomicUsize::new(0);
+
+        let tunnel = open_tunnel_with_login(
+            &expired_client,
+            async || {
+                logins.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            async || {
+                let mut fresh_client = MockTunnelClient::new();
+                fresh_client
+                    .expect_list_tunnels_for_thing_page()
+                    .times(1)
+                    .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
+                fresh_client
+                    .expect_open_tunnel_with_config()
+                    .times(1)
+                    .returning(|_, _, _| Ok(create_mock_open_tunnel_output("fresh-tunnel-789")));
+                Ok(Box::new(fresh_client) as Box<dyn TunnelClient>)
+            },
+            "device-with-expired-login",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        assert_eq!(tunnel.tunnel_id, "fresh-tunnel-789");
+        assert!(tunnel.credentials_refreshed);
+    }
+
+    #[tokio::test]
+    async fn test_login_that_does_not_help_is_reported_once() {
+        let expired_client = mock_expired_credentials();
+        let logins = AtomicUsize::new(0);
+
+        let result = open_tunnel_with_login(
+            &expired_client,
+            async || {
+                logins.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            },
+            async || Ok(Box::new(mock_expired_credentials()) as Box<dyn TunnelClient>),
+            "device-with-expired-login",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await;
+
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        let error = result.unwrap_err();
+        assert!(matches!(error, TunnelError::StillUnauthorized { .. }));
+        assert!(
+            error
+                .to_string()
+                .contains("Logged in to AWS, but profile iotmgmt_prod is still not authorized")
+        );
+        // The popup explains what AWS said instead of asking for another login
+        let ui_error = UiError::from(&error);
+        assert!(!ui_error.should_retry());
+        assert!(
+            ui_error
+                .user_message()
+                .starts_with("Logged in to AWS, but profile iotmgmt_prod is still not authorized")
+        );
+        assert!(
+            ui_error
+                .user_message()
+                .ends_with("AWS reported: the SSO session has expired")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_manual_auth_does_not_log_in() {
+        let expired_client = mock_expired_credentials();
+        let config = TunnelConfig {
+            auth_behavior: AuthBehavior::Manual,
+            ..TunnelConfig::default()
+        };
+
+        let result = open_tunnel_with_login(
+            &expired_client,
+            async || panic!("login must wait for the operator"),
+            async || panic!("no client should be rebuilt without a login"),
+            "device-with-expired-login",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await;
+
+        assert!(matches!(result, Err(TunnelError::AwsAuth { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_missing_credentials_do_not_log_in() {
+        let mut client = MockTunnelClient::new();
+        client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Err(SdkError::dispatch_failure(ConnectorError::other(
+                    Box::new(CredentialsError::not_loaded(
+                        "no providers in chain provided credentials",
+                    )),
+                    None,
+                )))
+            });
+        client.expect_open_tunnel_with_config().never();
+
+        let result = open_tunnel_with_login(
+            &client,
+            async || panic!("a login can't help without a profile"),
+            async || panic!("no client should be rebuilt without a login"),
+            "G111070",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await;
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, TunnelError::NoCredentials));
+        assert_eq!(
+            error.to_string(),
+            "AWS configuration error: No AWS credentials configured; set up a profile or run aws configure sso"
+        );
+    }
+
+    #[test]
+    fn test_login_cooldown_allows_one_attempt_per_period() {
+        let cooldown = LoginCooldown::new();
+        let period = Duration::from_secs(600);
+
+        assert!(cooldown.try_start(period).is_ok());
+        let remaining = cooldown.try_start(period).unwrap_err();
+        assert!(remaining <= period && remaining > Duration::from_secs(590));
+
+        cooldown.reset();
+        assert!(cooldown.try_start(period).is_ok());
+        assert!(cooldown.try_start(Duration::ZERO).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_login_within_cooldown_asks_operator_instead() {
+        let config = TunnelConfig::default();
+        let cooldown = LoginCooldown::new();
+        cooldown.try_start(config.sso_login_cooldown).unwrap();
+
+        let err = automatic_sso_login(&config, &cooldown).await.unwrap_err();
+
+        assert!(matches!(err, TunnelError::AwsAuth { .. }));
+        assert!(err.to_string().contains("~/.aws/config"));
+        assert!(err.to_string().contains("10 minutes"));
+    }
+
+    /// Mock client listing one open tunnel whose source side is in `source`
+    fn mock_open_tunnel(source: ConnectionStatus) -> MockTunnelClient {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "open-tunnel-456",
+                        TunnelStatus::Open,
+                    ))
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .with(eq("open-tunnel-456"))
+            .returning(move |_| Ok(create_mock_source_state(source.clone())));
+        mock_client
+    }
+
+    /// Mock client with one unused open tunnel that expects a rotation in `client_mode`
+    fn mock_reusable_tunnel(client_mode: ClientMode) -> MockTunnelClient {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Disconnected);
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), eq(client_mode), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .build())
+            });
+        mock_client.expect_open_tunnel_with_config().never();
+        mock_client
+    }
+
+    fn rotated(source_token: &str) -> RotateTunnelAccessTokenOutput {
+        RotateTunnelAccessTokenOutput::builder()
+            .source_access_token(source_token)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_transient_rotation_failure_is_retried_on_the_same_tunnel() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Disconnected);
+        let mut sequence = Sequence::new();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), always(), always())
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| {
+                Err(SdkError::dispatch_failure(ConnectorError::io(
+                    "connection reset".into(),
+                )))
+            });
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), always(), always())
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_, _, _| Ok(rotated("rotated-source-token")));
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-tunnel-456");
+        assert_eq!(tunnel.src_token, "rotated-source-token");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_gone_before_rotation_is_replaced() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Disconnected);
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .times(1)
+            .returning(|_, _, _| {
+                Err(SdkError::service_error(
+                    RotateTunnelAccessTokenError::ResourceNotFoundException(
+                        ResourceNotFoundException::builder()
+                            .message("Tunnel open-tunnel-456 not found")
+                            .build(),
+                    ),
+                    HttpResponse::new(StatusCode::try_from(404).unwrap(), SdkBody::empty()),
+                ))
+            });
+        mock_client
+            .expect_open_tunnel_with_config()
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+        assert_eq!(tunnel.action, ConnectAction::OpenedNew);
+    }
+
+    #[tokio::test]
+    async fn test_lasting_rotation_failure_never_opens_a_second_tunnel() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Disconnected);
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .times(2)
+            .returning(|_, _, _| {
+                Err(SdkError::dispatch_failure(ConnectorError::io(
+                    "connection reset".into(),
+                )))
+            });
+        mock_client.expect_open_tunnel_with_config().never();
+        let config = TunnelConfig {
+            rotate_retries: 1,
+            ..TunnelConfig::default()
+        };
+
+        let err = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(
+            err.to_string()
+                .contains("Failed to rotate access tokens for tunnel open-tunnel-456"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reused_tunnel_rotates_source_token_only_by_default() {
+        let mock_client = mock_reusable_tunnel(ClientMode::Source);
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-tunnel-456");
+        assert_eq!(tunnel.src_token, "rotated-source-token");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_reused_tunnel_rotates_both_tokens_when_opted_in() {
+        let mock_client = mock_reusable_tunnel(ClientMode::All);
+        let config = TunnelConfig {
+            rotate_destination_on_reuse: true,
+            ..TunnelConfig::default()
+        };
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.src_token, "rotated-source-token");
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_on_a_later_page_is_reused() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("device-with-open-tunnel"), eq(None))
+            .times(1)
+            .returning(|_, _| Ok(ListTunnelsOutput::builder().next_token("page-2").build()));
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .with(
+                eq("device-with-open-tunnel"),
+                eq(Some(String::from("page-2"))),
+            )
+            .times(1)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "open-tunnel-456",
+                        TunnelStatus::Open,
+                    ))
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .with(eq("open-tunnel-456"))
+            .returning(|_| Ok(create_mock_source_state(ConnectionStatus::Disconnected)));
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .times(1)
+            .returning(|_, _, _| Ok(rotated("rotated-source-token")));
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-tunnel-456");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_after_login_hands_localproxy_the_rotated_token() {
+        // The open tunnel's old token is never seen again; only the rotation's token
+        // may reach localproxy, and the device's token is left out entirely
+        let expired_client = mock_expired_credentials();
+
+        let tunnel = open_tunnel_with_login(
+            &expired_client,
+            async || Ok(()),
+            async || {
+                let fresh_client = mock_reusable_tunnel(ClientMode::Source);
+                Ok(Box::new(fresh_client) as Box<dyn TunnelClient>)
+            },
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "open-tunnel-456");
+        assert_eq!(tunnel.action, ConnectAction::ReusedExisting);
+        assert_eq!(tunnel.src_token, "rotated-source-token");
+        assert_eq!(tunnel.dst_token, None);
+        assert!(tunnel.credentials_refreshed);
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_is_left_alone_when_rotation_is_off() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Disconnected);
+        mock_client.expect_rotate_tunnel_tokens().never();
+        mock_client.expect_close_tunnel_by_id().never();
+        mock_client.expect_open_tunnel_with_config().never();
+        let config = TunnelConfig {
+            rotate_on_reuse: false,
+            ..TunnelConfig::default()
+        };
+
+        let result = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await;
+
+        match result {
+            Err(TunnelError::TunnelOperation { message }) => {
+                assert!(message.contains("open-tunnel-456"));
+                assert!(message.contains("rotate_on_reuse is off"));
+            }
+            other => panic!("Expected TunnelOperation, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_credential_refresh_rotates_source_token_only() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(always(), eq(ClientMode::All), always())
+            .never();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .with(eq("open-tunnel-456"), eq(ClientMode::Source), always())
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .build())
+            });
+        // Reusing a tunnel would rotate both tokens with this set; refreshing must not
+        let config = TunnelConfig {
+            rotate_destination_on_reuse: true,
+            ..TunnelConfig::default()
+        };
+
+        let src_token = refresh_source_token_with_client(
+            &mock_client,
+            "G111070",
+            "open-tunnel-456",
+            &ServicePortMap::new().with_service("SSH", 22),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(src_token, "rotated-source-token");
+    }
+
+    #[tokio::test]
+    async fn test_shared_tunnel_asks_before_disconnecting_another_client() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Connected);
+        mock_client.expect_rotate_tunnel_tokens().never();
+        mock_client.expect_close_tunnel_by_id().never();
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let result = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &TunnelConfig::default(),
+        )
+        .await;
+
+        match result {
+            Err(TunnelError::TunnelInUse { tunnel_id, .. }) => {
+                assert_eq!(tunnel_id, "open-tunnel-456")
+            }
+            other => panic!("Expected TunnelInUse, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_tunnel_force_new_leaves_it_open() {
+        let mut mock_client = mock_open_tunnel(ConnectionStatus::Connected);
+        mock_client.expect_rotate_tunnel_tokens().never();
+        mock_client.expect_close_tunnel_by_id().never();
+        mock_client
+            .expect_open_tunnel_with_config()
+            .times(1)
+            .returning(|_, _, _| Ok(create_mock_open_tunnel_output("new-tunnel-123")));
+        let config = TunnelConfig {
+            shared_tunnel: SharedTunnelPolicy::ForceNew,
+            ..TunnelConfig::default()
+        };
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.tunnel_id, "new-tunnel-123");
+        assert_eq!(tunnel.action, ConnectAction::OpenedNew);
+    }
+
+    #[tokio::test]
+    async fn test_shared_tunnel_proceed_skips_the_check() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_for_thing_page()
+            .times(1)
+            .returning(|_, _| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary(
+                        "open-tunnel-456",
+                        TunnelStatus::Open,
+                    ))
+                    .build())
+            });
+        mock_client.expect_describe_tunnel_by_id().never();
+        mock_client
+            .expect_rotate_tunnel_tokens()
+            .times(1)
+            .returning(|_, _, _| {
+                Ok(RotateTunnelAccessTokenOutput::builder()
+                    .source_access_token("rotated-source-token")
+                    .build())
+            });
+        let config = TunnelConfig {
+            shared_tunnel: SharedTunnelPolicy::Proceed,
+            ..TunnelConfig::default()
+        };
+
+        let tunnel = open_tunnel_for_device(
+            &mock_client,
+            "device-with-open-tunnel",
+            &ServicePortMap::default(),
+            &config,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tunnel.src_token, "rotated-source-token");
+    }
+
+    /// Test helper to describe a tunnel to `device_id`
+    fn create_mock_device_tunnel(tunnel_id: &str, device_id: &str) -> DescribeTunnelOutput {
+        DescribeTunnelOutput::builder()
+            .tunnel(
+                Tunnel::builder()
+                    .tunnel_id(tunnel_id)
+                    .destination_config(
+                        DestinationConfig::builder()
+                            .thing_name(device_id)
+                            .services("SSH")
+                            .build()
+                            .unwrap(),
+                    )
+                    .build(),
+            )
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_find_tunnels_matching_follows_pages_and_filters_status() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_page()
+            .with(eq(None))
+            .times(1)
+            .returning(|_| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary("tunnel-1", TunnelStatus::Open))
+                    .tunnel_summaries(create_mock_tunnel_summary("tunnel-2", TunnelStatus::Closed))
+                    .next_token("page-2")
+                    .build())
+            });
+        mock_client
+            .expect_list_tunnels_page()
+            .with(eq(Some(String::from("page-2"))))
+            .times(1)
+            .returning(|_| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary("tunnel-3", TunnelStatus::Open))
+                    .build())
+            });
+        mock_client.expect_describe_tunnel_by_id().never();
+        let filter = TunnelFilter {
+            status: Some(TunnelStatus::Open),
+            device_prefix: None,
+        };
+
+        let matching = find_tunnels_matching(&mock_client, &filter).await.unwrap();
+
+        assert_eq!(matching, ["tunnel-1", "tunnel-3"]);
+    }
+
+    #[tokio::test]
+    async fn test_close_tunnels_matching_device_prefix() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client
+            .expect_list_tunnels_page()
+            .times(1)
+            .returning(|_| {
+                Ok(ListTunnelsOutput::builder()
+                    .tunnel_summaries(create_mock_tunnel_summary("tunnel-1", TunnelStatus::Open))
+                    .tunnel_summaries(create_mock_tunnel_summary("tunnel-2", TunnelStatus::Open))
+                    .build())
+            });
+        mock_client
+            .expect_describe_tunnel_by_id()
+            .returning(|tunnel_id| {
+                let device_id = if tunnel_id == "tunnel-1" {
+                    "G111070"
+                } else {
+                    "H200001"
+                };
+                Ok(create_mock_device_tunnel(tunnel_id, device_id))
+            });
+        mock_client
+            .expect_close_tunnel_by_id()
+            .with(eq("tunnel-1"))
+            .times(1)
+            .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        let filter = TunnelFilter {
+            status: None,
+            device_prefix: Some(String::from("G111")),
+        };
+
+        let closed = close_tunnels_matching(&mock_client, &filter).await.unwrap();
+
+        assert_eq!(closed, ["tunnel-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_close_tunnels_stops_at_the_first_failure() {
+        let mut mock_client = MockTunnelClient::new();
+        let mut seq = Sequence::new();
+        mock_client
+            .expect_close_tunnel_by_id()
+            .with(eq("tunnel-1"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+        mock_client
+            .expect_close_tunnel_by_id()
+            .with(eq("tunnel-2"))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_| {
+                Err(SdkError::dispatch_failure(ConnectorError::other(
+                    "connection reset".into(),
+                    None,
+                )))
+            });
+        let tunnel_ids = ["tunnel-1", "tunnel-2", "tunnel-3"].map(String::from);
+
+        let error = close_tunnels(&mock_client, &tunnel_ids).await.unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Closed 1 of 3 tunnels, then failed to close tunnel-2"
+        );
+    }
+
+    #[test]
+    fn test_build_destination_config() {
+        let config = build_destination_config("G111070", &ServicePortMap::default()).unwrap();
+        assert_eq!(config.thing_name(), Some("G111070"));
+        assert!(!config.services().is_empty());
+
+        for device_id in ["", "  "] {
+            match build_destination_config(device_id, &ServicePortMap::default()) {
+                Err(TunnelError::TunnelOperation { message }) => {
+                    assert!(message.contains("device ID"))
+                }
+                other => panic!("Expected TunnelOperation, got {:?}", other),
+            }
+        }
+
+        match build_destination_config("G111070", &ServicePortMap::new()) {
+            Err(TunnelError::TunnelOperation { message }) => {
+                assert!(message.contains("At least one service"))
+            }
+            other => panic!("Expected TunnelOperation, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_tunnel_for_device_without_services_returns_an_error() {
+        // Rejected before AWS is asked anything
+        let mut mock_client = MockTunnelClient::new();
+        mock_client.expect_list_tunnels_for_thing_page().never();
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let result = open_tunnel_for_device(
+            &mock_client,
+            "G111070",
+            &ServicePortMap::new(),
+            &TunnelConfig::default(),
+        )
+        .await;
+
+        assert!(matches!(result, Err(TunnelError::TunnelOperation { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_open_only_without_services_makes_no_aws_calls() {
+        let mut mock_client = MockTunnelClient::new();
+        mock_client.expect_list_tunnels_for_thing_page().never();
+        mock_client.expect_open_tunnel_with_config().never();
+
+        let error = open_only_with_client(
+            &mock_client,
+            "G111070",
+            30,
+            &ServicePortMap::new(),
+            String::from("test"),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Tunnel operation failed: At least one service must be configured"
+        );
+    }
 }
 
 /// Integration test that combines multiple operations
@@ -214,23 +1710,23 @@ async fn test_tunnel_lifecycle() {
 
     // First, list tunnels (should be empty)
     mock_client
-        .expect_list_tunnels_for_thing()
-        .with(eq("new-device"))
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("new-device"), eq(None))
         .times(1)
-        .returning(|_| Ok(ListTunnelsOutput::builder().build()));
+        .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
 
     // Then open a new tunnel
     mock_client
         .expect_open_tunnel_with_config()
         .times(1)
-        .returning(|_| Ok(create_mock_open_tunnel_output("lifecycle-tunnel")));
+        .returning(|_, _, _| Ok(create_mock_open_tunnel_output("lifecycle-tunnel")));
 
     // Then list tunnels again (should show the new tunnel)
     mock_client
-        .expect_list_tunnels_for_thing()
-        .with(eq("new-device"))
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("new-device"), eq(None))
         .times(1)
-        .returning(|_| {
+        .returning(|_, _| {
             Ok(ListTunnelsOutput::builder()
                 .tunnel_summaries(create_mock_tunnel_summary(
                     "lifecycle-tunnel",
@@ -252,7 +1748,9 @@ async fn test_tunnel_lifecycle() {
         });
 
     // Execute the lifecycle
-    let list_result1 = mock_client.list_tunnels_for_thing("new-device").await;
+    let list_result1 = mock_client
+        .list_tunnels_for_thing_page("new-device", None)
+        .await;
     assert!(list_result1.is_ok());
     assert!(list_result1.unwrap().tunnel_summaries.is_none());
 
@@ -262,10 +1760,14 @@ async fn test_tunnel_lifecycle() {
         .build()
         .expect("Failed to build DestinationConfig");
 
-    let open_result = mock_client.open_tunnel_with_config(dest_config).await;
+    let open_result = mock_client
+        .open_tunnel_with_config(dest_config, None, None)
+        .await;
     assert!(open_result.is_ok());
 
-    let list_result2 = mock_client.list_tunnels_for_thing("new-device").await;
+    let list_result2 = mock_client
+        .list_tunnels_for_thing_page("new-device", None)
+        .await;
     assert!(list_result2.is_ok());
     let tunnels = list_result2.unwrap().tunnel_summaries.unwrap();
     assert_eq!(tunnels.len(), 1);
@@ -273,3 +1775,29 @@ async fn test_tunnel_lifecycle() {
     let close_result = mock_client.close_tunnel_by_id("lifecycle-tunnel").await;
     assert!(close_result.is_ok());
 }
+
+#[test]
+fn test_tunnel_expiry_adds_the_lifetime_to_the_creation_time() {
+    let created_at = aws_smithy_types::DateTime::from_secs(1_700_000_000);
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let timed = Tunnel::builder()
+        .created_at(created_at)
+        .timeout_config(
+            TimeoutConfig::builder()
+                .max_lifetime_timeout_minutes(30)
+                .build(),
+        )
+        .build();
+    // Opened without a timeout, it gets AWS's default of 12 hours
+    let untimed = Tunnel::builder().created_at(created_at).build();
+
+    assert_eq!(
+        tunnel_expiry(&timed),
+        Some(created + Duration::from_secs(30 * 60))
+    );
+    assert_eq!(
+        tunnel_expiry(&untimed),
+        Some(created + Duration::from_secs(12 * 60 * 60))
+    );
+    assert_eq!(tunnel_expiry(&Tunnel::builder().build()), None);
+}
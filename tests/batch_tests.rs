@@ -0,0 +1,411 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use aws_sdk_iotsecuretunneling::error::SdkError;
+use aws_sdk_iotsecuretunneling::operation::close_tunnel::CloseTunnelOutput;
+use aws_sdk_iotsecuretunneling::operation::describe_tunnel::DescribeTunnelOutput;
+use aws_sdk_iotsecuretunneling::operation::list_tunnels::ListTunnelsOutput;
+use aws_sdk_iotsecuretunneling::types::{
+    ConnectionState, ConnectionStatus, Tunnel, TunnelStatus, TunnelSummary,
+};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_types::DateTime;
+use mockall::predicate::*;
+use tunnel_manager::aws::{TunnelInfo, close_all_tunnels_for_device, list_tunnels};
+use tunnel_manager::aws_client::test_utils::MockTunnelClient;
+use tunnel_manager::batch::{close_stale_tunnels, close_tunnels_for_devices, read_device_list};
+use tunnel_manager::config::TunnelConfig;
+
+fn scratch_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tunnel-manager-{}-{}", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn summary(tunnel_id: &str, status: TunnelStatus) -> TunnelSummary {
+    TunnelSummary::builder()
+        .tunnel_id(tunnel_id)
+        .status(status)
+        .build()
+}
+
+#[test]
+fn test_read_device_list_skips_blanks_and_comments() {
+    let path = scratch_file(
+        "device-list",
+        "G111070\n\n  G111071  \n# decommissioned\nG111072\n",
+    );
+
+    let device_ids = read_device_list(&path).unwrap();
+
+    assert_eq!(device_ids, ["G111070", "G111071", "G111072"]);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_read_device_list_reports_missing_file() {
+    let path = std::env::temp_dir().join("tunnel-manager-no-such-device-list");
+
+    let error = read_device_list(&path).unwrap_err();
+
+    assert!(error.to_string().contains("Failed to read device list"));
+}
+
+#[tokio::test]
+async fn test_list_tunnels_skips_summaries_without_an_id() {
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(None))
+        .times(1)
+        .returning(move |_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(
+                    TunnelSummary::builder()
+                        .tunnel_id("tunnel-1")
+                        .status(TunnelStatus::Open)
+                        .created_at(DateTime::from(created))
+                        .build(),
+                )
+                .tunnel_summaries(TunnelSummary::builder().status(TunnelStatus::Open).build())
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Closed))
+                .build())
+        });
+
+    let tunnels = list_tunnels(&mock_client, "G111070", &TunnelConfig::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        tunnels,
+        [
+            TunnelInfo {
+                tunnel_id: String::from("tunnel-1"),
+                status: Some(TunnelStatus::Open),
+                created_at: Some(created),
+            },
+            TunnelInfo {
+                tunnel_id: String::from("tunnel-2"),
+                status: Some(TunnelStatus::Closed),
+                created_at: None,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_list_tunnels_follows_the_pages() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(None))
+        .times(1)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-1", TunnelStatus::Closed))
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Closed))
+                .next_token("page-2")
+                .build())
+        });
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(Some(String::from("page-2"))))
+        .times(1)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-3", TunnelStatus::Open))
+                .build())
+        });
+
+    let tunnels = list_tunnels(&mock_client, "G111070", &TunnelConfig::default())
+        .await
+        .unwrap();
+
+    let ids: Vec<&str> = tunnels.iter().map(|t| t.tunnel_id.as_str()).collect();
+    assert_eq!(ids, ["tunnel-1", "tunnel-2", "tunnel-3"]);
+    assert_eq!(tunnels[2].status, Some(TunnelStatus::Open));
+}
+
+#[tokio::test]
+async fn test_close_all_tunnels_for_device_closes_only_open_tunnels() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(None))
+        .times(1)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-1", TunnelStatus::Open))
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Closed))
+                .tunnel_summaries(summary("tunnel-3", TunnelStatus::Open))
+                .build())
+        });
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-1"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-3"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+
+    let result = close_all_tunnels_for_device(&mock_client, "G111070", &TunnelConfig::default())
+        .await
+        .unwrap();
+
+    assert_eq!(result.closed, ["tunnel-1", "tunnel-3"]);
+    assert!(result.failed.is_empty());
+}
+
+#[tokio::test]
+async fn test_close_all_tunnels_for_device_continues_past_a_failed_close() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(None))
+        .times(2)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-1", TunnelStatus::Open))
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Open))
+                .build())
+        });
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-1"))
+        .times(2)
+        .returning(|_| {
+            Err(SdkError::dispatch_failure(ConnectorError::other(
+                "connection reset".into(),
+                None,
+            )))
+        });
+    let mut second_close = 0;
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-2"))
+        .times(2)
+        .returning(move |_| {
+            second_close += 1;
+            if second_close == 1 {
+                Ok(CloseTunnelOutput::builder().build())
+            } else {
+                Err(SdkError::dispatch_failure(ConnectorError::other(
+                    "connection reset".into(),
+                    None,
+                )))
+            }
+        });
+    let config = TunnelConfig::default();
+
+    let result = close_all_tunnels_for_device(&mock_client, "G111070", &config)
+        .await
+        .unwrap();
+    assert_eq!(result.closed, ["tunnel-2"]);
+    // The failure is reported alongside, not only logged
+    assert_eq!(result.failed.len(), 1);
+    assert_eq!(result.failed[0].0, "tunnel-1");
+    assert!(
+        result.failed[0]
+            .1
+            .to_string()
+            .contains("Failed to close tunnel tunnel-1 for G111070")
+    );
+
+    // Once every close fails, the first failure is returned
+    let error = close_all_tunnels_for_device(&mock_client, "G111070", &config)
+        .await
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Closed 0 of 2 tunnels for G111070; the first to fail was tunnel-1")
+    );
+}
+
+#[tokio::test]
+async fn test_close_tunnels_for_devices_continues_past_failures() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111070"), eq(None))
+        .times(1)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-1", TunnelStatus::Open))
+                .build())
+        });
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111071"), eq(None))
+        .times(1)
+        .returning(|_, _| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Open))
+                .build())
+        });
+    mock_client
+        .expect_list_tunnels_for_thing_page()
+        .with(eq("G111072"), eq(None))
+        .times(1)
+        .returning(|_, _| Ok(ListTunnelsOutput::builder().build()));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-1"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-2"))
+        .times(1)
+        .returning(|_| {
+            Err(SdkError::dispatch_failure(ConnectorError::other(
+                "connection reset".into(),
+                None,
+            )))
+        });
+    let device_ids = ["G111070", "G111071", "G111072"].map(String::from);
+
+    let report =
+        close_tunnels_for_devices(&mock_client, &device_ids, &TunnelConfig::default()).await;
+
+    assert!(!report.is_success());
+    assert_eq!(report.tunnels_closed(), 1);
+    assert_eq!(report.closed.len(), 2);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, "G111071");
+    let summary = report.to_string();
+    assert!(summary.contains("G111070: closed 1 (tunnel-1)"));
+    assert!(summary.contains("G111072: no open tunnels"));
+    assert!(summary.contains("G111071: failed: Closed 0 of 1 tunnels"));
+    assert!(summary.ends_with("Closed 1 tunnels for 2 devices, 1 devices failed"));
+}
+
+#[tokio::test]
+async fn test_close_tunnels_for_devices_rejects_invalid_ids_without_calling_aws() {
+    let mock_client = MockTunnelClient::new();
+    let device_ids = ["not a device".to_string()];
+
+    let report =
+        close_tunnels_for_devices(&mock_client, &device_ids, &TunnelConfig::default()).await;
+
+    assert_eq!(report.failed.len(), 1);
+    assert!(report.closed.is_empty());
+}
+
+/// A tunnel created `hours` before `now` whose source end is in `status`
+fn described(
+    tunnel_id: &str,
+    now: SystemTime,
+    hours: u64,
+    status: ConnectionStatus,
+) -> DescribeTunnelOutput {
+    DescribeTunnelOutput::builder()
+        .tunnel(
+            Tunnel::builder()
+                .tunnel_id(tunnel_id)
+                .status(TunnelStatus::Open)
+                .created_at(DateTime::from(now - Duration::from_secs(hours * 3600)))
+                .source_connection_state(ConnectionState::builder().status(status).build())
+                .build(),
+        )
+        .build()
+}
+
+#[tokio::test]
+async fn test_close_stale_tunnels_skips_recent_and_connected_tunnels() {
+    let now = SystemTime::now();
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_page()
+        .times(1)
+        .returning(|_| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("stale", TunnelStatus::Open))
+                .tunnel_summaries(summary("recent", TunnelStatus::Open))
+                .tunnel_summaries(summary("connected", TunnelStatus::Open))
+                .tunnel_summaries(summary("closed", TunnelStatus::Closed))
+                .build())
+        });
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .with(eq("stale"))
+        .returning(move |id| Ok(described(id, now, 30, ConnectionStatus::Disconnected)));
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .with(eq("recent"))
+        .returning(move |id| Ok(described(id, now, 2, ConnectionStatus::Disconnected)));
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .with(eq("connected"))
+        .returning(move |id| Ok(described(id, now, 30, ConnectionStatus::Connected)));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("stale"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+
+    let report = close_stale_tunnels(&mock_client, Duration::from_secs(24 * 3600), now)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report.closed,
+        [(String::from("stale"), Duration::from_secs(30 * 3600))]
+    );
+    assert_eq!(report.in_use, ["connected"]);
+    assert!(report.failed.is_empty());
+    let summary = report.to_string();
+    assert!(summary.contains("stale: closed after 30 hours"));
+    assert!(summary.ends_with("Closed 1 stale tunnels"));
+}
+
+#[tokio::test]
+async fn test_close_stale_tunnels_continues_past_failures() {
+    let now = SystemTime::now();
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_list_tunnels_page()
+        .times(1)
+        .returning(|_| {
+            Ok(ListTunnelsOutput::builder()
+                .tunnel_summaries(summary("tunnel-1", TunnelStatus::Open))
+                .tunnel_summaries(summary("tunnel-2", TunnelStatus::Open))
+                .build())
+        });
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .with(eq("tunnel-1"))
+        .returning(|_| {
+            Err(SdkError::dispatch_failure(ConnectorError::other(
+                "connection reset".into(),
+                None,
+            )))
+        });
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .with(eq("tunnel-2"))
+        .returning(move |id| Ok(described(id, now, 30, ConnectionStatus::Disconnected)));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-2"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+
+    let report = close_stale_tunnels(&mock_client, Duration::from_secs(3600), now)
+        .await
+        .unwrap();
+
+    assert_eq!(report.closed.len(), 1);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, "tunnel-1");
+    assert!(
+        report
+            .to_string()
+            .ends_with("Closed 1 stale tunnels, 1 failed")
+    );
+}
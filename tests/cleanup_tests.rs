@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tunnel_manager::cleanup::CleanupGuard;
+
+fn recorder() -> (Arc<Mutex<Vec<&'static str>>>, impl Fn(&'static str) + Clone) {
+    let steps = Arc::new(Mutex::new(Vec::new()));
+    let record = {
+        let steps = steps.clone();
+        move |step| steps.lock().unwrap().push(step)
+    };
+    (steps, record)
+}
+
+#[test]
+fn test_steps_run_in_reverse_when_dropped() {
+    let (steps, record) = recorder();
+    {
+        let mut guard = CleanupGuard::new();
+        let first = record.clone();
+        guard.defer(move || first("close tunnel"));
+        guard.defer(move || record("kill localproxy"));
+    }
+
+    assert_eq!(*steps.lock().unwrap(), ["kill localproxy", "close tunnel"]);
+}
+
+#[test]
+fn test_disarmed_guard_keeps_everything() {
+    let (steps, record) = recorder();
+    let mut guard = CleanupGuard::new();
+    guard.defer(move || record("close tunnel"));
+
+    guard.disarm();
+
+    assert!(steps.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_cancelled_work_spawns_async_steps() {
+    let (closed_tx, closed_rx) = oneshot::channel();
+    let work = async move {
+        let mut guard = CleanupGuard::new();
+        guard.defer_async(async move {
+            let _ = closed_tx.send("tunnel-123");
+        });
+        // Never finishes, so only cancelling it ends the work
+        std::future::pending::<()>().await;
+        guard.disarm();
+    };
+
+    assert!(
+        tokio::time::timeout(Duration::from_millis(10), work)
+            .await
+            .is_err()
+    );
+
+    let closed = tokio::time::timeout(Duration::from_secs(1), closed_rx)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(closed, "tunnel-123");
+}
+
+#[test]
+fn test_steps_run_when_work_panics() {
+    let (steps, record) = recorder();
+
+    let result = std::panic::catch_unwind(move || {
+        let mut guard = CleanupGuard::new();
+        guard.defer(move || record("close tunnel"));
+        panic!("connect failed halfway");
+    });
+
+    assert!(result.is_err());
+    assert_eq!(*steps.lock().unwrap(), ["close tunnel"]);
+}
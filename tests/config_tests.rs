@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tunnel_manager::config::{
+    AuthBehavior, DEFAULT_PROFILE, DEFAULT_TITLE, PROFILE_ENV, REGION_ENV, TunnelConfig,
+};
+use tunnel_manager::error::TunnelError;
+use tunnel_manager::localproxy::ServicePortMap;
+
+#[test]
+fn test_empty_config_uses_defaults() {
+    let config = TunnelConfig::from_toml("").unwrap();
+
+    assert_eq!(config.auth_behavior, AuthBehavior::Automatic);
+    assert_eq!(config.profile, DEFAULT_PROFILE);
+    assert_eq!(config.region, None);
+    assert_eq!(config.sso_login_timeout, Duration::from_secs(120));
+    assert_eq!(config.sso_login_cooldown, Duration::from_secs(600));
+    assert_eq!(config.session_check_interval, Duration::from_secs(300));
+    assert_eq!(config.destination_timeout, Duration::from_secs(60));
+    assert_eq!(config.services, ServicePortMap::default());
+    assert!(!config.discover_service_ports);
+    assert!(!config.strict_region);
+    assert!(!config.rotate_destination_on_reuse);
+    assert_eq!(config.rotate_retries, 2);
+    assert!(config.max_session_duration.is_zero());
+    assert!(!config.close_tunnel_on_expiry);
+    assert!(!config.close_tunnels_on_exit);
+    assert!(config.close_stale_tunnels_after.is_zero());
+    assert!(config.confirm_quit);
+    assert_eq!(config.poll_rate_limit, 5);
+    assert!(config.device_profiles.is_empty());
+    assert!(!config.control.enabled);
+    assert!(config.control.token.is_none());
+}
+
+#[test]
+fn test_env_overrides_profile_and_region() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        profile = "iotmgmt_dev"
+        region = "eu-central-1"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.region.as_deref(), Some("eu-central-1"));
+
+    let env = HashMap::from([(PROFILE_ENV, "customer_prod"), (REGION_ENV, "us-east-1")]);
+    let overridden = config
+        .clone()
+        .with_env_overrides(|name| env.get(name).map(|value| value.to_string()));
+    assert_eq!(overridden.profile, "customer_prod");
+    assert_eq!(overridden.region.as_deref(), Some("us-east-1"));
+
+    let untouched = config.with_env_overrides(|name| (name == PROFILE_ENV).then(String::new));
+    assert_eq!(untouched.profile, "iotmgmt_dev");
+    assert_eq!(untouched.region.as_deref(), Some("eu-central-1"));
+}
+
+#[test]
+fn test_device_profile_overrides_services() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        auth_behavior = "manual"
+        sso_login_timeout = 30
+
+        [device_profiles.G111070.services]
+        SSH = 2222
+        GORT = 5600
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.auth_behavior, AuthBehavior::Manual);
+    assert_eq!(config.sso_login_timeout, Duration::from_secs(30));
+
+    let services = config.device_services("G111070").unwrap();
+    assert_eq!(services.port("GORT"), Some(5600));
+    assert_eq!(services.to_localproxy_arg(), "SSH=2222,GORT=5600");
+
+    assert!(config.device_services("G111071").is_none());
+}
+
+#[test]
+fn test_invalid_config_returns_config_error() {
+    let result = TunnelConfig::from_toml("services = \"SSH\"");
+    assert!(matches!(result, Err(TunnelError::Config { .. })));
+}
+
+#[test]
+fn test_service_aliases_fall_back_to_the_code() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [service_aliases]
+        GORT = "Gardin Orchestrator"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.service_label("GORT"), "Gardin Orchestrator");
+    assert_eq!(config.service_label("SSH"), "SSH");
+    // The codes sent to AWS and localproxy are untouched
+    assert_eq!(config.services.to_localproxy_arg(), "SSH=2222,GORT=5555");
+}
+
+#[test]
+fn test_attribute_overrides_known_services_only() {
+    let attributes = HashMap::from([
+        ("tunnel_port_GORT".to_string(), "5601".to_string()),
+        ("tunnel_port_SSH".to_string(), "not-a-port".to_string()),
+        ("tunnel_port_HTTP".to_string(), "8080".to_string()),
+    ]);
+
+    let services = ServicePortMap::default().with_attribute_overrides(&attributes);
+
+    assert_eq!(services.port("GORT"), Some(5601));
+    assert_eq!(services.port("SSH"), Some(2222));
+    assert_eq!(services.port("HTTP"), None);
+}
+
+#[test]
+fn test_explicit_proxy_settings() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [proxy]
+        https_proxy = "http://proxy.corp:3128"
+        no_proxy = "localhost"
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        config.proxy.https_proxy().as_deref(),
+        Some("http://proxy.corp:3128")
+    );
+    assert_eq!(config.proxy.no_proxy().as_deref(), Some("localhost"));
+}
+
+#[test]
+fn test_ca_bundle_must_be_readable_pem() {
+    let dir = std::env::temp_dir();
+    let missing = dir.join(format!(
+        "tunnel-manager-missing-ca-{}.pem",
+        std::process::id()
+    ));
+    let not_pem = dir.join(format!(
+        "tunnel-manager-not-pem-ca-{}.pem",
+        std::process::id()
+    ));
+    std::fs::write(&not_pem, "not a certificate").unwrap();
+
+    for (path, expected) in [
+        (&missing, "Can't read the CA bundle"),
+        (&not_pem, "has no certificates in it"),
+    ] {
+        let config = TunnelConfig::from_toml(&format!(
+            "[proxy]\nca_bundle = {:?}\n",
+            path.display().to_string()
+        ))
+        .unwrap();
+        let error = config.validate().unwrap_err();
+        assert!(error.to_string().contains(expected), "{}", error);
+    }
+    let _ = std::fs::remove_file(&not_pem);
+}
+
+#[test]
+fn test_default_device_id_validation() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        default_device_id = "G111070"
+        auto_connect = true
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.default_device_id.as_deref(), Some("G111070"));
+    assert!(config.auto_connect);
+    assert!(config.validate().is_ok());
+
+    for typo in ["G111 070", "G11107", "g111070"] {
+        let config = TunnelConfig::from_toml(&format!("default_device_id = \"{}\"", typo)).unwrap();
+        assert!(
+            matches!(config.validate(), Err(TunnelError::Config { .. })),
+            "{}",
+            typo
+        );
+    }
+}
+
+#[test]
+fn test_poll_rate_limit_must_allow_calls() {
+    let config = TunnelConfig::from_toml("poll_rate_limit = 0").unwrap();
+    assert!(matches!(config.validate(), Err(TunnelError::Config { .. })));
+}
+
+#[test]
+fn test_branding_overrides() {
+    let config = TunnelConfig::default();
+    assert_eq!(config.branding.title, DEFAULT_TITLE);
+    assert!(config.branding.icon.is_none());
+    assert!(config.branding.logo.is_none());
+
+    let config = TunnelConfig::from_toml(
+        r#"
+        [branding]
+        title = "Acme Tunnels"
+        logo = "/opt/acme/logo.svg"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.branding.title, "Acme Tunnels");
+    assert!(config.branding.icon.is_none());
+    assert_eq!(
+        config.branding.logo.as_deref(),
+        Some(std::path::Path::new("/opt/acme/logo.svg"))
+    );
+}
+
+#[test]
+fn test_session_limit() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        max_session_duration = 3600
+        session_expiry_warning = 120
+        close_tunnel_on_expiry = true
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.max_session_duration, Duration::from_secs(3600));
+    assert_eq!(config.session_expiry_warning, Duration::from_secs(120));
+    assert!(config.close_tunnel_on_expiry);
+}
+
+#[test]
+fn test_environment_replaces_only_its_settings() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        profile = "iotmgmt_prod"
+        extra_localproxy_args = ["-v", "5"]
+
+        [services]
+        SSH = 5555
+
+        [environments.staging]
+        profile = "iotmgmt_dev"
+
+        [environments.staging.services]
+        SSH = 6666
+        GORT = 5600
+
+        [environments.prod]
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.environment_names(), ["prod", "staging"]);
+
+    let staging = config.with_environment("staging").unwrap();
+    assert_eq!(staging.environment.as_deref(), Some("staging"));
+    assert_eq!(staging.profile, "iotmgmt_dev");
+    assert_eq!(staging.services.to_localproxy_arg(), "SSH=6666,GORT=5600");
+    assert_eq!(staging.extra_localproxy_args, ["-v", "5"]);
+
+    // Switching starts from the loaded config, so staging's settings don't carry over
+    let prod = config.with_environment("prod").unwrap();
+    assert_eq!(prod.profile, "iotmgmt_prod");
+    assert_eq!(prod.services.to_localproxy_arg(), "SSH=5555");
+}
+
+#[test]
+fn test_env_overrides_beat_an_environment() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [environments.staging]
+        profile = "iotmgmt_dev"
+        region = "eu-central-1"
+        "#,
+    )
+    .unwrap();
+
+    let staging = config.with_environment("staging").unwrap();
+    assert_eq!(staging.profile, "iotmgmt_dev");
+    assert_eq!(staging.region.as_deref(), Some("eu-central-1"));
+
+    let env = HashMap::from([(PROFILE_ENV, "customer_prod"), (REGION_ENV, "us-east-1")]);
+    let overridden =
+        staging.with_env_overrides(|name| env.get(name).map(|value| value.to_string()));
+    assert_eq!(overridden.profile, "customer_prod");
+    assert_eq!(overridden.region.as_deref(), Some("us-east-1"));
+    assert_eq!(overridden.environment.as_deref(), Some("staging"));
+}
+
+#[test]
+fn test_unknown_environment_is_rejected() {
+    let config = TunnelConfig::from_toml(r#"environment = "qa""#).unwrap();
+
+    assert!(matches!(config.validate(), Err(TunnelError::Config { .. })));
+    assert!(matches!(
+        config.with_environment("qa"),
+        Err(TunnelError::Config { .. })
+    ));
+}
+
+#[test]
+fn test_thing_name_mapping() {
+    assert_eq!(TunnelConfig::default().thing_name("G111070"), "G111070");
+
+    let config = TunnelConfig::from_toml(
+        r#"
+        thing_name_format = "acme-{device_id}-gw"
+
+        [device_profiles.G111071]
+        thing_name = "legacy-gateway"
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.thing_name("G111070"), "acme-G111070-gw");
+    assert_eq!(config.thing_name("G111071"), "legacy-gateway");
+
+    let config = TunnelConfig::from_toml(r#"thing_name_format = "acme-gateway""#).unwrap();
+    assert!(matches!(config.validate(), Err(TunnelError::Config { .. })));
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "control")]
+
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::control::ControlServer;
+use tunnel_manager::manager::ConnectionManager;
+
+const TOKEN: &str = "secret-token";
+
+async fn start_server() -> SocketAddr {
+    let mut config = TunnelConfig::default();
+    config.control.port = 0;
+    config.control.token = Some(TOKEN.to_string());
+
+    let server = ControlServer::bind(config, ConnectionManager::new())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    addr
+}
+
+async fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_control_requires_token() {
+    let config = TunnelConfig::default();
+    assert!(
+        ControlServer::bind(config, ConnectionManager::new())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_control_rejects_bad_token() {
+    let addr = start_server().await;
+
+    let missing = send(addr, "GET /status HTTP/1.1\r\n\r\n").await;
+    assert!(missing.starts_with("HTTP/1.1 401"));
+
+    let wrong = send(
+        addr,
+        "GET /status HTTP/1.1\r\nAuthorization: Bearer wrong-token\r\n\r\n",
+    )
+    .await;
+    assert!(wrong.starts_with("HTTP/1.1 401"));
+}
+
+#[tokio::test]
+async fn test_control_status_and_routes() {
+    let addr = start_server().await;
+    let auth = format!("Authorization: Bearer {}\r\n", TOKEN);
+
+    let status = send(addr, &format!("GET /status HTTP/1.1\r\n{}\r\n", auth)).await;
+    assert!(status.starts_with("HTTP/1.1 200"));
+    assert!(status.ends_with("\r\n\r\n[]"));
+
+    let disconnect = send(
+        addr,
+        &format!("POST /disconnect/G111070 HTTP/1.1\r\n{}\r\n", auth),
+    )
+    .await;
+    assert!(disconnect.starts_with("HTTP/1.1 404"));
+
+    let wrong_method = send(
+        addr,
+        &format!("GET /connect/G111070 HTTP/1.1\r\n{}\r\n", auth),
+    )
+    .await;
+    assert!(wrong_method.starts_with("HTTP/1.1 405"));
+
+    let unknown = send(addr, &format!("GET /tunnels HTTP/1.1\r\n{}\r\n", auth)).await;
+    assert!(unknown.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn test_control_rejects_overlong_lines() {
+    let addr = start_server().await;
+
+    // Exactly the line limit, with no end of line in sight
+    let request = format!("GET /{}", "a".repeat(8 * 1024 - 5));
+    let response = send(addr, &request).await;
+
+    assert!(response.starts_with("HTTP/1.1 400"));
+}
@@ -0,0 +1,91 @@
+use proptest::prelude::*;
+use tunnel_manager::device::validate_device_id;
+use tunnel_manager::error::TunnelError;
+
+#[test]
+fn test_valid_device_ids() {
+    for device_id in ["G111070", "G000000", "G999999"] {
+        assert!(validate_device_id(device_id).is_ok(), "{}", device_id);
+    }
+}
+
+#[test]
+fn test_invalid_device_ids() {
+    let too_long = "G".repeat(129);
+    for device_id in [
+        "",
+        "G",
+        "G11107",
+        "G1110700",
+        "g111070",
+        "G111 070",
+        "G111070/",
+        "G11107é",
+        "G１１１０７０",
+        "gateway_01",
+        "site-3:G111070",
+        too_long.as_str(),
+    ] {
+        assert!(
+            matches!(
+                validate_device_id(device_id),
+                Err(TunnelError::InvalidDeviceId { .. })
+            ),
+            "{}",
+            device_id
+        );
+    }
+}
+
+/// Whether `device_id` is `G` followed by exactly six ASCII digits
+fn is_device_id(device_id: &str) -> bool {
+    let bytes = device_id.as_bytes();
+    bytes.len() == 7 && bytes[0] == b'G' && bytes[1..].iter().all(u8::is_ascii_digit)
+}
+
+proptest! {
+    #[test]
+    fn prop_gardin_device_ids_accepted(device_id in "G[0-9]{6}") {
+        prop_assert!(validate_device_id(&device_id).is_ok());
+    }
+
+    #[test]
+    fn prop_wrong_digit_counts_rejected(digits in "[0-9]{0,5}|[0-9]{7,20}") {
+        let device_id = format!("G{}", digits);
+        prop_assert!(validate_device_id(&device_id).is_err());
+    }
+
+    #[test]
+    fn prop_other_prefixes_rejected(
+        prefix in any::<char>().prop_filter("not a G", |c| *c != 'G'),
+        digits in "[0-9]{6}",
+    ) {
+        let device_id = format!("{}{}", prefix, digits);
+        prop_assert!(validate_device_id(&device_id).is_err());
+    }
+
+    #[test]
+    fn prop_other_characters_rejected(
+        digits in "[0-9]{6}",
+        position in 0usize..6,
+        invalid in any::<char>().prop_filter("not an ASCII digit", |c| !c.is_ascii_digit()),
+    ) {
+        let mut digits: Vec<char> = digits.chars().collect();
+        digits[position] = invalid;
+        let device_id: String = std::iter::once('G').chain(digits).collect();
+        let rejected = matches!(
+            validate_device_id(&device_id),
+            Err(TunnelError::InvalidDeviceId { .. })
+        );
+        prop_assert!(rejected);
+    }
+
+    #[test]
+    fn prop_arbitrary_input_never_panics(
+        chars in proptest::collection::vec(any::<char>(), 0..12_000)
+    ) {
+        let device_id: String = chars.into_iter().collect();
+        let accepted = validate_device_id(&device_id).is_ok();
+        prop_assert_eq!(accepted, is_device_id(&device_id));
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::Path;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use aws_sdk_iotsecuretunneling::operation::close_tunnel::CloseTunnelOutput;
+#[cfg(unix)]
+use aws_sdk_iotsecuretunneling::operation::describe_tunnel::DescribeTunnelOutput;
+#[cfg(unix)]
+use aws_sdk_iotsecuretunneling::types::{ConnectionState, ConnectionStatus, Tunnel};
+#[cfg(unix)]
+use mockall::predicate::eq;
+#[cfg(unix)]
+use tokio::process::Command;
+#[cfg(unix)]
+use tunnel_manager::aws::{ConnectAction, TunnelConnection};
+#[cfg(unix)]
+use tunnel_manager::aws_client::test_utils::MockTunnelClient;
+#[cfg(unix)]
+use tunnel_manager::cleanup::CleanupGuard;
+#[cfg(unix)]
+use tunnel_manager::diagnostics::run_connection_test;
+use tunnel_manager::diagnostics::{CheckResult, check_assets_dir, check_region};
+#[cfg(unix)]
+use tunnel_manager::localproxy::{OutputTail, Readiness, ServicePortMap};
+
+#[test]
+fn test_assets_dir_check() {
+    let dir = std::env::temp_dir();
+    assert!(check_assets_dir(&dir).passed);
+
+    let missing = check_assets_dir(Path::new("definitely/not/here"));
+    assert!(!missing.passed);
+    assert!(missing.hint.is_some());
+}
+
+#[test]
+fn test_region_check() {
+    let mut overrides = HashMap::new();
+    assert!(check_region(Some("eu-west-1"), &overrides).passed);
+    assert!(!check_region(Some("eu-south-1"), &overrides).passed);
+    assert!(!check_region(None, &overrides).passed);
+
+    overrides.insert("eu-south-1".to_string(), "eu-south-1".to_string());
+    assert!(check_region(Some("eu-south-1"), &overrides).passed);
+}
+
+#[test]
+fn test_check_result_display() {
+    let passed = CheckResult::pass("Region", "eu-west-1");
+    assert_eq!(passed.to_string(), "[PASS] Region: eu-west-1");
+
+    let failed = CheckResult::fail("localproxy", "localproxy was not found", "Install it.");
+    assert_eq!(
+        failed.to_string(),
+        "[FAIL] localproxy: localproxy was not found\n       Install it."
+    );
+}
+
+/// A connection whose localproxy is a long sleep, as if the connect had just finished
+#[cfg(unix)]
+fn sleeping_connection(action: ConnectAction) -> TunnelConnection {
+    TunnelConnection {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+        region: "eu-west-1".to_string(),
+        action,
+        child: Command::new("sleep").arg("60").spawn().unwrap(),
+        services: ServicePortMap::default(),
+        readiness: Readiness::Ready,
+        pid_file: None,
+        started_at: Instant::now(),
+        warnings: Vec::new(),
+        output: OutputTail::default(),
+        credentials_refreshed: false,
+        expires_at: None,
+        tunnel_expires_at: None,
+        test_destination: None,
+        cleanup: CleanupGuard::new(),
+    }
+}
+
+#[cfg(unix)]
+fn describe_destination(status: ConnectionStatus) -> DescribeTunnelOutput {
+    DescribeTunnelOutput::builder()
+        .tunnel(
+            Tunnel::builder()
+                .tunnel_id("tunnel-123")
+                .destination_connection_state(ConnectionState::builder().status(status).build())
+                .build(),
+        )
+        .build()
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connection_test_closes_the_tunnel_it_opened() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .returning(|_| Ok(describe_destination(ConnectionStatus::Connected)));
+    mock_client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-123"))
+        .times(1)
+        .returning(|_| Ok(CloseTunnelOutput::builder().build()));
+
+    let test = run_connection_test(
+        &mock_client,
+        sleeping_connection(ConnectAction::OpenedNew),
+        Duration::from_secs(2),
+        Duration::from_secs(5),
+        Duration::from_millis(10),
+    )
+    .await
+    .unwrap();
+
+    assert!(test.passed());
+    assert!(test.destination_time.is_some());
+    assert!(test.checks().iter().all(|check| check.passed));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connection_test_reports_offline_device_and_keeps_reused_tunnel() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .returning(|_| Ok(describe_destination(ConnectionStatus::Disconnected)));
+    mock_client.expect_close_tunnel_by_id().never();
+
+    let test = run_connection_test(
+        &mock_client,
+        sleeping_connection(ConnectAction::ReusedExisting),
+        Duration::from_secs(2),
+        Duration::from_millis(100),
+        Duration::from_millis(10),
+    )
+    .await
+    .unwrap();
+
+    assert!(!test.passed());
+    assert_eq!(test.destination_time, None);
+    let checks = test.checks();
+    assert!(checks[0].passed);
+    assert!(!checks[1].passed);
+    assert!(checks[2].passed);
+    assert!(checks[2].detail.contains("left the reused tunnel open"));
+}
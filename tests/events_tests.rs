@@ -0,0 +1,67 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use tunnel_manager::events::{EventBus, TunnelEvent, to_ndjson, write_events};
+use tunnel_manager::localproxy::ServicePortMap;
+
+#[tokio::test]
+async fn test_subscribers_receive_published_events() {
+    let bus = EventBus::new();
+    // Publishing without subscribers is not an error
+    bus.publish(TunnelEvent::ConnectStarted {
+        device_id: "G111070".to_string(),
+    });
+
+    let mut first = bus.subscribe();
+    let mut second = bus.subscribe();
+    let event = TunnelEvent::Disconnected {
+        device_id: "G111070".to_string(),
+        reason: "requested".to_string(),
+    };
+    bus.publish(event.clone());
+
+    assert_eq!(first.recv().await.unwrap(), event);
+    assert_eq!(second.recv().await.unwrap(), event);
+}
+
+#[test]
+fn test_events_serialize_as_ndjson() {
+    let event = TunnelEvent::ProxyStarted {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+        pid: Some(4242),
+        services: ServicePortMap::default(),
+    };
+    let line = to_ndjson(&event, UNIX_EPOCH + Duration::from_millis(1500)).unwrap();
+
+    assert_eq!(
+        line,
+        concat!(
+            r#"{"timestamp":1500,"event":"proxy_started","device_id":"G111070","#,
+            r#""tunnel_id":"tunnel-123","pid":4242,"services":{"SSH":2222,"GORT":5555}}"#,
+            "\n"
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_write_events_until_bus_is_dropped() {
+    let bus = EventBus::new();
+    let events = bus.subscribe();
+    bus.publish(TunnelEvent::ConnectStarted {
+        device_id: "G111070".to_string(),
+    });
+    bus.publish(TunnelEvent::Error {
+        device_id: "G111070".to_string(),
+        message: "Tunnel not found".to_string(),
+    });
+    drop(bus);
+
+    let mut output = Vec::new();
+    write_events(events, &mut output).await.unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(r#""event":"connect_started""#));
+    assert!(lines[1].contains(r#""message":"Tunnel not found""#));
+}
@@ -0,0 +1,90 @@
+#![cfg(unix)]
+
+use std::path::PathBuf;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::headless::{EXIT_INTERRUPTED, connect_until_interrupted};
+use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+use tunnel_manager::manager::ConnectionManager;
+use tunnel_manager::token_file::TokenFile;
+
+fn token_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-headless-{}-{}",
+            name,
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    path
+}
+
+fn config_running(script: &str) -> TunnelConfig {
+    TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![String::from("-c"), script.to_string()],
+        }),
+        ..TunnelConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_interrupt_stops_localproxy_and_exits() {
+    let path = token_file("interrupt");
+    let config = config_running("echo Listening for new connection; sleep 30");
+    let manager = ConnectionManager::new();
+    let (interrupted, on_interrupt) = tokio::sync::oneshot::channel::<()>();
+
+    let watcher = manager.clone();
+    let interrupt = async move {
+        // Interrupt once the connection is up, as an operator pressing Ctrl-C would
+        while watcher.processes().await.is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        interrupted.send(()).unwrap();
+    };
+    let code = tokio::join!(
+        connect_until_interrupted(
+            &manager,
+            &config,
+            manager.connect_from_token_file(&path, &config),
+            async {
+                on_interrupt.await.unwrap();
+            },
+        ),
+        interrupt,
+    )
+    .0;
+
+    assert_eq!(code, EXIT_INTERRUPTED);
+    assert!(manager.processes().await.is_empty());
+    assert!(!manager.is_active("G111070").await);
+}
+
+#[tokio::test]
+async fn test_localproxy_exiting_ends_the_connection_with_a_failure() {
+    let path = token_file("exited");
+    let config = config_running("echo Listening for new connection; sleep 1; exit 3");
+    let manager = ConnectionManager::new();
+
+    let code = connect_until_interrupted(
+        &manager,
+        &config,
+        manager.connect_from_token_file(&path, &config),
+        std::future::pending(),
+    )
+    .await;
+
+    assert_eq!(code, 1);
+    assert!(!manager.is_active("G111070").await);
+}
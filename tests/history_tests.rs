@@ -0,0 +1,48 @@
+use std::fs;
+
+use tunnel_manager::history::ConnectionHistory;
+use tunnel_manager::state::LayoutMode;
+
+#[test]
+fn test_history_records_most_recent_first() {
+    let mut history = ConnectionHistory::default();
+    assert_eq!(history.last(), None);
+
+    history.record("G111070");
+    history.record("G111071");
+    history.record("G111070");
+    assert_eq!(history.last(), Some("G111070"));
+    assert_eq!(history.recent(), ["G111070", "G111071"]);
+
+    for i in 0..20 {
+        history.record(&format!("G2000{:02}", i));
+    }
+    assert_eq!(history.recent().len(), 10);
+    assert_eq!(history.last(), Some("G200019"));
+}
+
+#[test]
+fn test_history_round_trips_through_file() {
+    let dir = std::env::temp_dir().join(format!("tunnel-manager-history-{}", std::process::id()));
+    let path = dir.join("history.toml");
+    let _ = fs::remove_dir_all(&dir);
+
+    assert_eq!(
+        ConnectionHistory::load_from(&path).unwrap(),
+        ConnectionHistory::default()
+    );
+
+    let mut history = ConnectionHistory::default();
+    history.record("G111070");
+    history.set_profile("staging");
+    history.set_environment("prod");
+    history.set_layout(LayoutMode::Compact);
+    history.save_to(&path).unwrap();
+    let loaded = ConnectionHistory::load_from(&path).unwrap();
+    assert_eq!(loaded, history);
+    assert_eq!(loaded.profile(), Some("staging"));
+    assert_eq!(loaded.environment(), Some("prod"));
+    assert_eq!(loaded.layout(), LayoutMode::Compact);
+
+    let _ = fs::remove_dir_all(&dir);
+}
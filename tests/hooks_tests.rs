@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use tunnel_manager::aws::ConnectAction;
+use tunnel_manager::config::{HookCommand, TunnelConfig};
+use tunnel_manager::error::TunnelError;
+use tunnel_manager::hooks::{hook_env, run_hook};
+use tunnel_manager::localproxy::ServicePortMap;
+use tunnel_manager::manager::ConnectionSummary;
+
+fn connection() -> ConnectionSummary {
+    ConnectionSummary {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+        region: "eu-west-1".to_string(),
+        pid: None,
+        services: ServicePortMap::new()
+            .with_service("SSH", 2222)
+            .with_service("web-ui", 8080),
+        failed_services: Vec::new(),
+        ready: true,
+        auth_required: false,
+        waiting_for_device: false,
+        warnings: Vec::new(),
+        credentials_refreshed: false,
+        expires_in_secs: None,
+        tunnel_expires_in_secs: None,
+        action: ConnectAction::OpenedNew,
+    }
+}
+
+fn shell(script: &str) -> HookCommand {
+    HookCommand {
+        program: "sh".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+    }
+}
+
+#[test]
+fn test_hook_env_describes_the_connection() {
+    let env = hook_env("post_connect", &connection());
+    let get = |name: &str| {
+        env.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    assert_eq!(get("TUNNEL_MANAGER_HOOK"), Some("post_connect"));
+    assert_eq!(get("TUNNEL_MANAGER_DEVICE_ID"), Some("G111070"));
+    assert_eq!(get("TUNNEL_MANAGER_TUNNEL_ID"), Some("tunnel-123"));
+    assert_eq!(get("TUNNEL_MANAGER_REGION"), Some("eu-west-1"));
+    assert_eq!(get("TUNNEL_MANAGER_PORT_SSH"), Some("2222"));
+    // Service names are made safe for a variable name
+    assert_eq!(get("TUNNEL_MANAGER_PORT_WEB_UI"), Some("8080"));
+    assert!(get("TUNNEL_MANAGER_SERVICES").unwrap().contains("SSH=2222"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_hook_reports_failures_without_panicking() {
+    let timeout = Duration::from_secs(5);
+    let passes = shell(
+        r#"test "$TUNNEL_MANAGER_DEVICE_ID" = G111070 && test "$TUNNEL_MANAGER_PORT_SSH" = 2222"#,
+    );
+    run_hook("post_connect", &passes, &connection(), timeout)
+        .await
+        .unwrap();
+
+    let error = run_hook("pre_disconnect", &shell("exit 3"), &connection(), timeout)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, TunnelError::ProcessExecution { .. }));
+    assert!(error.to_string().contains("The pre_disconnect hook failed"));
+
+    let missing = HookCommand {
+        program: "/nonexistent/hook".to_string(),
+        args: Vec::new(),
+    };
+    let error = run_hook("post_connect", &missing, &connection(), timeout)
+        .await
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Failed to run the post_connect hook")
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_run_hook_stops_a_hook_that_runs_too_long() {
+    let started = std::time::Instant::now();
+    let error = run_hook(
+        "post_connect",
+        &shell("sleep 10"),
+        &connection(),
+        Duration::from_millis(200),
+    )
+    .await
+    .unwrap_err();
+
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(error.to_string().contains("didn't finish within"));
+}
+
+#[test]
+fn test_hooks_config() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [hooks]
+        timeout = 10
+        post_connect = { program = "open-ssh.sh", args = ["--new-window"] }
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.hooks.timeout, Duration::from_secs(10));
+    assert_eq!(
+        config.hooks.post_connect.as_ref().unwrap().args,
+        ["--new-window"]
+    );
+    assert!(config.hooks.pre_disconnect.is_none());
+    config.validate().unwrap();
+
+    let config = TunnelConfig::from_toml(
+        r#"
+        [hooks.pre_disconnect]
+        program = " "
+        "#,
+    )
+    .unwrap();
+    assert!(
+        config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("hooks.pre_disconnect needs a program")
+    );
+}
@@ -11,10 +11,10 @@ mod tests {
         let mut mock_client = MockTunnelClient::new();
 
         mock_client
-            .expect_list_tunnels_for_thing()
-            .with(eq("test-device"))
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("test-device"), eq(None))
             .times(1)
-            .returning(|_thing_name| {
+            .returning(|_thing_name, _| {
                 Ok(ListTunnelsOutput::builder()
                     .tunnel_summaries(
                         aws_sdk_iotsecuretunneling::types::TunnelSummary::builder()
@@ -25,7 +25,9 @@ mod tests {
                     .build())
             });
 
-        let result = mock_client.list_tunnels_for_thing("test-device").await;
+        let result = mock_client
+            .list_tunnels_for_thing_page("test-device", None)
+            .await;
         assert!(result.is_ok());
         let output = result.unwrap();
         assert!(output.tunnel_summaries.is_some());
@@ -40,14 +42,179 @@ mod tests {
         let mut mock_client = MockTunnelClient::new();
 
         mock_client
-            .expect_list_tunnels_for_thing()
-            .with(eq("empty-device"))
+            .expect_list_tunnels_for_thing_page()
+            .with(eq("empty-device"), eq(None))
             .times(1)
-            .returning(|_thing_name| Ok(ListTunnelsOutput::builder().build()));
+            .returning(|_thing_name, _| Ok(ListTunnelsOutput::builder().build()));
 
-        let result = mock_client.list_tunnels_for_thing("empty-device").await;
+        let result = mock_client
+            .list_tunnels_for_thing_page("empty-device", None)
+            .await;
         assert!(result.is_ok());
         let output = result.unwrap();
         assert!(output.tunnel_summaries.is_none() || output.tunnel_summaries.unwrap().is_empty());
     }
 }
+
+/// Whole flows against `FakeTunnelClient`, which keeps tunnel state between calls
+#[cfg(test)]
+mod fake_client_flows {
+    use aws_sdk_iotsecuretunneling::types::{ClientMode, ConnectionStatus, TunnelStatus};
+    use tunnel_manager::aws::{
+        ConnectAction, close_all_tunnels_for_device, open_only_with_client, open_tunnel_for_device,
+        reopen_tunnel_with_client, tunnel_expiry_with_client,
+    };
+    use tunnel_manager::aws_client::test_utils::FakeTunnelClient;
+    use tunnel_manager::batch::close_tunnels_for_devices;
+    use tunnel_manager::config::{SharedTunnelPolicy, TunnelConfig};
+    use tunnel_manager::error::TunnelError;
+    use tunnel_manager::localproxy::ServicePortMap;
+
+    #[tokio::test]
+    async fn test_connect_reuse_and_replace_a_tunnel() {
+        let client = FakeTunnelClient::new();
+        let services = ServicePortMap::default();
+        let config = TunnelConfig::default();
+
+        let opened = open_tunnel_for_device(&client, "G111070", &services, &config)
+            .await
+            .unwrap();
+        assert_eq!(opened.action, ConnectAction::OpenedNew);
+        assert_eq!(
+            client.open_tunnel_ids("G111070"),
+            [opened.tunnel_id.clone()]
+        );
+        let (_, destination_token) = client.tokens(&opened.tunnel_id).unwrap();
+        assert_eq!(opened.dst_token.as_ref(), Some(&destination_token));
+
+        // Connecting again reuses the tunnel with a new source token only
+        let reused = open_tunnel_for_device(&client, "G111070", &services, &config)
+            .await
+            .unwrap();
+        assert_eq!(reused.action, ConnectAction::ReusedExisting);
+        assert_eq!(reused.tunnel_id, opened.tunnel_id);
+        assert_ne!(reused.src_token, opened.src_token);
+        assert_eq!(reused.dst_token, None);
+        assert_eq!(
+            client.tokens(&opened.tunnel_id).unwrap(),
+            (reused.src_token.clone(), destination_token)
+        );
+
+        // Someone else on the source end stops a plain reuse
+        client.set_connection(
+            &opened.tunnel_id,
+            ClientMode::Source,
+            ConnectionStatus::Connected,
+        );
+        let error = open_tunnel_for_device(&client, "G111070", &services, &config)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, TunnelError::TunnelInUse { .. }));
+
+        let force_new = TunnelConfig {
+            shared_tunnel: SharedTunnelPolicy::ForceNew,
+            ..TunnelConfig::default()
+        };
+        let another = open_tunnel_for_device(&client, "G111070", &services, &force_new)
+            .await
+            .unwrap();
+        assert_eq!(another.action, ConnectAction::OpenedNew);
+        assert_ne!(another.tunnel_id, opened.tunnel_id);
+        // The busy tunnel is left open for whoever is using it
+        assert_eq!(
+            client.open_tunnel_ids("G111070"),
+            [opened.tunnel_id.clone(), another.tunnel_id]
+        );
+        assert_eq!(
+            client.tunnel(&opened.tunnel_id).unwrap().status(),
+            Some(&TunnelStatus::Open)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_only_rotates_both_tokens_of_an_open_tunnel() {
+        let client = FakeTunnelClient::new();
+        let tunnel_id = client.add_tunnel("G111070", TunnelStatus::Open);
+        client.set_connection(&tunnel_id, ClientMode::All, ConnectionStatus::Connected);
+
+        let tokens = open_only_with_client(
+            &client,
+            "G111070",
+            30,
+            &ServicePortMap::default(),
+            String::from("test"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tokens.tunnel_id, tunnel_id);
+        let (source, destination) = client.tokens(&tunnel_id).unwrap();
+        assert_eq!(tokens.source_token, source);
+        assert_eq!(tokens.destination_token, Some(destination));
+        // Both ends were dropped and have to connect again with the new tokens
+        let tunnel = client.tunnel(&tunnel_id).unwrap();
+        for state in [
+            tunnel.source_connection_state(),
+            tunnel.destination_connection_state(),
+        ] {
+            assert_eq!(
+                state.and_then(|state| state.status()),
+                Some(&ConnectionStatus::Disconnected)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen_opens_a_new_tunnel_and_leaves_the_old_one_open() {
+        let client = FakeTunnelClient::new();
+        let services = ServicePortMap::default();
+        let config = TunnelConfig::default();
+        let lifetime = std::time::Duration::from_secs(30 * 60);
+        let opened = open_only_with_client(&client, "G111070", 30, &services, String::from("test"))
+            .await
+            .unwrap();
+        let expiry = tunnel_expiry_with_client(&client, &opened.tunnel_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let remaining = expiry.duration_since(std::time::SystemTime::now()).unwrap();
+        assert!(remaining <= lifetime && remaining > lifetime - std::time::Duration::from_secs(60));
+
+        let reopened =
+            reopen_tunnel_with_client(&client, "G111070", &opened.tunnel_id, &services, &config)
+                .await
+                .unwrap();
+
+        assert_ne!(reopened.tunnel_id, opened.tunnel_id);
+        // The old tunnel stays open until localproxy has moved off it
+        let open = client.open_tunnel_ids("G111070");
+        assert_eq!(open.len(), 2);
+        assert!(open.contains(&opened.tunnel_id) && open.contains(&reopened.tunnel_id));
+        let (source, destination) = client.tokens(&reopened.tunnel_id).unwrap();
+        assert_eq!(reopened.source_token, source);
+        assert_eq!(reopened.destination_token, Some(destination));
+    }
+
+    #[tokio::test]
+    async fn test_close_tunnels_across_devices() {
+        let client = FakeTunnelClient::new();
+        let config = TunnelConfig::default();
+        client.add_tunnel("G111070", TunnelStatus::Open);
+        client.add_tunnel("G111070", TunnelStatus::Closed);
+        client.add_tunnel("G222080", TunnelStatus::Open);
+        let devices = ["G111070", "G222080"].map(String::from);
+
+        let report = close_tunnels_for_devices(&client, &devices, &config).await;
+        assert!(report.is_success());
+        assert_eq!(report.tunnels_closed(), 2);
+        assert!(client.open_tunnel_ids("G111070").is_empty());
+        assert!(client.open_tunnel_ids("G222080").is_empty());
+
+        // Nothing is left open, so a second pass closes nothing
+        let result = close_all_tunnels_for_device(&client, "G111070", &config)
+            .await
+            .unwrap();
+        assert!(result.closed.is_empty());
+        assert!(result.failed.is_empty());
+    }
+}
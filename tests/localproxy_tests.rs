@@ -0,0 +1,696 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+#[cfg(unix)]
+use std::process::{ExitStatus, Stdio};
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+use tokio::process::Command;
+use tracing::Level;
+use tunnel_manager::aws::build_destination_config;
+use tunnel_manager::config::{ProxySettings, TunnelConfig};
+use tunnel_manager::error::TunnelError;
+use tunnel_manager::localproxy::{
+    CommandTemplate, LocalproxyVersion, MIN_LOCALPROXY_VERSION, OutputTail, ServicePortMap,
+    ServiceStatus, TOKEN_ENV, apply_ca_bundle, apply_extra_args, apply_proxy_env,
+    build_destination_command, build_localproxy_command, build_templated_command,
+    destination_targets, is_ready_line, line_level, resolve_localproxy_region, service_line_status,
+};
+#[cfg(unix)]
+use tunnel_manager::localproxy::{Readiness, exit_error, wait_for_ready};
+
+#[test]
+fn test_localproxy_command_argv() {
+    let services = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("GORT", 5555);
+
+    let command = build_localproxy_command("eu-west-1", &services, "source-token");
+    let command = command.as_std();
+
+    assert_eq!(command.get_program(), "localproxy");
+    let args: Vec<&OsStr> = command.get_args().collect();
+    assert_eq!(
+        args,
+        [
+            "-r",
+            "eu-west-1",
+            "-s",
+            "SSH=2222,GORT=5555",
+            "-b",
+            "0.0.0.0"
+        ]
+    );
+}
+
+#[test]
+fn test_destination_command_argv() {
+    let services = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("GORT", 5555);
+    let targets = BTreeMap::from([
+        ("GORT".to_string(), "localhost:5555".to_string()),
+        ("SSH".to_string(), "localhost:22".to_string()),
+    ]);
+
+    let targets = destination_targets(&services, &targets).unwrap();
+    let command = build_destination_command("eu-west-1", &targets, "destination-token");
+    assert_eq!(command.token().expose(), "destination-token");
+    let command = command.as_std();
+
+    let args: Vec<&OsStr> = command.get_args().collect();
+    assert_eq!(
+        args,
+        [
+            "-r",
+            "eu-west-1",
+            "-d",
+            "SSH=localhost:22,GORT=localhost:5555"
+        ]
+    );
+    assert_eq!(command.get_envs().count(), 0);
+}
+
+#[test]
+fn test_destination_targets_needs_every_service() {
+    let targets = BTreeMap::from([("SSH".to_string(), "localhost:22".to_string())]);
+
+    let error = destination_targets(&ServicePortMap::default(), &targets).unwrap_err();
+
+    assert!(
+        error.to_string().contains("no target for GORT"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_localproxy_command_token_env() {
+    let command = build_localproxy_command("eu-west-1", &ServicePortMap::default(), "source-token");
+
+    // The token only joins the environment when localproxy is spawned
+    assert_eq!(command.as_std().get_envs().count(), 0);
+    assert_eq!(command.token().expose(), "source-token");
+    assert_eq!(TOKEN_ENV, "AWSIOT_TUNNEL_ACCESS_TOKEN");
+}
+
+#[test]
+fn test_localproxy_command_never_prints_the_token() {
+    let proxy = ProxySettings {
+        https_proxy: Some("http://proxy.corp:3128".to_string()),
+        ..ProxySettings::default()
+    };
+    let mut command =
+        build_localproxy_command("eu-west-1", &ServicePortMap::default(), "source-token");
+    apply_proxy_env(&mut command, &proxy);
+
+    let debug = format!("{:?}", command);
+    let display = command.to_string();
+
+    assert!(!debug.contains("source-token"), "{}", debug);
+    assert!(!display.contains("source-token"), "{}", display);
+    assert!(!format!("{:?}", command.as_std()).contains("source-token"));
+    assert!(debug.contains("AccessToken(***)"), "{}", debug);
+    assert!(
+        display.starts_with(
+            "AWSIOT_TUNNEL_ACCESS_TOKEN=*** HTTPS_PROXY=http://proxy.corp:3128 localproxy -r eu-west-1"
+        ),
+        "{}",
+        display
+    );
+}
+
+#[test]
+fn test_service_port_map_replaces_existing_service() {
+    let services = ServicePortMap::default().with_service("SSH", 2022);
+
+    assert_eq!(services.len(), 2);
+    assert_eq!(services.port("SSH"), Some(2022));
+    assert_eq!(services.to_localproxy_arg(), "SSH=2022,GORT=5555");
+}
+
+#[test]
+fn test_service_order_round_trips_from_config() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [services]
+        VNC = 5900
+        GORT = 5555
+        SSH = 2222
+        "#,
+    )
+    .unwrap();
+
+    let destination = build_destination_config("G111070", &config.services).unwrap();
+    assert_eq!(destination.services(), ["VNC", "GORT", "SSH"]);
+
+    let command = build_localproxy_command("eu-west-1", &config.services, "source-token");
+    let args: Vec<&OsStr> = command.as_std().get_args().collect();
+    assert_eq!(args[3], "VNC=5900,GORT=5555,SSH=2222");
+}
+
+#[test]
+fn test_ready_markers() {
+    assert!(is_ready_line(
+        "[info] Successfully established websocket connection with proxy server: wss://data.tunneling.iot.eu-west-1.amazonaws.com:443"
+    ));
+    assert!(is_ready_line(
+        "[info] Listening for new connection on port 2222"
+    ));
+    assert!(!is_ready_line("[info] Starting proxy in source mode"));
+}
+
+#[test]
+fn test_line_level_from_localproxy_tags() {
+    assert_eq!(
+        line_level("[2024-05-01T10:00:00] [error] Failed to bind on port 2222"),
+        Level::ERROR
+    );
+    assert_eq!(
+        line_level("[warning] Web socket ping timed out"),
+        Level::WARN
+    );
+    assert_eq!(line_level("[debug] Sending ping"), Level::DEBUG);
+    assert_eq!(
+        line_level("[info] Starting proxy in source mode"),
+        Level::INFO
+    );
+    assert_eq!(line_level("untagged output"), Level::INFO);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_localproxy_output_masks_the_token() {
+    let template = CommandTemplate {
+        program: String::from("sh"),
+        args: vec![
+            String::from("-c"),
+            format!(
+                "echo \"[debug] token ${}\"; echo 'Listening for new connection'; sleep 5",
+                TOKEN_ENV
+            ),
+        ],
+    };
+    let command = build_templated_command(
+        &template,
+        "eu-west-1",
+        &ServicePortMap::default(),
+        "source-token",
+    );
+    let output = OutputTail::default().redacting(command.token());
+    let mut child = command.spawn().unwrap();
+
+    let readiness = wait_for_ready(&mut child, Duration::from_secs(5), &output)
+        .await
+        .unwrap();
+
+    assert_eq!(readiness, Readiness::Ready);
+    let lines = output.lines();
+    assert_eq!(lines[0], "[debug] token ***");
+    assert!(lines.iter().all(|line| !line.contains("source-token")));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_detects_marker() {
+    let mut child = Command::new("sh")
+        .args([
+            "-c",
+            "echo 'Listening for new connection on port 2222'; sleep 5",
+        ])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let readiness = wait_for_ready(&mut child, Duration::from_secs(5), &OutputTail::default())
+        .await
+        .unwrap();
+    assert_eq!(readiness, Readiness::Ready);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_times_out() {
+    let mut child = Command::new("sh")
+        .args(["-c", "echo 'Starting proxy in source mode'; sleep 5"])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    let readiness = wait_for_ready(
+        &mut child,
+        Duration::from_millis(200),
+        &OutputTail::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(readiness, Readiness::TimedOut);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_fails_when_proxy_exits() {
+    let mut child = Command::new("sh")
+        .args(["-c", "echo 'Invalid access token'"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let result = wait_for_ready(&mut child, Duration::from_secs(5), &OutputTail::default()).await;
+    assert!(matches!(result, Err(TunnelError::LocalProxyStartup { .. })));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_reports_exit_code_and_output() {
+    let mut child = Command::new("sh")
+        .args([
+            "-c",
+            "echo 'Starting proxy in source mode'; echo 'bind: Address already in use' >&2; exit 1",
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let output = OutputTail::default();
+    match wait_for_ready(&mut child, Duration::from_secs(5), &output).await {
+        Err(TunnelError::LocalProxyStartup { message }) => {
+            assert!(message.contains("could not listen on its local ports"));
+            assert!(message.contains("exit code 1"));
+            assert!(message.contains("Address already in use"));
+        }
+        other => panic!("Expected LocalProxyStartup, got {:?}", other),
+    }
+    assert_eq!(output.lines().len(), 2);
+}
+
+#[test]
+fn test_service_line_status() {
+    assert_eq!(
+        service_line_status(
+            "[info] Listening for new connection on port 2222",
+            "SSH",
+            2222
+        ),
+        Some(ServiceStatus::Listening)
+    );
+    assert_eq!(
+        service_line_status(
+            "[error] Failed to bind to 0.0.0.0:5555 for service GORT: Address already in use",
+            "GORT",
+            5555
+        ),
+        Some(ServiceStatus::BindFailed)
+    );
+    // Another service's port, or a port that only contains this one's digits
+    assert_eq!(
+        service_line_status(
+            "[info] Listening for new connection on port 22220",
+            "SSH",
+            2222
+        ),
+        None
+    );
+    assert_eq!(
+        service_line_status("[info] Starting proxy in source mode", "SSH", 2222),
+        None
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_reports_services_that_failed_to_bind() {
+    let mut child = Command::new("sh")
+        .args([
+            "-c",
+            "echo 'Listening for new connection on port 2222'; echo 'Failed to bind to port 5555: Address already in use'; sleep 5",
+        ])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let output = OutputTail::for_services(&ServicePortMap::default());
+
+    let readiness = wait_for_ready(&mut child, Duration::from_secs(5), &output)
+        .await
+        .unwrap();
+
+    assert_eq!(readiness, Readiness::Ready);
+    assert_eq!(
+        output.service_status(),
+        [
+            ("SSH".to_string(), ServiceStatus::Listening),
+            ("GORT".to_string(), ServiceStatus::BindFailed)
+        ]
+    );
+    assert_eq!(output.failed_services(), ["GORT"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_wait_for_ready_fails_when_no_service_binds() {
+    let mut child = Command::new("sh")
+        .args([
+            "-c",
+            "echo 'Successfully established websocket connection'; echo 'bind error on port 2222'; echo 'bind error on port 5555'; sleep 5",
+        ])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let output = OutputTail::for_services(&ServicePortMap::default());
+
+    let result = wait_for_ready(&mut child, Duration::from_secs(5), &output).await;
+    assert!(matches!(result, Err(TunnelError::LocalProxyStartup { .. })));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_exit_error_classification() {
+    let status = ExitStatus::from_raw(1 << 8);
+    let output = |line: &str| vec![line.to_string()];
+
+    assert!(matches!(
+        exit_error(status, &output("HTTP 401 Unauthorized")),
+        TunnelError::LocalProxyStartup { message } if message.contains("rejected localproxy's access token")
+    ));
+    assert!(matches!(
+        exit_error(status, &output("[error] Websocket connection closed")),
+        TunnelError::Connection { message } if message.contains("lost its connection")
+    ));
+    // Digits that happen to read 401 aren't an HTTP status
+    assert!(matches!(
+        exit_error(
+            status,
+            &[
+                String::from("Listening for new connection on port 40122"),
+                String::from("[error] Websocket connection closed (pid 24015)"),
+            ]
+        ),
+        TunnelError::Connection { message } if message.contains("lost its connection")
+    ));
+    match exit_error(status, &[]) {
+        TunnelError::Connection { message } => {
+            assert_eq!(message, "localproxy exited unexpectedly (exit code 1).")
+        }
+        other => panic!("Expected Connection, got {:?}", other),
+    }
+    assert!(matches!(
+        exit_error(ExitStatus::from_raw(9), &[]),
+        TunnelError::Connection { message } if message.contains("terminated by a signal")
+    ));
+}
+
+#[test]
+fn test_output_tail_keeps_latest_lines() {
+    let tail = OutputTail::default();
+    for i in 0..25 {
+        tail.push(format!("line {}", i));
+    }
+
+    let lines = tail.lines();
+    assert_eq!(lines.len(), 20);
+    assert_eq!(lines.first().map(String::as_str), Some("line 5"));
+    assert_eq!(lines.last().map(String::as_str), Some("line 24"));
+}
+
+#[test]
+fn test_resolve_localproxy_region() {
+    let overrides = HashMap::from([("eu-south-1".to_string(), "eu-central-1".to_string())]);
+
+    assert_eq!(
+        resolve_localproxy_region("eu-west-1", &overrides).unwrap(),
+        "eu-west-1"
+    );
+    assert_eq!(
+        resolve_localproxy_region("eu-south-1", &overrides).unwrap(),
+        "eu-central-1"
+    );
+    assert!(matches!(
+        resolve_localproxy_region("mars-north-1", &overrides),
+        Err(TunnelError::TunnelOperation { .. })
+    ));
+}
+
+#[test]
+fn test_proxy_env_passed_to_localproxy() {
+    let proxy = ProxySettings {
+        https_proxy: Some("http://proxy.corp:3128".to_string()),
+        no_proxy: Some("localhost,169.254.169.254".to_string()),
+        ca_bundle: None,
+    };
+
+    let mut command =
+        build_localproxy_command("eu-west-1", &ServicePortMap::default(), "source-token");
+    apply_proxy_env(&mut command, &proxy);
+
+    let envs: HashMap<&OsStr, Option<&OsStr>> = command.as_std().get_envs().collect();
+    assert_eq!(
+        envs[OsStr::new("HTTPS_PROXY")],
+        Some(OsStr::new("http://proxy.corp:3128"))
+    );
+    assert_eq!(
+        envs[OsStr::new("NO_PROXY")],
+        Some(OsStr::new("localhost,169.254.169.254"))
+    );
+    assert!(!envs.contains_key(OsStr::new(TOKEN_ENV)));
+}
+
+#[test]
+fn test_ca_bundle_passed_to_localproxy() {
+    let proxy = ProxySettings {
+        ca_bundle: Some("/etc/pki/corp-root-ca.pem".into()),
+        ..ProxySettings::default()
+    };
+
+    let mut command =
+        build_localproxy_command("eu-west-1", &ServicePortMap::default(), "source-token");
+    apply_ca_bundle(&mut command, &proxy);
+
+    let envs: HashMap<&OsStr, Option<&OsStr>> = command.as_std().get_envs().collect();
+    assert_eq!(
+        envs[OsStr::new("SSL_CERT_FILE")],
+        Some(OsStr::new("/etc/pki/corp-root-ca.pem"))
+    );
+    // The bundle's directory isn't hashed, so it would do nothing as --capath
+    assert!(!command.as_std().get_args().any(|arg| arg == "--capath"));
+}
+
+#[test]
+fn test_service_map_validation() {
+    assert!(ServicePortMap::default().validate().is_ok());
+
+    let over_limit = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("GORT", 5555)
+        .with_service("HTTP", 8080)
+        .with_service("VNC", 5900);
+    match over_limit.validate() {
+        Err(TunnelError::TunnelOperation { message }) => {
+            assert!(message.contains("At most 3 services allowed"))
+        }
+        other => panic!("Expected TunnelOperation, got {:?}", other),
+    }
+
+    let duplicate = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("ssh", 2223);
+    match duplicate.validate() {
+        Err(TunnelError::TunnelOperation { message }) => {
+            assert!(message.contains("'SSH' and 'ssh'"))
+        }
+        other => panic!("Expected TunnelOperation, got {:?}", other),
+    }
+
+    assert!(ServicePortMap::new().validate().is_err());
+}
+
+#[test]
+fn test_service_selection_flows_to_destination_and_localproxy() {
+    let services = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("GORT", 5555)
+        .with_service("HTTP", 8080);
+
+    let gort_only = services.only(&["GORT"]).unwrap();
+    assert_eq!(gort_only.to_localproxy_arg(), "GORT=5555");
+    let destination = build_destination_config("G111070", &gort_only).unwrap();
+    assert_eq!(destination.services(), ["GORT"]);
+
+    // Declaration order wins over the order picked
+    let picked = services.only(&["HTTP", "SSH"]).unwrap();
+    assert_eq!(picked.to_localproxy_arg(), "SSH=2222,HTTP=8080");
+}
+
+#[test]
+fn test_service_selection_validation() {
+    let over_limit = ServicePortMap::new()
+        .with_service("SSH", 2222)
+        .with_service("GORT", 5555)
+        .with_service("HTTP", 8080)
+        .with_service("VNC", 5900);
+    assert!(over_limit.only(&["SSH", "VNC"]).is_ok());
+    assert!(over_limit.only(&["SSH", "GORT", "HTTP", "VNC"]).is_err());
+
+    let services = ServicePortMap::default();
+    assert!(services.only::<&str>(&[]).is_err());
+    match services.only(&["RDP"]) {
+        Err(TunnelError::TunnelOperation { message }) => {
+            assert!(message.contains("'RDP' isn't configured; choose from SSH, GORT"))
+        }
+        other => panic!("Expected TunnelOperation, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_extra_args_appended_after_managed_flags() {
+    let mut command = build_localproxy_command("eu-west-1", &ServicePortMap::default(), "token");
+    let extra = ["--capath".to_string(), "/etc/ssl/certs".to_string()];
+    apply_extra_args(&mut command, &extra).unwrap();
+
+    let args: Vec<&OsStr> = command.as_std().get_args().collect();
+    assert_eq!(&args[..2], ["-r", "eu-west-1"]);
+    assert_eq!(&args[args.len() - 2..], ["--capath", "/etc/ssl/certs"]);
+}
+
+#[test]
+fn test_extra_args_cannot_override_managed_flags() {
+    for arg in [
+        "-t",
+        "-tTOKEN",
+        "--access-token",
+        "--access-token=x",
+        "-r",
+        "--bind-address",
+    ] {
+        let mut command =
+            build_localproxy_command("eu-west-1", &ServicePortMap::default(), "token");
+        let result = apply_extra_args(&mut command, &[arg.to_string()]);
+        assert!(
+            matches!(result, Err(TunnelError::Config { .. })),
+            "{} should be rejected",
+            arg
+        );
+    }
+
+    let config =
+        TunnelConfig::from_toml(r#"extra_localproxy_args = ["--access-token", "stolen"]"#).unwrap();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_command_template_fills_placeholders() {
+    let config = TunnelConfig::from_toml(
+        r#"
+        [localproxy_command]
+        program = "docker"
+        args = ["run", "--rm", "-e", "{token_env}", "aws-localproxy", "localproxy",
+                "-r", "{region}", "-s", "{services}", "-b", "{bind}"]
+        "#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    let template = config.localproxy_command.unwrap();
+
+    let command = build_templated_command(
+        &template,
+        "eu-west-1",
+        &ServicePortMap::default(),
+        "source-token",
+    );
+    assert_eq!(command.token().expose(), "source-token");
+    let command = command.as_std();
+
+    assert_eq!(command.get_program(), "docker");
+    let args: Vec<&OsStr> = command.get_args().collect();
+    assert_eq!(
+        args,
+        [
+            "run",
+            "--rm",
+            "-e",
+            TOKEN_ENV,
+            "aws-localproxy",
+            "localproxy",
+            "-r",
+            "eu-west-1",
+            "-s",
+            "SSH=2222,GORT=5555",
+            "-b",
+            "0.0.0.0"
+        ]
+    );
+    // The token only ever travels in the environment, added when spawning
+    assert_eq!(command.get_envs().count(), 0);
+}
+
+#[test]
+fn test_command_template_validation() {
+    let template = |program: &str, arg: &str| CommandTemplate {
+        program: program.to_string(),
+        args: vec![arg.to_string()],
+    };
+
+    assert!(
+        template("localproxy-wrapper", "{region}")
+            .validate()
+            .is_ok()
+    );
+    for invalid in [
+        template("", "{region}"),
+        template("docker", "{token}"),
+        template("docker", "{regoin}"),
+        template("docker", "{region"),
+    ] {
+        assert!(
+            matches!(invalid.validate(), Err(TunnelError::Config { .. })),
+            "{:?} should be rejected",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn test_localproxy_version_parse() {
+    let version = |major, minor, patch| LocalproxyVersion {
+        major,
+        minor,
+        patch,
+    };
+
+    assert_eq!(LocalproxyVersion::parse("3.1.1\n"), Some(version(3, 1, 1)));
+    assert_eq!(LocalproxyVersion::parse("v2.1.0"), Some(version(2, 1, 0)));
+    assert_eq!(
+        LocalproxyVersion::parse("localproxy 3.1.2-2a5f3b1"),
+        Some(version(3, 1, 2))
+    );
+    assert_eq!(LocalproxyVersion::parse("1.4"), Some(version(1, 4, 0)));
+    assert_eq!(LocalproxyVersion::parse("unknown"), None);
+    assert_eq!(version(2, 1, 0).to_string(), "2.1.0");
+}
+
+#[test]
+fn test_localproxy_version_minimum() {
+    assert!(MIN_LOCALPROXY_VERSION.require_supported().is_ok());
+    assert!(
+        LocalproxyVersion::parse("3.0.0")
+            .unwrap()
+            .require_supported()
+            .is_ok()
+    );
+
+    let err = LocalproxyVersion::parse("1.4.2")
+        .unwrap()
+        .require_supported()
+        .unwrap_err();
+    assert!(matches!(err, TunnelError::LocalProxyStartup { .. }));
+    let message = err.to_string();
+    assert!(message.contains("localproxy 2.1.0 required, found 1.4.2"));
+    assert!(message.contains("aws-iot-securetunneling-localproxy"));
+}
@@ -0,0 +1,95 @@
+use tracing::Level;
+use tracing::level_filters::LevelFilter;
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::logs::{BUFFER_LINES, LogBuffer, LogFilter, LogLevel, LogLine, Verbosity};
+
+fn line(level: Level, message: &str) -> LogLine {
+    LogLine {
+        level,
+        target: String::from("localproxy"),
+        message: message.to_string(),
+    }
+}
+
+fn messages(buffer: &LogBuffer, filter: LogFilter) -> Vec<String> {
+    buffer
+        .lines(filter)
+        .into_iter()
+        .map(|line| line.message)
+        .collect()
+}
+
+#[test]
+fn test_filter_hides_lines_without_dropping_them() {
+    let buffer = LogBuffer::new();
+    buffer.push(line(Level::DEBUG, "ping"));
+    buffer.push(line(Level::INFO, "connected"));
+    buffer.push(line(Level::WARN, "slow"));
+    buffer.push(line(Level::ERROR, "lost"));
+
+    assert_eq!(messages(&buffer, LogFilter::Errors), ["lost"]);
+    assert_eq!(
+        messages(&buffer, LogFilter::Info),
+        ["connected", "slow", "lost"]
+    );
+    assert_eq!(
+        messages(&buffer, LogFilter::Debug),
+        ["ping", "connected", "slow", "lost"]
+    );
+}
+
+#[test]
+fn test_buffer_keeps_latest_lines() {
+    let buffer = LogBuffer::new();
+    for i in 0..BUFFER_LINES + 5 {
+        buffer.push(line(Level::INFO, &i.to_string()));
+    }
+
+    let kept = messages(&buffer, LogFilter::Debug);
+    assert_eq!(kept.len(), BUFFER_LINES);
+    assert_eq!(kept[0], "5");
+    assert_eq!(kept[BUFFER_LINES - 1], (BUFFER_LINES + 4).to_string());
+}
+
+#[test]
+fn test_clear_empties_buffer_and_bumps_version() {
+    let buffer = LogBuffer::new();
+    buffer.push(line(Level::ERROR, "lost"));
+    let before = buffer.version();
+
+    buffer.clear();
+
+    assert!(buffer.lines(LogFilter::Debug).is_empty());
+    assert!(buffer.version() > before);
+    assert_eq!(
+        line(Level::WARN, "slow").to_string(),
+        " WARN localproxy: slow"
+    );
+}
+
+#[test]
+fn test_verbosity_from_args() {
+    assert_eq!(
+        Verbosity::from_args(Vec::<String>::new()),
+        Verbosity::Normal
+    );
+    assert_eq!(Verbosity::from_args(["--quiet"]), Verbosity::Quiet);
+    assert_eq!(
+        Verbosity::from_args(["-q", "--verbose"]),
+        Verbosity::Verbose
+    );
+    assert_eq!(Verbosity::from_args(["--other"]), Verbosity::Normal);
+
+    assert_eq!(Verbosity::Quiet.level(), Some(LevelFilter::ERROR));
+    assert_eq!(Verbosity::Normal.level(), None);
+    assert_eq!(Verbosity::Verbose.level(), Some(LevelFilter::DEBUG));
+}
+
+#[test]
+fn test_config_log_level() {
+    assert_eq!(TunnelConfig::default().log_level, LogLevel::Info);
+
+    let config = TunnelConfig::from_toml(r#"log_level = "warn""#).unwrap();
+    assert_eq!(LevelFilter::from(config.log_level), LevelFilter::WARN);
+    assert!(TunnelConfig::from_toml(r#"log_level = "loud""#).is_err());
+}
@@ -0,0 +1,531 @@
+use std::time::Duration;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::error::TunnelError;
+use tunnel_manager::manager::ConnectionManager;
+
+#[tokio::test]
+async fn test_new_manager_has_no_connections() {
+    let manager = ConnectionManager::new();
+    assert!(manager.status().await.is_empty());
+    assert!(!manager.is_active("G111070").await);
+}
+
+#[tokio::test]
+async fn test_disconnect_unknown_device() {
+    let manager = ConnectionManager::new();
+    match manager.disconnect("G111070").await {
+        Err(TunnelError::TunnelNotFound { device_id }) => assert_eq!(device_id, "G111070"),
+        other => panic!("Expected TunnelNotFound, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_failed_connects_are_counted_by_category() {
+    let manager = ConnectionManager::new();
+    let config = TunnelConfig {
+        profile: String::from("tunnel-manager-test-missing-profile"),
+        ..TunnelConfig::default()
+    };
+
+    let error = manager.connect("G111070", &config).await.unwrap_err();
+
+    let stats = manager.stats().await;
+    assert_eq!(stats.active_connections, 0);
+    assert_eq!(stats.connects, 1);
+    assert_eq!(stats.failures.get(error.category()), Some(&1));
+    assert_eq!(stats.failures.get("config"), Some(&1));
+    assert_eq!(stats.last_connect_latency, None);
+}
+
+#[tokio::test]
+async fn test_shutdown_without_session_tunnels_skips_aws() {
+    let manager = ConnectionManager::new();
+    // A missing profile would fail any AWS call, and there is nothing to close
+    let config = TunnelConfig {
+        profile: String::from("tunnel-manager-test-missing-profile"),
+        close_tunnels_on_exit: true,
+        ..TunnelConfig::default()
+    };
+
+    tokio::time::timeout(Duration::from_secs(1), manager.shutdown(&config))
+        .await
+        .unwrap();
+
+    assert!(manager.status().await.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_cancelled_connect_does_not_leave_the_device_connecting() {
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-cancelled-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    // Never reports the tunnel up, so the connect is still waiting when it's dropped
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![String::from("-c"), String::from("sleep 30")],
+        }),
+        ready_timeout: Duration::from_secs(60),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+
+    let abandoned = tokio::time::timeout(
+        Duration::from_millis(300),
+        manager.connect_from_token_file(&path, &config),
+    )
+    .await;
+
+    assert!(abandoned.is_err());
+    assert!(manager.pending().await.is_empty());
+    assert!(!manager.is_active("G111070").await);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_simultaneous_reconnects_rotate_the_token_once() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let dir = std::env::temp_dir().join(format!(
+        "tunnel-manager-manager-rotation-{}",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    let token_path = dir.join("token");
+    // Records the token it was started with, then reports the tunnel up
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                format!(
+                    "echo \"$AWSIOT_TUNNEL_ACCESS_TOKEN\" > '{}'; echo Listening for new connection; sleep 30",
+                    token_path.display()
+                ),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+
+    let rotations = AtomicUsize::new(0);
+    let rotate = async |_: &str, _: &str, _: &ServicePortMap| {
+        let n = rotations.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        Ok(format!("token-{}", n))
+    };
+    let (first, second) = tokio::join!(
+        manager.rotate_and_restart("G111070", &config, rotate),
+        manager.rotate_and_restart("G111070", &config, rotate),
+    );
+
+    assert_eq!(rotations.load(Ordering::SeqCst), 1);
+    assert!(first.unwrap() ^ second.unwrap());
+    assert_eq!(
+        std::fs::read_to_string(&token_path).unwrap().trim(),
+        "token-1"
+    );
+    manager.disconnect("G111070").await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_restarting_localproxy_does_not_hold_up_the_manager() {
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-slow-restart-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    // Reports the tunnel up straight away, but only after a while once restarted
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from(
+                    "[ \"$AWSIOT_TUNNEL_ACCESS_TOKEN\" = token-0 ] || sleep 2; echo Listening for new connection; sleep 30",
+                ),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+
+    let meanwhile = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let status = tokio::time::timeout(Duration::from_millis(500), manager.status())
+            .await
+            .expect("status waited for the restart");
+        (status, manager.reap_exited().await)
+    };
+    let (rotated, (status, reaped)) = tokio::join!(
+        manager.rotate_and_restart("G111070", &config, async |_, _, _| {
+            Ok(String::from("token-1"))
+        }),
+        meanwhile,
+    );
+
+    assert!(rotated.unwrap());
+    assert_eq!(status.len(), 1);
+    // Its localproxy was stopped while the new one started, which isn't an exit
+    assert!(reaped.is_empty());
+    assert!(manager.status().await[0].ready);
+    manager.disconnect("G111070").await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_a_failed_restart_drops_the_connection() {
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-failed-restart-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    // Only starts with the first token
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from(
+                    "[ \"$AWSIOT_TUNNEL_ACCESS_TOKEN\" = token-0 ] || exit 1; echo Listening for new connection; sleep 30",
+                ),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+
+    let rotated = manager
+        .rotate_and_restart("G111070", &config, async |_, _, _| {
+            Ok(String::from("token-1"))
+        })
+        .await;
+
+    assert!(rotated.is_err());
+    assert!(!manager.is_active("G111070").await);
+    assert!(manager.status().await.is_empty());
+    assert!(manager.reap_exited().await.is_empty());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connected_is_announced_once_and_on_reconnects_only_when_opted_in() {
+    use tunnel_manager::events::TunnelEvent;
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-connected-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    let mut config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("echo Listening for new connection; sleep 30"),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    let mut events = manager.events().subscribe();
+    let connected = |reconnect| TunnelEvent::Connected {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+        reconnect,
+    };
+    let rotate = async |_: &str, _: &str, _: &ServicePortMap| Ok(String::from("token-1"));
+
+    manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+    manager
+        .rotate_and_restart("G111070", &config, rotate)
+        .await
+        .unwrap();
+    config.announce_reconnects = true;
+    manager
+        .rotate_and_restart("G111070", &config, rotate)
+        .await
+        .unwrap();
+    manager.disconnect("G111070").await.unwrap();
+
+    let mut announced = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        if matches!(event, TunnelEvent::Connected { .. }) {
+            announced.push(event);
+        }
+    }
+    assert_eq!(announced, [connected(false), connected(true)]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_killing_a_listed_process_drops_its_connection() {
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-processes-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("echo Listening for new connection; sleep 30"),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    let connection = manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+
+    let processes = manager.processes().await;
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0].device_id, "G111070");
+    assert_eq!(Some(processes[0].pid), connection.pid);
+    assert_eq!(processes[0].services.port("SSH"), Some(2222));
+
+    manager.force_disconnect("G111070").await.unwrap();
+
+    assert!(manager.processes().await.is_empty());
+    assert!(!manager.is_active("G111070").await);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_failed_disconnect_keeps_the_connection_for_force_disconnect() {
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-stubborn-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    // Ignores being asked to stop, like a localproxy stuck closing its connections
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("trap '' TERM; echo Listening for new connection; sleep 30"),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    manager
+        .connect_from_token_file(&path, &config)
+        .await
+        .unwrap();
+
+    // Waiting on localproxy doesn't hold up the rest of the manager
+    let meanwhile = async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        tokio::time::timeout(Duration::from_millis(500), manager.processes())
+            .await
+            .expect("processes waited for the disconnect")
+    };
+    let (disconnected, processes) = tokio::join!(manager.disconnect("G111070"), meanwhile);
+    match disconnected {
+        Err(TunnelError::Disconnection { device_id, .. }) => assert_eq!(device_id, "G111070"),
+        other => panic!("Expected Disconnection, got {:?}", other),
+    }
+    assert!(processes.is_empty());
+    assert!(manager.is_active("G111070").await);
+    assert_eq!(manager.processes().await.len(), 1);
+
+    manager.force_disconnect("G111070").await.unwrap();
+    assert!(!manager.is_active("G111070").await);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_expired_sessions_are_disconnected_and_optionally_closed() {
+    use mockall::predicate::eq;
+    use tunnel_manager::aws_client::TunnelClient;
+    use tunnel_manager::aws_client::test_utils::MockTunnelClient;
+    use tunnel_manager::localproxy::{CommandTemplate, ServicePortMap};
+    use tunnel_manager::token_file::TokenFile;
+
+    let path = std::env::temp_dir()
+        .join(format!(
+            "tunnel-manager-manager-expiry-{}",
+            std::process::id()
+        ))
+        .join("G111070.toml");
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("token-0"),
+        services: ServicePortMap::new().with_service("SSH", 2222),
+    }
+    .write(&path)
+    .unwrap();
+    let mut config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("echo Listening for new connection; sleep 30"),
+            ],
+        }),
+        max_session_duration: Duration::from_secs(1),
+        ..TunnelConfig::default()
+    };
+    let manager = ConnectionManager::new();
+    let mut client = MockTunnelClient::new();
+    client
+        .expect_close_tunnel_by_id()
+        .with(eq("tunnel-123"))
+        .times(1)
+        .returning(|_| {
+            Ok(
+                aws_sdk_iotsecuretunneling::operation::close_tunnel::CloseTunnelOutput::builder()
+                    .build(),
+            )
+        });
+    let close = async |tunnel_id: &str| {
+        client
+            .close_tunnel_by_id(tunnel_id)
+            .await
+            .map(|_| ())
+            .map_err(|e| TunnelError::sdk_request("Failed to close tunnel", e))
+    };
+
+    // Left alone, then closed only once the setting asks for it
+    for close_tunnel_on_expiry in [false, true] {
+        config.close_tunnel_on_expiry = close_tunnel_on_expiry;
+        manager
+            .connect_from_token_file(&path, &config)
+            .await
+            .unwrap();
+        assert!(
+            manager
+                .expire_sessions_with(&config, close)
+                .await
+                .is_empty()
+        );
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        assert_eq!(
+            manager.expire_sessions_with(&config, close).await,
+            ["G111070"]
+        );
+        assert!(!manager.is_active("G111070").await);
+        assert!(manager.processes().await.is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+#![cfg(feature = "metrics")]
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::manager::{ConnectStats, ConnectionManager};
+use tunnel_manager::metrics::{MetricsServer, render_metrics};
+
+#[test]
+fn test_render_metrics() {
+    let mut stats = ConnectStats {
+        active_connections: 2,
+        connects: 5,
+        last_connect_latency: Some(Duration::from_millis(2500)),
+        ..ConnectStats::default()
+    };
+    stats.failures.insert("auth", 2);
+    stats.failures.insert("localproxy", 1);
+
+    let text = render_metrics(&stats);
+
+    assert!(text.contains("# TYPE tunnel_manager_active_connections gauge\n"));
+    assert!(text.contains("\ntunnel_manager_active_connections 2\n"));
+    assert!(text.contains("\ntunnel_manager_connects_total 5\n"));
+    assert!(text.contains("\ntunnel_manager_connect_failures_total{category=\"auth\"} 2\n"));
+    assert!(text.contains("\ntunnel_manager_connect_failures_total{category=\"localproxy\"} 1\n"));
+    assert!(text.contains("\ntunnel_manager_last_connect_seconds 2.5\n"));
+}
+
+#[test]
+fn test_render_metrics_before_any_connect() {
+    let text = render_metrics(&ConnectStats::default());
+
+    assert!(text.contains("\ntunnel_manager_connects_total 0\n"));
+    assert!(!text.contains("connect_failures_total{"));
+    assert!(!text.contains("last_connect_seconds"));
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_routes() {
+    let mut config = TunnelConfig::default();
+    config.metrics.port = 0;
+    let server = MetricsServer::bind(&config, ConnectionManager::new())
+        .await
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let send = async |request: &str| {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    let metrics = send("GET /metrics HTTP/1.1\r\nAccept: text/plain\r\n\r\n").await;
+    assert!(metrics.starts_with("HTTP/1.1 200"));
+    assert!(metrics.contains("tunnel_manager_active_connections 0\n"));
+
+    let wrong_method = send("POST /metrics HTTP/1.1\r\n\r\n").await;
+    assert!(wrong_method.starts_with("HTTP/1.1 405"));
+
+    let unknown = send("GET /status HTTP/1.1\r\n\r\n").await;
+    assert!(unknown.starts_with("HTTP/1.1 404"));
+}
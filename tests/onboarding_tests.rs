@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::PathBuf;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::onboarding::{SetupChoices, apply_setup, find_localproxy_in, save_setup_to};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "tunnel-manager-onboarding-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_apply_setup_keeps_existing_settings() {
+    let contents = "# Tunnel Manager settings\nprofile = \"old\"\nauto_connect = true\n\n[proxy]\nhttps_proxy = \"http://proxy:3128\"\n";
+    let choices = SetupChoices {
+        profile: String::from("iotmgmt_dev"),
+        localproxy: Some(PathBuf::from("/opt/localproxy/bin/localproxy")),
+    };
+
+    let updated = apply_setup(contents, &choices).unwrap();
+
+    assert!(updated.starts_with("# Tunnel Manager settings\n"));
+    let config = TunnelConfig::from_toml(&updated).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.profile, "iotmgmt_dev");
+    assert!(config.auto_connect);
+    assert_eq!(
+        config.proxy.https_proxy.as_deref(),
+        Some("http://proxy:3128")
+    );
+    let template = config.localproxy_command.unwrap();
+    assert_eq!(template.program, "/opt/localproxy/bin/localproxy");
+    assert_eq!(
+        template.args,
+        ["-r", "{region}", "-s", "{services}", "-b", "{bind}"]
+    );
+}
+
+#[test]
+fn test_save_setup_creates_the_config_file() {
+    let dir = scratch_dir("save");
+    let path = dir.join("tunnel-manager").join("config.toml");
+    let choices = SetupChoices {
+        profile: String::from("iotmgmt_prod"),
+        localproxy: None,
+    };
+
+    save_setup_to(&path, &choices).unwrap();
+
+    let config = TunnelConfig::from_toml(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(config.profile, "iotmgmt_prod");
+    assert!(config.localproxy_command.is_none());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_find_localproxy_in_searches_dirs_in_order() {
+    let dir = scratch_dir("find");
+    let (first, second) = (dir.join("first"), dir.join("second"));
+    fs::create_dir_all(&first).unwrap();
+    fs::create_dir_all(&second).unwrap();
+    assert_eq!(find_localproxy_in([first.clone(), second.clone()]), None);
+
+    fs::write(second.join("localproxy"), "").unwrap();
+    assert_eq!(
+        find_localproxy_in([first.clone(), second.clone()]),
+        Some(second.join("localproxy"))
+    );
+
+    // A directory with the right name isn't a binary
+    fs::create_dir_all(first.join("localproxy")).unwrap();
+    assert_eq!(
+        find_localproxy_in([first, second.clone()]),
+        Some(second.join("localproxy"))
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
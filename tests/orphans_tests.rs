@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::PathBuf;
+
+use tunnel_manager::orphans::{PidFile, find_orphans};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("tunnel-manager-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_pid_file_removed_on_drop() {
+    let dir = scratch_dir("pid-file");
+    let pid_file = PidFile::create_in(&dir, 4242, "G111070").unwrap();
+    let path = pid_file.path().to_path_buf();
+
+    let contents = fs::read_to_string(&path).unwrap();
+    assert!(contents.starts_with("4242\nG111070\n"));
+
+    drop(pid_file);
+    assert!(!path.exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_stale_pid_files_are_removed() {
+    let dir = scratch_dir("stale");
+    fs::create_dir_all(&dir).unwrap();
+    // A pid that can't be running and a file that doesn't parse
+    fs::write(dir.join("4194305.pid"), "4194305\nG111070\n1\n").unwrap();
+    fs::write(dir.join("garbage.pid"), "not a pid").unwrap();
+
+    assert!(find_orphans(&dir).await.is_empty());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_own_pid_files_are_not_orphans() {
+    let dir = scratch_dir("own");
+    let pid_file = PidFile::create_in(&dir, std::process::id(), "G111070").unwrap();
+
+    assert!(find_orphans(&dir).await.is_empty());
+    assert!(pid_file.path().exists());
+    drop(pid_file);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_orphaned_localproxy_found_and_terminated() {
+    use tokio::process::Command;
+    use tunnel_manager::orphans::terminate;
+
+    // A copy of `sleep` named localproxy stands in for a process left by a crashed run
+    let dir = scratch_dir("orphan");
+    let bin_dir = dir.join("bin");
+    fs::create_dir_all(&bin_dir).unwrap();
+    let fake = bin_dir.join("localproxy");
+    let sleep = ["/bin/sleep", "/usr/bin/sleep"]
+        .into_iter()
+        .find(|path| fs::metadata(path).is_ok())
+        .unwrap();
+    fs::copy(sleep, &fake).unwrap();
+
+    let mut child = Command::new(&fake)
+        .arg("30")
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let pid = child.id().unwrap();
+    let pid_dir = dir.join("pids");
+    fs::create_dir_all(&pid_dir).unwrap();
+    fs::write(
+        pid_dir.join(format!("{}.pid", pid)),
+        format!("{}\nG111070\n1\n", pid),
+    )
+    .unwrap();
+
+    let orphans = find_orphans(&pid_dir).await;
+    assert_eq!(orphans.len(), 1);
+    assert_eq!(orphans[0].pid, pid);
+    assert_eq!(orphans[0].device_id, "G111070");
+
+    terminate(&orphans[0]).await.unwrap();
+    assert!(!child.wait().await.unwrap().success());
+    assert_eq!(fs::read_dir(&pid_dir).unwrap().count(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_force_kill_stops_a_process_ignoring_term() {
+    use std::time::Duration;
+    use tokio::process::Command;
+    use tunnel_manager::orphans::force_kill;
+
+    let mut child = Command::new("sh")
+        .args(["-c", "trap '' TERM; sleep 30"])
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    force_kill(child.id().unwrap()).await.unwrap();
+    let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!status.success());
+}
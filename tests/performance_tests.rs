@@ -1,58 +1,120 @@
-use std::time::{Duration, Instant};
+use std::hint::black_box;
+use std::time::Duration;
 use tunnel_manager::error::{TunnelError, UiError};
 
+/// Small timing harness for the error paths
+///
+/// Each measurement runs the operation in several batches and keeps the median time per
+/// iteration, so one slow batch on a busy CI runner doesn't fail the test. With
+/// `TUNNEL_MANAGER_BENCH` set the timings are only printed. Otherwise they are checked
+/// against deliberately generous limits, which `TUNNEL_MANAGER_PERF_SCALE` multiplies
+/// for slow machines.
+mod bench {
+    use std::future::Future;
+    use std::time::{Duration, Instant};
+
+    const BATCHES: usize = 9;
+    const ITERATIONS: usize = 1000;
+
+    fn per_iteration(mut batches: Vec<Duration>) -> Duration {
+        batches.sort();
+        batches[batches.len() / 2] / ITERATIONS as u32
+    }
+
+    /// Median time per call of `op`, which is given the iteration number
+    pub fn measure(mut op: impl FnMut(usize)) -> Duration {
+        let batches = (0..BATCHES)
+            .map(|_| {
+                let start = Instant::now();
+                for i in 0..ITERATIONS {
+                    op(i);
+                }
+                start.elapsed()
+            })
+            .collect();
+        per_iteration(batches)
+    }
+
+    /// Async version of `measure`
+    pub async fn measure_async<F, Fut>(mut op: F) -> Duration
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut batches = Vec::with_capacity(BATCHES);
+        for _ in 0..BATCHES {
+            let start = Instant::now();
+            for i in 0..ITERATIONS {
+                op(i).await;
+            }
+            batches.push(start.elapsed());
+        }
+        per_iteration(batches)
+    }
+
+    fn scale() -> f64 {
+        std::env::var("TUNNEL_MANAGER_PERF_SCALE")
+            .ok()
+            .and_then(|scale| scale.parse().ok())
+            .filter(|scale: &f64| *scale > 0.0)
+            .unwrap_or(1.0)
+    }
+
+    /// Print the timing in bench mode, otherwise assert it is within `limit` per iteration
+    pub fn check(name: &str, per_iteration: Duration, limit: Duration) {
+        if std::env::var_os("TUNNEL_MANAGER_BENCH").is_some() {
+            println!("{}: {:?} per iteration", name, per_iteration);
+            return;
+        }
+        let limit = limit.mul_f64(scale());
+        assert!(
+            per_iteration < limit,
+            "{} took {:?} per iteration, limit {:?}",
+            name,
+            per_iteration,
+            limit
+        );
+    }
+}
+
 #[cfg(test)]
 mod performance_tests {
     use super::*;
 
     #[test]
     fn test_error_creation_performance() {
-        let start = Instant::now();
-
-        // Create 1000 errors to test performance
-        for i in 0..1000 {
-            let _error = TunnelError::InvalidDeviceId {
+        let per_iteration = bench::measure(|i| {
+            black_box(TunnelError::InvalidDeviceId {
                 device_id: format!("device-{}", i),
-            };
-        }
+            });
+        });
 
-        let duration = start.elapsed();
-        // Should complete in less than 1ms for 1000 error creations
-        assert!(duration < Duration::from_millis(1));
+        bench::check("error creation", per_iteration, Duration::from_micros(20));
     }
 
     #[test]
     fn test_error_conversion_performance() {
-        let start = Instant::now();
-
-        // Test error conversion performance
-        for i in 0..1000 {
+        let per_iteration = bench::measure(|i| {
             let tunnel_error = TunnelError::AwsAuth {
                 message: format!("Auth failed for attempt {}", i),
+                source: None,
             };
-            let _ui_error: UiError = tunnel_error.into();
-        }
+            black_box(UiError::from(tunnel_error));
+        });
 
-        let duration = start.elapsed();
-        // Should complete in less than 2ms for 1000 conversions
-        assert!(duration < Duration::from_millis(2));
+        bench::check("error conversion", per_iteration, Duration::from_micros(20));
     }
 
     #[test]
     fn test_error_display_performance() {
-        let start = Instant::now();
-
-        // Test error display formatting performance
-        for i in 0..1000 {
+        let per_iteration = bench::measure(|i| {
             let error = TunnelError::TunnelNotFound {
                 device_id: format!("device-{}", i),
             };
-            let _display_string = error.to_string();
-        }
+            black_box(error.to_string());
+        });
 
-        let duration = start.elapsed();
-        // Should complete in less than 5ms for 1000 display operations
-        assert!(duration < Duration::from_millis(5));
+        bench::check("error display", per_iteration, Duration::from_micros(50));
     }
 
     #[test]
@@ -69,19 +131,18 @@ mod performance_tests {
             UiError::Unknown,
         ];
 
-        let start = Instant::now();
-
-        // Test UI error message retrieval performance
-        for _ in 0..1000 {
+        let per_iteration = bench::measure(|_| {
             for error in &errors {
-                let _message = error.user_message();
-                let _should_retry = error.should_retry();
+                black_box(error.user_message());
+                black_box(error.should_retry());
             }
-        }
+        });
 
-        let duration = start.elapsed();
-        // Should complete in less than 2ms for 5000 operations (1000 iterations * 5 errors)
-        assert!(duration < Duration::from_millis(2));
+        bench::check(
+            "UI error messages",
+            per_iteration,
+            Duration::from_micros(20),
+        );
     }
 
     #[tokio::test]
@@ -94,24 +155,18 @@ mod performance_tests {
             }
         }
 
-        let start = Instant::now();
-
-        // Test async error handling performance
-        for i in 0..1000 {
-            let result = mock_async_operation(i % 2 == 0).await;
-            match result {
-                Ok(_) => {
-                    // Handle success case
-                }
-                Err(e) => {
-                    let _ui_error: UiError = e.into();
-                }
+        let per_iteration = bench::measure_async(|i| async move {
+            if let Err(e) = mock_async_operation(i % 2 == 0).await {
+                black_box(UiError::from(e));
             }
-        }
+        })
+        .await;
 
-        let duration = start.elapsed();
-        // Should complete in less than 10ms for 1000 async operations
-        assert!(duration < Duration::from_millis(10));
+        bench::check(
+            "async error handling",
+            per_iteration,
+            Duration::from_micros(50),
+        );
     }
 }
 
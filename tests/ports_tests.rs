@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::error::TunnelError;
+use tunnel_manager::localproxy::ServicePortMap;
+use tunnel_manager::ports::{PortAllocation, allocate_ports};
+
+fn ports(services: &ServicePortMap) -> HashSet<u16> {
+    services.iter().map(|(_, port)| port).collect()
+}
+
+#[test]
+fn test_fixed_allocation_refuses_taken_ports() {
+    let services = ServicePortMap::default();
+
+    let first = allocate_ports(PortAllocation::Fixed, &services, &HashSet::new()).unwrap();
+    assert_eq!(first, services);
+
+    assert!(allocate_ports(PortAllocation::Fixed, &services, &ports(&first)).is_err());
+}
+
+#[test]
+fn test_stride_allocation_reuses_lowest_free_slot() {
+    let strategy = PortAllocation::Stride { stride: 10 };
+    let services = ServicePortMap::default();
+
+    let first = allocate_ports(strategy, &services, &HashSet::new()).unwrap();
+    let second = allocate_ports(strategy, &services, &ports(&first)).unwrap();
+    assert_eq!(second.to_localproxy_arg(), "SSH=2232,GORT=5565");
+
+    let mut taken = ports(&first);
+    taken.extend(ports(&second));
+    let third = allocate_ports(strategy, &services, &taken).unwrap();
+    assert_eq!(third.to_localproxy_arg(), "SSH=2242,GORT=5575");
+
+    // Once the first connection goes away its slot is handed out again
+    let mut taken = ports(&second);
+    taken.extend(ports(&third));
+    let fourth = allocate_ports(strategy, &services, &taken).unwrap();
+    assert_eq!(fourth, first);
+}
+
+#[test]
+fn test_stride_allocation_runs_out_of_ports() {
+    let services = ServicePortMap::new().with_service("SSH", 65530);
+    let taken = HashSet::from([65530]);
+
+    let error =
+        allocate_ports(PortAllocation::Stride { stride: 10 }, &services, &taken).unwrap_err();
+    assert!(matches!(error, TunnelError::Config { .. }));
+}
+
+#[test]
+fn test_stride_allocation_stops_at_the_last_slot() {
+    // Every slot up to u16::MAX fits, so only the end of the range stops the search
+    let services = ServicePortMap::new().with_service("SSH", 0);
+    let taken: HashSet<u16> = (0..=u16::MAX).collect();
+
+    let error =
+        allocate_ports(PortAllocation::Stride { stride: 1 }, &services, &taken).unwrap_err();
+    assert!(error.to_string().contains("No free local ports left"));
+}
+
+#[test]
+fn test_ephemeral_allocation_picks_distinct_ports() {
+    let services = ServicePortMap::default();
+    let allocated = allocate_ports(PortAllocation::Ephemeral, &services, &HashSet::new()).unwrap();
+
+    assert_eq!(allocated.services().collect::<Vec<_>>(), ["SSH", "GORT"]);
+    assert_eq!(ports(&allocated).len(), 2);
+    assert!(allocated.iter().all(|(_, port)| port != 0));
+}
+
+#[test]
+fn test_port_allocation_from_config() {
+    let config = TunnelConfig::from_toml("").unwrap();
+    assert_eq!(config.port_allocation, PortAllocation::Fixed);
+
+    let config = TunnelConfig::from_toml(
+        r#"
+        [port_allocation]
+        strategy = "stride"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.port_allocation,
+        PortAllocation::Stride { stride: 10 }
+    );
+
+    let config = TunnelConfig::from_toml(
+        r#"
+        [port_allocation]
+        strategy = "ephemeral"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.port_allocation, PortAllocation::Ephemeral);
+}
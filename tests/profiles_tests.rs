@@ -0,0 +1,37 @@
+use tunnel_manager::profiles::{account_id, parse_profiles};
+
+#[test]
+fn test_parse_profiles() {
+    let contents = r#"
+[default]
+region = eu-west-1
+
+[profile iotmgmt_prod]
+sso_session = gardin
+region = eu-west-1
+
+[sso-session gardin]
+sso_region = eu-west-1
+
+[ profile  staging ]
+region = eu-west-2
+
+[profile iotmgmt_prod]
+output = json
+"#;
+
+    assert_eq!(
+        parse_profiles(contents),
+        ["default", "iotmgmt_prod", "staging"]
+    );
+    assert!(parse_profiles("").is_empty());
+}
+
+#[test]
+fn test_account_id_from_arn() {
+    assert_eq!(
+        account_id("arn:aws:sts::123456789012:assumed-role/Admin/operator"),
+        Some("123456789012")
+    );
+    assert_eq!(account_id("not an arn"), None);
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use aws_sdk_iotsecuretunneling::operation::describe_tunnel::DescribeTunnelOutput;
+use tunnel_manager::aws_client::test_utils::MockTunnelClient;
+use tunnel_manager::aws_client::{RateLimitedClient, TunnelClient};
+use tunnel_manager::rate_limit::RateLimiter;
+
+#[test]
+fn test_burst_up_to_ceiling_then_spaced_out() {
+    let limiter = RateLimiter::new();
+    let now = Instant::now();
+
+    let waits: Vec<Duration> = (0..8).map(|_| limiter.reserve(4, now)).collect();
+
+    assert!(waits[..4].iter().all(Duration::is_zero));
+    assert_eq!(waits[4], Duration::from_millis(250));
+    assert_eq!(waits[7], Duration::from_secs(1));
+}
+
+#[test]
+fn test_bucket_refills_over_time() {
+    let limiter = RateLimiter::new();
+    let start = Instant::now();
+    for _ in 0..5 {
+        limiter.reserve(5, start);
+    }
+
+    assert!(
+        limiter
+            .reserve(5, start + Duration::from_millis(200))
+            .is_zero()
+    );
+    assert!(
+        !limiter
+            .reserve(5, start + Duration::from_millis(200))
+            .is_zero()
+    );
+    // Idle time never builds up more than one second's worth of calls
+    let later = start + Duration::from_secs(60);
+    let waits: Vec<Duration> = (0..6).map(|_| limiter.reserve(5, later)).collect();
+    assert_eq!(waits.iter().filter(|wait| wait.is_zero()).count(), 5);
+}
+
+#[tokio::test]
+async fn test_rate_limited_client_shares_limiter() {
+    let mut mock_client = MockTunnelClient::new();
+    mock_client
+        .expect_describe_tunnel_by_id()
+        .times(2)
+        .returning(|_| Ok(DescribeTunnelOutput::builder().build()));
+    let limiter = RateLimiter::new();
+    let client = RateLimitedClient::new(mock_client, limiter.clone(), 2);
+
+    let started = Instant::now();
+    client.describe_tunnel_by_id("tunnel-123").await.unwrap();
+    client.describe_tunnel_by_id("tunnel-123").await.unwrap();
+    assert!(started.elapsed() < Duration::from_millis(250));
+
+    // Both calls came out of the shared bucket, so the next caller has to wait
+    assert!(!limiter.reserve(2, Instant::now()).is_zero());
+}
@@ -0,0 +1,253 @@
+use tunnel_manager::state::{AppMsg, ConnectionState, StatusLevel};
+
+#[test]
+fn test_connection_state_display() {
+    assert_eq!(ConnectionState::default().to_string(), "Disconnected");
+    assert_eq!(
+        ConnectionState::Connecting {
+            device_id: "G111070".to_string()
+        }
+        .to_string(),
+        "Connecting to G111070..."
+    );
+    assert_eq!(
+        ConnectionState::Connected {
+            device_id: "G111070".to_string(),
+            tunnel_id: "tunnel-123".to_string(),
+        }
+        .to_string(),
+        "Connected to G111070 (tunnel-123)"
+    );
+}
+
+#[test]
+fn test_connection_state_predicates() {
+    let connecting = ConnectionState::Connecting {
+        device_id: "G111070".to_string(),
+    };
+    assert!(connecting.is_connecting());
+    assert!(!connecting.is_connected());
+
+    let connected = ConnectionState::Connected {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+    };
+    assert!(connected.is_connected());
+    assert!(!ConnectionState::Failed.is_connected());
+}
+
+#[test]
+fn test_authentication_required_keeps_connection() {
+    let state = ConnectionState::AuthenticationRequired {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+    };
+    assert!(state.is_connected());
+    assert_eq!(state.device_id(), Some("G111070"));
+    assert_eq!(
+        state.to_string(),
+        "Connected to G111070, log in to AWS to keep the session"
+    );
+    assert_eq!(ConnectionState::Failed.device_id(), None);
+    assert_eq!(state.tunnel_id(), Some("tunnel-123"));
+    assert_eq!(
+        ConnectionState::Connecting {
+            device_id: "G111070".to_string()
+        }
+        .tunnel_id(),
+        None
+    );
+}
+
+#[test]
+fn test_waiting_for_device_counts_as_connected() {
+    let waiting = ConnectionState::WaitingForDevice {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-123".to_string(),
+    };
+    assert!(waiting.is_connected());
+    assert!(!waiting.is_connecting());
+    assert_eq!(waiting.device_id(), Some("G111070"));
+    assert_eq!(waiting.tunnel_id(), Some("tunnel-123"));
+    assert_eq!(
+        waiting.to_string(),
+        "Waiting for G111070 to connect to the tunnel..."
+    );
+}
+
+#[test]
+fn test_status_levels_and_labels() {
+    let cases = [
+        (ConnectionState::Disconnected, StatusLevel::Idle, "Idle"),
+        (
+            ConnectionState::Connecting {
+                device_id: "G111070".to_string(),
+            },
+            StatusLevel::Pending,
+            "Connecting",
+        ),
+        (
+            ConnectionState::Connected {
+                device_id: "G111070".to_string(),
+                tunnel_id: "tunnel-123".to_string(),
+            },
+            StatusLevel::Ok,
+            "Connected",
+        ),
+        (
+            ConnectionState::AuthenticationRequired {
+                device_id: "G111070".to_string(),
+                tunnel_id: "tunnel-123".to_string(),
+            },
+            StatusLevel::Pending,
+            "Login required",
+        ),
+        (ConnectionState::Failed, StatusLevel::Error, "Failed"),
+    ];
+
+    for (state, level, label) in cases {
+        assert_eq!(state.level(), level);
+        assert_eq!(state.short_label(), label);
+    }
+}
+
+fn connected(device_id: &str) -> ConnectionState {
+    ConnectionState::Connected {
+        device_id: device_id.to_string(),
+        tunnel_id: format!("tunnel-{}", device_id),
+    }
+}
+
+#[test]
+fn test_connect_transitions() {
+    let connecting = ConnectionState::default().apply(AppMsg::ConnectRequested {
+        device_id: "G111070".to_string(),
+    });
+    assert_eq!(
+        connecting,
+        ConnectionState::Connecting {
+            device_id: "G111070".to_string()
+        }
+    );
+
+    let waiting = connecting.apply(AppMsg::Connected {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-G111070".to_string(),
+        waiting_for_device: true,
+    });
+    assert!(matches!(waiting, ConnectionState::WaitingForDevice { .. }));
+
+    let up = connecting.apply(AppMsg::Connected {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-G111070".to_string(),
+        waiting_for_device: false,
+    });
+    assert_eq!(up, connected("G111070"));
+    assert_eq!(
+        up.apply(AppMsg::Disconnected),
+        ConnectionState::Disconnected
+    );
+
+    assert_eq!(
+        connecting.apply(AppMsg::ConnectFailed),
+        ConnectionState::Failed
+    );
+    assert_eq!(
+        connecting.apply(AppMsg::ConnectAbandoned),
+        ConnectionState::Disconnected
+    );
+}
+
+#[test]
+fn test_second_connect_is_ignored() {
+    let request = AppMsg::ConnectRequested {
+        device_id: "G111071".to_string(),
+    };
+    let connecting = ConnectionState::Connecting {
+        device_id: "G111070".to_string(),
+    };
+
+    assert_eq!(connecting.apply(request.clone()), connecting);
+    assert_eq!(
+        connected("G111070").apply(request.clone()),
+        connected("G111070")
+    );
+    assert!(ConnectionState::Failed.apply(request).is_connecting());
+}
+
+#[test]
+fn test_sync_follows_the_manager() {
+    let live = vec![connected("G111071"), connected("G111070")];
+
+    // Polls don't override a connect in flight
+    let connecting = ConnectionState::Connecting {
+        device_id: "G111070".to_string(),
+    };
+    assert_eq!(connecting.apply(AppMsg::Synced(Vec::new())), connecting);
+
+    // The connection shown is tracked, and dropped once the manager no longer has it
+    let login_required = ConnectionState::AuthenticationRequired {
+        device_id: "G111070".to_string(),
+        tunnel_id: "tunnel-G111070".to_string(),
+    };
+    assert_eq!(
+        connected("G111070").apply(AppMsg::Synced(vec![login_required.clone()])),
+        login_required
+    );
+    assert_eq!(
+        connected("G111070").apply(AppMsg::Synced(vec![connected("G111071")])),
+        ConnectionState::Disconnected
+    );
+
+    // With nothing shown, a connection made elsewhere is picked up
+    assert_eq!(
+        ConnectionState::default().apply(AppMsg::Synced(live)),
+        connected("G111071")
+    );
+    assert_eq!(
+        ConnectionState::Failed.apply(AppMsg::Synced(Vec::new())),
+        ConnectionState::Failed
+    );
+}
+
+#[test]
+fn test_reconnect_attempts_keep_the_device() {
+    let state = ConnectionState::default().apply(AppMsg::ConnectRequested {
+        device_id: "G111070".to_string(),
+    });
+    let state = state.apply(AppMsg::ReconnectAttempt {
+        attempt: 2,
+        max_attempts: 4,
+    });
+    assert_eq!(
+        state,
+        ConnectionState::Reconnecting {
+            device_id: "G111070".to_string(),
+            attempt: 2,
+            max_attempts: 4,
+        }
+    );
+    assert!(state.is_connecting());
+    assert!(state.is_reconnecting());
+    assert_eq!(state.level(), StatusLevel::Pending);
+    assert_eq!(state.short_label(), "Reconnecting");
+    assert_eq!(state.to_string(), "Reconnecting to G111070 (attempt 2/4)…");
+
+    // Still in flight, so polls and a second connect leave it alone
+    assert_eq!(state.apply(AppMsg::Synced(Vec::new())), state);
+    let state = state.apply(AppMsg::ReconnectAttempt {
+        attempt: 3,
+        max_attempts: 4,
+    });
+    assert_eq!(state.device_id(), Some("G111070"));
+    assert_eq!(state.apply(AppMsg::ConnectFailed), ConnectionState::Failed);
+
+    // Nothing to retry once idle
+    assert_eq!(
+        ConnectionState::Disconnected.apply(AppMsg::ReconnectAttempt {
+            attempt: 2,
+            max_attempts: 4,
+        }),
+        ConnectionState::Disconnected
+    );
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::time::Duration;
+
+use tunnel_manager::aws::connect_from_token_file;
+#[cfg(unix)]
+use tunnel_manager::aws::restart_localproxy;
+use tunnel_manager::config::TunnelConfig;
+#[cfg(unix)]
+use tunnel_manager::error::UiError;
+#[cfg(unix)]
+use tunnel_manager::localproxy::CommandTemplate;
+use tunnel_manager::localproxy::ServicePortMap;
+use tunnel_manager::token_file::{TokenFile, permissions_warning};
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "tunnel-manager-token-file-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn sample_file() -> TokenFile {
+    TokenFile {
+        device_id: String::from("G111070"),
+        tunnel_id: String::from("tunnel-123"),
+        region: String::from("eu-west-1"),
+        source_token: String::from("secret-source-token"),
+        services: ServicePortMap::new()
+            .with_service("SSH", 2222)
+            .with_service("GORT", 5600),
+    }
+}
+
+#[test]
+fn test_token_file_round_trip() {
+    let path = scratch_dir("round-trip").join("G111070.toml");
+    let file = sample_file();
+
+    file.write(&path).unwrap();
+
+    assert_eq!(TokenFile::read(&path).unwrap(), file);
+    assert!(permissions_warning(&path).is_none());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_token_file_readable_by_others_is_warned_and_tightened_on_write() {
+    use std::os::unix::fs::PermissionsExt;
+    let path = scratch_dir("permissions").join("G111070.toml");
+    sample_file().write(&path).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+    let warning = permissions_warning(&path).unwrap();
+    assert!(warning.contains("chmod 600"));
+
+    sample_file().write(&path).unwrap();
+    assert!(permissions_warning(&path).is_none());
+}
+
+#[test]
+fn test_token_file_debug_hides_source_token() {
+    let debug = format!("{:?}", sample_file());
+
+    assert!(debug.contains("G111070"));
+    assert!(!debug.contains("secret-source-token"));
+}
+
+#[tokio::test]
+async fn test_connect_from_invalid_token_file_fails_before_localproxy() {
+    let dir = scratch_dir("invalid");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("broken.toml");
+    fs::write(&path, "device_id = \"G111070\"\n").unwrap();
+
+    let err = connect_from_token_file(&path, &TunnelConfig::default())
+        .await
+        .err()
+        .unwrap();
+
+    assert!(err.to_string().contains("Invalid token file"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_connect_fails_when_the_handshake_never_completes() {
+    let path = scratch_dir("handshake").join("G111070.toml");
+    sample_file().write(&path).unwrap();
+    // Stays running without ever reporting the tunnel, like a hung handshake
+    let config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sleep"),
+            args: vec![String::from("30")],
+        }),
+        handshake_timeout: Duration::from_secs(1),
+        ..TunnelConfig::default()
+    };
+
+    let err = connect_from_token_file(&path, &config).await.err().unwrap();
+
+    match UiError::from(&err) {
+        UiError::ConnectionFailed { message } => {
+            assert!(message.starts_with("Proxy failed to establish tunnel connection"))
+        }
+        other => panic!("Expected ConnectionFailed, got {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_restart_keeps_the_connection_region() {
+    let path = scratch_dir("restart-region").join("G111070.toml");
+    sample_file().write(&path).unwrap();
+    // Prints the region it was given, then reports the tunnel up
+    let mut config = TunnelConfig {
+        localproxy_command: Some(CommandTemplate {
+            program: String::from("sh"),
+            args: vec![
+                String::from("-c"),
+                String::from("echo region {region}; echo Listening for new connection; sleep 30"),
+            ],
+        }),
+        ..TunnelConfig::default()
+    };
+
+    let mut connection = connect_from_token_file(&path, &config).await.unwrap();
+    assert_eq!(connection.region, "eu-west-1");
+
+    config
+        .localproxy_region_overrides
+        .insert(String::from("eu-west-1"), String::from("eu-west-1-test"));
+    restart_localproxy(&mut connection, "new-source-token", &config)
+        .await
+        .unwrap();
+
+    assert_eq!(connection.region, "eu-west-1");
+    assert!(
+        connection
+            .output
+            .lines()
+            .contains(&String::from("region eu-west-1-test"))
+    );
+}
@@ -0,0 +1,52 @@
+use tunnel_manager::config::TunnelConfig;
+use tunnel_manager::update::{Release, check_for_update, is_newer};
+
+#[test]
+fn test_is_newer_compares_numerically() {
+    assert!(is_newer("0.2.0", "0.1.0"));
+    assert!(is_newer("0.10.0", "0.9.3"));
+    assert!(is_newer("v1.0.0", "0.9.0"));
+    assert!(!is_newer("0.1.0", "0.1.0"));
+    assert!(!is_newer("0.1", "0.1.0"));
+    assert!(!is_newer("0.0.9", "0.1.0"));
+}
+
+#[test]
+fn test_is_newer_ignores_suffixes_and_garbage() {
+    assert!(!is_newer("0.1.0-beta.1", "0.1.0"));
+    assert!(is_newer("0.2.0+build.5", "0.1.0"));
+    assert!(!is_newer("latest", "0.1.0"));
+    assert!(!is_newer("", "0.1.0"));
+}
+
+#[test]
+fn test_release_reads_plain_and_github_responses() {
+    let plain: Release = serde_json::from_str(r#"{"version": "1.2.0"}"#).unwrap();
+    assert_eq!(plain.version, "1.2.0");
+    assert_eq!(plain.url, None);
+
+    let github: Release = serde_json::from_str(
+        r#"{"tag_name": "v1.2.0", "html_url": "https://example.com/releases/v1.2.0", "draft": false}"#,
+    )
+    .unwrap();
+    assert_eq!(github.version, "v1.2.0");
+    assert_eq!(
+        github.url.as_deref(),
+        Some("https://example.com/releases/v1.2.0")
+    );
+}
+
+#[tokio::test]
+async fn test_update_check_is_off_by_default() {
+    assert_eq!(check_for_update(&TunnelConfig::default()).await, None);
+}
+
+#[tokio::test]
+async fn test_unreachable_endpoint_fails_silently() {
+    let config = TunnelConfig {
+        update_check_url: Some(String::from("http://127.0.0.1:9/latest")),
+        ..TunnelConfig::default()
+    };
+
+    assert_eq!(check_for_update(&config).await, None);
+}